@@ -0,0 +1,618 @@
+//! Authentication of the clients with the `AUTH` command, and access control lists restricting the
+//! commands and keys each user may use.
+//!
+//! The users are loaded from a file holding one user per line, made of its name, the hash of its
+//! password (see `hash_password`) or `nopass`, followed by its rules:
+//!
+//! ```text
+//! # name    password                  rules
+//! admin     pbkdf2-sha256$100000$...  +@all allkeys
+//! reporting pbkdf2-sha256$100000$...  +@read -SCAN ~reports:*
+//! default   nopass                    +PUBLISH
+//! ```
+//!
+//! - `+<command>` and `-<command>` allow or deny a command, e.g. `+GET`.
+//! - `+@<category>` and `-@<category>` allow or deny the commands of a category, one of `read`,
+//!   `write`, `pubsub`, `admin` and `cluster`, or `all` for every command.
+//! - `~<pattern>` allows the keys matching the glob pattern, `allkeys` is short for `~*`.
+//!
+//! The command rules apply in order, the last one matching a command wins, and the commands no
+//! rule matches are denied. `PING`, `AUTH`, `UNSUBSCRIBE` and `UNWATCH` are always allowed. Every
+//! key a command touches must match one of the user's patterns, and the prefix of a `SCAN` or a
+//! `WATCH` must be covered by a pattern such as `reports:*`.
+//!
+//! The connections that did not authenticate run as the `default` user, if it has no password.
+//!
+//! In cluster mode, the nodes authenticate on each other with the credentials of
+//! `ClusterOptions::credentials`. Their user must be allowed the `@cluster` commands, `RSET` and
+//! `MSET`, which the nodes forward to the owners of the keys, and every key, e.g.
+//! `node pbkdf2-sha256$100000$...  +@cluster +RSET +MSET allkeys`.
+//!
+//! Checking a password takes a while on purpose, so it runs on tokio's blocking threads, and the
+//! server closes the connections failing to authenticate `MAX_AUTH_FAILURES` times.
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::env::{Random, SystemRandom};
+use crate::pubsub::glob_match;
+use crate::{Command, Result};
+
+/// Name of the user the connections that did not authenticate run as.
+pub const DEFAULT_USER: &str = "default";
+
+/// Number of PBKDF2 iterations used by `hash_password`.
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+/// Number of failed `AUTH` after which the server closes the connection.
+pub const MAX_AUTH_FAILURES: u32 = 3;
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+/// The names of the commands, as used in the rules.
+const COMMANDS: &[&str] = &[
+    "SET",
+    "GET",
+    "UNSET",
+    "PING",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUBLISH",
+    "WATCH",
+    "UNWATCH",
+    "CHANGES",
+    "SNAPSHOT",
+    "SCAN",
+    "MSET",
+    "ADDNODE",
+    "REMOVENODE",
+    "MIGRATE",
+    "TOPOLOGY",
+    "RGET",
+    "RSET",
+    "REPLICAREAD",
+    "REPLICAWRITE",
+    "TREEHASHES",
+    "BUCKETVERSIONS",
+    "AUTH",
+];
+
+/// The reason a command was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unauthorized {
+    /// The user does not exist, or the password is wrong.
+    InvalidCredentials,
+    /// The connection did not authenticate, and there is no `default` user without password.
+    NotAuthenticated,
+    /// The user is not allowed to run the command.
+    Command { user: String, command: String },
+    /// The user is not allowed to access the key, or the keys starting with the prefix.
+    Key { user: String, key: String },
+}
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unauthorized::InvalidCredentials => write!(f, "Invalid user name or password"),
+            Unauthorized::NotAuthenticated => write!(f, "Authentication required"),
+            Unauthorized::Command { user, command } => {
+                write!(f, "User {} is not allowed to run {}", user, command)
+            }
+            Unauthorized::Key { user, key } => {
+                write!(f, "User {} is not allowed to access the key {}", user, key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+/// The users allowed to connect to the server, along with their rules.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+}
+
+impl Acl {
+    /// Reads the users from the file, see the module's documentation for its format.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+        Acl::parse(&config).map_err(|err| format!("{}: {}", path.display(), err).into())
+    }
+
+    /// Parses the users of a configuration, see the module's documentation for its format.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut acl = Acl::default();
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let user = User::parse(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+            if acl.users.contains_key(&user.name) {
+                return Err(format!("line {}: duplicate user {}", number + 1, user.name).into());
+            }
+            acl.users.insert(user.name.clone(), Arc::new(user));
+        }
+        Ok(acl)
+    }
+
+    pub fn user(&self, name: &str) -> Option<&Arc<User>> {
+        self.users.get(name)
+    }
+
+    /// The user of the connections that did not authenticate, if any.
+    pub fn default_user(&self) -> Option<Arc<User>> {
+        self.users
+            .get(DEFAULT_USER)
+            .filter(|user| user.password.is_none())
+            .cloned()
+    }
+
+    /// Returns the user if the password is the right one.
+    ///
+    /// This hashes the password, which takes a while, even for the users that do not exist, so
+    /// that the time taken does not tell whether they do.
+    pub fn authenticate(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> std::result::Result<Arc<User>, Unauthorized> {
+        let user = match self.users.get(name) {
+            Some(user) => user,
+            None => {
+                self.unknown_user_hash().verify(password);
+                return Err(Unauthorized::InvalidCredentials);
+            }
+        };
+        match &user.password {
+            Some(hash) if !hash.verify(password) => Err(Unauthorized::InvalidCredentials),
+            _ => Ok(user.clone()),
+        }
+    }
+
+    /// A hash no password matches, as slow to check as the slowest hash of the users.
+    fn unknown_user_hash(&self) -> PasswordHash {
+        let iterations = self
+            .users
+            .values()
+            .filter_map(|user| user.password.as_ref())
+            .map(|hash| hash.iterations)
+            .max()
+            .unwrap_or(DEFAULT_ITERATIONS);
+        PasswordHash {
+            iterations,
+            salt: vec![0; 16],
+            hash: vec![],
+        }
+    }
+
+    /// Checks that the user of a connection, `None` if it did not authenticate, may run the
+    /// command.
+    pub fn check(
+        &self,
+        user: Option<&User>,
+        command: &Command,
+    ) -> std::result::Result<(), Unauthorized> {
+        let command = match command {
+            Command::Deadline(_, command) => command.as_ref(),
+            command => command,
+        };
+        if category(command).is_none() {
+            return Ok(());
+        }
+        match user {
+            Some(user) => user.check(command),
+            None => Err(Unauthorized::NotAuthenticated),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    name: String,
+    /// `None` if the user has no password.
+    password: Option<PasswordHash>,
+    rules: Vec<Rule>,
+    keys: Vec<String>,
+}
+
+impl User {
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+        let name = fields.next().ok_or("missing user name")?.to_string();
+        let password = match fields.next().ok_or("missing password")? {
+            "nopass" => None,
+            hash => Some(PasswordHash::parse(hash)?),
+        };
+        let mut user = User {
+            name,
+            password,
+            rules: vec![],
+            keys: vec![],
+        };
+        for field in fields {
+            match field {
+                "allkeys" => user.keys.push(String::from("*")),
+                _ if field.starts_with('~') => user.keys.push(field[1..].to_string()),
+                _ if field.starts_with('+') => user.rules.push(Rule::parse(true, &field[1..])?),
+                _ if field.starts_with('-') => user.rules.push(Rule::parse(false, &field[1..])?),
+                _ => return Err(format!("invalid rule {}", field).into()),
+            }
+        }
+        Ok(user)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, command: &Command) -> std::result::Result<(), Unauthorized> {
+        let allowed = self
+            .rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(command))
+            .is_some_and(|rule| rule.allow);
+        if !allowed {
+            return Err(Unauthorized::Command {
+                user: self.name.clone(),
+                command: command.name().to_string(),
+            });
+        }
+        for key in keys(command) {
+            let allowed = match key {
+                Key::Exact(key) => self.keys.iter().any(|pattern| glob_match(pattern, key)),
+                Key::Prefix(prefix) => self.keys.iter().any(|pattern| covers(pattern, prefix)),
+            };
+            if !allowed {
+                let key = match key {
+                    Key::Exact(key) => key.to_string(),
+                    Key::Prefix(prefix) => format!("{}*", prefix),
+                };
+                return Err(Unauthorized::Key {
+                    user: self.name.clone(),
+                    key,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Category {
+    Read,
+    Write,
+    PubSub,
+    Admin,
+    Cluster,
+}
+
+/// The category of the command, `None` for the ones every user may run.
+fn category(command: &Command) -> Option<Category> {
+    match command {
+        Command::Get(_) | Command::Scan(..) | Command::ReplicatedGet(..) | Command::Watch(_) => {
+            Some(Category::Read)
+        }
+        Command::Set(..) | Command::Clear(_) | Command::MSet(_) | Command::ReplicatedSet(..) => {
+            Some(Category::Write)
+        }
+        Command::Publish(..) | Command::Subscribe(_) | Command::PSubscribe(_) => {
+            Some(Category::PubSub)
+        }
+        Command::Snapshot(_)
+        | Command::Changes(..)
+        | Command::AddNode(_)
+        | Command::RemoveNode(_)
+        | Command::Migrate(..) => Some(Category::Admin),
+        Command::Topology
+        | Command::ReplicaRead(_)
+        | Command::ReplicaWrite(..)
+        | Command::TreeHashes(..)
+        | Command::BucketVersions(..) => Some(Category::Cluster),
+        Command::Deadline(_, command) => category(command),
+        Command::Ping(_) | Command::Auth(..) | Command::Unsubscribe(_) | Command::Unwatch(_) => {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Selector {
+    All,
+    Category(Category),
+    Command(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    selector: Selector,
+}
+
+impl Rule {
+    fn parse(allow: bool, selector: &str) -> Result<Self> {
+        let selector = match selector.strip_prefix('@') {
+            Some("all") => Selector::All,
+            Some("read") => Selector::Category(Category::Read),
+            Some("write") => Selector::Category(Category::Write),
+            Some("pubsub") => Selector::Category(Category::PubSub),
+            Some("admin") => Selector::Category(Category::Admin),
+            Some("cluster") => Selector::Category(Category::Cluster),
+            Some(category) => return Err(format!("unknown category {}", category).into()),
+            None => {
+                let name = selector.to_uppercase();
+                if !COMMANDS.contains(&name.as_str()) {
+                    return Err(format!("unknown command {}", selector).into());
+                }
+                Selector::Command(name)
+            }
+        };
+        Ok(Rule { allow, selector })
+    }
+
+    fn matches(&self, command: &Command) -> bool {
+        match &self.selector {
+            Selector::All => true,
+            Selector::Category(selected) => category(command) == Some(*selected),
+            Selector::Command(name) => command.name() == name,
+        }
+    }
+}
+
+enum Key<'a> {
+    Exact(&'a str),
+    Prefix(&'a str),
+}
+
+/// The keys the command reads or writes.
+fn keys(command: &Command) -> Vec<Key<'_>> {
+    match command {
+        Command::Get(key)
+        | Command::Set(key, _)
+        | Command::Clear(key)
+        | Command::ReplicatedGet(key, _)
+        | Command::ReplicatedSet(key, ..)
+        | Command::ReplicaRead(key)
+        | Command::ReplicaWrite(key, _) => vec![Key::Exact(key)],
+        Command::MSet(entries) => entries.iter().map(|(key, _)| Key::Exact(key)).collect(),
+        Command::Scan(_, prefix, _) | Command::Watch(prefix) => vec![Key::Prefix(prefix)],
+        _ => vec![],
+    }
+}
+
+/// Whether every key starting with the prefix matches the pattern, which is only known for the
+/// patterns made of a literal followed by a single `*`.
+fn covers(pattern: &str, prefix: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(literal) => !literal.contains(['*', '?', '[', '\\']) && prefix.starts_with(literal),
+        None => false,
+    }
+}
+
+/// Hashes the password with PBKDF2-HMAC-SHA256 and a random salt, in the format of the ACL files.
+pub fn hash_password(password: &str) -> String {
+    hash_password_with_iterations(password, DEFAULT_ITERATIONS)
+}
+
+/// Like `hash_password`, with the given number of iterations.
+pub fn hash_password_with_iterations(password: &str, iterations: u32) -> String {
+    let random = SystemRandom::default();
+    let mut salt = [0u8; 16];
+    salt[..8].copy_from_slice(&random.next_u64().to_be_bytes());
+    salt[8..].copy_from_slice(&random.next_u64().to_be_bytes());
+    let hash = PasswordHash {
+        iterations,
+        hash: derive(password, &salt, iterations),
+        salt: salt.to_vec(),
+    };
+    hash.to_string()
+}
+
+#[derive(Debug, Clone)]
+struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Parses a hash of the form `pbkdf2-sha256$<iterations>$<salt>$<hash>`, where the salt and
+    /// the hash are base64 encoded.
+    fn parse(encoded: &str) -> Result<Self> {
+        let invalid = || format!("invalid password hash {}", encoded);
+        let parts: Vec<&str> = encoded.split('$').collect();
+        if parts.len() != 4 || parts[0] != HASH_SCHEME {
+            return Err(invalid().into());
+        }
+        let iterations = parts[1].parse().map_err(|_| invalid())?;
+        let salt = STANDARD_NO_PAD.decode(parts[2]).map_err(|_| invalid())?;
+        let hash = STANDARD_NO_PAD.decode(parts[3]).map_err(|_| invalid())?;
+        if iterations == 0 || hash.is_empty() {
+            return Err(invalid().into());
+        }
+        Ok(PasswordHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let hash = derive(password, &self.salt, self.iterations);
+        // Compares every byte, so that the time taken does not tell how much of the hash matched.
+        hash.iter()
+            .zip(&self.hash)
+            .fold(hash.len() ^ self.hash.len(), |diff, (a, b)| {
+                diff | (a ^ b) as usize
+            })
+            == 0
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}${}${}${}",
+            HASH_SCHEME,
+            self.iterations,
+            STANDARD_NO_PAD.encode(&self.salt),
+            STANDARD_NO_PAD.encode(&self.hash)
+        )
+    }
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut hash = vec![0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(config: &str) -> Acl {
+        Acl::parse(config).unwrap()
+    }
+
+    fn get(key: &str) -> Command {
+        Command::Get(key.into())
+    }
+
+    fn set(key: &str) -> Command {
+        Command::Set(key.into(), "value".into())
+    }
+
+    #[test]
+    fn it_verifies_passwords() {
+        let hash = hash_password_with_iterations("secret", 10);
+        let acl = acl(&format!("alice {} +@all allkeys", hash));
+        assert_eq!(acl.authenticate("alice", "secret").unwrap().name(), "alice");
+        assert_eq!(
+            acl.authenticate("alice", "wrong").unwrap_err(),
+            Unauthorized::InvalidCredentials
+        );
+        assert_eq!(
+            acl.authenticate("bob", "secret").unwrap_err(),
+            Unauthorized::InvalidCredentials
+        );
+
+        // every hash has its own salt.
+        assert_ne!(hash, hash_password_with_iterations("secret", 10));
+    }
+
+    #[test]
+    fn it_hashes_the_passwords_of_unknown_users() {
+        let hash = hash_password_with_iterations("secret", 10);
+        let users = acl(&format!("alice {} +@all allkeys\nbob nopass +@all", hash));
+        let unknown = users.unknown_user_hash();
+        assert_eq!(unknown.iterations, 10);
+        assert!(!unknown.verify("secret"));
+        assert!(!unknown.verify(""));
+
+        let without_passwords = acl("bob nopass +@all");
+        assert_eq!(
+            without_passwords.unknown_user_hash().iterations,
+            DEFAULT_ITERATIONS
+        );
+    }
+
+    #[test]
+    fn it_applies_the_last_matching_rule() {
+        let acl = acl("alice nopass +@read -SCAN +@pubsub allkeys\nbob nopass +@all -@write ~*");
+        let alice = acl.user("alice").unwrap();
+        assert!(acl.check(Some(alice), &get("key")).is_ok());
+        assert!(acl
+            .check(Some(alice), &Command::Publish("a".into(), "b".into()))
+            .is_ok());
+        assert_eq!(
+            acl.check(Some(alice), &Command::Scan(None, "".into(), 10)),
+            Err(Unauthorized::Command {
+                user: "alice".into(),
+                command: "SCAN".into()
+            })
+        );
+        assert!(acl.check(Some(alice), &set("key")).is_err());
+
+        let bob = acl.user("bob").unwrap();
+        assert!(acl.check(Some(bob), &get("key")).is_ok());
+        assert!(acl.check(Some(bob), &Command::MSet(vec![])).is_err());
+        assert!(acl
+            .check(Some(bob), &Command::Deadline(0, Box::new(set("key"))))
+            .is_err());
+        assert!(acl.check(Some(bob), &Command::Ping("".into())).is_ok());
+    }
+
+    #[test]
+    fn it_restricts_keys() {
+        let acl = acl("alice nopass +@all ~users:* ~config");
+        let alice = acl.user("alice").unwrap();
+        assert!(acl.check(Some(alice), &get("users:1")).is_ok());
+        assert!(acl.check(Some(alice), &get("config")).is_ok());
+        assert_eq!(
+            acl.check(Some(alice), &get("orders:1")),
+            Err(Unauthorized::Key {
+                user: "alice".into(),
+                key: "orders:1".into()
+            })
+        );
+        let entries = vec![
+            ("users:1".into(), "a".into()),
+            ("orders:1".into(), "b".into()),
+        ];
+        assert!(acl.check(Some(alice), &Command::MSet(entries)).is_err());
+
+        assert!(acl
+            .check(Some(alice), &Command::Watch("users:1".into()))
+            .is_ok());
+        assert!(acl
+            .check(Some(alice), &Command::Scan(None, "users:".into(), 1))
+            .is_ok());
+        assert!(acl
+            .check(Some(alice), &Command::Scan(None, "user".into(), 1))
+            .is_err());
+        assert!(acl.check(Some(alice), &Command::Watch("".into())).is_err());
+    }
+
+    #[test]
+    fn it_only_lets_the_default_user_without_password_in() {
+        let hash = hash_password_with_iterations("secret", 10);
+        let acl = Acl::parse(&format!("default {} +@all allkeys", hash)).unwrap();
+        assert!(acl.default_user().is_none());
+        assert_eq!(
+            acl.check(None, &get("key")),
+            Err(Unauthorized::NotAuthenticated)
+        );
+        assert!(acl.check(None, &Command::Ping("".into())).is_ok());
+
+        let acl = Acl::parse("# comment\n\ndefault nopass +GET allkeys").unwrap();
+        let user = acl.default_user().unwrap();
+        assert!(acl.check(Some(&user), &get("key")).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_invalid_configurations() {
+        for config in &[
+            "alice",
+            "alice nopass +FLUSHALL",
+            "alice nopass +@everything",
+            "alice nopass allkey",
+            "alice pbkdf2-sha256$10$salt",
+            "alice md5$10$c2FsdA$aGFzaA",
+            "alice nopass\nalice nopass",
+        ] {
+            assert!(Acl::parse(config).is_err(), "{}", config);
+        }
+    }
+}
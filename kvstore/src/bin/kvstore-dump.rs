@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use kvstore::dump::{read_entries, Exporter, Format};
+use kvstore::protocol::Response;
+use kvstore::{snapshot, Client};
+
+const USAGE: &str = "Usage:
+    kvstore-dump export (--addr <address> | --snapshot <file>) [options]
+    kvstore-dump import --addr <address> [options]
+
+Options:
+    --format <jsonl|csv>   File format, guessed from the file extension by default (jsonl)
+    --prefix <prefix>      Only export / import the keys starting with the prefix
+    --base64               Export the values base64 encoded
+    --output <file>        Export to the file instead of the standard output
+    --input <file>         Import from the file instead of the standard input
+    --batch-size <n>       Number of keys written per request when importing (default 500)";
+
+/// Number of keys requested per scan page when exporting from a server.
+const SCAN_PAGE_SIZE: u32 = 500;
+
+#[derive(Default)]
+struct Options {
+    addr: Option<String>,
+    snapshot: Option<String>,
+    format: Option<Format>,
+    prefix: String,
+    base64: bool,
+    output: Option<String>,
+    input: Option<String>,
+    batch_size: usize,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        batch_size: 500,
+        ..Default::default()
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or(format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--addr" => options.addr = Some(value()?),
+            "--snapshot" => options.snapshot = Some(value()?),
+            "--format" => options.format = Some(value()?.parse()?),
+            "--prefix" => options.prefix = value()?,
+            "--base64" => options.base64 = true,
+            "--output" => options.output = Some(value()?),
+            "--input" => options.input = Some(value()?),
+            "--batch-size" => {
+                options.batch_size = value()?
+                    .parse()
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .ok_or_else(|| String::from("Invalid batch size"))?
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Returns the format given on the command line, or guess it from the file extension.
+fn format_of(options: &Options, file: &Option<String>) -> Format {
+    options.format.unwrap_or(match file {
+        Some(file) if file.ends_with(".csv") => Format::Csv,
+        _ => Format::JsonLines,
+    })
+}
+
+async fn export(options: Options) -> kvstore::Result<u64> {
+    let out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let mut exporter = Exporter::new(out, format_of(&options, &options.output), options.base64)?;
+    let mut exported = 0;
+
+    if let Some(path) = &options.snapshot {
+        let (_, mut entries) = snapshot::read(path)?;
+        entries.retain(|(key, _)| key.starts_with(&options.prefix));
+        entries.sort();
+        for (key, value) in entries {
+            exporter.write(&key, &value)?;
+            exported += 1;
+        }
+    } else if let Some(addr) = &options.addr {
+        let mut client = kvstore::create(addr.as_str()).await?;
+        let mut after = None;
+        loop {
+            let page = match client
+                .scan(after.take(), options.prefix.clone(), SCAN_PAGE_SIZE)
+                .await?
+            {
+                Some(Response::Entries(page)) => page,
+                res => return Err(format!("Unexpected response {:?}", res).into()),
+            };
+            if page.is_empty() {
+                break;
+            }
+            for (key, value) in page.iter() {
+                exporter.write(key, value)?;
+            }
+            exported += page.len() as u64;
+            after = page.last().map(|(key, _)| key.clone());
+        }
+    } else {
+        return Err("Either --addr or --snapshot is required to export".into());
+    }
+    exporter.finish()?;
+    Ok(exported)
+}
+
+async fn import(options: Options) -> kvstore::Result<u64> {
+    let addr = options
+        .addr
+        .as_ref()
+        .ok_or("--addr is required to import")?;
+    let input: Box<dyn Read> = match &options.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let mut client = kvstore::create(addr.as_str()).await?;
+    let mut batch = Vec::with_capacity(options.batch_size);
+    let mut imported = 0;
+    for entry in read_entries(input, format_of(&options, &options.input))? {
+        let (key, value) = entry?;
+        if !key.starts_with(&options.prefix) {
+            continue;
+        }
+        batch.push((key, value));
+        if batch.len() >= options.batch_size {
+            imported += flush(&mut client, &mut batch).await?;
+            eprintln!("Imported {} keys", imported);
+        }
+    }
+    imported += flush(&mut client, &mut batch).await?;
+    Ok(imported)
+}
+
+/// Write the pending batch to the server, and return the number of written keys.
+async fn flush(client: &mut Client, batch: &mut Vec<(String, String)>) -> kvstore::Result<u64> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let count = batch.len() as u64;
+    match client.mset(std::mem::take(batch)).await? {
+        Some(Response::Ok(_)) => Ok(count),
+        res => Err(format!("Unexpected response {:?}", res).into()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, options) = match args.split_first() {
+        Some((command, rest)) => match parse_args(rest) {
+            Ok(options) => (command.clone(), options),
+            Err(msg) => {
+                eprintln!("{}\n{}", msg, USAGE);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match command.as_str() {
+        "export" => export(options)
+            .await
+            .map(|n| format!("Exported {} keys", n)),
+        "import" => import(options)
+            .await
+            .map(|n| format!("Imported {} keys", n)),
+        _ => {
+            eprintln!("Unknown command {}\n{}", command, USAGE);
+            std::process::exit(1);
+        }
+    };
+    match result {
+        Ok(summary) => eprintln!("{}", summary),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::io::BufRead;
+
+const USAGE: &str = "Usage: kvstore-passwd
+
+Reads a password from the standard input, and prints its hash for the ACL file of the server.";
+
+fn main() {
+    if std::env::args().len() > 1 {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+
+    let mut password = String::new();
+    if let Err(err) = std::io::stdin().lock().read_line(&mut password) {
+        eprintln!("Unable to read the password: {}", err);
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("The password is empty");
+        std::process::exit(1);
+    }
+    println!("{}", kvstore::acl::hash_password(password));
+}
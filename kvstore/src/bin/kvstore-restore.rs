@@ -0,0 +1,47 @@
+use kvstore::{snapshot, Response};
+
+const USAGE: &str = "Usage: kvstore-restore <snapshot> [address]
+
+Verifies the snapshot file, and loads it into the server listening on the given address if any.";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+
+    let (info, entries) = match snapshot::read(&args[0]) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            eprintln!("Invalid snapshot {}: {}", args[0], err);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Snapshot version {}, taken at {} ms, {} keys",
+        info.version, info.timestamp, info.entries
+    );
+
+    if let Some(addr) = args.get(1) {
+        let mut client = match kvstore::create(addr.as_str()).await {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("Unable to connect to {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        };
+        for (key, value) in entries {
+            let error = match client.set(key.clone(), value).await {
+                Ok(Some(Response::Ok(_))) => continue,
+                Ok(Some(response)) => format!("{:?}", response),
+                Ok(None) => String::from("the connection was closed"),
+                Err(err) => err.to_string(),
+            };
+            eprintln!("Unable to restore the key {}: {}", key, error);
+            std::process::exit(1);
+        }
+        println!("Restored {} keys into {}", info.entries, addr);
+    }
+}
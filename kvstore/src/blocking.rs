@@ -0,0 +1,364 @@
+//! A blocking client, for the programs that do not run an async runtime.
+//!
+//! It offers the same operations as `crate::Client` over a standard `TcpStream`, and shares the
+//! encoding of the protocol with it. Unlike the async client, it does not reconnect when its
+//! connection is lost.
+//!
+//! The client never starts nor needs a tokio runtime, and the encoding it shares with the async
+//! client does not use one either. tokio is still a dependency of the crate though, as the
+//! other modules and the `protocol` re-exports are built on it, so enabling this feature does
+//! not reduce the dependencies.
+
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::client::ClientError;
+use crate::cluster::VectorClock;
+use crate::notify::Notification;
+use crate::protocol::{Command, Parser, Response, Writer};
+use crate::pubsub::Message;
+use crate::Result;
+
+/// A blocking connection to a server.
+pub struct Client {
+    connection: Connection,
+}
+
+pub fn create<A: ToSocketAddrs>(addr: A) -> Result<Client> {
+    let stream = TcpStream::connect(addr)?;
+    Ok(Client {
+        connection: Connection::new(stream),
+    })
+}
+
+/// Creates a client, failing if the connection could not be established in time.
+pub fn create_with_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Client> {
+    let stream = TcpStream::connect_timeout(addr, timeout)?;
+    Ok(Client {
+        connection: Connection::new(stream),
+    })
+}
+
+impl Client {
+    /// Sets how long waiting for a response may take, `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.connection.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Sets how long sending a command may take, `None` waits forever.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.connection.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<Option<Response>> {
+        self.execute(Command::Set(key, value))
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<Response>> {
+        self.execute(Command::Get(key))
+    }
+
+    pub fn unset(&mut self, key: String) -> Result<Option<Response>> {
+        self.execute(Command::Clear(key))
+    }
+
+    pub fn ping(&mut self, key: String) -> Result<Option<Response>> {
+        self.execute(Command::Ping(key))
+    }
+
+    /// Authenticate the connection as the given user.
+    pub fn auth(&mut self, user: String, password: String) -> Result<()> {
+        match self.execute(Command::Auth(user, password))? {
+            Some(Response::Ok(_)) => Ok(()),
+            Some(Response::Error(msg)) => Err(msg.into()),
+            response => Err(format!("Unexpected response {:?}", response).into()),
+        }
+    }
+
+    /// See `crate::Client::publish`.
+    pub fn publish(&mut self, channel: String, message: String) -> Result<Option<Response>> {
+        self.execute(Command::Publish(channel, message))
+    }
+
+    /// See `crate::Client::changes`.
+    pub fn changes(&mut self, from: u64, limit: u32) -> Result<Option<Response>> {
+        self.execute(Command::Changes(from, limit))
+    }
+
+    /// See `crate::Client::snapshot`.
+    pub fn snapshot(&mut self, name: String) -> Result<Option<Response>> {
+        self.execute(Command::Snapshot(name))
+    }
+
+    /// See `crate::Client::scan`.
+    pub fn scan(
+        &mut self,
+        after: Option<String>,
+        prefix: String,
+        count: u32,
+    ) -> Result<Option<Response>> {
+        self.execute(Command::Scan(after, prefix, count))
+    }
+
+    pub fn mset(&mut self, entries: Vec<(String, String)>) -> Result<Option<Response>> {
+        self.execute(Command::MSet(entries))
+    }
+
+    pub fn add_node(&mut self, node: String) -> Result<Option<Response>> {
+        self.execute(Command::AddNode(node))
+    }
+
+    pub fn remove_node(&mut self, node: String) -> Result<Option<Response>> {
+        self.execute(Command::RemoveNode(node))
+    }
+
+    /// See `crate::Client::migrate`.
+    pub fn migrate(&mut self, start: u64, end: u64) -> Result<Option<Response>> {
+        self.execute(Command::Migrate(start, end))
+    }
+
+    /// See `crate::Client::replicated_get`.
+    pub fn replicated_get(&mut self, key: String, quorum: u8) -> Result<Option<Response>> {
+        self.execute(Command::ReplicatedGet(key, quorum))
+    }
+
+    /// See `crate::Client::replicated_set`.
+    pub fn replicated_set(
+        &mut self,
+        key: String,
+        value: String,
+        context: VectorClock,
+        quorum: u8,
+    ) -> Result<Option<Response>> {
+        self.execute(Command::ReplicatedSet(key, Some(value), context, quorum))
+    }
+
+    /// See `crate::Client::replicated_unset`.
+    pub fn replicated_unset(
+        &mut self,
+        key: String,
+        context: VectorClock,
+        quorum: u8,
+    ) -> Result<Option<Response>> {
+        self.execute(Command::ReplicatedSet(key, None, context, quorum))
+    }
+
+    /// Subscribe to the given channels, turning the client into a `Subscriber`.
+    pub fn subscribe(self, channels: &[String]) -> Result<Subscriber> {
+        let mut subscriber = Subscriber {
+            connection: self.connection,
+            pending: VecDeque::new(),
+        };
+        subscriber.subscribe(channels)?;
+        Ok(subscriber)
+    }
+
+    /// Subscribe to the channels matching the given glob patterns, turning the client into a
+    /// `Subscriber`.
+    pub fn psubscribe(self, patterns: &[String]) -> Result<Subscriber> {
+        let mut subscriber = Subscriber {
+            connection: self.connection,
+            pending: VecDeque::new(),
+        };
+        subscriber.psubscribe(patterns)?;
+        Ok(subscriber)
+    }
+
+    /// Watch the keys starting with the given prefixes, turning the client into a `Watcher`.
+    pub fn watch(self, prefixes: &[String]) -> Result<Watcher> {
+        let mut watcher = Watcher {
+            connection: self.connection,
+            pending: VecDeque::new(),
+        };
+        watcher.watch(prefixes)?;
+        Ok(watcher)
+    }
+
+    fn execute(&mut self, command: Command) -> Result<Option<Response>> {
+        self.connection.write_command(&command)?;
+        match self.connection.read_response()? {
+            Some(Response::Unauthorized(msg)) => Err(ClientError::Unauthorized(msg).into()),
+            response => Ok(response),
+        }
+    }
+}
+
+/// A blocking connection in push mode, receiving the messages published on its channels.
+///
+/// It's also an iterator over the messages, ending when the connection is closed.
+pub struct Subscriber {
+    connection: Connection,
+
+    // messages received while waiting for a subscription acknowledgement.
+    pending: VecDeque<Response>,
+}
+
+impl Subscriber {
+    pub fn subscribe(&mut self, channels: &[String]) -> Result<()> {
+        for channel in channels {
+            let command = Command::Subscribe(channel.clone());
+            self.connection
+                .send_push_command(&command, &mut self.pending)?;
+        }
+        Ok(())
+    }
+
+    pub fn psubscribe(&mut self, patterns: &[String]) -> Result<()> {
+        for pattern in patterns {
+            let command = Command::PSubscribe(pattern.clone());
+            self.connection
+                .send_push_command(&command, &mut self.pending)?;
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, names: &[String]) -> Result<()> {
+        for name in names {
+            let command = Command::Unsubscribe(name.clone());
+            self.connection
+                .send_push_command(&command, &mut self.pending)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next published message, `None` is returned if the connection is closed.
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        match self.connection.next_push(&mut self.pending)? {
+            Some(Response::Message(channel, payload)) => Ok(Some(Message { channel, payload })),
+            Some(response) => Err(format!("Unexpected response: {:?}", response).into()),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+/// A blocking connection in push mode, receiving the changes of the keys it watches.
+///
+/// It's also an iterator over the notifications, ending when the connection is closed.
+pub struct Watcher {
+    connection: Connection,
+
+    // events received while waiting for a watch acknowledgement.
+    pending: VecDeque<Response>,
+}
+
+impl Watcher {
+    pub fn watch(&mut self, prefixes: &[String]) -> Result<()> {
+        for prefix in prefixes {
+            let command = Command::Watch(prefix.clone());
+            self.connection
+                .send_push_command(&command, &mut self.pending)?;
+        }
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, prefixes: &[String]) -> Result<()> {
+        for prefix in prefixes {
+            let command = Command::Unwatch(prefix.clone());
+            self.connection
+                .send_push_command(&command, &mut self.pending)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next notification, `None` is returned if the connection is closed.
+    pub fn next_notification(&mut self) -> Result<Option<Notification>> {
+        match self.connection.next_push(&mut self.pending)? {
+            Some(Response::Event(event)) => Ok(Some(Notification::Event(event))),
+            Some(Response::Lagged(missed)) => Ok(Some(Notification::Lagged(missed))),
+            Some(response) => Err(format!("Unexpected response: {:?}", response).into()),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<Notification>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_notification().transpose()
+    }
+}
+
+/// The blocking counterpart of `ConnectionHandler`, on the client side.
+struct Connection {
+    stream: TcpStream,
+
+    // the data received from the server that was not parsed yet.
+    buf: BytesMut,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            buf: BytesMut::with_capacity(1 << 10),
+        }
+    }
+
+    fn write_command(&mut self, command: &Command) -> Result<()> {
+        let mut frame = vec![];
+        Writer::encode_command(&mut frame, command);
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Reads from the socket until a full response is received, `None` is returned when the
+    /// connection is closed and no bytes are left in the buffer.
+    fn read_response(&mut self) -> Result<Option<Response>> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(response) = Parser::next_frame(&mut self.buf, Parser::parse_response)? {
+                return Ok(Some(response));
+            }
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err("Connection closed in the middle of a frame".into());
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Send a push mode command, and wait for its acknowledgement. Responses pushed before the
+    /// acknowledgement are added to `pending`.
+    fn send_push_command(
+        &mut self,
+        command: &Command,
+        pending: &mut VecDeque<Response>,
+    ) -> Result<()> {
+        self.write_command(command)?;
+        loop {
+            match self.read_response()? {
+                Some(Response::Ok(_)) => return Ok(()),
+                Some(Response::Error(msg)) => return Err(msg.into()),
+                Some(Response::Unauthorized(msg)) => {
+                    return Err(ClientError::Unauthorized(msg).into())
+                }
+                Some(response) => pending.push_back(response),
+                None => return Err("Connection closed".into()),
+            }
+        }
+    }
+
+    fn next_push(&mut self, pending: &mut VecDeque<Response>) -> Result<Option<Response>> {
+        if let Some(response) = pending.pop_front() {
+            return Ok(Some(response));
+        }
+        self.read_response()
+    }
+}
@@ -0,0 +1,509 @@
+use crate::executor::Context;
+use crate::metrics::ErrorKind;
+use crate::notify::{Event, Operation};
+use crate::protocol::{get_event, get_u64, put_string, Incomplete};
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default size of a single segment file of the change log.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 16 << 20;
+
+/// How often the expired segments are looked for when the log is idle, the appends remove them
+/// otherwise.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A change applied to the store, as recorded in the change log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// The position of the change in the log, the first change gets the sequence number 1.
+    pub seq: u64,
+    /// The time at which the change was recorded, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+/// Groups the options of the change log.
+#[derive(Debug, Clone)]
+pub struct ChangeLogOptions {
+    /// The directory holding the segment files of the log.
+    pub dir: PathBuf,
+    /// Size after which the active segment is closed and a new one is started.
+    pub segment_bytes: u64,
+    /// Maximum size of the log, older segments are removed once it's exceeded.
+    pub max_bytes: Option<u64>,
+    /// Maximum age of the changes kept in the log, segments holding only older changes are removed.
+    pub max_age: Option<Duration>,
+}
+
+impl ChangeLogOptions {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        ChangeLogOptions {
+            dir: dir.into(),
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            max_bytes: None,
+            max_age: None,
+        }
+    }
+}
+
+/// A persistent and ordered log of the changes applied to the store, used to feed external
+/// consumers (change data capture).
+///
+/// Every change gets a monotonically increasing sequence number, which consumers use as an offset
+/// to resume reading after a disconnect. The log is split into segment files named after the
+/// sequence number of their first change, retention is enforced by removing whole segments, the
+/// active segment is never removed.
+pub struct ChangeLog {
+    options: ChangeLogOptions,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Segments ordered by their first sequence number, the last one is the active segment.
+    segments: Vec<Segment>,
+    /// The active segment, opened in append mode.
+    file: File,
+    next_seq: u64,
+}
+
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+    size: u64,
+    /// Timestamp of the last change of the segment, used to enforce the age based retention.
+    last_timestamp: u64,
+}
+
+impl ChangeLog {
+    /// Opens the change log stored in the options' directory, creating it if needed.
+    ///
+    /// A partially written change at the end of the log (e.g. after a crash) is discarded.
+    pub fn open(options: ChangeLogOptions) -> Result<ChangeLog> {
+        fs::create_dir_all(&options.dir)?;
+        let mut segments = vec![];
+        for entry in fs::read_dir(&options.dir)? {
+            let path = entry?.path();
+            let first_seq = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|seq| seq.parse::<u64>().ok());
+            if let Some(first_seq) = first_seq {
+                segments.push(Segment {
+                    first_seq,
+                    path,
+                    size: 0,
+                    last_timestamp: 0,
+                });
+            }
+        }
+        segments.sort_by_key(|segment| segment.first_seq);
+
+        let mut next_seq = 1;
+        for segment in segments.iter_mut() {
+            let data = fs::read(&segment.path)?;
+            let (changes, valid) = decode_changes(&data)?;
+            if valid < data.len() {
+                // Drop the torn write at the end of the segment.
+                let file = OpenOptions::new().write(true).open(&segment.path)?;
+                file.set_len(valid as u64)?;
+            }
+            segment.size = valid as u64;
+            // An empty segment still tells which sequence number comes next.
+            next_seq = next_seq.max(segment.first_seq);
+            if let Some(last) = changes.last() {
+                segment.last_timestamp = last.timestamp;
+                next_seq = last.seq + 1;
+            }
+        }
+
+        if segments.is_empty() {
+            segments.push(Segment {
+                first_seq: next_seq,
+                path: segment_path(&options.dir, next_seq),
+                size: 0,
+                last_timestamp: 0,
+            });
+        }
+        let active = segments.last().unwrap();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active.path)?;
+
+        Ok(ChangeLog {
+            options,
+            inner: Mutex::new(Inner {
+                segments,
+                file,
+                next_seq,
+            }),
+        })
+    }
+
+    /// Record a change of the given key, and return its sequence number.
+    pub fn append(&self, key: &str, operation: Operation, value: Option<&str>) -> Result<u64> {
+        self.append_all(&[(key, operation, value)])
+    }
+
+    /// Record the changes with a single write, and return the sequence number of the last one.
+    pub fn append_all(&self, changes: &[(&str, Operation, Option<&str>)]) -> Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let timestamp = now_millis();
+        let mut buf = vec![];
+        for (i, (key, operation, value)) in changes.iter().enumerate() {
+            let change = Change {
+                seq: inner.next_seq + i as u64,
+                timestamp,
+                event: Event {
+                    key: key.to_string(),
+                    operation: *operation,
+                    value: value.map(String::from),
+                },
+            };
+            encode_change(&mut buf, &change);
+        }
+        if let Err(err) = inner.file.write_all(&buf) {
+            self.discard_torn_write(&mut inner);
+            return Err(err.into());
+        }
+        inner.next_seq += changes.len() as u64;
+        let last = inner.next_seq - 1;
+
+        let active = inner.segments.last_mut().unwrap();
+        active.size += buf.len() as u64;
+        active.last_timestamp = timestamp;
+        if active.size >= self.options.segment_bytes {
+            self.roll(&mut inner)?;
+        }
+        self.enforce_retention(&mut inner, timestamp)?;
+        Ok(last)
+    }
+
+    /// Read at most `limit` changes, starting from the one with the sequence number `from`.
+    ///
+    /// An error is returned if the requested changes are no longer retained.
+    pub fn read(&self, from: u64, limit: usize) -> Result<Vec<Change>> {
+        // Only the list of segments is read under the lock, so that readers don't block the
+        // appends.
+        let (earliest, paths) = {
+            let inner = self.inner.lock().unwrap();
+            let earliest = inner.segments[0].first_seq;
+            // Skip the segments that only hold changes before the requested offset.
+            let start = inner
+                .segments
+                .iter()
+                .rposition(|segment| segment.first_seq <= from)
+                .unwrap_or(0);
+            let paths: Vec<PathBuf> = inner.segments[start..]
+                .iter()
+                .map(|segment| segment.path.clone())
+                .collect();
+            (earliest, paths)
+        };
+        let not_retained = || {
+            format!(
+                "Offset {} is no longer retained, the earliest offset is {}",
+                from, earliest
+            )
+        };
+        if from < earliest {
+            return Err(not_retained().into());
+        }
+
+        let mut changes = vec![];
+        for path in paths {
+            // The active segment is flushed after every append, a change being appended while it's
+            // read is incomplete and skipped.
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                // The segment was removed by the retention since the list was taken.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(not_retained().into())
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let (decoded, _) = decode_changes(&data)?;
+            for change in decoded.into_iter().filter(|change| change.seq >= from) {
+                if changes.len() == limit {
+                    return Ok(changes);
+                }
+                changes.push(change);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Removes the segments that exceed the retention, which the appends do as well, for the logs
+    /// that may stay idle longer than their maximum age.
+    pub fn expire(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        self.enforce_retention(&mut inner, now_millis())
+    }
+
+    /// The sequence number that will be assigned to the next change.
+    pub fn next_seq(&self) -> u64 {
+        self.inner.lock().unwrap().next_seq
+    }
+
+    /// Drops the part of a failed append that made it to the active segment, the next changes
+    /// would follow it and make the rest of the segment unreadable. If the segment can't be
+    /// truncated it's closed instead, the torn change at its end is skipped when reading it.
+    fn discard_torn_write(&self, inner: &mut Inner) {
+        let size = inner.segments.last().unwrap().size;
+        if inner.file.set_len(size).is_err() {
+            let _ = self.roll(inner);
+        }
+    }
+
+    /// Close the active segment, and start a new one.
+    fn roll(&self, inner: &mut Inner) -> Result<()> {
+        let path = segment_path(&self.options.dir, inner.next_seq);
+        inner.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        inner.segments.push(Segment {
+            first_seq: inner.next_seq,
+            path,
+            size: 0,
+            last_timestamp: 0,
+        });
+        Ok(())
+    }
+
+    fn enforce_retention(&self, inner: &mut Inner, now: u64) -> Result<()> {
+        while inner.segments.len() > 1 {
+            let total: u64 = inner.segments.iter().map(|segment| segment.size).sum();
+            let oldest = &inner.segments[0];
+            let too_big = self.options.max_bytes.is_some_and(|max| total > max);
+            let too_old = self
+                .options
+                .max_age
+                .is_some_and(|age| oldest.last_timestamp + (age.as_millis() as u64) < now);
+            if !too_big && !too_old {
+                break;
+            }
+            fs::remove_file(&oldest.path)?;
+            inner.segments.remove(0);
+        }
+        Ok(())
+    }
+}
+
+/// Removes the expired segments of the change log of the server periodically, if its changes have
+/// a maximum age.
+pub(crate) async fn expire(ctx: Context) {
+    let (changelog, max_age) = match &ctx.changelog {
+        Some(changelog) => match changelog.options.max_age {
+            Some(max_age) => (changelog, max_age),
+            None => return,
+        },
+        None => return,
+    };
+    loop {
+        ctx.env
+            .clock
+            .sleep(max_age.clamp(Duration::from_millis(1), RETENTION_INTERVAL))
+            .await;
+        // The segments are removed again on the next tick, the failure is only counted.
+        if changelog.expire().is_err() {
+            ctx.metrics.error(ErrorKind::Retention);
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", first_seq))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// A change is encoded as its sequence number and timestamp (8 bytes each), followed by the event
+// in the same format used by the protocol.
+fn encode_change(buf: &mut Vec<u8>, change: &Change) {
+    buf.extend_from_slice(&change.seq.to_be_bytes());
+    buf.extend_from_slice(&change.timestamp.to_be_bytes());
+    let event = &change.event;
+    put_string(buf, &event.key);
+    buf.push(match event.operation {
+        Operation::Set => 0,
+        Operation::Unset => 1,
+    });
+    match &event.value {
+        Some(value) => {
+            buf.push(1);
+            put_string(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+/// Decode all of the complete changes in the buffer, and return them along with the number of
+/// bytes they occupy.
+fn decode_changes(data: &[u8]) -> Result<(Vec<Change>, usize)> {
+    let mut cursor = Cursor::new(data);
+    let mut changes = vec![];
+    let mut valid = 0;
+    while (cursor.position() as usize) < data.len() {
+        match decode_change(&mut cursor) {
+            Ok(change) => {
+                changes.push(change);
+                valid = cursor.position() as usize;
+            }
+            Err(err) if err.is::<Incomplete>() => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((changes, valid))
+}
+
+fn decode_change(cursor: &mut Cursor<&[u8]>) -> Result<Change> {
+    let seq = get_u64(cursor)?;
+    let timestamp = get_u64(cursor)?;
+    let event = get_event(cursor)?;
+    Ok(Change {
+        seq,
+        timestamp,
+        event,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvstore-cdc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_append_read() {
+        let dir = temp_dir("append-read");
+        let log = ChangeLog::open(ChangeLogOptions::new(&dir)).unwrap();
+        assert_eq!(log.append("a", Operation::Set, Some("1")).unwrap(), 1);
+        assert_eq!(log.append("b", Operation::Set, Some("2")).unwrap(), 2);
+        assert_eq!(log.append("a", Operation::Unset, None).unwrap(), 3);
+
+        let changes = log.read(2, 10).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].seq, 2);
+        assert_eq!(changes[0].event.key, "b");
+        assert_eq!(changes[1].event.operation, Operation::Unset);
+        assert_eq!(log.read(1, 1).unwrap().len(), 1);
+        assert!(log.read(4, 10).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_all() {
+        let dir = temp_dir("append-all");
+        let log = ChangeLog::open(ChangeLogOptions::new(&dir)).unwrap();
+        log.append("a", Operation::Set, Some("1")).unwrap();
+        let changes = [
+            ("b", Operation::Set, Some("2")),
+            ("a", Operation::Unset, None),
+        ];
+        assert_eq!(log.append_all(&changes).unwrap(), 3);
+        let seqs: Vec<u64> = log.read(1, 10).unwrap().iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert_eq!(log.next_seq(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_resumes_sequence() {
+        let dir = temp_dir("reopen");
+        {
+            let log = ChangeLog::open(ChangeLogOptions::new(&dir)).unwrap();
+            log.append("a", Operation::Set, Some("1")).unwrap();
+            log.append("b", Operation::Set, Some("2")).unwrap();
+        }
+        // simulate a torn write at the end of the active segment.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 1))
+            .unwrap();
+        file.write_all(&[0, 0, 0]).unwrap();
+
+        let log = ChangeLog::open(ChangeLogOptions::new(&dir)).unwrap();
+        assert_eq!(log.next_seq(), 3);
+        assert_eq!(log.append("c", Operation::Set, Some("3")).unwrap(), 3);
+        let keys: Vec<String> = log
+            .read(1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.event.key)
+            .collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_size_retention() {
+        let dir = temp_dir("retention");
+        let mut options = ChangeLogOptions::new(&dir);
+        options.segment_bytes = 64;
+        options.max_bytes = Some(128);
+        let log = ChangeLog::open(options).unwrap();
+        for i in 0..20 {
+            log.append(&format!("key{}", i), Operation::Set, Some("value"))
+                .unwrap();
+        }
+        let earliest = log.inner.lock().unwrap().segments[0].first_seq;
+        assert!(earliest > 1);
+        assert!(log.read(1, 10).is_err());
+        let changes = log.read(earliest, 100).unwrap();
+        assert_eq!(changes.last().unwrap().seq, 20);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_age_retention_of_idle_log() {
+        let dir = temp_dir("age-retention");
+        let mut options = ChangeLogOptions::new(&dir);
+        options.segment_bytes = 64;
+        options.max_age = Some(Duration::from_millis(20));
+        let log = ChangeLog::open(options).unwrap();
+        for i in 0..5 {
+            log.append(&format!("key{}", i), Operation::Set, Some("value"))
+                .unwrap();
+        }
+        assert!(log.read(1, 10).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        log.expire().unwrap();
+        assert!(log.read(1, 10).is_err());
+        assert_eq!(log.inner.lock().unwrap().segments.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_discard_torn_write() {
+        let dir = temp_dir("torn-write");
+        let log = ChangeLog::open(ChangeLogOptions::new(&dir)).unwrap();
+        log.append("a", Operation::Set, Some("1")).unwrap();
+        // simulate an append failing part-way.
+        {
+            let mut inner = log.inner.lock().unwrap();
+            inner.file.write_all(&[0, 0, 0, 0, 0, 0, 0, 9, 1]).unwrap();
+            log.discard_torn_write(&mut inner);
+        }
+        assert_eq!(log.append("b", Operation::Set, Some("2")).unwrap(), 2);
+        let keys: Vec<String> = log
+            .read(1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.event.key)
+            .collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,41 +1,1065 @@
 use crate::handler::ConnectionHandler;
-use tokio::net::{TcpStream, ToSocketAddrs};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 
-use crate::protocol::{Command, Response};
+use crate::cluster::membership::Rng;
+use crate::cluster::VectorClock;
+use crate::env::{BoxFuture, Connection, Network, Random, SystemRandom};
+use crate::notify::Notification;
+use crate::protocol::{epoch_millis, Command, Response};
+use crate::pubsub::Message;
 use crate::Result;
 
+/// A connection to a server.
+///
+/// When the connection is lost, the client reconnects to the server on the next command, waiting
+/// between the attempts as set by its `RetryPolicy`. Idempotent commands are sent again
+/// transparently, the other ones fail with a `ClientError::ConnectionLost` unless the policy allows
+/// retrying them too.
 pub struct Client {
-    handler: ConnectionHandler,
+    // `None` after the connection was lost, until the next command reconnects.
+    handler: Option<ConnectionHandler>,
+
+    // the address to reconnect to, `None` for the clients created through a `Network`, which do
+    // not reconnect.
+    addr: Option<Address>,
+    options: ClientOptions,
+
+    // the deadline shared by the next requests, set by the caller.
+    deadline: Option<SystemTime>,
+
+    // used to add jitter to the backoff delays.
+    rng: Rng,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// How long establishing a connection may take.
+    pub connect_timeout: Option<Duration>,
+    /// How long sending a command may take.
+    pub write_timeout: Option<Duration>,
+    /// How long the response to a command may take to arrive, once the command was sent.
+    pub read_timeout: Option<Duration>,
+    /// The deadline of every request, counted from the moment it's made. The server refuses to
+    /// execute the requests it receives past their deadline, as told by its wall clock, so the
+    /// clocks of the clients and servers must be synchronized.
+    pub request_timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+    /// The user the client authenticates as, on every connection it opens.
+    pub credentials: Option<Credentials>,
+    /// Encrypts the connections to TCP addresses with TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConnector>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Some(Duration::from_secs(10)),
+            write_timeout: None,
+            read_timeout: None,
+            request_timeout: None,
+            retry: RetryPolicy::default(),
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// The name and password of a user, see `kvstore::acl`.
+#[derive(Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new<U: Into<String>, P: Into<String>>(user: U, password: P) -> Self {
+        Credentials {
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+/// How a client reconnects and retries the commands that failed because the connection was lost.
+///
+/// The delay before a retry grows exponentially with the number of failed attempts, and part of
+/// it is random so that clients disconnected at the same time don't reconnect in lockstep.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries of a request, 0 disables them. The lost connections and the
+    /// failed connection attempts of the request all count.
+    pub max_retries: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delays.
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after every failed attempt.
+    pub multiplier: f64,
+    /// Fraction of every delay that is random, between 0 and 1.
+    pub jitter: f64,
+    /// Whether the commands that are not idempotent are retried too, in which case they may be
+    /// applied more than once.
+    pub retry_writes: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_writes: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Checks that the multiplier is a finite number of at least 1, and that the jitter is finite,
+    /// the clients refuse the other policies.
+    pub fn validate(&self) -> Result<()> {
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err(format!("Invalid backoff multiplier {}", self.multiplier).into());
+        }
+        if !self.jitter.is_finite() {
+            return Err(format!("Invalid backoff jitter {}", self.jitter).into());
+        }
+        Ok(())
+    }
+
+    /// The delay before the given retry, counted from 0, before the jitter is applied. The delay
+    /// of an invalid policy is the maximum one.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        Duration::try_from_secs_f64(delay.min(self.max_backoff.as_secs_f64()))
+            .unwrap_or(self.max_backoff)
+    }
+
+    fn delay(&self, retry: u32, rng: &mut Rng) -> Duration {
+        let jitter = match self.jitter.is_nan() {
+            true => 0.0,
+            false => self.jitter.clamp(0.0, 1.0),
+        };
+        self.backoff(retry).mul_f64(1.0 - jitter * rng.next_f64())
+    }
+}
+
+/// Error returned by the client when the connection to the server is lost, when the server does
+/// not answer in time, or when it refuses the command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// The connection was lost after sending the command, which may or may not have been applied.
+    /// `attempts` counts the times it was sent.
+    ConnectionLost { attempts: u32, cause: String },
+    /// No connection could be established with the server, the command was not sent.
+    Unavailable { attempts: u32, cause: String },
+    /// A timeout of the client's options elapsed. The connection is closed, as the response of the
+    /// command could still arrive and be mistaken for the one of the next command.
+    Timeout(Stage),
+    /// The deadline of the request passed, either while the client was waiting for the server or
+    /// before the server could execute the command.
+    DeadlineExceeded,
+    /// The server refused to authenticate the client, or the client's user is not allowed to run
+    /// the command.
+    Unauthorized(String),
+}
+
+/// The step of a request that timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Write,
+    Read,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::ConnectionLost { attempts, cause } => write!(
+                f,
+                "Connection lost after sending the command {} time(s): {}",
+                attempts, cause
+            ),
+            ClientError::Unavailable { attempts, cause } => write!(
+                f,
+                "Unable to connect to the server after {} attempt(s): {}",
+                attempts, cause
+            ),
+            ClientError::Timeout(Stage::Connect) => write!(f, "Timed out while connecting"),
+            ClientError::Timeout(Stage::Write) => write!(f, "Timed out while sending the command"),
+            ClientError::Timeout(Stage::Read) => write!(f, "Timed out waiting for the response"),
+            ClientError::DeadlineExceeded => write!(f, "The deadline of the request passed"),
+            ClientError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+        }
+    }
 }
 
-pub async fn create<T: ToSocketAddrs>(addr: T) -> Result<Client> {
-    let stream = TcpStream::connect(addr).await?;
-    let handler = ConnectionHandler::new(stream);
-    Ok(Client { handler })
+impl std::error::Error for ClientError {}
+
+/// The address of a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// The resolved addresses of a TCP server, tried in order.
+    Tcp(Vec<SocketAddr>),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+/// Scheme of the addresses of Unix domain sockets, e.g. `unix:///run/kvstore.sock`.
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// The values a client can connect to: socket addresses, strings and `(host, port)` pairs resolved
+/// by tokio such as `"localhost:6379"`, or strings starting with `UNIX_SCHEME` for a Unix domain
+/// socket.
+pub trait ToAddress {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>>;
+}
+
+impl ToAddress for Address {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Box::pin(async move { Ok(self) })
+    }
+}
+
+impl ToAddress for SocketAddr {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Address::Tcp(vec![self]).to_address()
+    }
+}
+
+impl ToAddress for SocketAddrV4 {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        SocketAddr::V4(self).to_address()
+    }
+}
+
+impl ToAddress for SocketAddrV6 {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        SocketAddr::V6(self).to_address()
+    }
+}
+
+impl ToAddress for (IpAddr, u16) {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        SocketAddr::from(self).to_address()
+    }
+}
+
+impl ToAddress for (Ipv4Addr, u16) {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        SocketAddr::from(self).to_address()
+    }
+}
+
+impl ToAddress for (Ipv6Addr, u16) {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        SocketAddr::from(self).to_address()
+    }
+}
+
+impl ToAddress for &[SocketAddr] {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Address::Tcp(self.to_vec()).to_address()
+    }
+}
+
+impl<const N: usize> ToAddress for [SocketAddr; N] {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Address::Tcp(self.to_vec()).to_address()
+    }
+}
+
+impl ToAddress for Vec<SocketAddr> {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Address::Tcp(self).to_address()
+    }
+}
+
+impl ToAddress for (String, u16) {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host(self).await?.collect();
+            Ok(Address::Tcp(addrs))
+        })
+    }
+}
+
+impl ToAddress for (&str, u16) {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        (self.0.to_string(), self.1).to_address()
+    }
+}
+
+impl ToAddress for String {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        Box::pin(async move {
+            if let Some(path) = self.strip_prefix(UNIX_SCHEME) {
+                return Ok(Address::Unix(PathBuf::from(path)));
+            }
+            let addrs = tokio::net::lookup_host(self).await?.collect();
+            Ok(Address::Tcp(addrs))
+        })
+    }
+}
+
+impl ToAddress for &String {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        self.clone().to_address()
+    }
+}
+
+impl ToAddress for &str {
+    fn to_address(self) -> BoxFuture<'static, io::Result<Address>> {
+        self.to_string().to_address()
+    }
+}
+
+pub async fn create<T: ToAddress>(addr: T) -> Result<Client> {
+    create_with_options(addr, ClientOptions::default()).await
+}
+
+pub async fn create_with_options<T: ToAddress>(addr: T, options: ClientOptions) -> Result<Client> {
+    options.retry.validate()?;
+    let addr = addr.to_address().await?;
+    let handler = connect(&addr, options.connect_timeout, &options).await?;
+    Ok(Client::new(handler, Some(addr), options))
+}
+
+/// Creates a client connected through the given network, e.g. a simulated one.
+///
+/// The client does not reconnect once its connection is lost.
+pub async fn create_with(network: &dyn Network, addr: &str) -> Result<Client> {
+    let stream = network.connect(addr).await?;
+    create_over(stream, None).await
+}
+
+/// Creates a client over an established connection, which it does not reconnect once lost.
+pub(crate) async fn create_over(
+    stream: Connection,
+    credentials: Option<&Credentials>,
+) -> Result<Client> {
+    let mut handler = ConnectionHandler::new(stream);
+    if let Some(credentials) = credentials {
+        authenticate(&mut handler, credentials).await?;
+    }
+    Ok(Client::new(handler, None, ClientOptions::default()))
 }
 
 impl Client {
+    fn new(handler: ConnectionHandler, addr: Option<Address>, options: ClientOptions) -> Self {
+        Client {
+            handler: Some(handler),
+            addr,
+            options,
+            deadline: None,
+            rng: Rng::new(SystemRandom::default().next_u64()),
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.options.retry
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.options.retry = policy;
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: ClientOptions) {
+        self.options = options;
+    }
+
+    /// Sets the deadline of the next requests, e.g. to propagate the deadline of the work they are
+    /// part of. The earliest of this deadline and the one from the request timeout applies.
+    pub fn set_deadline(&mut self, deadline: Option<SystemTime>) {
+        self.deadline = deadline;
+    }
+
     pub async fn set(&mut self, key: String, value: String) -> Result<Option<Response>> {
         let command = Command::Set(key, value);
-        self.handler.write_command(&command).await?;
-        return self.handler.read_response().await;
+        self.request(&command).await
     }
 
     pub async fn get(&mut self, key: String) -> Result<Option<Response>> {
         let command = Command::Get(key);
-        self.handler.write_command(&command).await?;
-        return self.handler.read_response().await;
+        self.request(&command).await
     }
 
     pub async fn unset(&mut self, key: String) -> Result<Option<Response>> {
         let command = Command::Clear(key);
-        self.handler.write_command(&command).await?;
-        return self.handler.read_response().await;
+        self.request(&command).await
     }
 
     pub async fn ping(&mut self, key: String) -> Result<Option<Response>> {
         let command = Command::Ping(key);
-        self.handler.write_command(&command).await?;
-        return self.handler.read_response().await;
+        self.request(&command).await
+    }
+
+    /// Authenticate as the given user, the client authenticates again as this user every time it
+    /// reconnects.
+    pub async fn auth(&mut self, user: String, password: String) -> Result<()> {
+        let command = Command::Auth(user.clone(), password.clone());
+        match self.request(&command).await? {
+            Some(Response::Ok(_)) => {
+                self.options.credentials = Some(Credentials { user, password });
+                Ok(())
+            }
+            Some(Response::Error(msg)) => Err(msg.into()),
+            response => Err(format!("Unexpected response {:?}", response).into()),
+        }
+    }
+
+    /// Publish the message on the given channel, the response holds the number of subscribers
+    /// that received it.
+    pub async fn publish(&mut self, channel: String, message: String) -> Result<Option<Response>> {
+        let command = Command::Publish(channel, message);
+        self.request(&command).await
+    }
+
+    /// Read at most `limit` changes from the server's change log, starting from the `from`
+    /// sequence number.
+    ///
+    /// To resume reading after a disconnect, consumers should pass the sequence number following
+    /// the last change they processed.
+    pub async fn changes(&mut self, from: u64, limit: u32) -> Result<Option<Response>> {
+        let command = Command::Changes(from, limit);
+        self.request(&command).await
+    }
+
+    /// Ask the server to write a snapshot of the whole store to the file with the given name, in
+    /// its snapshot directory. The response holds the path of the written file.
+    pub async fn snapshot(&mut self, name: String) -> Result<Option<Response>> {
+        let command = Command::Snapshot(name);
+        self.request(&command).await
+    }
+
+    /// Return at most `count` key-value pairs whose key starts with `prefix`, in ascending order of
+    /// keys, starting right after the key `after` if any.
+    ///
+    /// To iterate over all of the matching keys, pass the last key of each page as the `after`
+    /// of the next call, the iteration is done once a page is empty. The server caps the size of
+    /// a page, so a page holding less than `count` pairs is not necessarily the last one.
+    pub async fn scan(
+        &mut self,
+        after: Option<String>,
+        prefix: String,
+        count: u32,
+    ) -> Result<Option<Response>> {
+        let command = Command::Scan(after, prefix, count);
+        self.request(&command).await
+    }
+
+    /// Set all of the given key-value pairs in a single round trip.
+    pub async fn mset(&mut self, entries: Vec<(String, String)>) -> Result<Option<Response>> {
+        let command = Command::MSet(entries);
+        self.request(&command).await
+    }
+
+    /// Add the node to the ring of the cluster the server is part of.
+    pub async fn add_node(&mut self, node: String) -> Result<Option<Response>> {
+        let command = Command::AddNode(node);
+        self.request(&command).await
+    }
+
+    /// Remove the node from the ring of the cluster the server is part of.
+    pub async fn remove_node(&mut self, node: String) -> Result<Option<Response>> {
+        let command = Command::RemoveNode(node);
+        self.request(&command).await
+    }
+
+    /// Ask the server to move the keys it holds in the given range of the ring, and that are owned
+    /// by other nodes, to their owners. The response holds the number of moved keys.
+    pub async fn migrate(&mut self, start: u64, end: u64) -> Result<Option<Response>> {
+        let command = Command::Migrate(start, end);
+        self.request(&command).await
+    }
+
+    /// Read a replicated key, waiting for `quorum` of its replicas to answer, or for the server's
+    /// default read quorum if it's 0.
+    ///
+    /// The response holds the versions of the key, more than one if conflicting writes happened.
+    /// A version without a value means the key was removed.
+    pub async fn replicated_get(&mut self, key: String, quorum: u8) -> Result<Option<Response>> {
+        let command = Command::ReplicatedGet(key, quorum);
+        self.execute(command).await
+    }
+
+    /// Write a replicated key, waiting for `quorum` of its replicas to acknowledge the write, or for
+    /// the server's default write quorum if it's 0.
+    ///
+    /// The context tells which versions the write supersedes, it should be the merged clocks of
+    /// the versions last read (see `cluster::context`), or an empty clock for a new key. Versions
+    /// left out of the context remain as siblings of the written one. The response holds the
+    /// written version.
+    pub async fn replicated_set(
+        &mut self,
+        key: String,
+        value: String,
+        context: VectorClock,
+        quorum: u8,
+    ) -> Result<Option<Response>> {
+        let command = Command::ReplicatedSet(key, Some(value), context, quorum);
+        self.execute(command).await
+    }
+
+    /// Remove a replicated key, the context and quorum work as in `replicated_set`.
+    pub async fn replicated_unset(
+        &mut self,
+        key: String,
+        context: VectorClock,
+        quorum: u8,
+    ) -> Result<Option<Response>> {
+        let command = Command::ReplicatedSet(key, None, context, quorum);
+        self.execute(command).await
+    }
+
+    /// Send any command and return its response, used by the nodes of a cluster to talk to each
+    /// other.
+    pub(crate) async fn execute(&mut self, command: Command) -> Result<Option<Response>> {
+        self.request(&command).await
+    }
+
+    /// Sends the command over the current connection, without reconnecting nor sending it again
+    /// if the connection is lost, e.g. to check the health of the connection.
+    pub(crate) async fn send_once(&mut self, command: &Command) -> Result<Option<Response>> {
+        let handler = match self.handler.as_mut() {
+            Some(handler) => handler,
+            None => return Err("The connection was lost".into()),
+        };
+        let result = match handler.write_command(command).await {
+            Ok(()) => handler.read_response().await,
+            Err(err) => Err(err),
+        };
+        if !matches!(result, Ok(Some(_))) {
+            self.handler = None;
+        }
+        result
+    }
+
+    /// Subscribe to the given channels.
+    ///
+    /// The connection switches to push mode, so the client is consumed and turned into a
+    /// `Subscriber`.
+    pub async fn subscribe(self, channels: &[String]) -> Result<Subscriber> {
+        let mut subscriber = Subscriber::new(self.into_handler().await?);
+        subscriber.subscribe(channels).await?;
+        Ok(subscriber)
+    }
+
+    /// Subscribe to all the channels matching the given glob patterns.
+    ///
+    /// The connection switches to push mode, so the client is consumed and turned into a
+    /// `Subscriber`.
+    pub async fn psubscribe(self, patterns: &[String]) -> Result<Subscriber> {
+        let mut subscriber = Subscriber::new(self.into_handler().await?);
+        subscriber.psubscribe(patterns).await?;
+        Ok(subscriber)
+    }
+
+    /// Watch the changes of all the keys starting with the given prefixes, an empty prefix
+    /// matches every key.
+    ///
+    /// The connection switches to push mode, so the client is consumed and turned into a
+    /// `Watcher`.
+    pub async fn watch(self, prefixes: &[String]) -> Result<Watcher> {
+        let mut watcher = Watcher {
+            handler: self.into_handler().await?,
+            pending: VecDeque::new(),
+        };
+        watcher.watch(prefixes).await?;
+        Ok(watcher)
+    }
+
+    /// Sends the command and returns its response, reconnecting and sending it again as allowed
+    /// by the retry policy if the connection is lost.
+    ///
+    /// The failed connection attempts and the lost connections share the retries of the policy.
+    async fn request(&mut self, command: &Command) -> Result<Option<Response>> {
+        let deadline = self.request_deadline();
+        let wrapped;
+        let command = match deadline {
+            Some(deadline) => {
+                wrapped = Command::Deadline(epoch_millis(deadline), Box::new(command.clone()));
+                &wrapped
+            }
+            None => command,
+        };
+        let policy = self.options.retry.clone();
+        let retry = self.addr.is_some() && (command.is_idempotent() || policy.retry_writes);
+        let (mut attempts, mut failures) = (0, 0);
+        loop {
+            let write = budget(self.options.write_timeout, Stage::Write, deadline)?;
+            let read = budget(self.options.read_timeout, Stage::Read, deadline)?;
+            if self.handler.is_none() {
+                match self.reconnect(deadline, &mut failures).await {
+                    Ok(handler) => self.handler = Some(handler),
+                    // The command was sent before, it may have been applied.
+                    Err(err) if attempts > 0 => {
+                        let cause = match err.downcast_ref() {
+                            Some(ClientError::Unavailable { cause, .. }) => cause.clone(),
+                            _ => return Err(err),
+                        };
+                        return Err(ClientError::ConnectionLost { attempts, cause }.into());
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            let handler = self.handler.as_mut().unwrap();
+            attempts += 1;
+            let result = match within(write, handler.write_command(command)).await {
+                Ok(()) => within(read, handler.read_response()).await,
+                Err(err) => Err(err),
+            };
+            let cause = match result {
+                Ok(Some(Response::DeadlineExceeded)) => {
+                    return Err(ClientError::DeadlineExceeded.into())
+                }
+                Ok(Some(Response::Unauthorized(msg))) => {
+                    return Err(ClientError::Unauthorized(msg).into())
+                }
+                Ok(Some(response)) => return Ok(Some(response)),
+                Ok(None) => String::from("Connection closed"),
+                Err(err) => {
+                    if err.is::<ClientError>() {
+                        self.handler = None;
+                        return Err(err);
+                    }
+                    err.to_string()
+                }
+            };
+            self.handler = None;
+            failures += 1;
+            if !retry || failures > policy.max_retries {
+                return Err(ClientError::ConnectionLost { attempts, cause }.into());
+            }
+            let delay = policy.delay(failures - 1, &mut self.rng);
+            sleep(delay, deadline).await?;
+        }
+    }
+
+    /// The deadline of a request made now.
+    fn request_deadline(&self) -> Option<SystemTime> {
+        let timeout = self
+            .options
+            .request_timeout
+            .map(|timeout| SystemTime::now() + timeout);
+        match (timeout, self.deadline) {
+            (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+            (timeout, deadline) => timeout.or(deadline),
+        }
+    }
+
+    /// Connects to the server, counting the failed attempts in `failures` until they exceed the
+    /// retries of the policy.
+    async fn reconnect(
+        &mut self,
+        deadline: Option<SystemTime>,
+        failures: &mut u32,
+    ) -> Result<ConnectionHandler> {
+        let addr = match &self.addr {
+            Some(addr) => addr,
+            None => {
+                let cause = String::from("The client does not reconnect");
+                return Err(ClientError::Unavailable { attempts: 0, cause }.into());
+            }
+        };
+        let policy = &self.options.retry;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let timeout = budget(self.options.connect_timeout, Stage::Connect, deadline)?;
+            let cause = match within(timeout, connect(addr, None, &self.options)).await {
+                Ok(handler) => return Ok(handler),
+                // Neither waiting nor trying again with the same credentials can help.
+                Err(err)
+                    if matches!(
+                        err.downcast_ref(),
+                        Some(ClientError::DeadlineExceeded | ClientError::Unauthorized(_))
+                    ) =>
+                {
+                    return Err(err)
+                }
+                Err(err) => err.to_string(),
+            };
+            *failures += 1;
+            if *failures > policy.max_retries {
+                return Err(ClientError::Unavailable { attempts, cause }.into());
+            }
+            sleep(policy.delay(*failures - 1, &mut self.rng), deadline).await?;
+        }
+    }
+
+    async fn into_handler(mut self) -> Result<ConnectionHandler> {
+        match self.handler.take() {
+            Some(handler) => Ok(handler),
+            None => self.reconnect(None, &mut 0).await,
+        }
+    }
+}
+
+/// Opens a connection to the address, to the first of the TCP addresses that accepts it, and
+/// authenticates it if the options hold credentials.
+async fn connect(
+    addr: &Address,
+    timeout: Option<Duration>,
+    options: &ClientOptions,
+) -> Result<ConnectionHandler> {
+    let timeout = timeout.map(|timeout| (timeout, ClientError::Timeout(Stage::Connect)));
+    within(timeout, async {
+        let mut handler = open(addr, options).await?;
+        if let Some(credentials) = &options.credentials {
+            authenticate(&mut handler, credentials).await?;
+        }
+        Ok(handler)
+    })
+    .await
+}
+
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn open(addr: &Address, options: &ClientOptions) -> Result<ConnectionHandler> {
+    Ok(match addr {
+        Address::Tcp(addrs) => {
+            let stream = TcpStream::connect(&addrs[..]).await?;
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &options.tls {
+                return Ok(ConnectionHandler::new(tls.connect(stream).await?));
+            }
+            ConnectionHandler::new(stream)
+        }
+        Address::Unix(path) => ConnectionHandler::new(connect_unix(path).await?),
+    })
+}
+
+async fn authenticate(handler: &mut ConnectionHandler, credentials: &Credentials) -> Result<()> {
+    let command = Command::Auth(credentials.user.clone(), credentials.password.clone());
+    handler.write_command(&command).await?;
+    match handler.read_response().await? {
+        Some(Response::Ok(_)) => Ok(()),
+        Some(Response::Unauthorized(msg)) => Err(ClientError::Unauthorized(msg).into()),
+        Some(Response::Error(msg)) => Err(msg.into()),
+        Some(response) => Err(format!("Unexpected response {:?}", response).into()),
+        None => Err("Connection closed".into()),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_: &std::path::Path) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// How long a step of a request may take, along with the error returned once it elapsed: the
+/// timeout of the step, or the time left until the deadline if it's earlier.
+fn budget(
+    timeout: Option<Duration>,
+    stage: Stage,
+    deadline: Option<SystemTime>,
+) -> Result<Option<(Duration, ClientError)>> {
+    let left = match deadline {
+        Some(deadline) => match deadline.duration_since(SystemTime::now()) {
+            Ok(left) => Some(left),
+            Err(_) => return Err(ClientError::DeadlineExceeded.into()),
+        },
+        None => None,
+    };
+    Ok(match (timeout, left) {
+        (Some(timeout), Some(left)) if left < timeout => {
+            Some((left, ClientError::DeadlineExceeded))
+        }
+        (Some(timeout), _) => Some((timeout, ClientError::Timeout(stage))),
+        (None, Some(left)) => Some((left, ClientError::DeadlineExceeded)),
+        (None, None) => None,
+    })
+}
+
+/// Runs the future, failing with the error of the budget if it does not complete in time.
+async fn within<T, F: Future<Output = Result<T>>>(
+    budget: Option<(Duration, ClientError)>,
+    future: F,
+) -> Result<T> {
+    match budget {
+        Some((limit, err)) => match tokio::time::timeout(limit, future).await {
+            Ok(res) => res,
+            Err(_) => Err(err.into()),
+        },
+        None => future.await,
+    }
+}
+
+/// Waits before a retry, unless the deadline passes first.
+async fn sleep(delay: Duration, deadline: Option<SystemTime>) -> Result<()> {
+    if let Some(deadline) = deadline {
+        if SystemTime::now() + delay >= deadline {
+            return Err(ClientError::DeadlineExceeded.into());
+        }
+    }
+    tokio::time::sleep(delay).await;
+    Ok(())
+}
+
+/// A client connection in push mode, receiving the messages published on its channels.
+pub struct Subscriber {
+    handler: ConnectionHandler,
+
+    // messages received while waiting for a subscription acknowledgement.
+    pending: VecDeque<Response>,
+}
+
+impl Subscriber {
+    fn new(handler: ConnectionHandler) -> Self {
+        Subscriber {
+            handler,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Subscribe to more channels.
+    pub async fn subscribe(&mut self, channels: &[String]) -> Result<()> {
+        for channel in channels {
+            let command = Command::Subscribe(channel.clone());
+            send_push_command(&mut self.handler, &command, &mut self.pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to more glob patterns.
+    pub async fn psubscribe(&mut self, patterns: &[String]) -> Result<()> {
+        for pattern in patterns {
+            let command = Command::PSubscribe(pattern.clone());
+            send_push_command(&mut self.handler, &command, &mut self.pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from the given channels or patterns.
+    pub async fn unsubscribe(&mut self, names: &[String]) -> Result<()> {
+        for name in names {
+            let command = Command::Unsubscribe(name.clone());
+            send_push_command(&mut self.handler, &command, &mut self.pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next published message, `None` is returned if the connection is closed.
+    pub async fn next_message(&mut self) -> Result<Option<Message>> {
+        match next_push(&mut self.handler, &mut self.pending).await? {
+            Some(Response::Message(channel, payload)) => Ok(Some(Message { channel, payload })),
+            Some(response) => Err(format!("Unexpected response: {:?}", response).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Convert the subscriber into a `Stream` of the published messages.
+    ///
+    /// The stream ends when the connection is closed, or after yielding the first error.
+    pub fn into_stream(mut self) -> impl Stream<Item = Result<Message>> {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let item = match self.next_message().await {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => return,
+                    Err(err) => Err(err),
+                };
+                let failed = item.is_err();
+                if sender.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+}
+
+/// A client connection in push mode, receiving the changes of the keys it watches.
+pub struct Watcher {
+    handler: ConnectionHandler,
+
+    // events received while waiting for a watch acknowledgement.
+    pending: VecDeque<Response>,
+}
+
+impl Watcher {
+    /// Watch more key prefixes.
+    pub async fn watch(&mut self, prefixes: &[String]) -> Result<()> {
+        for prefix in prefixes {
+            let command = Command::Watch(prefix.clone());
+            send_push_command(&mut self.handler, &command, &mut self.pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Stop watching the given key prefixes.
+    pub async fn unwatch(&mut self, prefixes: &[String]) -> Result<()> {
+        for prefix in prefixes {
+            let command = Command::Unwatch(prefix.clone());
+            send_push_command(&mut self.handler, &command, &mut self.pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next notification, `None` is returned if the connection is closed.
+    ///
+    /// A `Notification::Lagged` is received when the watcher was too slow, and the server had to
+    /// drop some events.
+    pub async fn next_notification(&mut self) -> Result<Option<Notification>> {
+        match next_push(&mut self.handler, &mut self.pending).await? {
+            Some(Response::Event(event)) => Ok(Some(Notification::Event(event))),
+            Some(Response::Lagged(missed)) => Ok(Some(Notification::Lagged(missed))),
+            Some(response) => Err(format!("Unexpected response: {:?}", response).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Convert the watcher into a `Stream` of notifications.
+    ///
+    /// The stream ends when the connection is closed, or after yielding the first error.
+    pub fn into_stream(mut self) -> impl Stream<Item = Result<Notification>> {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let item = match self.next_notification().await {
+                    Ok(Some(notification)) => Ok(notification),
+                    Ok(None) => return,
+                    Err(err) => Err(err),
+                };
+                let failed = item.is_err();
+                if sender.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+}
+
+/// Send a push mode command, and wait for its acknowledgement.
+///
+/// Responses pushed before the acknowledgement are added to `pending`, to be returned by
+/// `next_push` later.
+async fn send_push_command(
+    handler: &mut ConnectionHandler,
+    command: &Command,
+    pending: &mut VecDeque<Response>,
+) -> Result<()> {
+    handler.write_command(command).await?;
+    loop {
+        match handler.read_response().await? {
+            Some(Response::Ok(_)) => return Ok(()),
+            Some(Response::Error(msg)) => return Err(msg.into()),
+            Some(Response::Unauthorized(msg)) => return Err(ClientError::Unauthorized(msg).into()),
+            Some(response) => pending.push_back(response),
+            None => return Err("Connection closed".into()),
+        }
+    }
+}
+
+/// Returns the next response pushed by the server.
+async fn next_push(
+    handler: &mut ConnectionHandler,
+    pending: &mut VecDeque<Response>,
+) -> Result<Option<Response>> {
+    if let Some(response) = pending.pop_front() {
+        return Ok(Some(response));
+    }
+    handler.read_response().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10), Duration::from_secs(1));
+
+        let mut rng = Rng::new(1);
+        for retry in 0..10 {
+            let delay = policy.delay(retry, &mut rng);
+            assert!(delay <= policy.backoff(retry));
+            assert!(delay >= policy.backoff(retry) / 2);
+        }
+    }
+
+    #[test]
+    fn test_invalid_backoff() {
+        let mut rng = Rng::new(1);
+        for (multiplier, jitter) in [
+            (-2.0, 0.5),
+            (0.5, 0.5),
+            (f64::NAN, 0.5),
+            (f64::INFINITY, 0.5),
+            (2.0, f64::NAN),
+            (2.0, f64::INFINITY),
+        ] {
+            let policy = RetryPolicy {
+                multiplier,
+                jitter,
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err(), "{} {}", multiplier, jitter);
+            // the delays of a policy set on an existing client stay bounded.
+            for retry in 0..4 {
+                assert!(policy.delay(retry, &mut rng) <= policy.max_backoff);
+            }
+        }
+        assert!(RetryPolicy::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_to_address() {
+        let addr = "unix:///run/kvstore.sock".to_address().await.unwrap();
+        assert_eq!(addr, Address::Unix(PathBuf::from("/run/kvstore.sock")));
+
+        let addr = "127.0.0.1:6379".to_address().await.unwrap();
+        let expected: SocketAddr = "127.0.0.1:6379".parse().unwrap();
+        assert_eq!(addr, Address::Tcp(vec![expected]));
+
+        let tcp = Address::Tcp(vec![expected]);
+        assert_eq!(("127.0.0.1", 6379).to_address().await.unwrap(), tcp);
+        let ip = Ipv4Addr::LOCALHOST;
+        assert_eq!((IpAddr::from(ip), 6379).to_address().await.unwrap(), tcp);
+        assert_eq!((ip, 6379).to_address().await.unwrap(), tcp);
+        let v4 = SocketAddrV4::new(ip, 6379);
+        assert_eq!(v4.to_address().await.unwrap(), tcp);
+        assert_eq!([expected].to_address().await.unwrap(), tcp);
     }
 }
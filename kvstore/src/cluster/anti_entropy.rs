@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::cluster::merkle::{self, MerkleTree};
+use crate::cluster::{version, Cluster};
+use crate::executor::{store_versions, Context};
+use crate::metrics::ErrorKind;
+use crate::protocol::{Command, Response};
+use crate::{Result, Storage};
+
+/// Groups the options of the anti-entropy process, which keeps the replicas of the replicated keys
+/// in sync in the background.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AntiEntropyOptions {
+    /// Time between two synchronization rounds, every round syncs the node with one of its peers.
+    /// The node only answers the rounds of its peers when it's `None`.
+    pub interval: Option<Duration>,
+    /// Depth of the Merkle trees, which split the hash space in `2^depth` buckets. It must be the
+    /// same on all of the nodes of the cluster, and at most `MAX_TREE_DEPTH`.
+    pub depth: u8,
+    /// Maximum number of tree nodes or buckets exchanged per request.
+    pub batch_size: usize,
+    /// Pause between two requests of a round, leaving room for the client traffic.
+    pub pause: Duration,
+}
+
+impl Default for AntiEntropyOptions {
+    fn default() -> Self {
+        AntiEntropyOptions {
+            interval: None,
+            depth: 10,
+            batch_size: 64,
+            pause: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Maximum depth of the Merkle trees, every tree holds `2^(depth + 1)` hashes.
+pub const MAX_TREE_DEPTH: u8 = 20;
+
+/// The Merkle trees of a node, one per peer, each covering the replicated keys both nodes hold a
+/// replica of.
+///
+/// The replicated keys are indexed by bucket, and the trees and the index are updated along with
+/// every change of the storage. The index is built with a single pass over the storage when first
+/// needed, and the trees are rebuilt from it after the topology of the cluster changed, as the
+/// keys shared with each peer move along.
+pub(crate) struct AntiEntropy {
+    options: AntiEntropyOptions,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The replicated keys held by the node, by bucket, `None` until first built.
+    keys: Option<HashMap<u32, BTreeSet<String>>>,
+    /// The nodes of the cluster when the trees were built.
+    built_for: Vec<String>,
+    trees: HashMap<String, MerkleTree>,
+}
+
+impl AntiEntropy {
+    pub(crate) fn new(options: AntiEntropyOptions) -> Result<Self> {
+        if options.depth > MAX_TREE_DEPTH {
+            return Err(format!(
+                "The depth of the Merkle trees must be at most {}, got {}",
+                MAX_TREE_DEPTH, options.depth
+            )
+            .into());
+        }
+        Ok(AntiEntropy {
+            options,
+            state: Mutex::new(State {
+                keys: None,
+                built_for: vec![],
+                trees: HashMap::new(),
+            }),
+        })
+    }
+
+    pub(crate) fn options(&self) -> &AntiEntropyOptions {
+        &self.options
+    }
+
+    /// Track the change of the value of a key, from `previous` to `current` (`None` once removed),
+    /// only the values holding encoded versions are part of the trees.
+    ///
+    /// Must be called while holding the lock of the storage, so that the trees are updated in the
+    /// same order as the storage.
+    pub(crate) fn record(
+        &self,
+        cluster: &Cluster,
+        key: &str,
+        previous: Option<&str>,
+        current: Option<&str>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let State { keys, trees, .. } = &mut *state;
+        let keys = match keys {
+            Some(keys) => keys,
+            // The change is picked up when the index is first built.
+            None => return,
+        };
+        let depth = self.options.depth;
+        let bucket = merkle::bucket(depth, key);
+        let current = current.filter(|current| version::decode(current).is_ok());
+        let indexed = match current {
+            Some(_) => !keys.entry(bucket).or_default().insert(key.to_string()),
+            None => match keys.get_mut(&bucket) {
+                Some(bucket_keys) => {
+                    let removed = bucket_keys.remove(key);
+                    if bucket_keys.is_empty() {
+                        keys.remove(&bucket);
+                    }
+                    removed
+                }
+                None => false,
+            },
+        };
+        for peer in shared_with(cluster, key) {
+            let tree = trees.entry(peer).or_insert_with(|| MerkleTree::new(depth));
+            if let (true, Some(previous)) = (indexed, previous) {
+                tree.remove(key, previous);
+            }
+            if let Some(current) = current {
+                tree.insert(key, current);
+            }
+        }
+    }
+
+    /// Returns the hashes of the tree shared with the peer, at the given positions of the level.
+    pub(crate) fn hashes(
+        &self,
+        cluster: &Cluster,
+        store: &(dyn Storage + Send + Sync),
+        peer: &str,
+        level: u8,
+        positions: &[u32],
+    ) -> Vec<u64> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state, cluster, store);
+        match state.trees.get(peer) {
+            Some(tree) => tree.hashes(level, positions),
+            None => MerkleTree::new(self.options.depth).hashes(level, positions),
+        }
+    }
+
+    /// Returns the encoded versions of the keys shared with the peer that fall in the buckets.
+    pub(crate) fn bucket_entries(
+        &self,
+        cluster: &Cluster,
+        store: &(dyn Storage + Send + Sync),
+        peer: &str,
+        buckets: &[u32],
+    ) -> Vec<(String, String)> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state, cluster, store);
+        let keys = state.keys.as_ref().unwrap();
+        buckets
+            .iter()
+            .filter_map(|bucket| keys.get(bucket))
+            .flatten()
+            .filter(|key| shared_with(cluster, key).any(|node| node == peer))
+            .filter_map(|key| match store.get(key) {
+                Ok(Some(value)) => Some((key.clone(), value.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Build the index if needed, and rebuild the trees from it if the topology changed since they
+    /// were built.
+    fn refresh(&self, state: &mut State, cluster: &Cluster, store: &(dyn Storage + Send + Sync)) {
+        let depth = self.options.depth;
+        let State {
+            keys,
+            built_for,
+            trees,
+        } = state;
+        let keys = keys.get_or_insert_with(|| {
+            let mut keys: HashMap<u32, BTreeSet<String>> = HashMap::new();
+            // A storage that can't be iterated holds no replicated keys to repair.
+            for (key, value) in store.iter().into_iter().flatten() {
+                if version::decode(value).is_ok() {
+                    keys.entry(merkle::bucket(depth, key))
+                        .or_default()
+                        .insert(key.clone());
+                }
+            }
+            keys
+        });
+        // The ring always holds this node, so the trees are built on the first call.
+        let nodes: Vec<String> = cluster.ring().nodes().map(String::from).collect();
+        if *built_for == nodes {
+            return;
+        }
+        trees.clear();
+        for key in keys.values().flatten() {
+            let value = match store.get(key) {
+                Ok(Some(value)) => value,
+                _ => continue,
+            };
+            for peer in shared_with(cluster, key) {
+                trees
+                    .entry(peer)
+                    .or_insert_with(|| MerkleTree::new(depth))
+                    .insert(key, value);
+            }
+        }
+        *built_for = nodes;
+    }
+}
+
+/// Returns the peers holding a replica of the key, if this node holds one too.
+fn shared_with(cluster: &Cluster, key: &str) -> impl Iterator<Item = String> {
+    let mut replicas = cluster.replicas(key);
+    if !replicas.iter().any(|node| node == cluster.node()) {
+        replicas.clear();
+    }
+    let node = cluster.node().to_string();
+    replicas.into_iter().filter(move |peer| *peer != node)
+}
+
+/// Runs the anti-entropy rounds forever, syncing with one peer after the other.
+pub(crate) async fn run(ctx: Context, interval: Duration) {
+    // Nodes start at a random peer, so they don't all sync with the same one at the same time.
+    let mut round = ctx.env.random.next_u64() as usize;
+    loop {
+        ctx.env.clock.sleep(interval).await;
+        let peers: Vec<String> = match &ctx.cluster {
+            Some(cluster) => cluster
+                .ring()
+                .nodes()
+                .filter(|node| *node != cluster.node() && !ctx.is_unavailable(node))
+                .map(String::from)
+                .collect(),
+            None => return,
+        };
+        if peers.is_empty() {
+            continue;
+        }
+        let peer = &peers[round % peers.len()];
+        round = round.wrapping_add(1);
+        // The peer is synced again in a later round, the failure is only counted.
+        if sync(&ctx, peer).await.is_err() {
+            ctx.metrics.error(ErrorKind::AntiEntropy);
+        }
+    }
+}
+
+/// Reconciles the replicated keys shared with the peer, and returns the number of keys sent to it.
+///
+/// Both trees are walked down from the root, only following the nodes whose hashes differ, so only
+/// the keys of the differing buckets are exchanged. The versions of the peer are merged locally,
+/// and the local versions the peer is missing are sent to it.
+pub(crate) async fn sync(ctx: &Context, peer: &str) -> Result<usize> {
+    let (cluster, anti_entropy) = match (&ctx.cluster, &ctx.anti_entropy) {
+        (Some(cluster), Some(anti_entropy)) => (cluster, anti_entropy),
+        _ => return Err("Cluster mode is not enabled".into()),
+    };
+    let (mut client, _) = cluster.peers().get(&ctx.env, peer).await?;
+    let result = exchange(ctx, cluster, anti_entropy, peer, &mut client).await;
+    // The connection is in an unknown state after a failure, e.g. a response may still be pending.
+    if result.is_ok() {
+        cluster.peers().put(peer, client);
+    }
+    result
+}
+
+async fn exchange(
+    ctx: &Context,
+    cluster: &Cluster,
+    anti_entropy: &AntiEntropy,
+    peer: &str,
+    client: &mut Client,
+) -> Result<usize> {
+    let options = anti_entropy.options().clone();
+    let node = cluster.node().to_string();
+
+    let mut positions = vec![0];
+    for level in 0..=options.depth {
+        let mut differing = vec![];
+        for batch in positions.chunks(options.batch_size.max(1)) {
+            let command = Command::TreeHashes(node.clone(), level, batch.to_vec());
+            let theirs = match client.execute(command).await? {
+                Some(Response::Hashes(hashes)) => hashes,
+                res => return Err(format!("Unexpected response {:?}", res).into()),
+            };
+            let ours = {
+                let guard = ctx.store.lock().unwrap();
+                anti_entropy.hashes(cluster, &**guard, peer, level, batch)
+            };
+            if ours.len() != theirs.len() {
+                return Err("The trees of the nodes have different depths".into());
+            }
+            for (position, (ours, theirs)) in batch.iter().zip(ours.iter().zip(theirs.iter())) {
+                if ours != theirs {
+                    differing.push(*position);
+                }
+            }
+            ctx.env.clock.sleep(options.pause).await;
+        }
+        positions = if level == options.depth {
+            differing
+        } else {
+            differing.iter().flat_map(|p| [2 * p, 2 * p + 1]).collect()
+        };
+        if positions.is_empty() {
+            return Ok(0);
+        }
+    }
+
+    let mut sent = 0;
+    for batch in positions.chunks(options.batch_size.max(1)) {
+        let command = Command::BucketVersions(node.clone(), batch.to_vec());
+        // Ordered maps keep the exchange deterministic, which simulations rely on.
+        let theirs: BTreeMap<String, String> = match client.execute(command).await? {
+            Some(Response::Entries(entries)) => entries.into_iter().collect(),
+            res => return Err(format!("Unexpected response {:?}", res).into()),
+        };
+        let ours: BTreeMap<String, String> = {
+            let guard = ctx.store.lock().unwrap();
+            anti_entropy
+                .bucket_entries(cluster, &**guard, peer, batch)
+                .into_iter()
+                .collect()
+        };
+        for (key, data) in theirs.iter() {
+            if ours.get(key) != Some(data) {
+                let versions = version::decode(data)?;
+                let mut guard = ctx.store.lock().unwrap();
+                if let Err(Response::Error(err)) =
+                    store_versions(ctx, guard.as_mut(), key, &versions)
+                {
+                    return Err(err.into());
+                }
+            }
+        }
+        for (key, data) in ours {
+            if theirs.get(&key) != Some(&data) {
+                let command = Command::ReplicaWrite(key, version::decode(&data)?);
+                match client.execute(command).await? {
+                    Some(Response::Ok(_)) => sent += 1,
+                    res => return Err(format!("Unexpected response {:?}", res).into()),
+                }
+            }
+        }
+        ctx.env.clock.sleep(options.pause).await;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{ClusterOptions, VectorClock, Versioned};
+    use crate::storage::memory::InMemStorage;
+
+    fn replicated(value: &str) -> String {
+        let mut clock = VectorClock::new();
+        clock.set("a", 1);
+        version::encode(&[Versioned {
+            clock,
+            value: Some(value.to_string()),
+        }])
+    }
+
+    fn leaves(anti_entropy: &AntiEntropy, cluster: &Cluster, store: &InMemStorage) -> Vec<u64> {
+        let positions: Vec<u32> = (0..1 << 4).collect();
+        anti_entropy.hashes(cluster, store, "b", 4, &positions)
+    }
+
+    #[test]
+    fn test_trees_follow_the_changes() {
+        let cluster = Cluster::new(ClusterOptions::new("a", vec![String::from("b")]));
+        let options = AntiEntropyOptions {
+            depth: 4,
+            ..Default::default()
+        };
+        let anti_entropy = AntiEntropy::new(options.clone()).unwrap();
+        let mut store = InMemStorage::new();
+        for i in 0..10 {
+            store.set(format!("key{}", i), replicated("v")).unwrap();
+        }
+        store.set(String::from("plain"), String::from("v")).unwrap();
+        leaves(&anti_entropy, &cluster, &store);
+
+        // a replicated key is added, another one is overwritten by a plain value, and a third one
+        // is removed.
+        let changes = [
+            ("key10", None, Some(replicated("v"))),
+            ("key1", Some(replicated("v")), Some(String::from("plain"))),
+            ("key2", Some(replicated("v")), None),
+        ];
+        for (key, previous, current) in changes {
+            match &current {
+                Some(current) => store.set(key.to_string(), current.clone()).unwrap(),
+                None => store.unset(&key.to_string()).map(|_| ()).unwrap(),
+            }
+            anti_entropy.record(&cluster, key, previous.as_deref(), current.as_deref());
+        }
+
+        let rebuilt = AntiEntropy::new(options).unwrap();
+        assert_eq!(
+            leaves(&anti_entropy, &cluster, &store),
+            leaves(&rebuilt, &cluster, &store)
+        );
+        let buckets: Vec<u32> = (0..1 << 4).collect();
+        let mut keys: Vec<String> = anti_entropy
+            .bucket_entries(&cluster, &store, "b", &buckets)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        let mut expected: Vec<String> = [0, 3, 4, 5, 6, 7, 8, 9, 10]
+            .iter()
+            .map(|i| format!("key{}", i))
+            .collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let options = AntiEntropyOptions {
+            depth: MAX_TREE_DEPTH + 1,
+            ..Default::default()
+        };
+        assert!(AntiEntropy::new(options).is_err());
+    }
+}
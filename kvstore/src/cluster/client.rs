@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::client::{self, Client, ClientError, ClientOptions};
+use crate::cluster::Ring;
+use crate::protocol::{Command, Response};
+use crate::Result;
+
+/// Why a command could not be sent to a node.
+enum Failure {
+    /// No connection could be established with the node, the command was not sent.
+    Unreachable,
+    /// The connection was lost after sending the command, which may or may not have been applied.
+    Lost(String),
+    /// Any other error of the client, e.g. the node refused to authenticate it or a timeout
+    /// elapsed, which is returned to the caller.
+    Failed(crate::Err),
+}
+
+/// Error returned by `ClusterClient::mset` when some of the batches were set and another one
+/// failed, the keys of the batches sent after it are not set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialWrite {
+    /// Number of keys set.
+    pub applied: usize,
+    /// The keys that were not set, or may not have been if the connection was lost.
+    pub failed: Vec<String>,
+    /// Why the batch failed.
+    pub cause: String,
+}
+
+impl fmt::Display for PartialWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Set {} key(s), failed to set {} key(s): {}",
+            self.applied,
+            self.failed.len(),
+            self.cause
+        )
+    }
+}
+
+impl std::error::Error for PartialWrite {}
+
+/// Maximum number of times a command is sent to another node before giving up, bounding the
+/// number of redirects followed while the topology is changing.
+const MAX_ATTEMPTS: usize = 5;
+
+/// A client of a sharded cluster.
+///
+/// The client keeps its own copy of the cluster's ring, and one connection per node, which lets it
+/// send every command straight to the node owning its key. Whenever a node redirects a command or
+/// can't be reached, the client refreshes its copy of the topology, and retries the command on the
+/// right node. A write whose connection is lost after it was sent fails with
+/// `ClientError::ConnectionLost` instead, as it may already have been applied.
+///
+/// The connections to the nodes are made with the options of the client, e.g. its credentials,
+/// TLS, timeouts and retry policy.
+pub struct ClusterClient {
+    /// Addresses used to discover the topology when none of the known nodes answers.
+    seeds: Vec<String>,
+    ring: Ring,
+    connections: HashMap<String, Client>,
+    options: ClientOptions,
+}
+
+/// Creates a client of the cluster the given seed nodes are part of.
+pub async fn connect(seeds: &[String]) -> Result<ClusterClient> {
+    connect_with_options(seeds, ClientOptions::default()).await
+}
+
+/// Like `connect`, opening the connections to the nodes with the given options.
+pub async fn connect_with_options(
+    seeds: &[String],
+    options: ClientOptions,
+) -> Result<ClusterClient> {
+    let mut client = ClusterClient {
+        seeds: seeds.to_vec(),
+        ring: Ring::new(0),
+        connections: HashMap::new(),
+        options,
+    };
+    client.refresh().await?;
+    Ok(client)
+}
+
+impl ClusterClient {
+    pub async fn set(&mut self, key: String, value: String) -> Result<Option<Response>> {
+        let command = Command::Set(key.clone(), value);
+        self.execute(&key, command).await
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<Response>> {
+        let command = Command::Get(key.clone());
+        self.execute(&key, command).await
+    }
+
+    pub async fn unset(&mut self, key: String) -> Result<Option<Response>> {
+        let command = Command::Clear(key.clone());
+        self.execute(&key, command).await
+    }
+
+    /// Set all of the given key-value pairs, the pairs are split per owner, and every node gets a
+    /// single `MSet` command. The response holds the total number of set keys.
+    ///
+    /// The batches are sent one after the other. If one fails once others were set, the error is
+    /// a `PartialWrite` telling which keys were not set, otherwise the response or the error of
+    /// the failed batch is returned as is.
+    pub async fn mset(&mut self, entries: Vec<(String, String)>) -> Result<Option<Response>> {
+        let mut remaining = entries;
+        let mut count = 0;
+        for _ in 0..MAX_ATTEMPTS {
+            let mut batches: HashMap<String, Vec<(String, String)>> = HashMap::new();
+            for (key, value) in remaining.drain(..) {
+                let owner = self.owner(&key)?;
+                batches.entry(owner).or_default().push((key, value));
+            }
+            let mut batches = batches.into_iter();
+            let mut stale = false;
+            while let Some((node, batch)) = batches.next() {
+                let failure = match self.send(&node, &Command::MSet(batch.clone())).await {
+                    Ok(Response::Ok(_)) => {
+                        count += batch.len();
+                        continue;
+                    }
+                    // The batch is split again using the refreshed topology.
+                    Ok(Response::Moved(_)) | Err(Failure::Unreachable) => {
+                        stale = true;
+                        remaining.extend(batch);
+                        continue;
+                    }
+                    Ok(response) => Ok(Some(response)),
+                    // Setting a batch twice is harmless, but notifies the watchers and records the
+                    // changes twice, so it's not sent again like any other write.
+                    Err(Failure::Lost(cause)) => {
+                        Err(ClientError::ConnectionLost { attempts: 1, cause }.into())
+                    }
+                    Err(Failure::Failed(err)) => Err(err),
+                };
+                if count == 0 {
+                    return failure;
+                }
+                let cause = match failure {
+                    Ok(Some(Response::Error(msg))) => msg,
+                    Ok(response) => format!("Unexpected response {:?}", response),
+                    Err(err) => err.to_string(),
+                };
+                let failed = batch
+                    .into_iter()
+                    .chain(batches.flat_map(|(_, batch)| batch))
+                    .chain(remaining)
+                    .map(|(key, _)| key)
+                    .collect();
+                return Err(PartialWrite {
+                    applied: count,
+                    failed,
+                    cause,
+                }
+                .into());
+            }
+            if !stale {
+                return Ok(Some(Response::Ok(count.to_string())));
+            }
+            self.refresh().await?;
+        }
+        Err(format!(
+            "Unable to execute the command after {} attempts",
+            MAX_ATTEMPTS
+        )
+        .into())
+    }
+
+    /// The addresses of the nodes of the cluster, as currently known by the client.
+    pub fn nodes(&self) -> Vec<String> {
+        self.ring.nodes().map(String::from).collect()
+    }
+
+    /// Fetch the topology from one of the known nodes, or from the seeds if none of them answers.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let mut candidates: Vec<String> = self.ring.nodes().map(String::from).collect();
+        candidates.extend(self.seeds.iter().cloned());
+        for node in candidates {
+            if let Ok(Response::Topology(vnodes, nodes)) =
+                self.send(&node, &Command::Topology).await
+            {
+                self.ring = Ring::with_nodes(vnodes as usize, nodes.iter());
+                let ring = &self.ring;
+                self.connections.retain(|node, _| ring.contains(node));
+                return Ok(());
+            }
+        }
+        Err("Unable to fetch the topology from any of the known nodes".into())
+    }
+
+    /// Send the command to the owner of the key, following redirects.
+    async fn execute(&mut self, key: &str, command: Command) -> Result<Option<Response>> {
+        let mut node = self.owner(key)?;
+        for _ in 0..MAX_ATTEMPTS {
+            match self.send(&node, &command).await {
+                Ok(Response::Moved(owner)) => {
+                    // The topology changed, the error is ignored as the redirect is enough to
+                    // make progress.
+                    let _ = self.refresh().await;
+                    node = owner;
+                }
+                Ok(response) => return Ok(Some(response)),
+                // The command may have been applied before the connection was lost, so it's only
+                // sent again if that has no effect.
+                Err(Failure::Lost(cause)) if !command.is_idempotent() => {
+                    return Err(ClientError::ConnectionLost { attempts: 1, cause }.into());
+                }
+                Err(Failure::Failed(err)) => return Err(err),
+                // The node is unreachable, it might have left the cluster.
+                Err(_) => {
+                    self.refresh().await?;
+                    node = self.owner(key)?;
+                }
+            }
+        }
+        Err(format!(
+            "Unable to execute the command after {} attempts",
+            MAX_ATTEMPTS
+        )
+        .into())
+    }
+
+    /// Send the command to the node, opening a connection to it if needed. The connection is
+    /// dropped if it's lost or the node is unreachable.
+    ///
+    /// The client of the node reconnects and sends the command again as allowed by its retry
+    /// policy, the failures left are the ones it gave up on.
+    async fn send(
+        &mut self,
+        node: &str,
+        command: &Command,
+    ) -> std::result::Result<Response, Failure> {
+        if !self.connections.contains_key(node) {
+            let client = client::create_with_options(node, self.options.clone())
+                .await
+                .map_err(|err| match err.downcast_ref() {
+                    Some(ClientError::Unauthorized(_)) => Failure::Failed(err),
+                    _ => Failure::Unreachable,
+                })?;
+            self.connections.insert(node.to_string(), client);
+        }
+        let client = self.connections.get_mut(node).unwrap();
+        let failure = match client.execute(command.clone()).await {
+            Ok(Some(response)) => return Ok(response),
+            Ok(None) => Failure::Lost(String::from("The connection was closed")),
+            Err(err) => match err.downcast_ref() {
+                Some(ClientError::Unavailable { .. }) => Failure::Unreachable,
+                Some(ClientError::ConnectionLost { cause, .. }) => Failure::Lost(cause.clone()),
+                _ => return Err(Failure::Failed(err)),
+            },
+        };
+        self.connections.remove(node);
+        Err(failure)
+    }
+
+    fn owner(&self, key: &str) -> Result<String> {
+        match self.ring.owner(key) {
+            Some(owner) => Ok(owner.to_string()),
+            None => Err("The cluster has no nodes".into()),
+        }
+    }
+}
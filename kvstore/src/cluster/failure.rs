@@ -0,0 +1,262 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Groups the options of the phi accrual failure detector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhiOptions {
+    /// Suspicion level above which a peer is considered unavailable. A threshold of 8 means that
+    /// the odds of a false failure are about 1 in 10^8, given the heartbeats seen so far.
+    pub threshold: f64,
+    /// Thresholds overriding the default one for some peers, keyed by address.
+    pub thresholds: HashMap<String, f64>,
+    /// Number of heartbeat inter-arrival times kept per peer to estimate their distribution.
+    pub window: usize,
+    /// Lower bound of the standard deviation of the inter-arrival times, which keeps very regular
+    /// heartbeats from making the detector overly sensitive.
+    pub min_std_deviation: Duration,
+    /// Extra delay tolerated on top of the mean inter-arrival time, e.g. to ride out GC pauses.
+    pub acceptable_pause: Duration,
+    /// Inter-arrival time assumed for a peer until its first heartbeats are seen.
+    pub first_heartbeat_estimate: Duration,
+}
+
+impl Default for PhiOptions {
+    fn default() -> Self {
+        PhiOptions {
+            threshold: 8.0,
+            thresholds: HashMap::new(),
+            window: 1000,
+            min_std_deviation: Duration::from_millis(100),
+            acceptable_pause: Duration::from_millis(0),
+            first_heartbeat_estimate: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A phi accrual failure detector, as described by Hayashibara et al.
+///
+/// Instead of a binary alive / dead output based on a fixed timeout, the detector learns the
+/// distribution of the heartbeat inter-arrival times of every peer, and turns the time elapsed
+/// since the last heartbeat into a continuous suspicion level, phi. A phi of `x` means that the
+/// odds of the peer still being alive are about 1 in `10^x`, so peers with irregular heartbeats
+/// are given more slack than the ones with steady heartbeats.
+///
+/// The detector is safe to share between threads, any peer-to-peer feature can feed it with
+/// heartbeats and query it.
+pub struct FailureDetector {
+    options: PhiOptions,
+    state: Mutex<State>,
+}
+
+struct State {
+    peers: HashMap<String, History>,
+    thresholds: HashMap<String, f64>,
+}
+
+/// The heartbeat history of a peer.
+struct History {
+    last: Instant,
+    /// Inter-arrival times in milliseconds, along with their running sums.
+    intervals: VecDeque<f64>,
+    sum: f64,
+    squares: f64,
+}
+
+impl History {
+    fn push(&mut self, interval: f64, window: usize) {
+        if self.intervals.len() >= window.max(1) {
+            if let Some(oldest) = self.intervals.pop_front() {
+                self.sum -= oldest;
+                self.squares -= oldest * oldest;
+            }
+        }
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.squares += interval * interval;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.intervals.len() as f64
+    }
+
+    fn std_deviation(&self) -> f64 {
+        let mean = self.mean();
+        (self.squares / self.intervals.len() as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+}
+
+impl FailureDetector {
+    pub fn new(options: PhiOptions) -> Self {
+        let thresholds = options.thresholds.clone();
+        FailureDetector {
+            options,
+            state: Mutex::new(State {
+                peers: HashMap::new(),
+                thresholds,
+            }),
+        }
+    }
+
+    /// Record a heartbeat from the peer, received now.
+    pub fn heartbeat(&self, peer: &str) {
+        self.heartbeat_at(peer, Instant::now());
+    }
+
+    pub fn heartbeat_at(&self, peer: &str, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        match state.peers.get_mut(peer) {
+            Some(history) => {
+                let interval = now.saturating_duration_since(history.last);
+                history.last = now;
+                history.push(millis(interval), self.options.window);
+            }
+            None => {
+                // Seed the history with the estimate, so that the first heartbeats don't make the
+                // peer look either very steady or very erratic.
+                let estimate = millis(self.options.first_heartbeat_estimate);
+                let mut history = History {
+                    last: now,
+                    intervals: VecDeque::new(),
+                    sum: 0.0,
+                    squares: 0.0,
+                };
+                history.push(estimate - estimate / 4.0, self.options.window);
+                history.push(estimate + estimate / 4.0, self.options.window);
+                state.peers.insert(peer.to_string(), history);
+            }
+        }
+    }
+
+    /// The current suspicion level of the peer, 0 if no heartbeat was ever received from it.
+    pub fn phi(&self, peer: &str) -> f64 {
+        self.phi_at(peer, Instant::now())
+    }
+
+    pub fn phi_at(&self, peer: &str, now: Instant) -> f64 {
+        let state = self.state.lock().unwrap();
+        let history = match state.peers.get(peer) {
+            Some(history) => history,
+            None => return 0.0,
+        };
+        let elapsed = millis(now.saturating_duration_since(history.last));
+        let mean = history.mean() + millis(self.options.acceptable_pause);
+        let std_deviation = history
+            .std_deviation()
+            .max(millis(self.options.min_std_deviation));
+        phi(elapsed, mean, std_deviation)
+    }
+
+    /// Whether the suspicion level of the peer is below its threshold.
+    pub fn is_available(&self, peer: &str) -> bool {
+        self.is_available_at(peer, Instant::now())
+    }
+
+    pub fn is_available_at(&self, peer: &str, now: Instant) -> bool {
+        self.phi_at(peer, now) < self.threshold(peer)
+    }
+
+    /// The threshold of the peer, the default one unless it was overridden.
+    pub fn threshold(&self, peer: &str) -> f64 {
+        let state = self.state.lock().unwrap();
+        state
+            .thresholds
+            .get(peer)
+            .copied()
+            .unwrap_or(self.options.threshold)
+    }
+
+    /// Override the threshold of the peer, e.g. for a peer behind a flaky link.
+    pub fn set_threshold(&self, peer: &str, threshold: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.thresholds.insert(peer.to_string(), threshold);
+    }
+
+    /// Forget the heartbeat history of the peer, e.g. after it left the cluster.
+    pub fn remove(&self, peer: &str) {
+        self.state.lock().unwrap().peers.remove(peer);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Returns `-log10(P(interval > elapsed))`, assuming normally distributed inter-arrival times. The
+/// normal CDF is approximated with a logistic function, which is accurate enough and keeps phi
+/// finite for large values.
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the detector with heartbeats at the given intervals, in milliseconds, and returns the
+    /// time of the last one.
+    fn feed(detector: &FailureDetector, peer: &str, start: Instant, intervals: &[u64]) -> Instant {
+        let mut now = start;
+        detector.heartbeat_at(peer, now);
+        for interval in intervals {
+            now += Duration::from_millis(*interval);
+            detector.heartbeat_at(peer, now);
+        }
+        now
+    }
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let detector = FailureDetector::new(PhiOptions::default());
+        let last = feed(&detector, "a", Instant::now(), &[1000; 20]);
+        let at = |ms| last + Duration::from_millis(ms);
+
+        assert!(detector.phi_at("a", at(0)) < 0.1);
+        assert!(detector.phi_at("a", at(1000)) < 1.0);
+        assert!(detector.phi_at("a", at(1500)) < detector.phi_at("a", at(2000)));
+        assert!(detector.is_available_at("a", at(1000)));
+        assert!(!detector.is_available_at("a", at(5000)));
+        assert_eq!(detector.phi_at("unknown", at(5000)), 0.0);
+    }
+
+    #[test]
+    fn test_erratic_peers_get_more_slack() {
+        let detector = FailureDetector::new(PhiOptions::default());
+        let start = Instant::now();
+        let steady = feed(&detector, "steady", start, &[1000; 20]);
+        let erratic = feed(&detector, "erratic", start, &[200, 1800].repeat(10));
+        assert_eq!(steady, erratic);
+
+        let later = steady + Duration::from_millis(2500);
+        assert!(detector.phi_at("erratic", later) < detector.phi_at("steady", later));
+        assert!(detector.is_available_at("erratic", later));
+        assert!(!detector.is_available_at("steady", later));
+    }
+
+    #[test]
+    fn test_per_peer_threshold() {
+        let mut options = PhiOptions::default();
+        options.thresholds.insert(String::from("flaky"), 16.0);
+        let detector = FailureDetector::new(options);
+        let start = Instant::now();
+        feed(&detector, "flaky", start, &[1000; 20]);
+        let last = feed(&detector, "other", start, &[1000; 20]);
+
+        let later = last + Duration::from_millis(1700);
+        assert!(detector.is_available_at("flaky", later));
+        assert!(!detector.is_available_at("other", later));
+
+        detector.set_threshold("other", 32.0);
+        assert_eq!(detector.threshold("other"), 32.0);
+        assert!(detector.is_available_at("other", later));
+    }
+}
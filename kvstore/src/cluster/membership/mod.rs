@@ -0,0 +1,660 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::cluster::failure::{FailureDetector, PhiOptions};
+
+mod transport;
+pub use transport::{Transport, UdpTransport};
+
+mod sim;
+pub use sim::{SimNetwork, SimTransport};
+
+/// The state of a member of the cluster, as known by a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    Alive,
+    /// The member failed to answer a probe, it's declared dead unless it refutes the suspicion
+    /// before the suspicion timeout.
+    Suspect,
+    Dead,
+}
+
+/// A member of the cluster, which is also the unit of gossip: nodes spread the latest state they
+/// know of the members they heard about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub addr: String,
+    pub state: MemberState,
+    /// Only the member itself bumps its incarnation, to refute a suspicion. Updates about a member
+    /// with a higher incarnation override the ones with a lower incarnation.
+    pub incarnation: u64,
+}
+
+/// The members of the cluster known by a node, sorted by address, the node included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClusterView {
+    pub members: Vec<Member>,
+}
+
+impl ClusterView {
+    /// Returns the state of the member, `None` if it's unknown.
+    pub fn state(&self, addr: &str) -> Option<MemberState> {
+        self.members
+            .iter()
+            .find(|member| member.addr == addr)
+            .map(|member| member.state)
+    }
+
+    /// Returns the addresses of the members that are not dead, suspects included.
+    pub fn live(&self) -> impl Iterator<Item = &str> {
+        self.members
+            .iter()
+            .filter(|member| member.state != MemberState::Dead)
+            .map(|member| member.addr.as_str())
+    }
+}
+
+/// The kind of a message exchanged between the nodes, along with its sequence number which ties
+/// the acknowledgements to the probes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKind {
+    Ping(u64),
+    /// Asks the receiver to probe the member with the given address on behalf of the sender.
+    PingReq(u64, String),
+    Ack(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The address of the sender, as known by the other members.
+    pub from: String,
+    pub kind: MessageKind,
+    /// Membership updates piggybacked on the message.
+    pub gossip: Vec<Member>,
+}
+
+/// Groups the options of the membership protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MembershipOptions {
+    /// Every protocol period, the node probes one of the other members.
+    pub protocol_period: Duration,
+    /// Time to wait for the acknowledgement of a direct probe before asking other members to probe
+    /// the target, it must be shorter than the protocol period.
+    pub ack_timeout: Duration,
+    /// Number of members asked to probe a target that did not answer a direct probe.
+    pub indirect_probes: usize,
+    /// Time given to a suspect member to refute the suspicion before it's declared dead.
+    pub suspicion_timeout: Duration,
+    /// Maximum number of membership updates piggybacked on a single message.
+    pub max_gossip: usize,
+    /// Every update is piggybacked `retransmit_multiplier * log2(n)` times, `n` being the number of
+    /// members, which is enough for it to reach all of them with a high probability.
+    pub retransmit_multiplier: u32,
+    /// Settings of the failure detector fed with the messages received from the other members.
+    pub phi: PhiOptions,
+}
+
+impl Default for MembershipOptions {
+    fn default() -> Self {
+        MembershipOptions {
+            protocol_period: Duration::from_secs(1),
+            ack_timeout: Duration::from_millis(300),
+            indirect_probes: 3,
+            suspicion_timeout: Duration::from_secs(5),
+            max_gossip: 8,
+            retransmit_multiplier: 3,
+            phi: PhiOptions::default(),
+        }
+    }
+}
+
+/// The membership subsystem of a node, based on the SWIM protocol.
+///
+/// Every protocol period, the node probes a member picked in a round-robin fashion. If the member
+/// does not acknowledge the probe in time, other members are asked to probe it too, and if none of
+/// the probes are acknowledged by the end of the period, the member is suspected. A suspect member
+/// that does not refute the suspicion in time is declared dead. Membership updates are spread by
+/// piggybacking them on the probes and acknowledgements, so there is no extra traffic.
+///
+/// Every message received from a member also counts as a heartbeat of a phi accrual failure
+/// detector, which gives a continuous suspicion level of the members in between.
+///
+/// The protocol runs in a background task, stopped when the `Membership` is dropped.
+pub struct Membership {
+    view: watch::Receiver<ClusterView>,
+    detector: Arc<FailureDetector>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Membership {
+    /// Starts the membership protocol for the node with the given address, joining the cluster
+    /// through the seeds. Messages are sent with the transport, and received from `incoming`.
+    pub fn start<T: Transport>(
+        addr: String,
+        seeds: &[String],
+        transport: T,
+        incoming: mpsc::Receiver<Message>,
+        options: MembershipOptions,
+    ) -> Self {
+        let (view_tx, view) = watch::channel(ClusterView::default());
+        let (stop, stopped) = oneshot::channel();
+        let detector = Arc::new(FailureDetector::new(options.phi.clone()));
+        let mut swim = Swim::new(addr, transport, options, view_tx, detector.clone());
+        for seed in seeds {
+            if *seed != swim.addr {
+                swim.apply(Member {
+                    addr: seed.clone(),
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                });
+            }
+        }
+        swim.enqueue(swim.local());
+        swim.publish();
+        tokio::spawn(swim.run(incoming, stopped));
+        Membership {
+            view,
+            detector,
+            _stop: stop,
+        }
+    }
+
+    /// The current view of the cluster.
+    pub fn view(&self) -> ClusterView {
+        self.view.borrow().clone()
+    }
+
+    /// Returns a receiver notified whenever the view of the cluster changes.
+    pub fn subscribe(&self) -> watch::Receiver<ClusterView> {
+        self.view.clone()
+    }
+
+    /// The failure detector tracking the heartbeats of the members.
+    pub fn detector(&self) -> &Arc<FailureDetector> {
+        &self.detector
+    }
+}
+
+/// The probe of the current protocol period.
+struct Probe {
+    target: String,
+    seq: u64,
+    /// Time at which other members are asked to probe the target.
+    indirect_at: Instant,
+    indirect_sent: bool,
+}
+
+/// A probe sent on behalf of another member, the acknowledgement is forwarded to it.
+struct Relay {
+    origin: String,
+    origin_seq: u64,
+    expires_at: Instant,
+}
+
+struct Swim<T> {
+    addr: String,
+    incarnation: u64,
+    options: MembershipOptions,
+    transport: T,
+    rng: Rng,
+    /// All of the known members, but this node.
+    members: HashMap<String, Member>,
+    /// The time at which the suspect members are declared dead.
+    suspicions: HashMap<String, Instant>,
+    /// The updates to piggyback, along with the remaining number of times to send them.
+    gossip: Vec<(Member, u32)>,
+    probe: Option<Probe>,
+    relays: HashMap<u64, Relay>,
+    next_seq: u64,
+    next_period: Instant,
+    /// The members left to probe in the current round, in a random order.
+    targets: Vec<String>,
+    view: watch::Sender<ClusterView>,
+    detector: Arc<FailureDetector>,
+}
+
+impl<T: Transport> Swim<T> {
+    fn new(
+        addr: String,
+        transport: T,
+        options: MembershipOptions,
+        view: watch::Sender<ClusterView>,
+        detector: Arc<FailureDetector>,
+    ) -> Self {
+        Swim {
+            rng: Rng::new(crate::cluster::hash(&addr)),
+            addr,
+            incarnation: 0,
+            next_period: Instant::now() + options.protocol_period,
+            options,
+            transport,
+            members: HashMap::new(),
+            suspicions: HashMap::new(),
+            gossip: vec![],
+            probe: None,
+            relays: HashMap::new(),
+            next_seq: 0,
+            targets: vec![],
+            view,
+            detector,
+        }
+    }
+
+    async fn run(mut self, mut incoming: mpsc::Receiver<Message>, mut stop: oneshot::Receiver<()>) {
+        loop {
+            let deadline = self.next_deadline();
+            tokio::select! {
+                _ = &mut stop => return,
+                message = incoming.recv() => match message {
+                    Some(message) => self.handle(message),
+                    None => return,
+                },
+                _ = tokio::time::sleep_until(deadline) => self.tick(Instant::now()),
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let mut deadline = self.next_period;
+        if let Some(probe) = self.probe.as_ref().filter(|probe| !probe.indirect_sent) {
+            deadline = deadline.min(probe.indirect_at);
+        }
+        for at in self.suspicions.values() {
+            deadline = deadline.min(*at);
+        }
+        deadline
+    }
+
+    fn tick(&mut self, now: Instant) {
+        if let Some(probe) = self.probe.as_mut() {
+            if !probe.indirect_sent && now >= probe.indirect_at {
+                probe.indirect_sent = true;
+                let (seq, target) = (probe.seq, probe.target.clone());
+                let mut helpers: Vec<String> = self
+                    .members
+                    .values()
+                    .filter(|member| member.state != MemberState::Dead && member.addr != target)
+                    .map(|member| member.addr.clone())
+                    .collect();
+                helpers.sort();
+                self.rng.shuffle(&mut helpers);
+                helpers.truncate(self.options.indirect_probes);
+                for helper in helpers {
+                    self.send(&helper, MessageKind::PingReq(seq, target.clone()));
+                }
+            }
+        }
+
+        let expired: Vec<String> = self
+            .suspicions
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            if let Some(member) = self.members.get(&addr) {
+                let dead = Member {
+                    state: MemberState::Dead,
+                    ..member.clone()
+                };
+                self.apply(dead);
+            }
+        }
+
+        if now >= self.next_period {
+            if let Some(probe) = self.probe.take() {
+                self.suspect(&probe.target);
+            }
+            self.next_period = now + self.options.protocol_period;
+            self.relays.retain(|_, relay| relay.expires_at > now);
+            self.start_probe(now);
+        }
+    }
+
+    fn start_probe(&mut self, now: Instant) {
+        let target = loop {
+            if self.targets.is_empty() {
+                self.targets = self
+                    .members
+                    .values()
+                    .filter(|member| member.state != MemberState::Dead)
+                    .map(|member| member.addr.clone())
+                    .collect();
+                self.targets.sort();
+                self.rng.shuffle(&mut self.targets);
+                if self.targets.is_empty() {
+                    return;
+                }
+            }
+            let target = self.targets.pop().unwrap();
+            // Members might have died since the round started.
+            if matches!(self.members.get(&target), Some(member) if member.state != MemberState::Dead)
+            {
+                break target;
+            }
+        };
+        let seq = self.next_seq();
+        self.send(&target, MessageKind::Ping(seq));
+        self.probe = Some(Probe {
+            target,
+            seq,
+            indirect_at: now + self.options.ack_timeout,
+            indirect_sent: false,
+        });
+    }
+
+    fn handle(&mut self, message: Message) {
+        if message.from != self.addr {
+            self.detector.heartbeat(&message.from);
+        }
+        for update in message.gossip {
+            self.apply(update);
+        }
+        match self.members.get(&message.from) {
+            // The gossip about the sender might have been lost, the message itself is enough to
+            // know it joined.
+            None if message.from != self.addr => self.apply(Member {
+                addr: message.from.clone(),
+                state: MemberState::Alive,
+                incarnation: 0,
+            }),
+            // The sender is alive but might not know it's suspected, the reply tells it so it can
+            // refute the suspicion.
+            Some(member) if member.state != MemberState::Alive => self.enqueue(member.clone()),
+            _ => {}
+        }
+        match message.kind {
+            MessageKind::Ping(seq) => self.send(&message.from, MessageKind::Ack(seq)),
+            MessageKind::PingReq(seq, target) => {
+                let local_seq = self.next_seq();
+                self.relays.insert(
+                    local_seq,
+                    Relay {
+                        origin: message.from,
+                        origin_seq: seq,
+                        expires_at: Instant::now() + self.options.protocol_period,
+                    },
+                );
+                self.send(&target, MessageKind::Ping(local_seq));
+            }
+            MessageKind::Ack(seq) => {
+                if self.probe.as_ref().map(|probe| probe.seq) == Some(seq) {
+                    self.probe = None;
+                } else if let Some(relay) = self.relays.remove(&seq) {
+                    self.send(&relay.origin, MessageKind::Ack(relay.origin_seq));
+                }
+            }
+        }
+    }
+
+    fn suspect(&mut self, addr: &str) {
+        if let Some(member) = self.members.get(addr) {
+            if member.state == MemberState::Alive {
+                let suspect = Member {
+                    state: MemberState::Suspect,
+                    ..member.clone()
+                };
+                self.apply(suspect);
+            }
+        }
+    }
+
+    /// Apply a membership update, ignoring it if it's older than what the node already knows.
+    fn apply(&mut self, update: Member) {
+        if update.addr == self.addr {
+            // Refute the suspicion by bumping the incarnation, which overrides it everywhere.
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                self.enqueue(self.local());
+                self.publish();
+            }
+            return;
+        }
+        let newer = match self.members.get(&update.addr) {
+            None => true,
+            Some(current) => match (update.state, current.state) {
+                (MemberState::Alive, _) => update.incarnation > current.incarnation,
+                (MemberState::Suspect, MemberState::Alive) => {
+                    update.incarnation >= current.incarnation
+                }
+                (MemberState::Suspect, _) => update.incarnation > current.incarnation,
+                (MemberState::Dead, MemberState::Dead) => false,
+                (MemberState::Dead, _) => update.incarnation >= current.incarnation,
+            },
+        };
+        if !newer {
+            return;
+        }
+        if self.members.get(&update.addr).map(|member| member.state) == Some(MemberState::Dead) {
+            // The member rejoined, the gap since it died would skew its heartbeat history.
+            self.detector.remove(&update.addr);
+        }
+        match update.state {
+            MemberState::Suspect => {
+                let at = Instant::now() + self.options.suspicion_timeout;
+                self.suspicions.insert(update.addr.clone(), at);
+            }
+            _ => {
+                self.suspicions.remove(&update.addr);
+            }
+        }
+        self.members.insert(update.addr.clone(), update.clone());
+        self.enqueue(update);
+        self.publish();
+    }
+
+    fn local(&self) -> Member {
+        Member {
+            addr: self.addr.clone(),
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+        }
+    }
+
+    /// Queue the update to be piggybacked on the next messages.
+    fn enqueue(&mut self, update: Member) {
+        self.gossip.retain(|(queued, _)| queued.addr != update.addr);
+        let members = self.members.len() as u32 + 1;
+        // ceil(log2(members + 1))
+        let log = 32 - members.leading_zeros();
+        self.gossip
+            .push((update, self.options.retransmit_multiplier * log.max(1)));
+    }
+
+    /// Returns the updates to piggyback on a message, the least sent ones first.
+    fn piggyback(&mut self) -> Vec<Member> {
+        self.gossip
+            .sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+        let count = self.gossip.len().min(self.options.max_gossip);
+        let updates = self.gossip[..count]
+            .iter_mut()
+            .map(|(update, remaining)| {
+                *remaining -= 1;
+                update.clone()
+            })
+            .collect();
+        self.gossip.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+
+    fn send(&mut self, to: &str, kind: MessageKind) {
+        let message = Message {
+            from: self.addr.clone(),
+            kind,
+            gossip: self.piggyback(),
+        };
+        self.transport.send(to, message);
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    fn publish(&self) {
+        let mut members: Vec<Member> = self.members.values().cloned().collect();
+        members.push(self.local());
+        members.sort_by(|a, b| a.addr.cmp(&b.addr));
+        let _ = self.view.send(ClusterView { members });
+    }
+}
+
+/// A small xorshift pseudo random generator, good enough to pick probe targets and simulate
+/// network faults, and reproducible given the seed.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // The state must never be 0.
+        Rng(seed | 1)
+    }
+
+    /// Scrambles the seed with splitmix64 first, so that close seeds, e.g. the ones of the runs
+    /// of a simulation, give unrelated sequences.
+    #[cfg(any(test, feature = "simulation", feature = "testing"))]
+    pub(crate) fn scrambled(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Rng::new(z ^ (z >> 31))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Returns a number in `[0, 1)`.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn shuffle<V>(&mut self, values: &mut [V]) {
+        for i in (1..values.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            values.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> MembershipOptions {
+        MembershipOptions {
+            protocol_period: Duration::from_millis(200),
+            ack_timeout: Duration::from_millis(60),
+            suspicion_timeout: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    /// Starts the given number of nodes on the network, all of them joining through the first one.
+    fn start_nodes(network: &SimNetwork, count: usize) -> Vec<Membership> {
+        let seeds = vec![String::from("node0")];
+        (0..count)
+            .map(|i| {
+                let addr = format!("node{}", i);
+                let (transport, incoming) = network.endpoint(&addr);
+                Membership::start(addr, &seeds, transport, incoming, options())
+            })
+            .collect()
+    }
+
+    fn states(membership: &Membership) -> Vec<MemberState> {
+        membership
+            .view()
+            .members
+            .iter()
+            .map(|member| member.state)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_join() {
+        let network = SimNetwork::new(1);
+        let nodes = start_nodes(&network, 5);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        for node in nodes.iter() {
+            assert_eq!(states(node), vec![MemberState::Alive; 5]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_detect_failure() {
+        let network = SimNetwork::new(2);
+        let mut nodes = start_nodes(&network, 5);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let mut view = nodes[0].subscribe();
+        // stopping the node makes it silent, as if it crashed.
+        drop(nodes.remove(3));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        for node in nodes.iter() {
+            assert_eq!(node.view().state("node3"), Some(MemberState::Dead));
+            assert_eq!(node.view().live().count(), 4);
+        }
+        assert!(view.changed().await.is_ok());
+        assert_eq!(view.borrow().state("node3"), Some(MemberState::Dead));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failure_detector() {
+        let network = SimNetwork::new(5);
+        network.set_delay(Duration::from_millis(1), Duration::from_millis(20));
+        let mut nodes = start_nodes(&network, 5);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        for node in nodes.iter() {
+            for peer in node.view().members.iter() {
+                assert!(node.detector().is_available(&peer.addr));
+            }
+        }
+
+        drop(nodes.remove(3));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let detector = nodes[0].detector();
+        assert!(detector.phi("node3") > detector.threshold("node3"));
+        assert!(detector.phi("node1") < detector.threshold("node1"));
+
+        detector.set_threshold("node3", f64::MAX);
+        assert!(detector.is_available("node3"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refute_suspicion() {
+        let network = SimNetwork::new(3);
+        let nodes = start_nodes(&network, 4);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        // the node is unreachable long enough to be suspected, but not declared dead.
+        network.isolate("node2");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        network.heal("node2");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        for node in nodes.iter() {
+            let view = node.view();
+            let member = view.members.iter().find(|m| m.addr == "node2").unwrap();
+            assert_eq!(member.state, MemberState::Alive);
+            assert!(member.incarnation > 0);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lossy_network() {
+        let network = SimNetwork::new(4);
+        network.set_loss(0.1);
+        network.set_delay(Duration::from_millis(1), Duration::from_millis(20));
+        let nodes = start_nodes(&network, 8);
+        // indirect probes and refutations keep the live members from being declared dead.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        for node in nodes.iter() {
+            assert_eq!(node.view().live().count(), 8, "{:?}", node.view());
+        }
+    }
+}
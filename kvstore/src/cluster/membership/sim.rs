@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::{Message, Rng, Transport};
+
+/// Number of messages buffered per endpoint before the network starts dropping them.
+const ENDPOINT_BUFFER: usize = 1024;
+
+/// An in-memory network connecting membership endpoints, used to test the protocol under faults.
+///
+/// Every message can be lost with the configured probability, and is delivered after a random
+/// delay in the configured range, which also reorders messages. Nodes can be isolated to simulate
+/// partitions. The faults are drawn from a generator seeded at creation, and the delays rely on
+/// tokio's clock, so runs are reproducible with a paused clock.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+struct SimState {
+    endpoints: HashMap<String, mpsc::Sender<Message>>,
+    isolated: HashSet<String>,
+    loss: f64,
+    min_delay: Duration,
+    max_delay: Duration,
+    rng: Rng,
+}
+
+impl SimNetwork {
+    /// Creates a reliable network without delays, faults are enabled with the setters.
+    pub fn new(seed: u64) -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                endpoints: HashMap::new(),
+                isolated: HashSet::new(),
+                loss: 0.0,
+                min_delay: Duration::from_millis(0),
+                max_delay: Duration::from_millis(0),
+                rng: Rng::new(seed),
+            })),
+        }
+    }
+
+    /// Set the probability of a message to be lost, between 0 and 1.
+    pub fn set_loss(&self, loss: f64) {
+        self.state.lock().unwrap().loss = loss;
+    }
+
+    pub fn set_delay(&self, min: Duration, max: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.min_delay = min;
+        state.max_delay = max.max(min);
+    }
+
+    /// Drop all of the messages sent from or to the node, until it's healed.
+    pub fn isolate(&self, addr: &str) {
+        self.state.lock().unwrap().isolated.insert(addr.to_string());
+    }
+
+    pub fn heal(&self, addr: &str) {
+        self.state.lock().unwrap().isolated.remove(addr);
+    }
+
+    /// Connects a node with the given address to the network, and returns its transport along with
+    /// the channel receiving the messages sent to it.
+    pub fn endpoint(&self, addr: &str) -> (SimTransport, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(ENDPOINT_BUFFER);
+        self.state
+            .lock()
+            .unwrap()
+            .endpoints
+            .insert(addr.to_string(), tx);
+        let transport = SimTransport {
+            network: self.clone(),
+            addr: addr.to_string(),
+        };
+        (transport, rx)
+    }
+
+    fn deliver(&self, from: &str, to: &str, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.isolated.contains(from) || state.isolated.contains(to) {
+            return;
+        }
+        if state.loss > 0.0 && state.rng.next_f64() < state.loss {
+            return;
+        }
+        let endpoint = match state.endpoints.get(to) {
+            Some(endpoint) => endpoint.clone(),
+            None => return,
+        };
+        let jitter = (state.max_delay - state.min_delay).mul_f64(state.rng.next_f64());
+        let delay = state.min_delay + jitter;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = endpoint.try_send(message);
+        });
+    }
+}
+
+/// The transport of a node connected to a `SimNetwork`.
+pub struct SimTransport {
+    network: SimNetwork,
+    addr: String,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, to: &str, message: Message) {
+        self.network.deliver(&self.addr, to, message);
+    }
+}
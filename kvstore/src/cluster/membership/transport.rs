@@ -0,0 +1,138 @@
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use super::{Member, MemberState, Message, MessageKind};
+use crate::protocol::{get_string, get_u32, get_u64, get_u8, put_string};
+use crate::Result;
+
+/// Number of received messages buffered before the transport starts dropping them.
+const INCOMING_BUFFER: usize = 1024;
+
+/// Sends membership messages to the other members.
+///
+/// Delivery is best effort: messages might be lost, delayed or reordered, the protocol copes with
+/// it, so sending never blocks nor fails.
+pub trait Transport: Send + 'static {
+    fn send(&self, to: &str, message: Message);
+}
+
+/// A transport sending every message in its own UDP datagram.
+pub struct UdpTransport {
+    socket: Arc<UdpSocket>,
+}
+
+impl UdpTransport {
+    /// Binds the socket to the address, and returns the transport along with the channel receiving
+    /// the messages sent to it. Datagrams that can't be decoded are dropped.
+    pub async fn bind(addr: &str) -> Result<(UdpTransport, mpsc::Receiver<Message>)> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let (tx, rx) = mpsc::channel(INCOMING_BUFFER);
+        let receiver = socket.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 64 * 1024];
+            while let Ok((len, _)) = receiver.recv_from(&mut buf).await {
+                if let Ok(message) = decode(&buf[..len]) {
+                    if tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok((UdpTransport { socket }, rx))
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, to: &str, message: Message) {
+        let socket = self.socket.clone();
+        let to = to.to_string();
+        let data = encode(&message);
+        tokio::spawn(async move {
+            let _ = socket.send_to(&data, to.as_str()).await;
+        });
+    }
+}
+
+/// Encodes the message in the protocol's format: the sender, one byte for the kind followed by its
+/// fields, and the piggybacked updates.
+pub(crate) fn encode(message: &Message) -> Vec<u8> {
+    let mut buf = vec![];
+    put_string(&mut buf, &message.from);
+    match &message.kind {
+        MessageKind::Ping(seq) => {
+            buf.push(0);
+            buf.extend_from_slice(&seq.to_be_bytes());
+        }
+        MessageKind::PingReq(seq, target) => {
+            buf.push(1);
+            buf.extend_from_slice(&seq.to_be_bytes());
+            put_string(&mut buf, target);
+        }
+        MessageKind::Ack(seq) => {
+            buf.push(2);
+            buf.extend_from_slice(&seq.to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(&(message.gossip.len() as u32).to_be_bytes());
+    for member in message.gossip.iter() {
+        put_string(&mut buf, &member.addr);
+        buf.push(match member.state {
+            MemberState::Alive => 0,
+            MemberState::Suspect => 1,
+            MemberState::Dead => 2,
+        });
+        buf.extend_from_slice(&member.incarnation.to_be_bytes());
+    }
+    buf
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Message> {
+    let mut cur = Cursor::new(data);
+    let from = get_string(&mut cur)?;
+    let kind = match get_u8(&mut cur)? {
+        0 => MessageKind::Ping(get_u64(&mut cur)?),
+        1 => MessageKind::PingReq(get_u64(&mut cur)?, get_string(&mut cur)?),
+        2 => MessageKind::Ack(get_u64(&mut cur)?),
+        _ => return Err("Unknown message kind".into()),
+    };
+    let count = get_u32(&mut cur)?;
+    let mut gossip = vec![];
+    for _ in 0..count {
+        let addr = get_string(&mut cur)?;
+        let state = match get_u8(&mut cur)? {
+            0 => MemberState::Alive,
+            1 => MemberState::Suspect,
+            2 => MemberState::Dead,
+            _ => return Err("Unknown member state".into()),
+        };
+        let incarnation = get_u64(&mut cur)?;
+        gossip.push(Member {
+            addr,
+            state,
+            incarnation,
+        });
+    }
+    Ok(Message { from, kind, gossip })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let message = Message {
+            from: String::from("10.0.0.1:6555"),
+            kind: MessageKind::PingReq(42, String::from("10.0.0.2:6555")),
+            gossip: vec![Member {
+                addr: String::from("10.0.0.3:6555"),
+                state: MemberState::Suspect,
+                incarnation: 7,
+            }],
+        };
+        assert_eq!(decode(&encode(&message)).unwrap(), message);
+        assert!(decode(&encode(&message)[..10]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::cluster::hash;
+
+/// A Merkle tree over the hash space of the ring.
+///
+/// The leaves (buckets) split the hash space in `2^depth` contiguous ranges, and the hash of a
+/// bucket is the XOR of the hashes of the key-value pairs it holds, which lets the tree be updated
+/// in place whenever a pair is added or removed, whatever the order of the updates is. Two trees
+/// holding the same pairs have the same hashes, so comparing them from the root down quickly finds
+/// the ranges that differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    depth: u8,
+    /// The nodes of the tree, level by level: the root is at index 1, and the children of the node
+    /// at index `i` are at `2i` and `2i + 1`. The buckets take the last `2^depth` slots.
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    pub fn new(depth: u8) -> Self {
+        assert!(
+            depth < 32,
+            "The depth of a Merkle tree must be lower than 32"
+        );
+        MerkleTree {
+            depth,
+            nodes: vec![0; 2 << depth],
+        }
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// Returns the bucket the key falls in.
+    pub fn bucket(&self, key: &str) -> u32 {
+        bucket(self.depth, key)
+    }
+
+    /// Add the key-value pair to the tree.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.toggle(key, value);
+    }
+
+    /// Remove the key-value pair from the tree, it must have been inserted before.
+    pub fn remove(&mut self, key: &str, value: &str) {
+        self.toggle(key, value);
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// Returns the hashes of the nodes at the given positions of the level, the root being the
+    /// only node of level 0, and the buckets the nodes of the last level. Positions out of the level
+    /// are skipped.
+    pub fn hashes(&self, level: u8, positions: &[u32]) -> Vec<u64> {
+        if level > self.depth {
+            return vec![];
+        }
+        let first = 1usize << level;
+        positions
+            .iter()
+            .filter(|position| (**position as usize) < first)
+            .map(|position| self.nodes[first + *position as usize])
+            .collect()
+    }
+
+    fn toggle(&mut self, key: &str, value: &str) {
+        let mut index = (1usize << self.depth) + self.bucket(key) as usize;
+        self.nodes[index] ^= entry_hash(key, value);
+        while index > 1 {
+            index /= 2;
+            self.nodes[index] = combine(self.nodes[2 * index], self.nodes[2 * index + 1]);
+        }
+    }
+}
+
+/// Returns the bucket the key falls in, in a tree of the given depth.
+pub fn bucket(depth: u8, key: &str) -> u32 {
+    if depth == 0 {
+        return 0;
+    }
+    (hash(key) >> (64 - depth as u32)) as u32
+}
+
+fn entry_hash(key: &str, value: &str) -> u64 {
+    // Hashing the length of the key keeps ("ab", "c") and ("a", "bc") apart.
+    combine(hash(key) ^ key.len() as u64, hash(value))
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    if left == 0 && right == 0 {
+        // Keeps the hashes of empty subtrees equal to 0, whatever their height is.
+        return 0;
+    }
+    let mut h = left.rotate_left(23) ^ right.wrapping_mul(0x9e3779b97f4a7c15);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_independent() {
+        let mut first = MerkleTree::new(4);
+        let mut second = MerkleTree::new(4);
+        for i in 0..100 {
+            first.insert(&format!("key{}", i), "value");
+        }
+        for i in (0..100).rev() {
+            second.insert(&format!("key{}", i), "value");
+        }
+        assert_eq!(first, second);
+        assert_ne!(first.root(), 0);
+
+        for i in 0..100 {
+            first.remove(&format!("key{}", i), "value");
+        }
+        assert_eq!(first, MerkleTree::new(4));
+    }
+
+    #[test]
+    fn test_find_differences() {
+        let mut first = MerkleTree::new(6);
+        let mut second = MerkleTree::new(6);
+        for i in 0..1000 {
+            first.insert(&format!("key{}", i), "value");
+            second.insert(&format!("key{}", i), "value");
+        }
+        second.remove("key42", "value");
+        second.insert("key42", "changed");
+        assert_ne!(first.root(), second.root());
+
+        // walking down the differing nodes leads to the bucket of the changed key only.
+        let mut positions = vec![0];
+        for level in 0..=first.depth() {
+            let ours = first.hashes(level, &positions);
+            let theirs = second.hashes(level, &positions);
+            let differing: Vec<u32> = positions
+                .iter()
+                .zip(ours.iter().zip(theirs.iter()))
+                .filter(|(_, (ours, theirs))| ours != theirs)
+                .map(|(position, _)| *position)
+                .collect();
+            if level == first.depth() {
+                positions = differing;
+            } else {
+                positions = differing.iter().flat_map(|p| [2 * p, 2 * p + 1]).collect();
+            }
+        }
+        assert_eq!(positions, vec![first.bucket("key42")]);
+    }
+}
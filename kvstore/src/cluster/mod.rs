@@ -0,0 +1,164 @@
+mod ring;
+pub use ring::{hash, Ring, DEFAULT_VNODES};
+
+mod version;
+pub use version::{context, reconcile, Causality, VectorClock, Versioned};
+
+pub(crate) mod replication;
+
+mod merkle;
+pub use merkle::MerkleTree;
+
+pub(crate) mod anti_entropy;
+
+pub(crate) mod peers;
+pub use anti_entropy::{AntiEntropyOptions, MAX_TREE_DEPTH};
+use peers::Peers;
+
+mod failure;
+pub use failure::{FailureDetector, PhiOptions};
+
+pub mod membership;
+pub use membership::{ClusterView, Membership, MembershipOptions};
+
+pub mod client;
+pub use client::{connect, connect_with_options, ClusterClient, PartialWrite};
+
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::client::Credentials;
+
+/// Groups the options of a node running in cluster mode.
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    /// The address this node is reachable at, as known by the other nodes and the clients.
+    pub node: String,
+    /// The addresses of the other nodes of the cluster.
+    pub peers: Vec<String>,
+    /// Number of virtual nodes each node gets on the ring.
+    pub vnodes: usize,
+    /// Replication settings of the keys written with the replicated commands.
+    pub replication: ReplicationOptions,
+    /// Settings of the background repair of the replicated keys.
+    pub anti_entropy: AntiEntropyOptions,
+    /// Enables the gossip based membership protocol, run over UDP on the port of the node.
+    pub membership: Option<MembershipOptions>,
+    /// The user this node authenticates as on the other nodes, when they require authentication,
+    /// see `kvstore::acl`.
+    pub credentials: Option<Credentials>,
+    /// Encrypts the connections to the other nodes, required when they only accept TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConnector>,
+}
+
+impl ClusterOptions {
+    pub fn new<S: Into<String>>(node: S, peers: Vec<String>) -> Self {
+        ClusterOptions {
+            node: node.into(),
+            peers,
+            vnodes: DEFAULT_VNODES,
+            replication: ReplicationOptions::default(),
+            anti_entropy: AntiEntropyOptions::default(),
+            membership: None,
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// Settings of the leaderless replication of keys.
+///
+/// Every replicated key is stored on the first `replicas` distinct nodes found walking the ring
+/// clockwise from its hash. Picking read and write quorums such that `read_quorum + write_quorum`
+/// is greater than `replicas` makes reads see the latest acknowledged write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplicationOptions {
+    /// Number of nodes every key is replicated to, the N of the quorums.
+    pub replicas: usize,
+    /// Number of replicas answering a read before it completes, when not given by the request.
+    pub read_quorum: usize,
+    /// Number of replicas acknowledging a write before it completes, when not given by the request.
+    pub write_quorum: usize,
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> Self {
+        ReplicationOptions {
+            replicas: 3,
+            read_quorum: 2,
+            write_quorum: 2,
+        }
+    }
+}
+
+/// The view a node has of the cluster it's part of.
+///
+/// The keyspace is partitioned across the nodes using a consistent hashing ring, a node only serves
+/// the keys it owns, and redirects the clients to the owner of the other keys.
+pub struct Cluster {
+    node: String,
+    ring: RwLock<Ring>,
+    replication: ReplicationOptions,
+    peers: Arc<Peers>,
+}
+
+impl Cluster {
+    pub fn new(options: ClusterOptions) -> Self {
+        let mut ring = Ring::with_nodes(options.vnodes, options.peers.iter());
+        ring.add_node(&options.node);
+        let peers = Arc::new(Peers::new(&options));
+        Cluster {
+            node: options.node,
+            ring: RwLock::new(ring),
+            replication: options.replication,
+            peers,
+        }
+    }
+
+    /// The address of this node.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Returns the address of the node owning the key, `None` if it's owned by this node.
+    pub fn redirect(&self, key: &str) -> Option<String> {
+        let ring = self.ring.read().unwrap();
+        match ring.owner(key) {
+            Some(owner) if owner != self.node => Some(owner.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Returns the addresses of the nodes holding the replicas of the key, the first one being its
+    /// owner.
+    pub fn replicas(&self, key: &str) -> Vec<String> {
+        let ring = self.ring.read().unwrap();
+        ring.successors(hash(key))
+            .take(self.replication.replicas)
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn replication(&self) -> &ReplicationOptions {
+        &self.replication
+    }
+
+    /// The connections to the other nodes, shared by the requests between nodes.
+    pub(crate) fn peers(&self) -> &Arc<Peers> {
+        &self.peers
+    }
+
+    /// Read access to the current ring.
+    pub fn ring(&self) -> RwLockReadGuard<'_, Ring> {
+        self.ring.read().unwrap()
+    }
+
+    pub fn add_node(&self, node: &str) -> bool {
+        self.ring.write().unwrap().add_node(node)
+    }
+
+    pub fn remove_node(&self, node: &str) -> bool {
+        self.ring.write().unwrap().remove_node(node)
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::client::{self, Client, Credentials};
+use crate::cluster::ClusterOptions;
+use crate::env::Env;
+use crate::protocol::{Command, Response};
+use crate::Result;
+
+/// Maximum number of idle connections kept per peer.
+const MAX_IDLE: usize = 8;
+
+/// The idle connections to the other nodes of the cluster, reused by the requests between nodes
+/// instead of opening a connection per request.
+#[derive(Default)]
+pub(crate) struct Peers {
+    idle: Mutex<HashMap<String, Vec<Client>>>,
+    credentials: Option<Credentials>,
+    #[cfg(feature = "tls")]
+    tls: Option<crate::tls::TlsConnector>,
+}
+
+impl Peers {
+    pub(crate) fn new(options: &ClusterOptions) -> Self {
+        Peers {
+            credentials: options.credentials.clone(),
+            #[cfg(feature = "tls")]
+            tls: options.tls.clone(),
+            ..Peers::default()
+        }
+    }
+
+    /// Opens a new connection to the node, encrypted if the cluster uses TLS, and authenticated if
+    /// the node has credentials.
+    pub(crate) async fn connect(&self, env: &Env, node: &str) -> Result<Client> {
+        let stream = env.network.connect(node).await?;
+        #[cfg(feature = "tls")]
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => stream,
+        };
+        client::create_over(stream, self.credentials.as_ref()).await
+    }
+
+    /// Returns an idle connection to the node, or opens a new one, along with whether it was
+    /// reused.
+    pub(crate) async fn get(&self, env: &Env, node: &str) -> Result<(Client, bool)> {
+        let idle = self.idle.lock().unwrap().get_mut(node).and_then(Vec::pop);
+        match idle {
+            Some(client) => Ok((client, true)),
+            None => Ok((self.connect(env, node).await?, false)),
+        }
+    }
+
+    /// Hands back a connection whose last request succeeded, for the next requests to reuse it.
+    pub(crate) fn put(&self, node: &str, client: Client) {
+        let mut idle = self.idle.lock().unwrap();
+        let clients = idle.entry(node.to_string()).or_default();
+        if clients.len() < MAX_IDLE {
+            clients.push(client);
+        }
+    }
+
+    /// Sends the command to the node over a reused connection if there is one.
+    ///
+    /// A reused connection may have been closed by the peer while idle, in which case a command
+    /// that can safely be sent twice is sent again over a new connection.
+    pub(crate) async fn execute(
+        &self,
+        env: &Env,
+        node: &str,
+        command: Command,
+    ) -> Result<Option<Response>> {
+        let (mut client, reused) = self.get(env, node).await?;
+        let resend = reused && resendable(&command);
+        let reply = client.execute(command.clone()).await;
+        match reply {
+            Ok(Some(response)) => {
+                self.put(node, client);
+                Ok(Some(response))
+            }
+            _ if resend => {
+                let mut client = self.connect(env, node).await?;
+                let reply = client.execute(command).await;
+                if let Ok(Some(_)) = reply {
+                    self.put(node, client);
+                }
+                reply
+            }
+            reply => reply,
+        }
+    }
+}
+
+/// Whether sending the command twice has the same effect as sending it once, replica writes merge
+/// versions the replica may already hold, which has no effect the second time.
+fn resendable(command: &Command) -> bool {
+    match command {
+        Command::Deadline(_, command) => resendable(command),
+        Command::ReplicaWrite(..) => true,
+        command => command.is_idempotent(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_reuses_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { server::run(listener).await });
+        let (env, peers) = (Env::default(), Peers::default());
+
+        let ping = Command::Ping(String::from("hello"));
+        let reply = peers.execute(&env, &node, ping.clone()).await.unwrap();
+        assert!(matches!(reply, Some(Response::Ok(_))));
+        let (_, reused) = peers.get(&env, &node).await.unwrap();
+        assert!(reused);
+
+        // a connection closed by the peer while idle, the ping is sent again over a new one.
+        let closing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = closing.local_addr().unwrap().to_string();
+        let client = client::create_with(&*env.network, &addr).await.unwrap();
+        drop(closing.accept().await.unwrap());
+        peers.put(&node, client);
+        let reply = peers.execute(&env, &node, ping).await.unwrap();
+        assert!(matches!(reply, Some(Response::Ok(_))));
+        // but a write could have been applied, so it's not.
+        let closing_client = client::create_with(&*env.network, &addr).await.unwrap();
+        drop(closing.accept().await.unwrap());
+        peers.put(&node, closing_client);
+        let set = Command::Set(String::from("key"), String::from("value"));
+        assert!(!matches!(
+            peers.execute(&env, &node, set).await,
+            Ok(Some(Response::Ok(_)))
+        ));
+    }
+}
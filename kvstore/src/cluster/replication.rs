@@ -0,0 +1,241 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::cluster::peers::Peers;
+use crate::cluster::version::{self, reconcile, VectorClock, Versioned};
+use crate::env::{self, Env};
+use crate::protocol::{Command, Response};
+use crate::{Result, Storage};
+
+/// How long a coordinator waits for a replica to answer before considering it failed.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a node waits for the replica it forwarded a write to, which waits for the other
+/// replicas in turn.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// The answer of a replica to a command sent by `fan_out`.
+pub(crate) type Reply = (String, Result<Option<Response>>);
+
+/// Returns the versions of the key held in the storage.
+///
+/// Replicated keys are stored as regular values holding their encoded versions, so they are part of
+/// snapshots and scans like any other key.
+pub(crate) fn read(store: &(dyn Storage + Send + Sync), key: &str) -> Result<Vec<Versioned>> {
+    match store.get(&key.to_string())? {
+        Some(data) => version::decode(data)
+            .map_err(|_| format!("The key {} holds a value that is not replicated", key).into()),
+        None => Ok(vec![]),
+    }
+}
+
+/// Merge the versions into the ones held in the storage, and returns the encoded result if it
+/// changed.
+pub(crate) fn merge(
+    store: &mut (dyn Storage + Send + Sync),
+    key: &str,
+    incoming: &[Versioned],
+) -> Result<Option<String>> {
+    let current = read(store, key)?;
+    let merged = reconcile(&current, incoming);
+    if merged == current {
+        return Ok(None);
+    }
+    let data = version::encode(&merged);
+    store.set(key.to_string(), data.clone())?;
+    Ok(Some(data))
+}
+
+/// Creates the version of a new update coordinated by the node, superseding the versions in the
+/// context.
+///
+/// The counter of the coordinator is bumped past every counter it already stored for the key, which
+/// keeps two updates coordinated by the same node from getting identical clocks. The flip side is
+/// that concurrent updates coordinated by the same node are ordered instead of becoming siblings.
+pub(crate) fn stamp(
+    current: &[Versioned],
+    node: &str,
+    value: Option<String>,
+    context: VectorClock,
+) -> Versioned {
+    let mut clock = context;
+    let counter = current
+        .iter()
+        .map(|version| version.clock.get(node))
+        .chain(std::iter::once(clock.get(node)))
+        .max()
+        .unwrap_or(0);
+    clock.set(node, counter + 1);
+    Versioned { clock, value }
+}
+
+/// Returns the number of replicas to wait for, the requested one or the default if none was
+/// requested.
+///
+/// The default is capped to the number of replicas, so that clusters smaller than the replication
+/// factor remain usable, whereas a requested quorum that can't be met is reported as an error.
+pub(crate) fn quorum(
+    requested: u8,
+    default: usize,
+    replicas: usize,
+) -> std::result::Result<usize, Response> {
+    let quorum = match requested {
+        0 => default.min(replicas),
+        requested => requested as usize,
+    };
+    if quorum == 0 || quorum > replicas {
+        return Err(Response::Error(format!(
+            "Invalid quorum {}, the key has {} replicas",
+            quorum, replicas
+        )));
+    }
+    Ok(quorum)
+}
+
+/// Sends the command to all of the nodes concurrently, the replies are received from the returned
+/// channel as they arrive, and the channel is closed once every node answered or timed out.
+///
+/// If the command has a deadline, it's sent along with the command, and the nodes are not waited
+/// for past it.
+pub(crate) fn fan_out(
+    env: &Env,
+    peers: &Arc<Peers>,
+    nodes: Vec<String>,
+    command: Command,
+    deadline: Option<u64>,
+) -> mpsc::Receiver<Reply> {
+    let (tx, rx) = mpsc::channel(nodes.len().max(1));
+    let (command, timeout) = with_deadline(env, command, deadline, REPLICA_TIMEOUT);
+    for node in nodes {
+        let tx = tx.clone();
+        let command = command.clone();
+        let env = env.clone();
+        let peers = peers.clone();
+        tokio::spawn(async move {
+            let request = peers.execute(&env, &node, command);
+            let reply = match env::timeout(&*env.clock, timeout, request).await {
+                Some(reply) => reply,
+                None => Err(format!("{} did not answer in time", node).into()),
+            };
+            let _ = tx.send((node, reply)).await;
+        });
+    }
+    rx
+}
+
+/// Sends the command to the node and waits for its answer, like `fan_out` does for a single node
+/// but with a longer timeout, as the node is expected to coordinate the command.
+pub(crate) async fn forward(
+    env: &Env,
+    peers: &Peers,
+    node: &str,
+    command: Command,
+    deadline: Option<u64>,
+) -> Result<Option<Response>> {
+    let (command, timeout) = with_deadline(env, command, deadline, FORWARD_TIMEOUT);
+    match env::timeout(&*env.clock, timeout, peers.execute(env, node, command)).await {
+        Some(reply) => reply,
+        None => Err(format!("{} did not answer in time", node).into()),
+    }
+}
+
+/// Wraps the command with its deadline if any, and returns how long to wait for its answer: the
+/// timeout, or the time left until the deadline if it's earlier.
+fn with_deadline(
+    env: &Env,
+    command: Command,
+    deadline: Option<u64>,
+    timeout: Duration,
+) -> (Command, Duration) {
+    match deadline {
+        Some(deadline) => (
+            Command::Deadline(deadline, Box::new(command)),
+            timeout.min(env::until(&*env.clock, deadline)),
+        ),
+        None => (command, timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::epoch_millis;
+    use crate::storage::memory::InMemStorage;
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_stamp() {
+        let mut clock = VectorClock::new();
+        clock.set("b", 4);
+        let current = vec![Versioned {
+            clock: clock.clone(),
+            value: None,
+        }];
+        let first = stamp(&current, "a", Some(String::from("1")), clock.clone());
+        assert_eq!(first.clock.get("a"), 1);
+        assert_eq!(first.clock.get("b"), 4);
+
+        // a second update with the same context still gets a greater clock.
+        let second = stamp(&[first], "a", Some(String::from("2")), clock);
+        assert_eq!(second.clock.get("a"), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut store: Box<dyn Storage + Send + Sync> = Box::new(InMemStorage::new());
+        let first = vec![stamp(&[], "a", Some(String::from("1")), VectorClock::new())];
+        let concurrent = vec![stamp(&[], "b", Some(String::from("2")), VectorClock::new())];
+        assert!(merge(store.as_mut(), "key", &first).unwrap().is_some());
+        assert!(merge(store.as_mut(), "key", &first).unwrap().is_none());
+        merge(store.as_mut(), "key", &concurrent).unwrap();
+        assert_eq!(
+            read(store.as_ref(), "key").unwrap(),
+            [first, concurrent].concat()
+        );
+
+        store
+            .set(String::from("plain"), String::from("value"))
+            .unwrap();
+        assert!(read(store.as_ref(), "plain").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forward_times_out() {
+        // a node that never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let (env, peers) = (Env::default(), Peers::default());
+        let ping = Command::Ping(String::from("hello"));
+
+        let start = Instant::now();
+        assert!(forward(&env, &peers, &node, ping.clone(), None)
+            .await
+            .is_err());
+        assert!(start.elapsed() >= FORWARD_TIMEOUT);
+
+        // the deadline is earlier.
+        let start = Instant::now();
+        let deadline = epoch_millis(SystemTime::now()) + 100;
+        assert!(forward(&env, &peers, &node, ping, Some(deadline))
+            .await
+            .is_err());
+        assert!(start.elapsed() < FORWARD_TIMEOUT);
+    }
+
+    #[test]
+    fn test_quorum() {
+        assert_eq!(quorum(0, 2, 3), Ok(2));
+        assert_eq!(quorum(0, 2, 1), Ok(1));
+        assert_eq!(quorum(3, 2, 3), Ok(3));
+        assert!(quorum(4, 2, 3).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Default number of virtual nodes each node gets on the ring.
+pub const DEFAULT_VNODES: usize = 64;
+
+/// Hashes the key to its position on the ring.
+///
+/// This is 64 bits FNV-1a, followed by the finalizer of MurmurHash3 to spread similar keys (e.g.
+/// `user:1` and `user:2`) all over the ring. The hash must be stable across processes and
+/// versions, as every node and client has to agree on it.
+pub fn hash(key: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        h ^= byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+/// A consistent hashing ring, mapping keys to the nodes owning them.
+///
+/// Every node is placed at multiple positions (virtual nodes) on the ring, and a key is owned by
+/// the first node found walking the ring clockwise from the key's hash. Virtual nodes smooth out
+/// the share of the keyspace each node gets, and adding or removing a node only moves the keys of
+/// the ranges next to its virtual nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ring {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl Ring {
+    pub fn new(vnodes: usize) -> Self {
+        Ring {
+            vnodes,
+            points: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    /// Creates a ring holding all of the given nodes.
+    pub fn with_nodes<I, S>(vnodes: usize, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut ring = Ring::new(vnodes);
+        for node in nodes {
+            ring.add_node(node.as_ref());
+        }
+        ring
+    }
+
+    /// Adds the node to the ring, returns false if it was already part of it.
+    pub fn add_node(&mut self, node: &str) -> bool {
+        if !self.nodes.insert(node.to_string()) {
+            return false;
+        }
+        for i in 0..self.vnodes {
+            self.points.insert(vnode_hash(node, i), node.to_string());
+        }
+        true
+    }
+
+    /// Removes the node from the ring, returns false if it was not part of it.
+    pub fn remove_node(&mut self, node: &str) -> bool {
+        if !self.nodes.remove(node) {
+            return false;
+        }
+        for i in 0..self.vnodes {
+            let point = vnode_hash(node, i);
+            if self.points.get(&point).map(String::as_str) == Some(node) {
+                self.points.remove(&point);
+            }
+        }
+        true
+    }
+
+    /// Returns the node owning the key, `None` if the ring is empty.
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.owner_of_hash(hash(key))
+    }
+
+    /// Returns the node owning the given position of the ring, `None` if the ring is empty.
+    pub fn owner_of_hash(&self, hash: u64) -> Option<&str> {
+        self.successors(hash).next()
+    }
+
+    /// Returns the distinct nodes found walking the ring clockwise from the given position, the
+    /// first one being the owner of the position.
+    pub fn successors(&self, hash: u64) -> impl Iterator<Item = &str> {
+        let mut seen = BTreeSet::new();
+        self.points
+            .range(hash..)
+            .chain(self.points.range(..hash))
+            .map(|(_, node)| node.as_str())
+            .filter(move |node| seen.insert(*node))
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.contains(node)
+    }
+
+    pub fn vnodes(&self) -> usize {
+        self.vnodes
+    }
+}
+
+fn vnode_hash(node: &str, i: usize) -> u64 {
+    hash(&format!("{}#{}", node, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("10.0.0.{}:6555", i)).collect()
+    }
+
+    #[test]
+    fn test_empty_ring() {
+        let ring = Ring::new(DEFAULT_VNODES);
+        assert_eq!(ring.owner("key"), None);
+    }
+
+    #[test]
+    fn test_balanced_distribution() {
+        let ring = Ring::with_nodes(DEFAULT_VNODES, nodes(4));
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for i in 0..40_000 {
+            *counts
+                .entry(ring.owner(&format!("key{}", i)).unwrap())
+                .or_default() += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        for count in counts.values() {
+            // every node should get roughly a quarter of the keys.
+            assert!(
+                *count > 6_000 && *count < 14_000,
+                "unbalanced: {:?}",
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn test_minimal_movement() {
+        let mut ring = Ring::with_nodes(DEFAULT_VNODES, nodes(4));
+        let before = ring.clone();
+        ring.add_node("10.0.0.4:6555");
+        let mut moved = 0;
+        for i in 0..10_000 {
+            let key = format!("key{}", i);
+            if before.owner(&key) != ring.owner(&key) {
+                // keys only ever move to the new node.
+                assert_eq!(ring.owner(&key), Some("10.0.0.4:6555"));
+                moved += 1;
+            }
+        }
+        // the new node takes roughly a fifth of the keys.
+        assert!(moved > 1_000 && moved < 3_000, "moved {} keys", moved);
+
+        ring.remove_node("10.0.0.4:6555");
+        assert_eq!(ring, before);
+    }
+
+    #[test]
+    fn test_successors() {
+        let ring = Ring::with_nodes(DEFAULT_VNODES, nodes(3));
+        let successors: Vec<&str> = ring.successors(hash("key")).collect();
+        assert_eq!(successors.len(), 3);
+        assert_eq!(Some(successors[0]), ring.owner("key"));
+    }
+}
@@ -0,0 +1,209 @@
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use crate::Result;
+
+/// How two vector clocks relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Causality {
+    Equal,
+    /// The first clock happened before the second one.
+    Before,
+    /// The first clock happened after the second one.
+    After,
+    /// Neither clock happened before the other, the versions they stamp are conflicting.
+    Concurrent,
+}
+
+/// A vector clock, counting the updates of a value coordinated by each node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        VectorClock(BTreeMap::new())
+    }
+
+    /// The number of updates coordinated by the node.
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, node: &str, counter: u64) {
+        self.0.insert(node.to_string(), counter);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &u64)> {
+        self.0.iter()
+    }
+
+    /// Merge the other clock into this one, keeping the highest counter of every node.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, counter) in other.0.iter() {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(*counter);
+        }
+    }
+
+    pub fn compare(&self, other: &VectorClock) -> Causality {
+        let mut before = false;
+        let mut after = false;
+        for node in self.0.keys().chain(other.0.keys()) {
+            let (mine, theirs) = (self.get(node), other.get(node));
+            before |= mine < theirs;
+            after |= mine > theirs;
+        }
+        match (before, after) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+/// A value stamped with the vector clock of the update that produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned {
+    pub clock: VectorClock,
+    /// The value, `None` if the update removed the key.
+    ///
+    /// Removals are kept as tombstones, otherwise a replica that missed the removal would bring
+    /// the value back to life during read repair.
+    pub value: Option<String>,
+}
+
+/// Merge two lists of versions of the same key, keeping only the versions that are not superseded
+/// by another one. More than one version is left when some of them are concurrent, those are the
+/// siblings the client has to resolve.
+pub fn reconcile(current: &[Versioned], incoming: &[Versioned]) -> Vec<Versioned> {
+    let mut result: Vec<Versioned> = vec![];
+    for version in current.iter().chain(incoming.iter()) {
+        let superseded = result.iter().any(|kept| {
+            matches!(
+                version.clock.compare(&kept.clock),
+                Causality::Before | Causality::Equal
+            )
+        });
+        if superseded {
+            continue;
+        }
+        result.retain(|kept| kept.clock.compare(&version.clock) != Causality::Before);
+        result.push(version.clone());
+    }
+    result
+}
+
+/// Returns the clock to send along an update, to tell that it supersedes all of the given
+/// siblings.
+pub fn context(siblings: &[Versioned]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for sibling in siblings {
+        clock.merge(&sibling.clock);
+    }
+    clock
+}
+
+/// Encodes the versions of a key to store them as a plain string value in a `Storage`.
+///
+/// The versions are sorted by clock, so that replicas holding the same versions encode them the
+/// same, which the anti-entropy relies on.
+pub fn encode(versions: &[Versioned]) -> String {
+    let mut sorted: Vec<&Versioned> = versions.iter().collect();
+    sorted.sort_by(|a, b| a.clock.cmp(&b.clock));
+    let versions: Vec<Value> = sorted
+        .into_iter()
+        .map(|version| {
+            let clock: Map<String, Value> = version
+                .clock
+                .entries()
+                .map(|(node, counter)| (node.clone(), json!(counter)))
+                .collect();
+            json!({ "clock": clock, "value": version.value })
+        })
+        .collect();
+    Value::from(versions).to_string()
+}
+
+/// Decodes the versions of a key encoded with `encode`.
+pub fn decode(data: &str) -> Result<Vec<Versioned>> {
+    let versions: Value = serde_json::from_str(data)?;
+    let versions = versions.as_array().ok_or("Expected a list of versions")?;
+    let mut result = vec![];
+    for version in versions {
+        let mut clock = VectorClock::new();
+        let entries = version["clock"].as_object().ok_or("Expected a clock")?;
+        for (node, counter) in entries {
+            clock.set(node, counter.as_u64().ok_or("Expected a counter")?);
+        }
+        let value = version["value"].as_str().map(String::from);
+        result.push(Versioned { clock, value });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(entries: &[(&str, u64)]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for (node, counter) in entries {
+            clock.set(node, *counter);
+        }
+        clock
+    }
+
+    fn version(entries: &[(&str, u64)], value: &str) -> Versioned {
+        Versioned {
+            clock: clock(entries),
+            value: Some(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let a = clock(&[("a", 1)]);
+        let ab = clock(&[("a", 1), ("b", 1)]);
+        let b = clock(&[("b", 1)]);
+        assert_eq!(a.compare(&a), Causality::Equal);
+        assert_eq!(a.compare(&ab), Causality::Before);
+        assert_eq!(ab.compare(&a), Causality::After);
+        assert_eq!(a.compare(&b), Causality::Concurrent);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let old = vec![version(&[("a", 1)], "old")];
+        let new = vec![version(&[("a", 2)], "new")];
+        assert_eq!(reconcile(&old, &new), new);
+        assert_eq!(reconcile(&new, &old), new);
+
+        let concurrent = vec![version(&[("a", 1), ("b", 1)], "concurrent")];
+        let siblings = reconcile(&new, &concurrent);
+        assert_eq!(siblings, [new, concurrent].concat());
+
+        // a write with the context of both siblings resolves the conflict.
+        let mut resolved_clock = context(&siblings);
+        resolved_clock.set("a", 3);
+        let resolved = vec![Versioned {
+            clock: resolved_clock,
+            value: Some(String::from("resolved")),
+        }];
+        assert_eq!(reconcile(&siblings, &resolved), resolved);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let versions = vec![
+            version(&[("10.0.0.1:6555", 2)], "value"),
+            Versioned {
+                clock: clock(&[("10.0.0.2:6555", 1)]),
+                value: None,
+            },
+        ];
+        assert_eq!(decode(&encode(&versions)).unwrap(), versions);
+        let reversed: Vec<Versioned> = versions.iter().rev().cloned().collect();
+        assert_eq!(encode(&reversed), encode(&versions));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+/// File formats supported by the import / export tooling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One JSON object per line: `{"key": "...", "value": "..."}`.
+    JsonLines,
+    /// A `key,value` header followed by one record per key-value pair.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format {}, expected jsonl or csv", s)),
+        }
+    }
+}
+
+/// Name of the value field (or column) holding a plain value.
+const VALUE: &str = "value";
+
+/// Name of the value field (or column) holding a base64 encoded value.
+const VALUE_BASE64: &str = "value_base64";
+
+/// Writes key-value pairs to an output in one of the supported formats.
+///
+/// When `base64` is set, values are base64 encoded and stored in a `value_base64` field (or
+/// column) instead of `value`, which keeps values holding control characters or separators
+/// intact whatever the format is.
+pub struct Exporter<W: Write> {
+    sink: Sink<W>,
+    base64: bool,
+}
+
+enum Sink<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(out: W, format: Format, base64: bool) -> Result<Self> {
+        let value_field = if base64 { VALUE_BASE64 } else { VALUE };
+        let sink = match format {
+            Format::JsonLines => Sink::JsonLines(out),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record(["key", value_field])?;
+                Sink::Csv(Box::new(writer))
+            }
+        };
+        Ok(Exporter { sink, base64 })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<()> {
+        let encoded;
+        let (field, value) = if self.base64 {
+            encoded = STANDARD.encode(value);
+            (VALUE_BASE64, encoded.as_str())
+        } else {
+            (VALUE, value)
+        };
+        match &mut self.sink {
+            Sink::JsonLines(out) => {
+                let mut record = json!({ "key": key });
+                record[field] = Value::from(value);
+                writeln!(out, "{}", record)?;
+            }
+            Sink::Csv(writer) => writer.write_record([key, value])?,
+        }
+        Ok(())
+    }
+
+    /// Flush any buffered record to the output.
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::JsonLines(mut out) => out.flush()?,
+            Sink::Csv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over the key-value pairs read from an input.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// Returns an iterator over the key-value pairs stored in the input, decoding base64 values.
+///
+/// The store only holds UTF-8 strings, so a base64 value that does not decode to valid UTF-8 is
+/// reported as an error.
+pub fn read_entries<'a, R: Read + 'a>(input: R, format: Format) -> Result<Entries<'a>> {
+    match format {
+        Format::JsonLines => {
+            let lines = BufReader::new(input).lines();
+            let entries = lines
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| parse_json_line(&line?));
+            Ok(Box::new(entries))
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let base64 = match reader.headers()?.get(1) {
+                Some(VALUE) => false,
+                Some(VALUE_BASE64) => true,
+                _ => return Err("Expected a key,value or key,value_base64 header".into()),
+            };
+            let entries = reader.into_records().map(move |record| {
+                let record = record?;
+                match (record.get(0), record.get(1)) {
+                    (Some(key), Some(value)) => Ok((key.to_string(), decode(value, base64)?)),
+                    _ => Err("Expected a key and a value in every record".into()),
+                }
+            });
+            Ok(Box::new(entries))
+        }
+    }
+}
+
+fn parse_json_line(line: &str) -> Result<(String, String)> {
+    let record: Value = serde_json::from_str(line)?;
+    let key = match record.get("key") {
+        Some(Value::String(key)) => key.clone(),
+        _ => return Err(format!("Missing key in {}", line).into()),
+    };
+    let value = match (record.get(VALUE), record.get(VALUE_BASE64)) {
+        (Some(Value::String(value)), _) => value.clone(),
+        (_, Some(Value::String(value))) => decode(value, true)?,
+        _ => return Err(format!("Missing value in {}", line).into()),
+    };
+    Ok((key, value))
+}
+
+fn decode(value: &str, base64: bool) -> Result<String> {
+    if !base64 {
+        return Ok(value.to_string());
+    }
+    Ok(String::from_utf8(STANDARD.decode(value)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: Format, base64: bool) -> Vec<(String, String)> {
+        let mut out = vec![];
+        let mut exporter = Exporter::new(&mut out, format, base64).unwrap();
+        exporter.write("a", "plain").unwrap();
+        exporter.write("b", "with,comma\nand \"quotes\"").unwrap();
+        exporter.finish().unwrap();
+        read_entries(out.as_slice(), format)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let expected = vec![
+            (String::from("a"), String::from("plain")),
+            (
+                String::from("b"),
+                String::from("with,comma\nand \"quotes\""),
+            ),
+        ];
+        for format in [Format::JsonLines, Format::Csv] {
+            assert_eq!(round_trip(format, false), expected);
+            assert_eq!(round_trip(format, true), expected);
+        }
+    }
+
+    #[test]
+    fn test_base64_field() {
+        let mut out = vec![];
+        let mut exporter = Exporter::new(&mut out, Format::JsonLines, true).unwrap();
+        exporter.write("a", "hello").unwrap();
+        exporter.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"key\":\"a\",\"value_base64\":\"aGVsbG8=\"}\n"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! The environment the server runs in: the network, the clock and the source of randomness.
+//!
+//! The server only reaches the outside world through these traits, so that it can run on real
+//! sockets and time, or in a deterministic simulation (see `simulation`).
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+use crate::protocol::epoch_millis;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A bidirectional byte stream between two endpoints.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// A connection established through a `Network`.
+pub type Connection = Box<dyn Stream>;
+
+/// Establishes connections with the other endpoints.
+pub trait Network: Send + Sync {
+    fn connect(&self, addr: &str) -> BoxFuture<'static, io::Result<Connection>>;
+
+    fn bind(&self, addr: &str) -> BoxFuture<'static, io::Result<Box<dyn Listener>>>;
+}
+
+/// Accepts the connections made to an address.
+pub trait Listener: Send {
+    /// Waits for the next connection, and returns it along with the address of the peer.
+    ///
+    /// A listener that is closed for good, e.g. the one of a crashed simulated host, fails with
+    /// `io::ErrorKind::NotConnected`, the other errors are considered transient.
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>>;
+}
+
+pub trait Clock: Send + Sync {
+    /// The monotonic time, used to measure durations.
+    fn now(&self) -> Instant;
+
+    /// The wall clock time, only used for timestamps.
+    fn system_time(&self) -> SystemTime;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// A source of random numbers.
+pub trait Random: Send + Sync {
+    fn next_u64(&self) -> u64;
+}
+
+/// Groups the implementations of the environment used by a server.
+#[derive(Clone)]
+pub struct Env {
+    pub network: Arc<dyn Network>,
+    pub clock: Arc<dyn Clock>,
+    pub random: Arc<dyn Random>,
+}
+
+impl Env {
+    /// The real environment: TCP sockets, the system's clock and random seeds.
+    pub fn system() -> Self {
+        Env {
+            network: Arc::new(TcpNetwork),
+            clock: Arc::new(SystemClock),
+            random: Arc::new(SystemRandom::default()),
+        }
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::system()
+    }
+}
+
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    fn connect(&self, addr: &str) -> BoxFuture<'static, io::Result<Connection>> {
+        let addr = addr.to_string();
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(stream) as Connection)
+        })
+    }
+
+    fn bind(&self, addr: &str) -> BoxFuture<'static, io::Result<Box<dyn Listener>>> {
+        let addr = addr.to_string();
+        Box::pin(async move {
+            let listener = TcpListener::bind(addr).await?;
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>> {
+        Box::pin(async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as Connection, addr.to_string()))
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>> {
+        Box::pin(async move {
+            let (stream, addr) = tokio::net::UnixListener::accept(self).await?;
+            let addr = match addr.as_pathname() {
+                Some(path) => path.display().to_string(),
+                None => String::from("unix"),
+            };
+            Ok((Box::new(stream) as Connection, addr))
+        })
+    }
+}
+
+/// The clock of tokio's runtime, along with the system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Random numbers drawn from the randomly seeded hashers of the standard library.
+#[derive(Default)]
+pub struct SystemRandom {
+    counter: AtomicU64,
+}
+
+impl Random for SystemRandom {
+    fn next_u64(&self) -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        hasher.finish()
+    }
+}
+
+/// Runs the future until it completes or the duration elapsed on the clock, `None` is returned in
+/// the latter case.
+///
+/// Unlike `tokio::select!`, the future is always polled before the timer, so the outcome does not
+/// depend on a random pick when both are ready.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let mut sleep = clock.sleep(duration);
+    tokio::pin!(future);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// The time left on the clock until the deadline, in milliseconds since the UNIX epoch, zero if it
+/// already passed.
+pub(crate) fn until(clock: &dyn Clock, deadline: u64) -> Duration {
+    let now = epoch_millis(clock.system_time());
+    Duration::from_millis(deadline.saturating_sub(now))
+}
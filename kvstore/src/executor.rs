@@ -1,11 +1,95 @@
+use crate::acl::{Acl, User, MAX_AUTH_FAILURES};
+use crate::cdc::ChangeLog;
+use crate::cluster::anti_entropy::AntiEntropy;
+use crate::cluster::membership::{MemberState, Membership};
+use crate::cluster::replication::{self, Reply};
+use crate::cluster::{self, reconcile, Cluster, VectorClock, Versioned};
+use crate::env::{self, Env};
 use crate::handler::ConnectionHandler;
+use crate::metrics::Metrics;
+use crate::notify::{Notification, Notifier, Operation};
+use crate::pubsub::Broker;
+use crate::snapshot;
 use crate::Command;
 use crate::Storage;
 use crate::{Response, Result};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
 /// StorageEngine is a type alias to help reduce the verbosity of the storage interface type.
-type StorageEngine = Arc<Mutex<Box<dyn Storage + Send + Sync>>>;
+pub(crate) type StorageEngine = Arc<Mutex<Box<dyn Storage + Send + Sync>>>;
+
+/// Maximum number of changes returned by a single `Command::Changes`.
+const MAX_CHANGES_BATCH: u32 = 1024;
+
+/// Maximum number of key-value pairs returned by a single `Command::Scan`.
+pub(crate) const MAX_SCAN_BATCH: u32 = 1024;
+
+/// Number of key-value pairs sent per request when migrating keys to another node.
+const MIGRATE_BATCH: usize = 512;
+
+/// The error answered to `Command::Get` when the key does not exist, and to the `Command::Clear` of
+/// the gateways.
+pub(crate) const KEY_NOT_FOUND: &str = "Key not found";
+
+/// Context groups the server-wide state shared between all of the executor instances, all of its
+/// members are safe to access from multiple threads.
+#[derive(Clone)]
+pub(crate) struct Context {
+    /// The shared storage between all of the executor instances.
+    pub(crate) store: StorageEngine,
+
+    /// The broker used to deliver published messages to subscribed clients.
+    pub(crate) broker: Arc<Broker>,
+
+    /// The feed of keyspace changes, used by the watching clients.
+    pub(crate) notifier: Arc<Notifier>,
+
+    /// The log of all the applied changes, only present if change data capture is enabled.
+    pub(crate) changelog: Option<Arc<ChangeLog>>,
+
+    /// The directory where snapshots are written, only present if snapshots are enabled.
+    pub(crate) snapshot_dir: Option<PathBuf>,
+
+    /// Maximum length of the body of the HTTP requests.
+    pub(crate) http_max_body: usize,
+
+    /// The view of the cluster this node is part of, only present in cluster mode.
+    pub(crate) cluster: Option<Arc<Cluster>>,
+
+    /// The Merkle trees used to repair the replicated keys, only present in cluster mode.
+    pub(crate) anti_entropy: Option<Arc<AntiEntropy>>,
+
+    /// The membership protocol tracking the live nodes, only present if gossip is enabled.
+    pub(crate) membership: Option<Arc<Membership>>,
+
+    /// The users allowed to run commands, only present if authentication is enabled.
+    pub(crate) acl: Option<Arc<Acl>>,
+
+    /// The counters exported by the metrics endpoint.
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// The network, clock and randomness used to reach the other nodes.
+    pub(crate) env: Env,
+}
+
+impl Context {
+    /// Whether the membership protocol declared the node dead, or its suspicion level is above its
+    /// threshold. Contacting it would only delay the response until the request times out.
+    pub(crate) fn is_unavailable(&self, node: &str) -> bool {
+        match &self.membership {
+            Some(membership) => {
+                membership.view().state(node) == Some(MemberState::Dead)
+                    || !membership.detector().is_available(node)
+            }
+            None => false,
+        }
+    }
+}
 
 /// Executor is created per client, and it will handle the flow of oupdating the underlying storage
 /// for the whole duration of lifetime of the client.
@@ -19,87 +103,790 @@ pub(crate) struct Executor {
     /// The handler represents the client's connection that this executor will manage.
     handler: ConnectionHandler,
 
-    /// The shared storage between all of the executor instances and it's safe to access from
-    /// multiple threads.
-    store: Arc<Mutex<Box<dyn Storage + Send + Sync>>>,
+    /// The server-wide state shared with the other executors.
+    ctx: Context,
+
+    /// The user the connection authenticated as, or the default one.
+    user: Option<Arc<User>>,
+
+    /// Number of failed `AUTH` of the connection.
+    auth_failures: u32,
 }
 
 /// Execute comand dispatches the correct method to execute the command
-/// And writes the resulting `Response` to the handler's output.
-async fn execute_cmd(
-    store: &mut StorageEngine,
-    handler: &mut ConnectionHandler,
-    cmd: Command,
-) -> Result<()> {
-    let result = match cmd {
+/// And returns the resulting `Response`.
+///
+/// The gateways set `report_missing`, their protocols tell a missing key from an empty value when
+/// it's removed, unlike the native `UNSET`.
+async fn execute_cmd(ctx: &Context, cmd: Command, report_missing: bool) -> Response {
+    let (deadline, cmd) = match cmd {
+        Command::Deadline(deadline, cmd) => (Some(deadline), *cmd),
+        cmd => (None, cmd),
+    };
+    if let Some(deadline) = deadline {
+        if env::until(&*ctx.env.clock, deadline).is_zero() {
+            return Response::DeadlineExceeded;
+        }
+    }
+    if let Some(redirect) = check_ownership(ctx, &cmd) {
+        return redirect;
+    }
+    match cmd {
         Command::Ping(key) => handle_ping(key),
-        Command::Set(key, value) => handle_set(store, key, value),
-        Command::Get(key) => handle_get(store, key),
-        Command::Clear(key) => handle_unset(store, key),
+        Command::Set(key, value) => handle_set(ctx, key, value),
+        Command::Get(key) => handle_get(&ctx.store, key),
+        Command::Clear(key) => handle_unset(ctx, key, report_missing),
+        Command::Publish(channel, message) => handle_publish(&ctx.broker, channel, message),
+        Command::Changes(from, limit) => handle_changes(ctx, from, limit),
+        Command::Snapshot(name) => handle_snapshot(ctx, name).await,
+        Command::Scan(after, prefix, count) => handle_scan(&ctx.store, after, prefix, count),
+        Command::MSet(entries) => handle_mset(ctx, entries),
+        Command::AddNode(node) => handle_cluster_change(ctx, |cluster| cluster.add_node(&node)),
+        Command::RemoveNode(node) => {
+            handle_cluster_change(ctx, |cluster| cluster.remove_node(&node))
+        }
+        Command::Migrate(start, end) => handle_migrate(ctx, start, end).await,
+        Command::Topology => handle_topology(ctx),
+        Command::ReplicatedGet(key, quorum) => {
+            handle_replicated_get(ctx, key, quorum, deadline).await
+        }
+        Command::ReplicatedSet(key, value, context, quorum) => {
+            handle_replicated_set(ctx, key, value, context, quorum, deadline).await
+        }
+        Command::ReplicaRead(key) => handle_replica_read(&ctx.store, key),
+        Command::ReplicaWrite(key, versions) => handle_replica_write(ctx, key, versions),
+        Command::TreeHashes(node, level, positions) => {
+            handle_tree_hashes(ctx, node, level, positions)
+        }
+        Command::BucketVersions(node, buckets) => handle_bucket_versions(ctx, node, buckets),
+        // Subscriptions and watches are handled by the executor's push mode.
+        Command::Subscribe(_)
+        | Command::PSubscribe(_)
+        | Command::Unsubscribe(_)
+        | Command::Watch(_)
+        | Command::Unwatch(_) => Response::Error(String::from("Connection is not in push mode")),
+        Command::Deadline(..) => Response::Error(String::from("Deadlines can't be nested")),
+        // Authentication is handled by the executor when enabled.
+        Command::Auth(..) => Response::Error(String::from("Authentication is not enabled")),
+    }
+}
+
+/// The name of the command in the metrics, the one of the wrapped command for a deadline.
+fn command_name(cmd: &Command) -> &'static str {
+    match cmd {
+        Command::Deadline(_, cmd) => cmd.name(),
+        cmd => cmd.name(),
+    }
+}
+
+/// In cluster mode, returns a redirect to the owner of the first key of the command that is not
+/// owned by this node.
+fn check_ownership(ctx: &Context, cmd: &Command) -> Option<Response> {
+    let cluster = ctx.cluster.as_ref()?;
+    let redirect = match cmd {
+        Command::Get(key) | Command::Set(key, _) | Command::Clear(key) => cluster.redirect(key),
+        Command::MSet(entries) => entries.iter().find_map(|(key, _)| cluster.redirect(key)),
+        _ => None,
     };
-    handler.write_response(&result).await?;
-    Ok(())
+    redirect.map(Response::Moved)
 }
 
 fn handle_ping(key: String) -> Response {
     if key.is_empty() {
         // Default to a ping.
-        return Response::Ok("PONG".into());
+        Response::Ok("PONG".into())
     } else {
-        return Response::Ok(key);
+        Response::Ok(key)
     }
 }
 
-fn handle_set(store: &mut StorageEngine, key: String, value: String) -> Response {
-    let mut guard = store.lock().unwrap();
-    return match guard.set(key.clone(), value) {
-        Ok(_) => Response::Ok(key),
-        Err(_) => Response::Error(String::from("Error happened while setting the key")),
+fn handle_set(ctx: &Context, key: String, value: String) -> Response {
+    let mut guard = ctx.store.lock().unwrap();
+    // Changes are recorded while holding the lock, so that watchers and the change log see them in
+    // the same order they were applied. The change log is appended first, so that a change it
+    // failed to record is never applied.
+    if let Err(err) = log_change(ctx, &key, Operation::Set, Some(&value)) {
+        return err;
+    }
+    let previous = match &ctx.anti_entropy {
+        Some(_) => guard.get(&key).ok().flatten().cloned(),
+        None => None,
     };
+    match guard.set(key.clone(), value.clone()) {
+        Ok(_) => {
+            track(ctx, &key, previous.as_deref(), Some(&value));
+            ctx.notifier.notify(&key, Operation::Set, Some(&value));
+            Response::Ok(key)
+        }
+        Err(_) => Response::Error(String::from("Error happened while setting the key")),
+    }
 }
 
-fn handle_get(store: &mut StorageEngine, key: String) -> Response {
+fn handle_get(store: &StorageEngine, key: String) -> Response {
     let guard = store.lock().unwrap();
     match guard.get(&key) {
-        Ok(Some(val)) => {
-            return Response::Ok(val.clone());
-        }
-        _ => {
-            return Response::Error(String::from("Key not found"));
-        }
+        Ok(Some(val)) => Response::Ok(val.clone()),
+        _ => Response::Error(String::from(KEY_NOT_FOUND)),
     }
 }
 
-fn handle_unset(store: &mut StorageEngine, key: String) -> Response {
-    let mut guard = store.lock().unwrap();
+/// Answers with the removed value, empty if there was none. With `report_missing`, a missing key
+/// is answered with `KEY_NOT_FOUND` instead, to tell it apart from an empty value.
+fn handle_unset(ctx: &Context, key: String, report_missing: bool) -> Response {
+    let missing = || match report_missing {
+        true => Response::Error(String::from(KEY_NOT_FOUND)),
+        false => Response::Ok(String::new()),
+    };
+    let mut guard = ctx.store.lock().unwrap();
+    if !matches!(guard.get(&key), Ok(Some(_))) {
+        return missing();
+    }
+    if let Err(err) = log_change(ctx, &key, Operation::Unset, None) {
+        return err;
+    }
     match guard.unset(&key) {
         Ok(Some(value)) => {
-            return Response::Ok(value);
+            track(ctx, &key, Some(&value), None);
+            ctx.notifier.notify(&key, Operation::Unset, None);
+            Response::Ok(value)
+        }
+        _ => missing(),
+    }
+}
+
+fn handle_mset(ctx: &Context, entries: Vec<(String, String)>) -> Response {
+    let mut guard = ctx.store.lock().unwrap();
+    // The entries are applied all or nothing, the ones already applied are rolled back if one of
+    // them fails or the batch can't be recorded.
+    let mut applied: Vec<(&String, Option<String>)> = vec![];
+    let mut failed = None;
+    for (key, value) in entries.iter() {
+        let previous = guard.get(key).ok().flatten().cloned();
+        if guard.set(key.clone(), value.clone()).is_err() {
+            failed = Some(Response::Error(format!(
+                "Error happened while setting the key {}",
+                key
+            )));
+            break;
+        }
+        applied.push((key, previous));
+    }
+    if failed.is_none() && !entries.is_empty() {
+        if let Some(changelog) = &ctx.changelog {
+            let changes: Vec<(&str, Operation, Option<&str>)> = entries
+                .iter()
+                .map(|(key, value)| (key.as_str(), Operation::Set, Some(value.as_str())))
+                .collect();
+            if let Err(err) = changelog.append_all(&changes) {
+                failed = Some(Response::Error(format!(
+                    "Change could not be recorded: {}",
+                    err
+                )));
+            }
+        }
+    }
+    if let Some(response) = failed {
+        for (key, previous) in applied.into_iter().rev() {
+            let _ = match previous {
+                Some(previous) => guard.set(key.clone(), previous),
+                None => guard.unset(key).map(|_| ()),
+            };
         }
-        _ => {
-            return Response::Ok(String::new());
+        return response;
+    }
+    for ((key, value), (_, previous)) in entries.iter().zip(applied.iter()) {
+        track(ctx, key, previous.as_deref(), Some(value));
+        ctx.notifier.notify(key, Operation::Set, Some(value));
+    }
+    Response::Ok(entries.len().to_string())
+}
+
+fn handle_scan(
+    store: &StorageEngine,
+    after: Option<String>,
+    prefix: String,
+    count: u32,
+) -> Response {
+    if count == 0 {
+        return Response::Error(String::from("The count must be positive"));
+    }
+    let count = count.min(MAX_SCAN_BATCH) as usize;
+    // The keys are visited in order from the first one that can match, so the scan stops as soon
+    // as the page is full or the keys stop matching the prefix.
+    let start = match &after {
+        Some(after) if *after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(&prefix),
+    };
+    let guard = store.lock().unwrap();
+    let entries = match guard.range(start) {
+        Ok(entries) => entries,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    let page = entries
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .take(count)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    Response::Entries(page)
+}
+
+/// Keep the metrics, and the Merkle trees of the anti-entropy if it's enabled, in sync with the
+/// change of the key.
+fn track(ctx: &Context, key: &str, previous: Option<&str>, current: Option<&str>) {
+    ctx.metrics.change(key, previous, current);
+    if let (Some(cluster), Some(anti_entropy)) = (&ctx.cluster, &ctx.anti_entropy) {
+        anti_entropy.record(cluster, key, previous, current);
+    }
+}
+
+/// Append the change to the change log if it's enabled, before it's applied to the store.
+fn log_change(
+    ctx: &Context,
+    key: &str,
+    operation: Operation,
+    value: Option<&str>,
+) -> std::result::Result<(), Response> {
+    if let Some(changelog) = &ctx.changelog {
+        if let Err(err) = changelog.append(key, operation, value) {
+            return Err(Response::Error(format!(
+                "Change could not be recorded: {}",
+                err
+            )));
         }
     }
+    Ok(())
+}
+
+fn handle_publish(broker: &Broker, channel: String, message: String) -> Response {
+    let receivers = broker.publish(&channel, &message);
+    Response::Ok(receivers.to_string())
+}
+
+fn handle_changes(ctx: &Context, from: u64, limit: u32) -> Response {
+    let changelog = match &ctx.changelog {
+        Some(changelog) => changelog,
+        None => return Response::Error(String::from("Change data capture is not enabled")),
+    };
+    match changelog.read(from, limit.min(MAX_CHANGES_BATCH) as usize) {
+        Ok(changes) => Response::Changes(changes),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+async fn handle_snapshot(ctx: &Context, name: String) -> Response {
+    let dir = match &ctx.snapshot_dir {
+        Some(dir) => dir,
+        None => return Response::Error(String::from("Snapshots are not enabled")),
+    };
+    // Only allow plain file names, to never write outside of the snapshot directory.
+    if name.is_empty() || name.contains(['/', '\\']) || name == ".." {
+        return Response::Error(String::from("Invalid snapshot name"));
+    }
+    let path = dir.join(name);
+
+    // Copying the entries is much faster than writing them to disk, so the lock is only held while
+    // taking the copy, which is consistent as no writer can interleave with it.
+    let entries: Vec<(String, String)> = {
+        let guard = ctx.store.lock().unwrap();
+        let entries = match guard.iter() {
+            Ok(entries) => entries.map(|(k, v)| (k.clone(), v.clone())).collect(),
+            Err(err) => return Response::Error(format!("Unable to write the snapshot: {}", err)),
+        };
+        entries
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        snapshot::write(&path, entries.iter().map(|(k, v)| (k, v))).map(|_| path)
+    })
+    .await;
+    match result {
+        Ok(Ok(path)) => Response::Ok(path.display().to_string()),
+        Ok(Err(err)) => Response::Error(format!("Unable to write the snapshot: {}", err)),
+        Err(err) => Response::Error(format!("Unable to write the snapshot: {}", err)),
+    }
+}
+
+fn handle_cluster_change(ctx: &Context, change: impl FnOnce(&Cluster) -> bool) -> Response {
+    match &ctx.cluster {
+        Some(cluster) => Response::Ok(change(cluster).to_string()),
+        None => Response::Error(String::from("Cluster mode is not enabled")),
+    }
+}
+
+fn handle_topology(ctx: &Context) -> Response {
+    match &ctx.cluster {
+        Some(cluster) => {
+            let ring = cluster.ring();
+            let nodes = ring.nodes().map(String::from).collect();
+            Response::Topology(ring.vnodes() as u32, nodes)
+        }
+        None => Response::Error(String::from("Cluster mode is not enabled")),
+    }
+}
+
+/// Moves the local keys in the range that are owned by other nodes to their owners.
+///
+/// Nodes receiving keys must already know they own them, so the rings of all the nodes should be
+/// updated before starting a migration. Until the migration completes, reads of the keys being
+/// moved are redirected to the new owner which might not hold them yet.
+async fn handle_migrate(ctx: &Context, start: u64, end: u64) -> Response {
+    let cluster = match &ctx.cluster {
+        Some(cluster) => cluster,
+        None => return Response::Error(String::from("Cluster mode is not enabled")),
+    };
+    let in_range = |hash: u64| {
+        if start <= end {
+            start <= hash && hash <= end
+        } else {
+            hash >= start || hash <= end
+        }
+    };
+
+    let mut moves: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    {
+        let guard = ctx.store.lock().unwrap();
+        let ring = cluster.ring();
+        let entries = match guard.iter() {
+            Ok(entries) => entries,
+            Err(err) => return Response::Error(err.to_string()),
+        };
+        for (key, value) in entries {
+            let hash = cluster::hash(key);
+            if !in_range(hash) {
+                continue;
+            }
+            match ring.owner_of_hash(hash) {
+                Some(owner) if owner != cluster.node() => moves
+                    .entry(owner.to_string())
+                    .or_default()
+                    .push((key.clone(), value.clone())),
+                _ => {}
+            }
+        }
+    }
+
+    let mut migrated = 0;
+    for (owner, entries) in moves {
+        let mut client = match cluster.peers().connect(&ctx.env, &owner).await {
+            Ok(client) => client,
+            Err(err) => return Response::Error(format!("Unable to reach {}: {}", owner, err)),
+        };
+        for batch in entries.chunks(MIGRATE_BATCH) {
+            match client.mset(batch.to_vec()).await {
+                Ok(Some(Response::Ok(_))) => {}
+                res => {
+                    return Response::Error(format!(
+                        "Migration to {} failed after {} keys: {:?}",
+                        owner, migrated, res
+                    ))
+                }
+            }
+            // The keys are not deleted, they just live somewhere else now, so the removal is not
+            // recorded as a change.
+            let mut guard = ctx.store.lock().unwrap();
+            for (key, _) in batch {
+                if let Ok(Some(previous)) = guard.unset(key) {
+                    track(ctx, key, Some(&previous), None);
+                }
+            }
+            migrated += batch.len();
+        }
+    }
+    Response::Ok(migrated.to_string())
+}
+
+/// Reads the versions of the key from its replicas, returning once the quorum is reached.
+///
+/// Replicas holding stale versions are repaired in the background, including the ones answering
+/// after the response was sent.
+async fn handle_replicated_get(
+    ctx: &Context,
+    key: String,
+    quorum: u8,
+    deadline: Option<u64>,
+) -> Response {
+    let cluster = match &ctx.cluster {
+        Some(cluster) => cluster,
+        None => return Response::Error(String::from("Cluster mode is not enabled")),
+    };
+    let replicas = cluster.replicas(&key);
+    let quorum =
+        match replication::quorum(quorum, cluster.replication().read_quorum, replicas.len()) {
+            Ok(quorum) => quorum,
+            Err(err) => return err,
+        };
+    let (local, remote): (Vec<String>, Vec<String>) = replicas
+        .into_iter()
+        .filter(|node| !ctx.is_unavailable(node))
+        .partition(|node| node == cluster.node());
+
+    let command = Command::ReplicaRead(key.clone());
+    let mut pending = replication::fan_out(&ctx.env, cluster.peers(), remote, command, deadline);
+    let mut replies = vec![];
+    if !local.is_empty() {
+        let guard = ctx.store.lock().unwrap();
+        match replication::read(&**guard, &key) {
+            Ok(versions) => replies.push((cluster.node().to_string(), versions)),
+            Err(err) => return Response::Error(err.to_string()),
+        }
+    }
+    while replies.len() < quorum {
+        match pending.recv().await {
+            Some((node, Ok(Some(Response::Versions(versions))))) => replies.push((node, versions)),
+            Some(_) => {}
+            None => {
+                return Response::Error(format!(
+                    "Read quorum not reached, {} of {} replicas answered",
+                    replies.len(),
+                    quorum
+                ))
+            }
+        }
+    }
+    let merged = replies
+        .iter()
+        .fold(vec![], |merged, (_, versions)| reconcile(&merged, versions));
+    tokio::spawn(read_repair(
+        ctx.clone(),
+        key,
+        merged.clone(),
+        replies,
+        pending,
+    ));
+    Response::Versions(merged)
+}
+
+/// Writes the merged versions of a read to the replicas that answered with stale ones, once all
+/// of the pending replies were received.
+async fn read_repair(
+    ctx: Context,
+    key: String,
+    merged: Vec<Versioned>,
+    mut replies: Vec<(String, Vec<Versioned>)>,
+    mut pending: mpsc::Receiver<Reply>,
+) {
+    while let Some(reply) = pending.recv().await {
+        if let (node, Ok(Some(Response::Versions(versions)))) = reply {
+            replies.push((node, versions));
+        }
+    }
+    let cluster = match &ctx.cluster {
+        Some(cluster) => cluster.clone(),
+        None => return,
+    };
+    let node = cluster.node();
+    let mut stale = vec![];
+    for (replica, versions) in replies {
+        if reconcile(&versions, &merged) == versions {
+            continue;
+        }
+        if replica == node {
+            let mut guard = ctx.store.lock().unwrap();
+            let _ = store_versions(&ctx, guard.as_mut(), &key, &merged);
+        } else {
+            stale.push(replica);
+        }
+    }
+    // The replies are not needed, the next read repairs the replicas that failed again.
+    let command = Command::ReplicaWrite(key, merged);
+    replication::fan_out(&ctx.env, cluster.peers(), stale, command, None);
+}
+
+/// Writes a new version of the key, coordinated by this node, and returns it once the quorum of
+/// replicas acknowledged it. The write keeps on reaching the other replicas in the background.
+async fn handle_replicated_set(
+    ctx: &Context,
+    key: String,
+    value: Option<String>,
+    context: VectorClock,
+    quorum: u8,
+    deadline: Option<u64>,
+) -> Response {
+    let cluster = match &ctx.cluster {
+        Some(cluster) => cluster,
+        None => return Response::Error(String::from("Cluster mode is not enabled")),
+    };
+    let replicas = cluster.replicas(&key);
+    // Only replicas coordinate writes, as they know the counters they already handed out for the
+    // key, the others forward the write to the first replica.
+    if !replicas.iter().any(|node| node == cluster.node()) {
+        let owner = match replicas.first() {
+            Some(owner) => owner.clone(),
+            None => return Response::Error(String::from("The cluster has no nodes")),
+        };
+        let command = Command::ReplicatedSet(key, value, context, quorum);
+        let peers = cluster.peers();
+        return match replication::forward(&ctx.env, peers, &owner, command, deadline).await {
+            Ok(Some(response)) => response,
+            _ if deadline
+                .is_some_and(|deadline| env::until(&*ctx.env.clock, deadline).is_zero()) =>
+            {
+                Response::DeadlineExceeded
+            }
+            res => Response::Error(format!(
+                "Unable to forward the write to {}: {:?}",
+                owner, res
+            )),
+        };
+    }
+    let quorum =
+        match replication::quorum(quorum, cluster.replication().write_quorum, replicas.len()) {
+            Ok(quorum) => quorum,
+            Err(err) => return err,
+        };
+
+    let version = {
+        let mut guard = ctx.store.lock().unwrap();
+        let current = match replication::read(&**guard, &key) {
+            Ok(current) => current,
+            Err(err) => return Response::Error(err.to_string()),
+        };
+        let version = replication::stamp(&current, cluster.node(), value, context);
+        if let Err(err) = store_versions(ctx, guard.as_mut(), &key, std::slice::from_ref(&version))
+        {
+            return err;
+        }
+        version
+    };
+
+    let remote = replicas
+        .into_iter()
+        .filter(|node| node != cluster.node() && !ctx.is_unavailable(node))
+        .collect();
+    let command = Command::ReplicaWrite(key, vec![version.clone()]);
+    let mut pending = replication::fan_out(&ctx.env, cluster.peers(), remote, command, deadline);
+    let mut acknowledged = 1;
+    while acknowledged < quorum {
+        match pending.recv().await {
+            Some((_, Ok(Some(Response::Ok(_))))) => acknowledged += 1,
+            Some(_) => {}
+            None => {
+                return Response::Error(format!(
+                    "Write quorum not reached, {} of {} replicas acknowledged the write",
+                    acknowledged, quorum
+                ))
+            }
+        }
+    }
+    Response::Versions(vec![version])
+}
+
+fn handle_replica_read(store: &StorageEngine, key: String) -> Response {
+    let guard = store.lock().unwrap();
+    match replication::read(&**guard, &key) {
+        Ok(versions) => Response::Versions(versions),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn handle_replica_write(ctx: &Context, key: String, versions: Vec<Versioned>) -> Response {
+    let mut guard = ctx.store.lock().unwrap();
+    match store_versions(ctx, guard.as_mut(), &key, &versions) {
+        Ok(_) => Response::Ok(key),
+        Err(err) => err,
+    }
+}
+
+fn handle_tree_hashes(ctx: &Context, node: String, level: u8, positions: Vec<u32>) -> Response {
+    match (&ctx.cluster, &ctx.anti_entropy) {
+        (Some(cluster), Some(anti_entropy)) => {
+            let guard = ctx.store.lock().unwrap();
+            Response::Hashes(anti_entropy.hashes(cluster, &**guard, &node, level, &positions))
+        }
+        _ => Response::Error(String::from("Cluster mode is not enabled")),
+    }
+}
+
+fn handle_bucket_versions(ctx: &Context, node: String, buckets: Vec<u32>) -> Response {
+    match (&ctx.cluster, &ctx.anti_entropy) {
+        (Some(cluster), Some(anti_entropy)) => {
+            let guard = ctx.store.lock().unwrap();
+            Response::Entries(anti_entropy.bucket_entries(cluster, &**guard, &node, &buckets))
+        }
+        _ => Response::Error(String::from("Cluster mode is not enabled")),
+    }
+}
+
+/// Merge the versions into the ones stored for the key, recording the change if there is one.
+pub(crate) fn store_versions(
+    ctx: &Context,
+    store: &mut (dyn Storage + Send + Sync),
+    key: &str,
+    versions: &[Versioned],
+) -> std::result::Result<(), Response> {
+    let previous = store.get(&key.to_string()).ok().flatten().cloned();
+    match replication::merge(store, key, versions) {
+        Ok(Some(data)) => {
+            // The merge has to be applied to know its result, so it's rolled back if it can't be
+            // recorded.
+            if let Err(err) = log_change(ctx, key, Operation::Set, Some(&data)) {
+                let _ = match previous {
+                    Some(previous) => store.set(key.to_string(), previous).map(|_| ()),
+                    None => store.unset(&key.to_string()).map(|_| ()),
+                };
+                return Err(err);
+            }
+            track(ctx, key, previous.as_deref(), Some(&data));
+            ctx.notifier.notify(key, Operation::Set, Some(&data));
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(err) => Err(Response::Error(err.to_string())),
+    }
 }
 
 impl Executor {
-    pub(crate) fn new(handler: ConnectionHandler, store: StorageEngine) -> Self {
-        return Executor { handler, store };
+    pub(crate) fn new(handler: ConnectionHandler, ctx: Context) -> Self {
+        let user = ctx.acl.as_ref().and_then(|acl| acl.default_user());
+        Executor {
+            handler,
+            ctx,
+            user,
+            auth_failures: 0,
+        }
     }
 
+    /// Runs the connection until the client closes it, sends a malformed frame, or fails to
+    /// authenticate too many times.
     pub(crate) async fn run(&mut self) -> Result<()> {
-        loop {
-            let cmd = match self.handler.read_command().await {
-                Ok(val) => val,
-                Err(_msg) => None,
+        while self.auth_failures < MAX_AUTH_FAILURES {
+            let cmd = match self.handler.read_command().await? {
+                Some(cmd) => cmd,
+                None => break,
             };
-            if let Some(cmd) = cmd {
-                execute_cmd(&mut self.store, &mut self.handler, cmd).await?;
-            } else {
-                // Either there was an error trying to parse a command from the input stream,
-                // Or the client closed the socket. eitherway we won't be processing further
-                // requests for simplicity.
-                return Err("Connection closed / Poisoned message".into());
+            let started = self.ctx.env.clock.now();
+            let name = command_name(&cmd);
+            if let Some(response) = self.authorize(&cmd).await {
+                self.respond(name, started, &response).await?;
+                continue;
+            }
+            match cmd {
+                cmd @ Command::Subscribe(_)
+                | cmd @ Command::PSubscribe(_)
+                | cmd @ Command::Watch(_) => self.run_push_mode(cmd).await?,
+                cmd => {
+                    let report_missing = self.handler.is_gateway();
+                    let response = execute_cmd(&self.ctx, cmd, report_missing).await;
+                    self.respond(name, started, &response).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the execution of the command in the metrics, and writes its response.
+    async fn respond(
+        &mut self,
+        name: &'static str,
+        started: Instant,
+        response: &Response,
+    ) -> Result<()> {
+        let elapsed = self.ctx.env.clock.now().saturating_duration_since(started);
+        self.ctx.metrics.command(name, elapsed, response);
+        self.handler.write_response(response).await
+    }
+
+    /// Authenticates the connection on `AUTH`, and checks the other commands against the ACL of
+    /// its user. Returns the response to send instead of executing the command, if any.
+    ///
+    /// The password is checked on a blocking thread, hashing it would stall the other connections
+    /// of the worker.
+    async fn authorize(&mut self, cmd: &Command) -> Option<Response> {
+        let acl = self.ctx.acl.clone()?;
+        let inner = match cmd {
+            Command::Deadline(_, cmd) => cmd.as_ref(),
+            cmd => cmd,
+        };
+        let result = match inner {
+            Command::Auth(name, password) => {
+                let (name, password) = (name.clone(), password.clone());
+                let result = tokio::task::spawn_blocking(move || {
+                    acl.authenticate(&name, &password).map(|user| (user, name))
+                })
+                .await;
+                match result {
+                    Ok(Ok((user, name))) => {
+                        self.user = Some(user);
+                        Ok(Some(Response::Ok(name)))
+                    }
+                    Ok(Err(err)) => {
+                        self.auth_failures += 1;
+                        Err(err)
+                    }
+                    Err(err) => return Some(Response::Error(err.to_string())),
+                }
+            }
+            cmd => acl.check(self.user.as_deref(), cmd).map(|_| None),
+        };
+        result.unwrap_or_else(|err| Some(Response::Unauthorized(err.to_string())))
+    }
+
+    /// Runs the connection in push mode, starting with the given subscribe or watch command.
+    ///
+    /// While in push mode, published messages and keyspace events are pushed to the client as
+    /// soon as they are received, and only the subscription and watch commands, and `PING` are
+    /// accepted from the client. The executor goes back to the normal mode once the client
+    /// unsubscribes and unwatches everything.
+    async fn run_push_mode(&mut self, cmd: Command) -> Result<()> {
+        let mut subscription = self.ctx.broker.subscription();
+        let mut watch = self.ctx.notifier.watch();
+        let mut pending = Some(cmd);
+        loop {
+            if let Some(cmd) = pending.take() {
+                let started = self.ctx.env.clock.now();
+                let name = command_name(&cmd);
+                let response = if let Some(response) = self.authorize(&cmd).await {
+                    response
+                } else {
+                    match cmd {
+                        Command::Subscribe(channel) => {
+                            subscription.subscribe(channel.clone());
+                            Response::Ok(channel)
+                        }
+                        Command::PSubscribe(pattern) => {
+                            subscription.psubscribe(pattern.clone());
+                            Response::Ok(pattern)
+                        }
+                        Command::Unsubscribe(name) => {
+                            subscription.unsubscribe(&name);
+                            Response::Ok(name)
+                        }
+                        Command::Watch(prefix) => {
+                            watch.add(prefix.clone());
+                            Response::Ok(prefix)
+                        }
+                        Command::Unwatch(prefix) => {
+                            watch.remove(&prefix);
+                            Response::Ok(prefix)
+                        }
+                        Command::Ping(key) => handle_ping(key),
+                        _ => Response::Error(String::from(
+                            "Only (P)SUBSCRIBE, UNSUBSCRIBE, (UN)WATCH and PING are allowed in push mode",
+                        )),
+                    }
+                };
+                self.respond(name, started, &response).await?;
+                let locked_out = self.auth_failures >= MAX_AUTH_FAILURES;
+                if locked_out || (subscription.is_empty() && watch.is_empty()) {
+                    return Ok(());
+                }
+            }
+
+            tokio::select! {
+                message = subscription.recv() => {
+                    let response = Response::Message(message.channel, message.payload);
+                    self.handler.write_response(&response).await?;
+                }
+                notification = watch.recv() => {
+                    let response = match notification {
+                        Notification::Event(event) => Response::Event(event),
+                        Notification::Lagged(missed) => Response::Lagged(missed),
+                    };
+                    self.handler.write_response(&response).await?;
+                }
+                cmd = self.handler.read_command() => match cmd? {
+                    Some(cmd) => pending = Some(cmd),
+                    None => return Ok(()),
+                }
             }
         }
     }
@@ -1,10 +1,11 @@
-use bytes::{Buf, BytesMut};
-use std::io::Cursor;
-use tokio::io::{AsyncReadExt, BufWriter};
-use tokio::net::TcpStream;
+use bytes::BytesMut;
+use futures_util::SinkExt;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
-use crate::protocol::{Command, Parser, Response, Writer};
-use crate::Result;
+use crate::env::{Connection, Stream};
+use crate::protocol::{ClientCodec, Command, Gateway, Next, Response, ServerCodec};
+use crate::{Err, Result};
 
 /// A struct to encapsulate read / write logic of between the client and server.
 ///
@@ -12,72 +13,185 @@ use crate::Result;
 /// networking logic from other components.
 pub struct ConnectionHandler {
     // created per client connection, used to read/write commands and responses.
-    stream: BufWriter<TcpStream>,
-
-    // used as a temporary buffer of the data sent from the client.
-    buf: BytesMut,
+    framed: Framed<Connection, Codec>,
 }
 
 impl ConnectionHandler {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new<S: Stream + 'static>(stream: S) -> Self {
+        ConnectionHandler::with_codec(Box::new(stream), Codec::default())
+    }
+
+    /// A handler for the server side of a connection speaking the protocol of the gateway, e.g.
+    /// `protocol::resp`.
+    pub(crate) fn gateway<S: Stream + 'static>(stream: S, gateway: Box<dyn Gateway>) -> Self {
+        let codec = Codec {
+            gateway: Some(gateway),
+            ..Codec::default()
+        };
+        ConnectionHandler::with_codec(Box::new(stream), codec)
+    }
+
+    fn with_codec(stream: Connection, codec: Codec) -> Self {
         ConnectionHandler {
-            stream: BufWriter::new(stream),
             // size of the buffer is kind of arbitrary here.
-            buf: BytesMut::with_capacity(1 << 10),
+            framed: Framed::with_capacity(stream, codec, 1 << 10),
         }
     }
 
+    /// Whether the connection speaks the protocol of a gateway rather than the native one.
+    pub(crate) fn is_gateway(&self) -> bool {
+        self.framed.codec().gateway.is_some()
+    }
+
     pub async fn read_command(&mut self) -> Result<Option<Command>> {
-        if let None = self.ensure_filled().await? {
-            // `None` is returned when the connection is closed and no bytes can be read.
-            return Ok(None);
+        if self.is_gateway() {
+            return self.read_gateway_command().await;
+        }
+        match self.read_frame(Reading::Commands).await? {
+            Some(Frame::Command(command)) => Ok(Some(command)),
+            _ => Ok(None),
         }
-
-        let mut cursor = Cursor::new(&self.buf[..]);
-        let command = Parser::parse(&mut cursor)?;
-        // If reading the command was successfull, we should advance the internal
-        // pointer of the buffer as the cursor only updates it's own.
-        let length = cursor.position() as usize;
-        self.buf.advance(length);
-
-        Ok(Some(command))
     }
 
     pub async fn write_response(&mut self, resp: &Response) -> Result<()> {
-        Writer::write_response(&mut self.stream, resp).await?;
-        Ok(())
+        if let Some(gateway) = &mut self.framed.codec_mut().gateway {
+            let mut out = vec![];
+            gateway.write_response(resp, &mut out);
+            return self.framed.send(out).await;
+        }
+        self.framed.send(resp).await
     }
 
     pub async fn write_command(&mut self, cmd: &Command) -> Result<()> {
-        Writer::write_command(&mut self.stream, cmd).await?;
-        Ok(())
+        self.framed.send(cmd).await
     }
 
     pub async fn read_response(&mut self) -> Result<Option<Response>> {
-        if let None = self.ensure_filled().await? {
-            // `None` is returned when the connection is closed and no bytes can be read.
-            return Ok(None);
+        match self.read_frame(Reading::Responses).await? {
+            Some(Frame::Response(response)) => Ok(Some(response)),
+            _ => Ok(None),
         }
-        let mut cursor = Cursor::new(&self.buf[..]);
-        let response = Parser::parse_response(&mut cursor)?;
-        // If reading the command was successfull, we should advance the internal
-        // pointer of the buffer as the cursor only updates it's own.
-        let length = cursor.position() as usize;
-        self.buf.advance(length);
-
-        Ok(Some(response))
-    }
-
-    async fn ensure_filled(&mut self) -> Result<Option<usize>> {
-        // TODO: clean this up. Use a proper enum to indicate the state of the buffer.
-        if self.buf.is_empty() {
-            let read = self.stream.read_buf(&mut self.buf).await?;
-            if read == 0 {
-                // Connection is closed from the client
-                return Ok(None);
+    }
+
+    /// Reads from the socket until a full frame of the given kind is received.
+    ///
+    /// `None` is returned when the connection is closed and no bytes are left in the buffer.
+    ///
+    /// This method is cancellation safe: if it's used in a `tokio::select!` and another branch
+    /// completes first, no data is lost, and the partially received frame stays in the buffer.
+    async fn read_frame(&mut self, reading: Reading) -> Result<Option<Frame>> {
+        self.framed.codec_mut().reading = reading;
+        self.framed.next().await.transpose()
+    }
+
+    /// Like `read_frame` for the protocol of the gateway, the requests that don't translate to a
+    /// command are answered right away.
+    async fn read_gateway_command(&mut self) -> Result<Option<Command>> {
+        loop {
+            let (out, next) = match self.read_frame(Reading::Requests).await? {
+                Some(Frame::Request(out, next)) => (out, next),
+                _ => return Ok(None),
+            };
+            if !out.is_empty() {
+                self.framed.send(out).await?;
+            }
+            match next? {
+                Next::Command(command) => return Ok(Some(command)),
+                Next::Quit => return Ok(None),
+                Next::Incomplete => {}
             }
-            return Ok(Some(read));
         }
-        Ok(Some(0))
+    }
+}
+
+/// The kind of frames a handler is waiting for.
+#[derive(Default)]
+enum Reading {
+    #[default]
+    Commands,
+    Responses,
+    /// The requests of the gateway's protocol.
+    Requests,
+}
+
+enum Frame {
+    Command(Command),
+    Response(Response),
+    /// The replies of the requests the gateway answered, followed by what to do next. The replies
+    /// are sent even if the request is malformed, to tell the client what went wrong.
+    Request(Vec<u8>, Result<Next>),
+}
+
+/// Frames both sides of the native protocol with `ServerCodec` and `ClientCodec`, or the protocol
+/// of the gateway.
+#[derive(Default)]
+struct Codec {
+    reading: Reading,
+    gateway: Option<Box<dyn Gateway>>,
+}
+
+impl Codec {
+    fn decode_request(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        let gateway = self
+            .gateway
+            .as_mut()
+            .ok_or("The connection has no gateway")?;
+        let mut out = vec![];
+        match gateway.next_command(src, &mut out) {
+            Ok(Next::Incomplete) if out.is_empty() => Ok(None),
+            Err(err) if out.is_empty() => Err(err),
+            next => Ok(Some(Frame::Request(out, next))),
+        }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Frame;
+    type Error = Err;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        match self.reading {
+            Reading::Commands => Ok(ServerCodec.decode(src)?.map(Frame::Command)),
+            Reading::Responses => Ok(ClientCodec.decode(src)?.map(Frame::Response)),
+            Reading::Requests => self.decode_request(src),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        match self.reading {
+            Reading::Commands => Ok(ServerCodec.decode_eof(src)?.map(Frame::Command)),
+            Reading::Responses => Ok(ClientCodec.decode_eof(src)?.map(Frame::Response)),
+            Reading::Requests => match self.decode_request(src)? {
+                Some(frame) => Ok(Some(frame)),
+                None if src.is_empty() => Ok(None),
+                None => Err("Connection closed in the middle of a frame".into()),
+            },
+        }
+    }
+}
+
+impl Encoder<&Command> for Codec {
+    type Error = Err;
+
+    fn encode(&mut self, item: &Command, dst: &mut BytesMut) -> Result<()> {
+        ClientCodec.encode(item, dst)
+    }
+}
+
+impl Encoder<&Response> for Codec {
+    type Error = Err;
+
+    fn encode(&mut self, item: &Response, dst: &mut BytesMut) -> Result<()> {
+        ServerCodec.encode(item, dst)
+    }
+}
+
+/// The raw replies of the gateway.
+impl Encoder<Vec<u8>> for Codec {
+    type Error = Err;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&item);
+        Ok(())
     }
 }
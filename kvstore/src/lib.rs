@@ -8,13 +8,58 @@ pub mod protocol;
 pub use protocol::{Command, Parser, Response, Writer};
 
 pub mod client;
-pub use client::{create, Client};
+pub use client::{
+    create, Client, ClientError, ClientOptions, Credentials, RetryPolicy, Subscriber, Watcher,
+};
+
+pub mod pool;
+pub use pool::{Pool, PoolOptions, PooledClient};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub mod server;
 
+pub mod acl;
+pub use acl::{Acl, Unauthorized};
+
+pub mod env;
+pub use env::Env;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod linearizability;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod proxy;
+#[cfg(any(test, feature = "testing"))]
+pub use proxy::FaultProxy;
+
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
+
+pub mod pubsub;
+pub use pubsub::Message;
+
+pub mod notify;
+pub use notify::{Event, Notification, Operation};
+
+pub mod cdc;
+pub use cdc::{Change, ChangeLog, ChangeLogOptions};
+
+pub mod snapshot;
+
+pub mod dump;
+
+pub mod cluster;
+
 pub mod storage;
 pub use storage::{Storage, StorageOptions};
 
 mod executor;
 
+mod metrics;
+
 pub const DEFAULT_PORT: &str = "6555";
@@ -0,0 +1,428 @@
+//! Linearizability checking of the histories of single-key operations.
+//!
+//! Clients record the operations they issue concurrently with a `Recorder`, which notes when every
+//! operation was invoked and when it completed, along with its result. The history is then checked
+//! against the model of a register with a Wing & Gong search, memoizing the configurations already
+//! explored as in Lowe's variant and Knossos. Keys are independent registers, so every key is
+//! checked on its own.
+//!
+//! Operations that failed or timed out might have been applied or not, they are part of the history
+//! with an unknown outcome: they may take effect at any point after their invocation, or never.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::client::Client;
+use crate::protocol::Response;
+
+/// An operation on a register.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Input {
+    Get,
+    Set(String),
+    Unset,
+}
+
+/// The result of an operation on a register.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Output {
+    /// The value read by a `Get`, `None` if the key was not found.
+    Value(Option<String>),
+    Set,
+    /// The value removed by an `Unset`, `None` if the key was not set.
+    Removed(Option<String>),
+}
+
+/// An operation of the history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The client which issued the operation, a client issues one operation at a time.
+    pub process: usize,
+    pub key: String,
+    pub input: Input,
+    /// `None` if the outcome of the operation is unknown.
+    pub output: Option<Output>,
+    /// Logical timestamps of the invocation and of the completion of the operation, taken from the
+    /// same counter, `completed` is `None` if the outcome is unknown.
+    pub invoked: u64,
+    pub completed: Option<u64>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let input = match &self.input {
+            Input::Get => String::from("get"),
+            Input::Set(value) => format!("set {:?}", value),
+            Input::Unset => String::from("unset"),
+        };
+        let output = match &self.output {
+            Some(Output::Value(value)) | Some(Output::Removed(value)) => format!("{:?}", value),
+            Some(Output::Set) => String::from("ok"),
+            None => String::from("unknown"),
+        };
+        let completed = match self.completed {
+            Some(completed) => completed.to_string(),
+            None => String::from("?"),
+        };
+        write!(
+            f,
+            "[{}, {}] process {}: {} {} -> {}",
+            self.invoked, completed, self.process, input, self.key, output
+        )
+    }
+}
+
+/// A non-linearizable subset of a history, from which no operation can be removed without making
+/// it linearizable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counterexample {
+    pub key: String,
+    pub entries: Vec<Entry>,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The operations on {} are not linearizable:", self.key)?;
+        for entry in self.entries.iter() {
+            writeln!(f, "  {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the operations issued by concurrent clients. It's cheap to clone, clones share the same
+/// history.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    clock: Arc<AtomicU64>,
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+/// An operation that was invoked, but not completed yet.
+pub struct Call {
+    process: usize,
+    key: String,
+    input: Input,
+    invoked: u64,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Record the invocation of an operation, right before sending it.
+    pub fn invoke(&self, process: usize, key: &str, input: Input) -> Call {
+        Call {
+            process,
+            key: key.to_string(),
+            input,
+            invoked: self.tick(),
+        }
+    }
+
+    /// Record the completion of an operation, right after receiving its result, `None` if the
+    /// outcome is unknown.
+    pub fn complete(&self, call: Call, output: Option<Output>) {
+        let completed = output.as_ref().map(|_| self.tick());
+        self.entries.lock().unwrap().push(Entry {
+            process: call.process,
+            key: call.key,
+            input: call.input,
+            output,
+            invoked: call.invoked,
+            completed,
+        });
+    }
+
+    /// Read the key with the client, recording the operation.
+    pub async fn get(&self, client: &mut Client, process: usize, key: &str) {
+        let call = self.invoke(process, key, Input::Get);
+        let output = match client.get(key.to_string()).await {
+            Ok(Some(Response::Ok(value))) => Some(Output::Value(Some(value))),
+            Ok(Some(response)) if response.is_key_not_found() => Some(Output::Value(None)),
+            _ => None,
+        };
+        self.complete(call, output);
+    }
+
+    /// Write the key with the client, recording the operation. The value must not be empty, as the
+    /// server answers an `Unset` of a missing key with an empty value.
+    pub async fn set(&self, client: &mut Client, process: usize, key: &str, value: &str) {
+        let call = self.invoke(process, key, Input::Set(value.to_string()));
+        let output = match client.set(key.to_string(), value.to_string()).await {
+            Ok(Some(Response::Ok(_))) => Some(Output::Set),
+            _ => None,
+        };
+        self.complete(call, output);
+    }
+
+    /// Remove the key with the client, recording the operation.
+    pub async fn unset(&self, client: &mut Client, process: usize, key: &str) {
+        let call = self.invoke(process, key, Input::Unset);
+        let output = match client.unset(key.to_string()).await {
+            Ok(Some(Response::Ok(value))) if value.is_empty() => Some(Output::Removed(None)),
+            Ok(Some(Response::Ok(value))) => Some(Output::Removed(Some(value))),
+            _ => None,
+        };
+        self.complete(call, output);
+    }
+
+    /// The operations recorded so far, in the order they completed.
+    pub fn history(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+/// Checks that the history is linearizable, returning a minimal counterexample otherwise.
+pub fn check(history: &[Entry]) -> Result<(), Counterexample> {
+    let mut keys: BTreeMap<&str, Vec<Entry>> = BTreeMap::new();
+    for entry in history {
+        keys.entry(&entry.key).or_default().push(entry.clone());
+    }
+    for (key, entries) in keys {
+        if !is_linearizable(&entries) {
+            return Err(Counterexample {
+                key: key.to_string(),
+                entries: minimize(entries),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Removes the operations one after the other, as long as the remaining ones are still not
+/// linearizable.
+///
+/// The writes of the values read by the remaining operations are kept, otherwise a stale read
+/// would always boil down to the read of a value that was never written.
+fn minimize(mut entries: Vec<Entry>) -> Vec<Entry> {
+    // Removing a read might allow removing the write it observed, so passes are repeated until no
+    // operation can be removed.
+    let mut removed_any = true;
+    while removed_any {
+        removed_any = false;
+        let mut i = 0;
+        while i < entries.len() {
+            let mut candidate = entries.clone();
+            let removed = candidate.remove(i);
+            if is_observed(&removed, &candidate) || is_linearizable(&candidate) {
+                i += 1;
+            } else {
+                entries = candidate;
+                removed_any = true;
+            }
+        }
+    }
+    entries.sort_by_key(|entry| entry.invoked);
+    entries
+}
+
+/// Whether the entry is the only write of a value returned by one of the other entries.
+fn is_observed(entry: &Entry, others: &[Entry]) -> bool {
+    let value = match &entry.input {
+        Input::Set(value) => value,
+        _ => return false,
+    };
+    let written = |other: &Entry| other.input == entry.input;
+    let read = |other: &Entry| match &other.output {
+        Some(Output::Value(Some(read))) | Some(Output::Removed(Some(read))) => read == value,
+        _ => false,
+    };
+    others.iter().any(read) && !others.iter().any(written)
+}
+
+/// Applies the operation to the register, returning its new state if the operation could have
+/// returned the given output.
+fn step(state: &Option<String>, input: &Input, output: Option<&Output>) -> Option<Option<String>> {
+    let (next, expected) = match input {
+        Input::Get => (state.clone(), Output::Value(state.clone())),
+        Input::Set(value) => (Some(value.clone()), Output::Set),
+        Input::Unset => (None, Output::Removed(state.clone())),
+    };
+    match output {
+        Some(output) if *output != expected => None,
+        _ => Some(next),
+    }
+}
+
+/// The search of a linearization of the operations on a single register.
+///
+/// The operations are linearized one at a time. An operation can be picked next if no remaining
+/// operation completed before it was invoked, and if the register could have returned its output.
+/// Operations with an unknown outcome never have to be picked. Configurations are identified by
+/// the set of linearized operations and the state of the register, the ones already explored are
+/// not explored again.
+fn is_linearizable(entries: &[Entry]) -> bool {
+    let mut search = Search {
+        entries,
+        linearized: vec![false; entries.len()],
+        visited: HashSet::new(),
+    };
+    search.explore(None)
+}
+
+struct Search<'a> {
+    entries: &'a [Entry],
+    linearized: Vec<bool>,
+    visited: HashSet<(Vec<bool>, Option<String>)>,
+}
+
+impl Search<'_> {
+    fn explore(&mut self, state: Option<String>) -> bool {
+        // The earliest completion of the remaining operations, which must be linearized before
+        // any operation invoked after it.
+        let deadline = self
+            .remaining()
+            .filter_map(|(_, entry)| entry.completed)
+            .min();
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return true,
+        };
+        if !self
+            .visited
+            .insert((self.linearized.clone(), state.clone()))
+        {
+            return false;
+        }
+        let candidates: Vec<usize> = self
+            .remaining()
+            .filter(|(_, entry)| entry.invoked < deadline)
+            .map(|(i, _)| i)
+            .collect();
+        for i in candidates {
+            let entry = &self.entries[i];
+            if let Some(next) = step(&state, &entry.input, entry.output.as_ref()) {
+                self.linearized[i] = true;
+                if self.explore(next) {
+                    return true;
+                }
+                self.linearized[i] = false;
+            }
+        }
+        false
+    }
+
+    fn remaining(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| !self.linearized[*i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(process: usize, input: Input, output: Option<Output>, span: (u64, u64)) -> Entry {
+        Entry {
+            process,
+            key: String::from("key"),
+            input,
+            completed: output.as_ref().map(|_| span.1),
+            output,
+            invoked: span.0,
+        }
+    }
+
+    fn set(value: &str) -> Input {
+        Input::Set(value.to_string())
+    }
+
+    fn read(value: Option<&str>) -> Option<Output> {
+        Some(Output::Value(value.map(String::from)))
+    }
+
+    #[test]
+    fn test_sequential() {
+        let history = vec![
+            entry(0, Input::Get, read(None), (0, 1)),
+            entry(0, set("a"), Some(Output::Set), (2, 3)),
+            entry(1, Input::Get, read(Some("a")), (4, 5)),
+            entry(
+                1,
+                Input::Unset,
+                Some(Output::Removed(Some(String::from("a")))),
+                (6, 7),
+            ),
+            entry(0, Input::Get, read(None), (8, 9)),
+        ];
+        assert_eq!(check(&history), Ok(()));
+    }
+
+    #[test]
+    fn test_concurrent() {
+        // both reads overlap with both writes, they can be ordered in between.
+        let history = vec![
+            entry(0, set("a"), Some(Output::Set), (0, 5)),
+            entry(1, set("b"), Some(Output::Set), (1, 6)),
+            entry(2, Input::Get, read(Some("b")), (2, 7)),
+            entry(3, Input::Get, read(Some("a")), (3, 8)),
+        ];
+        assert_eq!(check(&history), Ok(()));
+    }
+
+    #[test]
+    fn test_stale_read() {
+        let history = vec![
+            entry(0, set("a"), Some(Output::Set), (0, 1)),
+            entry(1, Input::Get, read(Some("a")), (2, 3)),
+            entry(0, set("b"), Some(Output::Set), (4, 5)),
+            entry(2, Input::Get, read(Some("c")), (6, 7)),
+            entry(1, Input::Get, read(Some("a")), (8, 9)),
+        ];
+        let counterexample = check(&history).unwrap_err();
+        // the read of a value never written is enough to break linearizability.
+        assert_eq!(counterexample.entries, vec![history[3].clone()]);
+
+        // the writes of the values read are kept, to show the read is stale.
+        let history = vec![
+            history[0].clone(),
+            history[1].clone(),
+            history[2].clone(),
+            history[4].clone(),
+        ];
+        let counterexample = check(&history).unwrap_err();
+        assert_eq!(
+            counterexample.entries,
+            vec![history[0].clone(), history[2].clone(), history[3].clone()]
+        );
+        assert!(counterexample
+            .to_string()
+            .contains("[8, 9] process 1: get key -> Some(\"a\")"));
+    }
+
+    #[test]
+    fn test_unknown_outcome() {
+        // the timed out write might have been applied, at any time after it was invoked.
+        let history = vec![
+            entry(0, set("a"), None, (0, 0)),
+            entry(1, Input::Get, read(None), (1, 2)),
+            entry(1, Input::Get, read(Some("a")), (3, 4)),
+        ];
+        assert_eq!(check(&history), Ok(()));
+
+        // but it can't be applied before it was invoked.
+        let history = vec![
+            entry(1, Input::Get, read(Some("a")), (1, 2)),
+            entry(0, set("a"), None, (3, 3)),
+        ];
+        assert!(check(&history).is_err());
+    }
+
+    #[test]
+    fn test_keys_are_independent() {
+        let mut other = entry(1, Input::Get, read(None), (2, 3));
+        other.key = String::from("other");
+        let history = vec![entry(0, set("a"), Some(Output::Set), (0, 1)), other];
+        assert_eq!(check(&history), Ok(()));
+    }
+}
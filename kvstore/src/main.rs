@@ -1,11 +1,304 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use tokio::net::TcpListener;
 
-use kvstore::server;
+use kvstore::cluster::{ClusterOptions, MembershipOptions, PhiOptions, ReplicationOptions};
+use kvstore::server::{self, Endpoint, ServerOptions, UnixSocketOptions};
+use kvstore::{Acl, ChangeLogOptions, Credentials};
+
+/// The environment variable holding the password of `--cluster-user`, so that it does not show in
+/// the command line of the process.
+const CLUSTER_PASSWORD_VAR: &str = "KVSTORE_CLUSTER_PASSWORD";
+
+const USAGE: &str = "Usage: kvstore [--listen <addr>] [--restore <snapshot>] \
+    [--snapshot-dir <dir>] \
+    [--changelog-dir <dir> [--changelog-segment-bytes <bytes>] [--changelog-max-bytes <bytes>] \
+    [--changelog-max-age <secs>]] \
+    [--cluster-node <host:port> [--cluster-peer <host:port>]... [--vnodes <count>] \
+    [--replicas <count>] [--read-quorum <count>] [--write-quorum <count>] \
+    [--anti-entropy-interval <secs>] \
+    [--gossip [--phi-threshold <phi>] [--peer-phi-threshold <host:port>=<phi>]...]] \
+    [--unix-socket <path> [--unix-socket-mode <octal>]] \
+    [--tls-cert <pem> --tls-key <pem> [--tls-client-ca <pem>] \
+    [--peer-ca <pem> --peer-name <name>]] \
+    [--acl-file <path> [--cluster-user <name>]] \
+    [--resp <addr>] [--http <addr> [--http-max-body <bytes>]] [--metrics <addr>]";
+
+/// Parse the command line arguments into the address of the native listener and the server
+/// options.
+///
+/// The native listener defaults to the port of `--cluster-node` in cluster mode, the one the other
+/// nodes and the redirected clients connect to.
+fn parse_args() -> Result<(SocketAddr, ServerOptions), String> {
+    let mut options = ServerOptions::default();
+    let mut listen = None;
+    let mut segment_bytes = None;
+    let mut changelog_max_bytes = None;
+    let mut changelog_max_age = None;
+    let mut peers = vec![];
+    let mut vnodes = None;
+    let mut replication = ReplicationOptions::default();
+    let mut replication_set = false;
+    let mut anti_entropy_interval = None;
+    let mut gossip = false;
+    let mut phi = PhiOptions::default();
+    let mut phi_set = false;
+    let mut unix_socket_mode = None;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut tls_client_ca = None;
+    let mut peer_ca = None;
+    let mut peer_name = None;
+    let mut cluster_user = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--listen" => {
+                let addr = value()?
+                    .parse()
+                    .map_err(|_| "Invalid address for --listen")?;
+                listen = Some(addr);
+            }
+            "--restore" => options.restore_from = Some(PathBuf::from(value()?)),
+            "--snapshot-dir" => options.snapshot_dir = Some(PathBuf::from(value()?)),
+            "--changelog-dir" => options.changelog = Some(ChangeLogOptions::new(value()?)),
+            "--changelog-segment-bytes" => {
+                let bytes = value()?.parse().map_err(|_| "Invalid segment size")?;
+                segment_bytes = Some(bytes);
+            }
+            "--changelog-max-bytes" => {
+                let bytes = value()?.parse().map_err(|_| "Invalid change log size")?;
+                changelog_max_bytes = Some(bytes);
+            }
+            "--changelog-max-age" => {
+                let secs = value()?.parse().map_err(|_| "Invalid change log age")?;
+                changelog_max_age = Some(std::time::Duration::from_secs(secs));
+            }
+            "--cluster-node" => options.cluster = Some(ClusterOptions::new(value()?, vec![])),
+            "--cluster-peer" => peers.push(value()?),
+            "--vnodes" => vnodes = Some(value()?.parse().map_err(|_| "Invalid number of vnodes")?),
+            "--replicas" => {
+                replication.replicas =
+                    value()?.parse().map_err(|_| "Invalid number of replicas")?;
+                replication_set = true;
+            }
+            "--read-quorum" => {
+                replication.read_quorum = value()?.parse().map_err(|_| "Invalid read quorum")?;
+                replication_set = true;
+            }
+            "--write-quorum" => {
+                replication.write_quorum = value()?.parse().map_err(|_| "Invalid write quorum")?;
+                replication_set = true;
+            }
+            "--anti-entropy-interval" => {
+                let secs = value()?
+                    .parse()
+                    .map_err(|_| "Invalid anti-entropy interval")?;
+                anti_entropy_interval = Some(std::time::Duration::from_secs(secs));
+            }
+            "--gossip" => gossip = true,
+            "--phi-threshold" => {
+                phi.threshold = value()?.parse().map_err(|_| "Invalid phi threshold")?;
+                phi_set = true;
+            }
+            "--peer-phi-threshold" => {
+                let value = value()?;
+                let (peer, threshold) = value
+                    .rsplit_once('=')
+                    .ok_or("Expected <peer>=<threshold> for --peer-phi-threshold")?;
+                let threshold = threshold.parse().map_err(|_| "Invalid phi threshold")?;
+                phi.thresholds.insert(peer.to_string(), threshold);
+                phi_set = true;
+            }
+            "--unix-socket" => options.unix_socket = Some(UnixSocketOptions::new(value()?)),
+            "--unix-socket-mode" => {
+                let mode = u32::from_str_radix(&value()?, 8).map_err(|_| "Invalid socket mode")?;
+                unix_socket_mode = Some(mode);
+            }
+            "--tls-cert" => tls_cert = Some(PathBuf::from(value()?)),
+            "--tls-key" => tls_key = Some(PathBuf::from(value()?)),
+            "--tls-client-ca" => tls_client_ca = Some(PathBuf::from(value()?)),
+            "--peer-ca" => peer_ca = Some(PathBuf::from(value()?)),
+            "--peer-name" => peer_name = Some(value()?),
+            "--cluster-user" => cluster_user = Some(value()?),
+            "--acl-file" => {
+                let acl = Acl::load(value()?).map_err(|err| format!("Invalid ACL file {}", err))?;
+                options.acl = Some(acl);
+            }
+            "--resp" => {
+                let addr = value()?.parse().map_err(|_| "Invalid address for --resp")?;
+                options.resp = Some(Endpoint::Addr(addr));
+            }
+            "--http" => {
+                let addr = value()?.parse().map_err(|_| "Invalid address for --http")?;
+                options.http = Some(Endpoint::Addr(addr));
+            }
+            "--http-max-body" => {
+                let bytes = value()?.parse().map_err(|_| "Invalid HTTP body size")?;
+                options.http_max_body = Some(bytes);
+            }
+            "--metrics" => {
+                let addr = value()?
+                    .parse()
+                    .map_err(|_| "Invalid address for --metrics")?;
+                options.metrics = Some(Endpoint::Addr(addr));
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    match &mut options.changelog {
+        Some(changelog) => {
+            changelog.segment_bytes = segment_bytes.unwrap_or(changelog.segment_bytes);
+            changelog.max_bytes = changelog_max_bytes;
+            changelog.max_age = changelog_max_age;
+        }
+        None if segment_bytes.is_some()
+            || changelog_max_bytes.is_some()
+            || changelog_max_age.is_some() =>
+        {
+            return Err(String::from(
+                "--changelog-dir is required by the change log options",
+            ))
+        }
+        None => {}
+    }
+    match (&mut options.unix_socket, unix_socket_mode) {
+        (Some(unix_socket), Some(mode)) => unix_socket.mode = mode,
+        (None, Some(_)) => return Err(String::from("--unix-socket-mode requires --unix-socket")),
+        _ => {}
+    }
+    let peer = match (peer_ca, peer_name) {
+        (Some(ca), Some(name)) => Some((ca, name)),
+        (None, None) => None,
+        _ => {
+            return Err(String::from(
+                "--peer-ca and --peer-name are both required to connect to the other nodes",
+            ))
+        }
+    };
+    if peer.is_some() && options.cluster.is_none() {
+        return Err(String::from("--cluster-node is required in cluster mode"));
+    }
+    match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => set_tls(&mut options, cert, key, tls_client_ca, peer)?,
+        (None, None) if tls_client_ca.is_none() && peer.is_none() => {}
+        _ => {
+            return Err(String::from(
+                "--tls-cert and --tls-key are both required for TLS",
+            ))
+        }
+    }
+    match &mut options.cluster {
+        Some(cluster) => {
+            cluster.peers = peers;
+            cluster.vnodes = vnodes.unwrap_or(cluster.vnodes);
+            cluster.replication = replication;
+            cluster.anti_entropy.interval = anti_entropy_interval;
+            if let Some(user) = cluster_user {
+                let password = std::env::var(CLUSTER_PASSWORD_VAR).map_err(|_| {
+                    format!("{} is required by --cluster-user", CLUSTER_PASSWORD_VAR)
+                })?;
+                cluster.credentials = Some(Credentials::new(user, password));
+            }
+            if gossip {
+                cluster.membership = Some(MembershipOptions {
+                    phi,
+                    ..Default::default()
+                });
+            } else if phi_set {
+                return Err(String::from("--gossip is required by the failure detector"));
+            }
+        }
+        None if !peers.is_empty()
+            || vnodes.is_some()
+            || replication_set
+            || anti_entropy_interval.is_some()
+            || cluster_user.is_some()
+            || gossip
+            || phi_set =>
+        {
+            return Err(String::from("--cluster-node is required in cluster mode"))
+        }
+        None => {}
+    }
+    let listen = match listen {
+        Some(addr) => addr,
+        None => {
+            let port = match &options.cluster {
+                Some(cluster) => cluster.node.rsplit_once(':').map_or("", |(_, port)| port),
+                None => kvstore::DEFAULT_PORT,
+            };
+            let port = port
+                .parse()
+                .map_err(|_| "Expected <host:port> for --cluster-node")?;
+            SocketAddr::from(([0, 0, 0, 0], port))
+        }
+    };
+    Ok((listen, options))
+}
+
+/// Sets up TLS on the listeners, and on the connections to the other nodes in cluster mode. The
+/// nodes present their own certificate to each other, for the ones requiring mutual TLS.
+#[cfg(feature = "tls")]
+fn set_tls(
+    options: &mut ServerOptions,
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    peer: Option<(PathBuf, String)>,
+) -> Result<(), String> {
+    use kvstore::tls::{ClientTlsOptions, ServerTlsOptions, TlsAcceptor, TlsConnector};
+
+    let error = |err| format!("Unable to set up TLS: {}", err);
+    if let Some(cluster) = &mut options.cluster {
+        let (ca, server_name) =
+            peer.ok_or("--peer-ca and --peer-name are required for TLS in cluster mode")?;
+        let tls = ClientTlsOptions {
+            ca,
+            server_name,
+            identity: Some((cert.clone(), key.clone())),
+        };
+        cluster.tls = Some(TlsConnector::new(&tls).map_err(error)?);
+    }
+    let tls = ServerTlsOptions {
+        cert,
+        key,
+        client_ca,
+    };
+    options.tls = Some(TlsAcceptor::new(tls).map_err(error)?);
+    options.reload_tls_on_hangup = true;
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn set_tls(
+    _: &mut ServerOptions,
+    _: PathBuf,
+    _: PathBuf,
+    _: Option<PathBuf>,
+    _: Option<(PathBuf, String)>,
+) -> Result<(), String> {
+    Err(String::from(
+        "TLS requires the server to be built with the tls feature",
+    ))
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", kvstore::DEFAULT_PORT))
-        .await
-        .unwrap();
-    server::run(listener).await;
+    let (listen, options) = match parse_args() {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}\n{}", msg, USAGE);
+            std::process::exit(1);
+        }
+    };
+    let result = match TcpListener::bind(listen).await {
+        Ok(listener) => server::run_with_options(listener, options).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        eprintln!("Unable to start the server: {}", err);
+        std::process::exit(1);
+    }
 }
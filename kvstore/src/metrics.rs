@@ -0,0 +1,420 @@
+//! The counters of the server, exported in the Prometheus text format, see
+//! `ServerOptions::metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{Response, Storage};
+
+/// The upper bounds of the buckets of the command latency histograms, in seconds.
+const BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// The kinds of errors counted by the server.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ErrorKind {
+    /// The listener failed to accept a connection.
+    Accept,
+    /// The TLS handshake of a connection failed.
+    Handshake,
+    /// A connection failed, e.g. it sent a malformed frame.
+    Connection,
+    /// A command answered with an error.
+    Command,
+    /// A command was rejected by the ACL, or the authentication failed.
+    Unauthorized,
+    /// A command was redirected to the node owning its key.
+    Moved,
+    /// The deadline of a command passed before it was executed.
+    DeadlineExceeded,
+    /// An anti-entropy round with a peer failed.
+    AntiEntropy,
+    /// The change log failed to remove its expired segments.
+    Retention,
+    /// The TLS certificates could not be reloaded.
+    TlsReload,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 10] = [
+        ErrorKind::Accept,
+        ErrorKind::Handshake,
+        ErrorKind::Connection,
+        ErrorKind::Command,
+        ErrorKind::Unauthorized,
+        ErrorKind::Moved,
+        ErrorKind::DeadlineExceeded,
+        ErrorKind::AntiEntropy,
+        ErrorKind::Retention,
+        ErrorKind::TlsReload,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ErrorKind::Accept => "accept",
+            ErrorKind::Handshake => "handshake",
+            ErrorKind::Connection => "connection",
+            ErrorKind::Command => "command",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Moved => "moved",
+            ErrorKind::DeadlineExceeded => "deadline_exceeded",
+            ErrorKind::AntiEntropy => "anti_entropy",
+            ErrorKind::Retention => "retention",
+            ErrorKind::TlsReload => "tls_reload",
+        }
+    }
+
+    /// The kind of error of the response of a command, if it is one. A missing key is a normal
+    /// outcome, counting every cache miss would drown the actual errors.
+    fn of(response: &Response) -> Option<Self> {
+        match response {
+            _ if response.is_key_not_found() => None,
+            Response::Error(_) => Some(ErrorKind::Command),
+            Response::Unauthorized(_) => Some(ErrorKind::Unauthorized),
+            Response::Moved(_) => Some(ErrorKind::Moved),
+            Response::DeadlineExceeded => Some(ErrorKind::DeadlineExceeded),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations of each bucket, followed by the ones above the last bucket.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+/// The server-wide counters, shared between all of the executors.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// The accepted connections, by protocol.
+    connections: Mutex<BTreeMap<&'static str, u64>>,
+    open_connections: AtomicI64,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    /// The latency of the executed commands, by command.
+    commands: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    /// The keys of the store and their size, kept up to date by the changes so that rendering
+    /// does not walk the store.
+    keys: AtomicI64,
+    storage_bytes: AtomicI64,
+}
+
+impl Metrics {
+    /// Counts the connection, along with the bytes going through its stream until it's dropped.
+    pub(crate) fn meter<S>(self: &Arc<Self>, protocol: &'static str, stream: S) -> Metered<S> {
+        *self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(protocol)
+            .or_default() += 1;
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        Metered {
+            stream,
+            metrics: self.clone(),
+        }
+    }
+
+    /// Records the execution of a command, and its error if it failed.
+    pub(crate) fn command(&self, name: &'static str, elapsed: Duration, response: &Response) {
+        let mut commands = self.commands.lock().unwrap();
+        commands
+            .entry(name)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+        drop(commands);
+        if let Some(kind) = ErrorKind::of(response) {
+            self.error(kind);
+        }
+    }
+
+    pub(crate) fn error(&self, kind: ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the keys the store holds when the server starts, e.g. the ones of a snapshot, the
+    /// following changes are counted by `change`.
+    pub(crate) fn load(&self, store: &dyn Storage) {
+        if let Ok(entries) = store.iter() {
+            for (key, value) in entries {
+                self.change(key, None, Some(value));
+            }
+        }
+    }
+
+    /// Records the change of a key from the previous value to the current one, `None` if the key
+    /// was absent or removed.
+    pub(crate) fn change(&self, key: &str, previous: Option<&str>, current: Option<&str>) {
+        let size = |value: Option<&str>| value.map_or(0, |value| (key.len() + value.len()) as i64);
+        let keys = current.is_some() as i64 - previous.is_some() as i64;
+        self.keys.fetch_add(keys, Ordering::Relaxed);
+        self.storage_bytes
+            .fetch_add(size(current) - size(previous), Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "connections_total",
+            "counter",
+            "Connections accepted, by protocol.",
+        );
+        for (protocol, count) in self.connections.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "kvstore_connections_total{{protocol=\"{}\"}} {}",
+                protocol, count
+            );
+        }
+        let values = [
+            (
+                "open_connections",
+                "gauge",
+                "Connections currently open.",
+                self.open_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "received_bytes_total",
+                "counter",
+                "Bytes received from the connections.",
+                self.received_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "sent_bytes_total",
+                "counter",
+                "Bytes sent to the connections.",
+                self.sent_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "keys",
+                "gauge",
+                "Keys held by the storage.",
+                self.keys.load(Ordering::Relaxed),
+            ),
+            (
+                "storage_bytes",
+                "gauge",
+                "Size of the keys and the values held by the storage.",
+                self.storage_bytes.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "kvstore_{} {}", name, value);
+        }
+
+        header(
+            &mut out,
+            "command_duration_seconds",
+            "histogram",
+            "Time to execute the commands, by command.",
+        );
+        for (name, histogram) in self.commands.lock().unwrap().iter() {
+            let metric = "kvstore_command_duration_seconds";
+            let mut count = 0;
+            for (bound, observations) in BUCKETS.iter().zip(&histogram.counts) {
+                count += observations;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    metric, name, bound, count
+                );
+            }
+            count += histogram.counts[BUCKETS.len()];
+            let _ = writeln!(
+                out,
+                "{}_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                metric, name, count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{command=\"{}\"}} {}",
+                metric, name, histogram.sum
+            );
+            let _ = writeln!(out, "{}_count{{command=\"{}\"}} {}", metric, name, count);
+        }
+
+        header(&mut out, "errors_total", "counter", "Errors, by kind.");
+        for kind in ErrorKind::ALL {
+            let _ = writeln!(
+                out,
+                "kvstore_errors_total{{kind=\"{}\"}} {}",
+                kind.label(),
+                self.errors[kind as usize].load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP kvstore_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE kvstore_{} {}", name, kind);
+}
+
+/// A stream counting the bytes going through it, and its connection as open until it's dropped.
+pub(crate) struct Metered<S> {
+    stream: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        self.metrics
+            .open_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = (buf.filled().len() - filled) as u64;
+            self.metrics
+                .received_bytes
+                .fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            let written = written as u64;
+            self.metrics
+                .sent_bytes
+                .fetch_add(written, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::KEY_NOT_FOUND;
+    use crate::storage::memory::InMemStorage;
+    use crate::Storage;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn it_renders_the_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = metrics.meter("native", server);
+        let mut client = client;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+
+        let ok = Response::Ok(String::new());
+        metrics.command("GET", Duration::from_micros(50), &ok);
+        metrics.command(
+            "GET",
+            Duration::from_millis(2),
+            &Response::Error("x".into()),
+        );
+        metrics.command(
+            "SET",
+            Duration::from_secs(10),
+            &Response::Moved("node".into()),
+        );
+
+        let mut store = InMemStorage::new();
+        store.set("key".into(), "value".into()).unwrap();
+        store.set("other".into(), "value".into()).unwrap();
+        metrics.load(&store);
+        metrics.change("other", Some("value"), None);
+        metrics.change("key", Some("value"), Some("value2"));
+        metrics.change("key", Some("value2"), Some("value"));
+        let out = metrics.render();
+        for line in [
+            "kvstore_connections_total{protocol=\"native\"} 1",
+            "kvstore_open_connections 1",
+            "kvstore_received_bytes_total 4",
+            "kvstore_sent_bytes_total 5",
+            "kvstore_keys 1",
+            "kvstore_storage_bytes 8",
+            "kvstore_command_duration_seconds_bucket{command=\"GET\",le=\"0.0001\"} 1",
+            "kvstore_command_duration_seconds_bucket{command=\"GET\",le=\"0.001\"} 1",
+            "kvstore_command_duration_seconds_bucket{command=\"GET\",le=\"0.0025\"} 2",
+            "kvstore_command_duration_seconds_count{command=\"GET\"} 2",
+            "kvstore_command_duration_seconds_bucket{command=\"SET\",le=\"5\"} 0",
+            "kvstore_command_duration_seconds_bucket{command=\"SET\",le=\"+Inf\"} 1",
+            "kvstore_command_duration_seconds_sum{command=\"SET\"} 10",
+            "kvstore_errors_total{kind=\"command\"} 1",
+            "kvstore_errors_total{kind=\"moved\"} 1",
+            "kvstore_errors_total{kind=\"handshake\"} 0",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                out
+            );
+        }
+
+        drop(server);
+        assert!(metrics.render().contains("kvstore_open_connections 0\n"));
+    }
+
+    #[test]
+    fn it_does_not_count_missing_keys_as_errors() {
+        let metrics = Metrics::default();
+        let missing = Response::Error(KEY_NOT_FOUND.into());
+        metrics.command("GET", Duration::from_micros(50), &missing);
+        metrics.command("UNSET", Duration::from_micros(50), &missing);
+        let out = metrics.render();
+        assert!(
+            out.contains("kvstore_errors_total{kind=\"command\"} 0\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("kvstore_command_duration_seconds_count{command=\"GET\"} 1\n"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Default number of events that can be queued for a single watcher before new events start
+/// getting dropped.
+pub const DEFAULT_WATCH_CAPACITY: usize = 256;
+
+/// The kind of change applied to a key.
+///
+/// There is no `Expire`: the store has no expiration (TTL) of keys yet, so keys only ever change
+/// through a set or an unset. Expired keys are to be notified with their own operation once the
+/// store supports them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Set,
+    Unset,
+}
+
+/// A change applied to a key of the store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub key: String,
+    pub operation: Operation,
+    /// The new value of the key, `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// What a watcher receives: either a change event, or the number of events that were dropped
+/// because the watcher did not keep up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    Event(Event),
+    Lagged(u64),
+}
+
+/// The server-wide keyspace notification feed.
+///
+/// The feed is opt-in: when no client is watching, notifying an event is a cheap no-op. Each
+/// watcher has a bounded queue of events, filtered by the key prefixes it's interested in. When
+/// the queue is full the event is dropped for that watcher, and it's told about the number of
+/// events it missed right before the next event it receives.
+pub struct Notifier {
+    watchers: Mutex<Watchers>,
+
+    /// Maximum number of pending events per watcher.
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct Watchers {
+    next_id: u64,
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    prefixes: Vec<String>,
+    sender: Sender<(u64, Event)>,
+    // sequence number of the next event matching the watcher, it's incremented even when the event
+    // is dropped, which lets the receiving side detect the gaps.
+    next_seq: u64,
+}
+
+impl Notifier {
+    pub fn new(capacity: usize) -> Self {
+        Notifier {
+            watchers: Mutex::new(Watchers::default()),
+            capacity,
+        }
+    }
+
+    /// Creates a new watch with no prefixes, the watch is removed from the notifier once it's
+    /// dropped.
+    pub fn watch(self: &Arc<Self>) -> Watch {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let mut watchers = self.watchers.lock().unwrap();
+        let id = watchers.next_id;
+        watchers.next_id += 1;
+        watchers.entries.insert(
+            id,
+            Entry {
+                prefixes: vec![],
+                sender,
+                next_seq: 0,
+            },
+        );
+        Watch {
+            id,
+            notifier: Arc::clone(self),
+            receiver,
+            expected_seq: 0,
+            pending: None,
+        }
+    }
+
+    /// Send the event to all of the watchers interested in its key.
+    pub fn notify(&self, key: &str, operation: Operation, value: Option<&str>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.entries.is_empty() {
+            return;
+        }
+        for entry in watchers.entries.values_mut() {
+            if !entry.prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+                continue;
+            }
+            let event = Event {
+                key: key.to_string(),
+                operation,
+                value: value.map(String::from),
+            };
+            let seq = entry.next_seq;
+            entry.next_seq += 1;
+            // When the queue is full, the gap in the sequence numbers tells the watcher it missed
+            // events.
+            let _ = entry.sender.try_send((seq, event));
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Vec<String>)) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if let Some(entry) = watchers.entries.get_mut(&id) {
+            f(&mut entry.prefixes);
+        }
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new(DEFAULT_WATCH_CAPACITY)
+    }
+}
+
+/// The set of key prefixes a single connection is watching.
+pub struct Watch {
+    id: u64,
+    notifier: Arc<Notifier>,
+    receiver: Receiver<(u64, Event)>,
+    expected_seq: u64,
+    // event received right after a gap, returned after the `Lagged` notification.
+    pending: Option<Event>,
+}
+
+impl Watch {
+    /// Start receiving the events of the keys starting with `prefix`, an empty prefix matches
+    /// every key.
+    pub fn add(&mut self, prefix: String) {
+        self.notifier.update(self.id, |prefixes| {
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        });
+    }
+
+    /// Stop receiving the events of the keys starting with `prefix`.
+    pub fn remove(&mut self, prefix: &str) {
+        self.notifier
+            .update(self.id, |prefixes| prefixes.retain(|p| p != prefix));
+    }
+
+    /// Returns true if the watch does not match any key.
+    pub fn is_empty(&self) -> bool {
+        let watchers = self.notifier.watchers.lock().unwrap();
+        watchers
+            .entries
+            .get(&self.id)
+            .is_none_or(|entry| entry.prefixes.is_empty())
+    }
+
+    /// Wait for the next notification.
+    ///
+    /// This method is cancellation safe.
+    pub async fn recv(&mut self) -> Notification {
+        if let Some(event) = self.pending.take() {
+            return Notification::Event(event);
+        }
+        // The entry holding the sender lives as long as the watch, so the channel is never closed.
+        let (seq, event) = self.receiver.recv().await.unwrap();
+        let missed = seq - self.expected_seq;
+        self.expected_seq = seq + 1;
+        if missed > 0 {
+            self.pending = Some(event);
+            return Notification::Lagged(missed);
+        }
+        Notification::Event(event)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut watchers = self.notifier.watchers.lock().unwrap();
+        watchers.entries.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_prefix() {
+        let notifier = Arc::new(Notifier::default());
+        let mut watch = notifier.watch();
+        watch.add(String::from("user:"));
+
+        notifier.notify("user:1", Operation::Set, Some("alice"));
+        notifier.notify("order:1", Operation::Set, Some("book"));
+        notifier.notify("user:1", Operation::Unset, None);
+
+        let expected = Event {
+            key: String::from("user:1"),
+            operation: Operation::Set,
+            value: Some(String::from("alice")),
+        };
+        assert_eq!(watch.recv().await, Notification::Event(expected));
+        let expected = Event {
+            key: String::from("user:1"),
+            operation: Operation::Unset,
+            value: None,
+        };
+        assert_eq!(watch.recv().await, Notification::Event(expected));
+
+        watch.remove("user:");
+        assert!(watch.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_lag() {
+        let notifier = Arc::new(Notifier::new(2));
+        let mut watch = notifier.watch();
+        watch.add(String::new());
+
+        for i in 0..5 {
+            notifier.notify(&i.to_string(), Operation::Set, Some("v"));
+        }
+        match watch.recv().await {
+            Notification::Event(event) => assert_eq!(event.key, "0"),
+            lag => panic!("unexpected notification {:?}", lag),
+        }
+        match watch.recv().await {
+            Notification::Event(event) => assert_eq!(event.key, "1"),
+            lag => panic!("unexpected notification {:?}", lag),
+        }
+        notifier.notify("5", Operation::Set, Some("v"));
+        assert_eq!(watch.recv().await, Notification::Lagged(3));
+        match watch.recv().await {
+            Notification::Event(event) => assert_eq!(event.key, "5"),
+            lag => panic!("unexpected notification {:?}", lag),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! A pool of client connections, shared by the tasks of an application.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::client::{self, Address, Client, ClientOptions, RetryPolicy, ToAddress};
+use crate::protocol::{Command, Response};
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// Number of connections opened upfront, and kept open when they are idle.
+    pub min_connections: usize,
+    /// Maximum number of connections, checkouts wait for one to be returned past it.
+    pub max_connections: usize,
+    /// How long a checkout may take, opening or checking the connection included, before failing
+    /// with a `PoolError::Timeout`.
+    pub checkout_timeout: Duration,
+    /// How long a connection stays unused before it's closed.
+    pub idle_timeout: Duration,
+    /// Whether idle connections are checked with a PING before being handed out.
+    pub health_check: bool,
+    pub health_check_timeout: Duration,
+    /// The retry policy of the pooled clients.
+    pub retry: RetryPolicy,
+    /// The other options of the pooled clients, their retry policy is `retry`.
+    pub client: ClientOptions,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            min_connections: 1,
+            max_connections: 10,
+            checkout_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(60),
+            health_check: true,
+            health_check_timeout: Duration::from_secs(1),
+            retry: RetryPolicy::default(),
+            client: ClientOptions::default(),
+        }
+    }
+}
+
+/// Error returned when no connection could be checked out of the pool in time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolError {
+    Timeout(Duration),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Timeout(waited) => {
+                write!(f, "Timed out after {:?} waiting for a connection", waited)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// A snapshot of the state of a pool and of its counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolMetrics {
+    /// Connections currently open, idle or not.
+    pub connections: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    pub max_connections: usize,
+    pub checkouts: u64,
+    pub timeouts: u64,
+    pub created: u64,
+    /// Connections closed because they stayed idle for too long.
+    pub evicted: u64,
+    pub failed_health_checks: u64,
+    /// Time spent waiting for connections, summed over all of the checkouts.
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+impl PoolMetrics {
+    /// The fraction of the maximum number of connections in use.
+    pub fn utilization(&self) -> f64 {
+        if self.max_connections == 0 {
+            return 0.0;
+        }
+        self.in_use as f64 / self.max_connections as f64
+    }
+
+    pub fn average_wait(&self) -> Duration {
+        if self.checkouts == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_nanos((self.total_wait.as_nanos() / self.checkouts as u128) as u64)
+    }
+}
+
+/// A pool of connections to a server.
+///
+/// Cloning the pool is cheap, the clones share the same connections. Clients are checked out
+/// with `get`, and go back to the pool when the returned guard is dropped.
+///
+/// A client dropped in the middle of a command, e.g. when its future is cancelled, may have a
+/// response left to read: health checks catch these connections, and should stay enabled when
+/// commands can be cancelled.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addr: Address,
+    options: PoolOptions,
+    // bounds the number of connections in use, or being opened.
+    permits: Arc<Semaphore>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // the most recently returned connections are last, and handed out first.
+    idle: Vec<(Client, Instant)>,
+    // the connections in use, or being opened.
+    open: usize,
+    metrics: PoolMetrics,
+}
+
+impl Pool {
+    /// Creates a pool of connections to the server, and opens its minimum number of connections.
+    pub async fn new<T: ToAddress>(addr: T, options: PoolOptions) -> Result<Pool> {
+        options.client.retry.validate()?;
+        let shared = Arc::new(Shared {
+            addr: addr.to_address().await?,
+            permits: Arc::new(Semaphore::new(options.max_connections)),
+            state: Mutex::new(State {
+                metrics: PoolMetrics {
+                    max_connections: options.max_connections,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            options,
+        });
+        shared.fill().await?;
+        tokio::spawn(evict(Arc::downgrade(&shared)));
+        Ok(Pool { shared })
+    }
+
+    /// Checks a client out of the pool, waiting for one to be returned if all of them are in use.
+    ///
+    /// Idle connections are reused, most recent first, or a new one is opened.
+    pub async fn get(&self) -> Result<PooledClient> {
+        let shared = &self.shared;
+        let start = Instant::now();
+        // The timeout covers opening the connection or checking its health too.
+        let checkout = async {
+            let permit = shared.permits.clone().acquire_owned().await?;
+            Ok::<_, crate::Err>((permit, shared.checkout().await?))
+        };
+        let (permit, client) =
+            match tokio::time::timeout(shared.options.checkout_timeout, checkout).await {
+                Ok(checkout) => checkout?,
+                Err(_) => {
+                    shared.state.lock().unwrap().metrics.timeouts += 1;
+                    return Err(PoolError::Timeout(start.elapsed()).into());
+                }
+            };
+        let waited = start.elapsed();
+        let mut state = shared.state.lock().unwrap();
+        state.metrics.checkouts += 1;
+        state.metrics.total_wait += waited;
+        state.metrics.max_wait = state.metrics.max_wait.max(waited);
+        Ok(PooledClient {
+            client: Some(client),
+            shared: shared.clone(),
+            _permit: permit,
+        })
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.shared.state.lock().unwrap();
+        let idle = state.idle.len();
+        PoolMetrics {
+            connections: state.open + idle,
+            idle,
+            in_use: state.open,
+            ..state.metrics.clone()
+        }
+    }
+}
+
+impl Shared {
+    /// Returns a healthy idle connection, or opens a new one. The caller holds a permit.
+    async fn checkout(&self) -> Result<Client> {
+        let reservation = Reservation::new(self);
+        loop {
+            let idle = self.state.lock().unwrap().idle.pop();
+            let mut client = match idle {
+                Some((client, _)) => client,
+                None => {
+                    let client = self.connect().await?;
+                    reservation.keep();
+                    return Ok(client);
+                }
+            };
+            if !self.options.health_check || self.is_healthy(&mut client).await {
+                reservation.keep();
+                return Ok(client);
+            }
+            self.state.lock().unwrap().metrics.failed_health_checks += 1;
+        }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let options = ClientOptions {
+            retry: self.options.retry.clone(),
+            ..self.options.client.clone()
+        };
+        let client = client::create_with_options(self.addr.clone(), options).await?;
+        self.state.lock().unwrap().metrics.created += 1;
+        Ok(client)
+    }
+
+    async fn is_healthy(&self, client: &mut Client) -> bool {
+        let timeout = self.options.health_check_timeout;
+        // The client must not reconnect, the failed check closes the connection instead.
+        let ping = Command::Ping(String::new());
+        match tokio::time::timeout(timeout, client.send_once(&ping)).await {
+            Ok(Ok(Some(Response::Ok(pong)))) => pong == "PONG",
+            _ => false,
+        }
+    }
+
+    fn release(&self, client: Client) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        state.idle.push((client, Instant::now()));
+    }
+
+    /// Opens connections until the pool has its minimum number of them, or until it has its
+    /// maximum number of connections in use or being opened.
+    async fn fill(&self) -> Result<()> {
+        // the permits are held until the end, so that the opened connections count as in use.
+        let mut permits = vec![];
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if state.open + state.idle.len() >= self.options.min_connections {
+                    return Ok(());
+                }
+            }
+            match self.permits.clone().try_acquire_owned() {
+                Ok(permit) => permits.push(permit),
+                Err(_) => return Ok(()),
+            }
+            let reservation = Reservation::new(self);
+            let client = self.connect().await?;
+            reservation.keep();
+            self.release(client);
+        }
+    }
+}
+
+/// Counts a connection being checked out or opened as open, until it's either kept or the
+/// reservation is dropped, e.g. because opening it failed or the checkout was cancelled.
+struct Reservation<'a> {
+    shared: &'a Shared,
+    kept: bool,
+}
+
+impl<'a> Reservation<'a> {
+    fn new(shared: &'a Shared) -> Self {
+        shared.state.lock().unwrap().open += 1;
+        Reservation {
+            shared,
+            kept: false,
+        }
+    }
+
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.shared.state.lock().unwrap().open -= 1;
+        }
+    }
+}
+
+/// Closes the connections idle for too long, and reopens connections if the pool fell under its
+/// minimum, until the pool is dropped.
+async fn evict(shared: Weak<Shared>) {
+    let period = match shared.upgrade() {
+        Some(shared) => (shared.options.idle_timeout / 2).max(Duration::from_millis(10)),
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(period).await;
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let evicted = {
+            let mut state = shared.state.lock().unwrap();
+            let min = shared.options.min_connections;
+            let now = Instant::now();
+            let mut evicted = vec![];
+            // the oldest idle connections are first.
+            while state.open + state.idle.len() > min {
+                match state.idle.first() {
+                    Some((_, since)) if now - *since >= shared.options.idle_timeout => {
+                        evicted.push(state.idle.remove(0).0);
+                    }
+                    _ => break,
+                }
+            }
+            state.metrics.evicted += evicted.len() as u64;
+            evicted
+        };
+        drop(evicted);
+        // the server may be unreachable for now, the next round tries again.
+        let _ = shared.fill().await;
+    }
+}
+
+/// A client checked out of a pool, it goes back to the pool when dropped.
+pub struct PooledClient {
+    client: Option<Client>,
+    shared: Arc<Shared>,
+    // released after the client went back to the pool.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledClient {
+    /// Closes the connection instead of returning it to the pool, e.g. after an error left it in
+    /// an unknown state.
+    pub fn discard(mut self) {
+        self.client = None;
+        self.shared.state.lock().unwrap().open -= 1;
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.shared.release(client);
+        }
+    }
+}
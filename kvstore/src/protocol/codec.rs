@@ -0,0 +1,145 @@
+use bytes::BytesMut;
+use std::io::Cursor;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::protocol::{Command, Parser, Response, Writer};
+use crate::{Err, Result};
+
+/// The server side of the protocol, to use with `tokio_util::codec::Framed` over any transport:
+/// decodes the commands sent by the clients, and encodes the responses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerCodec;
+
+/// The client side of the protocol: encodes the commands, and decodes the responses of the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientCodec;
+
+impl Decoder for ServerCodec {
+    type Item = Command;
+    type Error = Err;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Command>> {
+        Parser::next_frame(src, Parser::parse)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Command>> {
+        decode_eof(src, Parser::parse)
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = Err;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<()> {
+        Writer::encode_response(dst, &item);
+        Ok(())
+    }
+}
+
+/// Encodes the response without taking it, e.g. to keep it for the metrics.
+impl Encoder<&Response> for ServerCodec {
+    type Error = Err;
+
+    fn encode(&mut self, item: &Response, dst: &mut BytesMut) -> Result<()> {
+        Writer::encode_response(dst, item);
+        Ok(())
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = Err;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>> {
+        Parser::next_frame(src, Parser::parse_response)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Response>> {
+        decode_eof(src, Parser::parse_response)
+    }
+}
+
+impl Encoder<Command> for ClientCodec {
+    type Error = Err;
+
+    fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<()> {
+        Writer::encode_command(dst, &item);
+        Ok(())
+    }
+}
+
+/// Encodes the command without taking it, e.g. to send it again after a lost connection.
+impl Encoder<&Command> for ClientCodec {
+    type Error = Err;
+
+    fn encode(&mut self, item: &Command, dst: &mut BytesMut) -> Result<()> {
+        Writer::encode_command(dst, item);
+        Ok(())
+    }
+}
+
+/// Decodes the last frames once the stream is closed, bytes left after them are a truncated frame.
+fn decode_eof<T>(
+    src: &mut BytesMut,
+    parse: fn(&mut Cursor<&[u8]>) -> Result<T>,
+) -> Result<Option<T>> {
+    match Parser::next_frame(src, parse)? {
+        Some(frame) => Ok(Some(frame)),
+        None if src.is_empty() => Ok(None),
+        None => Err("Connection closed in the middle of a frame".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_frames_received_in_pieces() {
+        let mut encoded = BytesMut::new();
+        let mut client = ClientCodec;
+        client
+            .encode(Command::Set("key".into(), "value".into()), &mut encoded)
+            .unwrap();
+        client
+            .encode(Command::Get("key".into()), &mut encoded)
+            .unwrap();
+
+        let mut server = ServerCodec;
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for byte in encoded.iter() {
+            src.extend_from_slice(&[*byte]);
+            if let Some(command) = server.decode(&mut src).unwrap() {
+                decoded.push(command);
+            }
+        }
+        assert_eq!(
+            decoded,
+            vec![
+                Command::Set("key".into(), "value".into()),
+                Command::Get("key".into())
+            ]
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn it_fails_on_truncated_frames_at_eof() {
+        let mut encoded = BytesMut::new();
+        ServerCodec
+            .encode(Response::Ok("value".into()), &mut encoded)
+            .unwrap();
+        let mut client = ClientCodec;
+        let mut src = encoded.clone();
+        assert_eq!(
+            client.decode_eof(&mut src).unwrap(),
+            Some(Response::Ok("value".into()))
+        );
+        assert_eq!(client.decode_eof(&mut src).unwrap(), None);
+
+        let mut src = encoded.split_to(encoded.len() - 1);
+        assert!(client.decode(&mut src).unwrap().is_none());
+        assert!(client.decode_eof(&mut src).is_err());
+    }
+}
@@ -0,0 +1,652 @@
+//! A REST gateway over HTTP/1.1, spoken on the server's HTTP listener so that the tools and the
+//! languages without a client, e.g. `curl`, can reach the keys.
+//!
+//! - `GET /keys/{key}` answers the value of the key, or `404 Not Found`.
+//! - `PUT /keys/{key}` sets the key to the body of the request, and answers `204 No Content`.
+//! - `DELETE /keys/{key}` unsets the key, and answers `204 No Content`, or `404 Not Found`.
+//! - `GET /keys?prefix=&after=&limit=` answers a page of the keys starting with the prefix, and
+//!   coming after the given key, in order. The response is a JSON object holding the `entries`,
+//!   and the key to continue `after` as `next`, null on the last page.
+//!
+//! The keys are percent-encoded in the paths. The values are sent as is, or as a JSON object
+//! holding the `key` and its `value` when the request accepts `application/json`, and a `PUT` with
+//! a JSON body sets the `value` of the object. The errors follow the same format, their JSON
+//! object holding the `error`.
+//!
+//! Like with the Redis protocol, the requests are translated into `Command`s which go through the
+//! executor. When authentication is enabled, the requests authenticate with the Basic scheme, and
+//! the connection is authenticated again whenever the credentials change.
+//!
+//! The same gateway serves the metrics of the server at `GET /metrics` on the metrics listener,
+//! which does not serve the keys.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Buf, BytesMut};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+
+use crate::acl::{Unauthorized, DEFAULT_USER};
+use crate::executor::{Context, KEY_NOT_FOUND, MAX_SCAN_BATCH};
+use crate::protocol::{Command, Gateway, Next, Response};
+use crate::server::DEFAULT_HTTP_MAX_BODY;
+use crate::Result;
+
+/// Maximum number of headers of a request.
+const MAX_HEADERS: usize = 64;
+
+/// Maximum length of the request line and the headers of a request.
+const MAX_HEAD: usize = 64 << 10;
+
+/// Number of entries of a page of the scan endpoint when the request does not set a limit.
+const DEFAULT_LIMIT: u32 = 100;
+
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain; charset=utf-8";
+const PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A parsed request, along with the headers the gateway cares about.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    content_type: Option<String>,
+    accept: Option<String>,
+    authorization: Option<String>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn accepts_json(&self) -> bool {
+        self.accept
+            .as_deref()
+            .is_some_and(|accept| accept.contains(JSON))
+    }
+}
+
+/// How the response of a command is turned into the response of the request.
+#[derive(Debug)]
+enum Route {
+    Get(String),
+    Put,
+    Delete,
+    Scan(u32),
+}
+
+/// A request waiting for the response of its command.
+struct Pending {
+    route: Route,
+    json: bool,
+    /// The command of the route, queued once the connection authenticated with the credentials
+    /// of the request.
+    after_auth: Option<(Option<String>, Command)>,
+}
+
+/// An HTTP response.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn empty(status: u16) -> Self {
+        Reply {
+            status,
+            content_type: TEXT,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn json(status: u16, value: Value) -> Self {
+        Reply {
+            content_type: JSON,
+            body: value.to_string().into_bytes(),
+            ..Reply::empty(status)
+        }
+    }
+
+    fn text(status: u16, text: String) -> Self {
+        Reply {
+            body: text.into_bytes(),
+            ..Reply::empty(status)
+        }
+    }
+
+    fn error(status: u16, msg: &str, json: bool) -> Self {
+        match json {
+            true => Reply::json(status, json!({ "error": msg })),
+            false => Reply::text(status, format!("{}\n", msg)),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    fn encode(&self, close: bool, out: &mut Vec<u8>) {
+        out.extend_from_slice(
+            format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status)).as_bytes(),
+        );
+        let mut header = |name: &str, value: &str| {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        };
+        if self.status != 204 {
+            header("Content-Type", self.content_type);
+            header("Content-Length", &self.body.len().to_string());
+        }
+        for (name, value) in &self.headers {
+            header(name, value);
+        }
+        if close {
+            header("Connection", "close");
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.body);
+    }
+}
+
+/// The state of a connection speaking HTTP.
+///
+/// The executor writes the response of a command before reading the next one, and every request
+/// runs a single command, so the requests are handled one at a time.
+pub(crate) struct Session {
+    /// The command of the current request not handed to the executor yet.
+    queued: Option<Command>,
+    pending: Option<Pending>,
+    /// The `Authorization` header the connection authenticated with.
+    authorization: Option<String>,
+    /// Whether `100 Continue` was sent for the current request.
+    continued: bool,
+    close: bool,
+    /// The server whose metrics are served, only set on the metrics listener.
+    exporter: Option<Context>,
+    /// Maximum length of the body of a request, which is buffered until it's complete.
+    max_body: usize,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            queued: None,
+            pending: None,
+            authorization: None,
+            continued: false,
+            close: false,
+            exporter: None,
+            max_body: DEFAULT_HTTP_MAX_BODY,
+        }
+    }
+}
+
+impl Gateway for Session {
+    fn next_command(&mut self, buf: &mut BytesMut, out: &mut Vec<u8>) -> Result<Next> {
+        loop {
+            if let Some(command) = self.queued.take() {
+                return Ok(Next::Command(command));
+            }
+            if self.close {
+                return Ok(Next::Quit);
+            }
+            let request = match self.parse_request(buf, out) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(Next::Incomplete),
+                // The rest of the buffer can't be parsed, the connection is closed.
+                Err(reply) => {
+                    reply.encode(true, out);
+                    return Ok(Next::Quit);
+                }
+            };
+            self.close = !request.keep_alive;
+            if let Err(reply) = self.request(request) {
+                reply.encode(self.close, out);
+            }
+        }
+    }
+
+    fn write_response(&mut self, response: &Response, out: &mut Vec<u8>) {
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let reply = match pending.after_auth.take() {
+            Some((authorization, command)) => match response {
+                Response::Unauthorized(msg) => unauthorized(msg, pending.json),
+                // Authentication is not enabled if the executor answers with an error.
+                _ => {
+                    self.authorization = authorization;
+                    self.queued = Some(command);
+                    self.pending = Some(pending);
+                    return;
+                }
+            },
+            None => reply(pending.route, response, pending.json),
+        };
+        reply.encode(self.close, out);
+    }
+}
+
+impl Session {
+    /// A session accepting bodies of at most `max_body` bytes.
+    pub(crate) fn new(max_body: usize) -> Self {
+        Session {
+            max_body,
+            ..Default::default()
+        }
+    }
+
+    /// A session only serving the metrics of the server.
+    pub(crate) fn metrics(ctx: Context) -> Self {
+        Session {
+            exporter: Some(ctx),
+            ..Default::default()
+        }
+    }
+
+    /// Parses the next request of the buffer, `None` if it does not hold a full request yet. The
+    /// requests that can't be parsed are answered with the returned error.
+    fn parse_request(
+        &mut self,
+        buf: &mut BytesMut,
+        out: &mut Vec<u8>,
+    ) -> std::result::Result<Option<Request>, Reply> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let head = match parsed.parse(buf) {
+            Ok(httparse::Status::Complete(head)) => head,
+            Ok(httparse::Status::Partial) if buf.len() > MAX_HEAD => {
+                return Err(Reply::error(431, "The headers are too large", false))
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(Reply::error(431, "Too many headers", false))
+            }
+            Err(err) => return Err(Reply::error(400, &err.to_string(), false)),
+        };
+        let mut request = Request {
+            method: parsed.method.unwrap_or_default().to_string(),
+            path: parsed.path.unwrap_or_default().to_string(),
+            body: vec![],
+            content_type: None,
+            accept: None,
+            authorization: None,
+            // HTTP/1.0 connections are closed after each request by default.
+            keep_alive: parsed.version == Some(1),
+        };
+        let mut length = 0;
+        let mut expect_continue = false;
+        for header in parsed.headers.iter() {
+            let value = std::str::from_utf8(header.value)
+                .map_err(|_| Reply::error(400, "Invalid header value", false))?
+                .trim();
+            let name = header.name.to_ascii_lowercase();
+            match name.as_str() {
+                "content-length" => {
+                    length = value
+                        .parse()
+                        .map_err(|_| Reply::error(400, "Invalid Content-Length", false))?
+                }
+                "transfer-encoding" => {
+                    return Err(Reply::error(
+                        501,
+                        "Transfer encodings are not supported",
+                        false,
+                    ))
+                }
+                "content-type" => request.content_type = Some(value.to_ascii_lowercase()),
+                "accept" => request.accept = Some(value.to_ascii_lowercase()),
+                "authorization" => request.authorization = Some(value.to_string()),
+                "connection" if value.eq_ignore_ascii_case("close") => request.keep_alive = false,
+                "connection" if value.eq_ignore_ascii_case("keep-alive") => {
+                    request.keep_alive = true
+                }
+                "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+                _ => {}
+            }
+        }
+        if length > self.max_body {
+            return Err(Reply::error(413, "The body is too large", false));
+        }
+        if buf.len() < head + length {
+            if expect_continue && !self.continued {
+                out.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                self.continued = true;
+            }
+            return Ok(None);
+        }
+        self.continued = false;
+        buf.advance(head);
+        request.body = buf.split_to(length).to_vec();
+        Ok(Some(request))
+    }
+
+    /// Translates the request into a command, which is queued, or returns its response if it does
+    /// not need the executor.
+    fn request(&mut self, request: Request) -> std::result::Result<(), Reply> {
+        let json = request.accepts_json();
+        let (path, query) = match request.path.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (request.path.as_str(), ""),
+        };
+        if let Some(ctx) = &self.exporter {
+            return Err(match (request.method.as_str(), path) {
+                ("GET", "/metrics") => Reply {
+                    content_type: PROMETHEUS,
+                    body: ctx.metrics.render().into_bytes(),
+                    ..Reply::empty(200)
+                },
+                (_, "/metrics") => {
+                    Reply::error(405, "Method not allowed", json).header("Allow", "GET")
+                }
+                _ => Reply::error(404, "Not found", json),
+            });
+        }
+        let (route, command) = match (path, path.strip_prefix("/keys/")) {
+            ("/keys", _) => match request.method.as_str() {
+                "GET" => scan(query).map_err(|msg| Reply::error(400, &msg, json))?,
+                _ => {
+                    return Err(Reply::error(405, "Method not allowed", json).header("Allow", "GET"))
+                }
+            },
+            (_, Some(key)) if !key.is_empty() => {
+                let key = decode(key).ok_or_else(|| Reply::error(400, "Invalid key", json))?;
+                match request.method.as_str() {
+                    "GET" => (Route::Get(key.clone()), Command::Get(key)),
+                    "PUT" => {
+                        let value = value(&request).map_err(|msg| Reply::error(400, msg, json))?;
+                        (Route::Put, Command::Set(key, value))
+                    }
+                    "DELETE" => (Route::Delete, Command::Clear(key)),
+                    _ => {
+                        return Err(Reply::error(405, "Method not allowed", json)
+                            .header("Allow", "GET, PUT, DELETE"))
+                    }
+                }
+            }
+            _ => return Err(Reply::error(404, "Not found", json)),
+        };
+        let mut pending = Pending {
+            route,
+            json,
+            after_auth: None,
+        };
+        if request.authorization == self.authorization {
+            self.queued = Some(command);
+        } else {
+            let (user, password) = match &request.authorization {
+                Some(authorization) => basic_credentials(authorization)
+                    .ok_or_else(|| unauthorized("Invalid credentials", json))?,
+                // Back to the default user.
+                None => (String::from(DEFAULT_USER), String::new()),
+            };
+            self.queued = Some(Command::Auth(user, password));
+            pending.after_auth = Some((request.authorization, command));
+        }
+        self.pending = Some(pending);
+        Ok(())
+    }
+}
+
+/// The route and the command of a scan, from the parameters of its query.
+fn scan(query: &str) -> std::result::Result<(Route, Command), String> {
+    let mut prefix = String::new();
+    let mut after = None;
+    let mut limit = DEFAULT_LIMIT;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        // Forms encode the spaces as `+`.
+        let value = decode(&value.replace('+', " "))
+            .ok_or_else(|| format!("Invalid value for {}", name))?;
+        match name {
+            "prefix" => prefix = value,
+            "after" => after = Some(value),
+            "limit" => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| (1..=MAX_SCAN_BATCH).contains(limit))
+                    .ok_or_else(|| format!("The limit must be between 1 and {}", MAX_SCAN_BATCH))?
+            }
+            _ => return Err(format!("Unknown parameter {}", name)),
+        }
+    }
+    Ok((Route::Scan(limit), Command::Scan(after, prefix, limit)))
+}
+
+/// The value set by a `PUT`, the body as is, or the `value` of a JSON body.
+fn value(request: &Request) -> std::result::Result<String, &'static str> {
+    let is_json = request
+        .content_type
+        .as_deref()
+        .is_some_and(|content_type| content_type.starts_with(JSON));
+    if !is_json {
+        return String::from_utf8(request.body.clone())
+            .map_err(|_| "The value must be valid UTF-8");
+    }
+    let body: Value = serde_json::from_slice(&request.body).map_err(|_| "Invalid JSON body")?;
+    match body.get("value") {
+        Some(Value::String(value)) => Ok(value.clone()),
+        _ => Err("Expected a JSON object with a string value"),
+    }
+}
+
+/// The user and the password of an `Authorization` header using the Basic scheme.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn decode(data: &str) -> Option<String> {
+    percent_decode_str(data)
+        .decode_utf8()
+        .ok()
+        .map(|data| data.into_owned())
+}
+
+fn unauthorized(msg: &str, json: bool) -> Reply {
+    Reply::error(401, msg, json).header("WWW-Authenticate", "Basic realm=\"kvstore\"")
+}
+
+fn reply(route: Route, response: &Response, json: bool) -> Reply {
+    match (route, response) {
+        (Route::Get(key), Response::Ok(value)) => match json {
+            true => Reply::json(200, json!({ "key": key, "value": value })),
+            false => Reply::text(200, value.clone()),
+        },
+        (Route::Get(_) | Route::Delete, Response::Error(msg)) if msg == KEY_NOT_FOUND => {
+            Reply::error(404, msg, json)
+        }
+        (Route::Put, Response::Ok(_)) => Reply::empty(204),
+        (Route::Delete, Response::Ok(_)) => Reply::empty(204),
+        (Route::Scan(limit), Response::Entries(entries)) => {
+            let next = match entries.last() {
+                Some((key, _)) if entries.len() == limit as usize => Value::from(key.as_str()),
+                _ => Value::Null,
+            };
+            let entries: Vec<Value> = entries
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect();
+            Reply::json(200, json!({ "entries": entries, "next": next }))
+        }
+        (_, Response::Unauthorized(msg)) if *msg == Unauthorized::NotAuthenticated.to_string() => {
+            unauthorized(msg, json)
+        }
+        (_, Response::Unauthorized(msg)) => Reply::error(403, msg, json),
+        (_, Response::Moved(node)) => {
+            Reply::error(421, &format!("The key is owned by {}", node), json)
+        }
+        (_, Response::Error(msg)) => Reply::error(500, msg, json),
+        (_, response) => Reply::error(500, &format!("Unexpected response {:?}", response), json),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        421 => "Misdirected Request",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the bytes to the session, answering every command with the given responses, and
+    /// returns the commands, along with what the session sent back.
+    fn exchange(
+        session: &mut Session,
+        input: &[u8],
+        responses: &[Response],
+    ) -> (Vec<Command>, String) {
+        let mut buf = BytesMut::from(input);
+        let mut out = vec![];
+        let mut commands = vec![];
+        let mut responses = responses.iter();
+        while let Next::Command(command) = session.next_command(&mut buf, &mut out).unwrap() {
+            commands.push(command);
+            session.write_response(responses.next().unwrap(), &mut out);
+        }
+        assert!(responses.next().is_none());
+        (commands, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn it_translates_the_routes() {
+        let mut session = Session::default();
+        let input = b"PUT /keys/a%20b HTTP/1.1\r\nContent-Length: 5\r\n\r\nvalue\
+            GET /keys/a%20b HTTP/1.1\r\n\r\n\
+            DELETE /keys/missing HTTP/1.1\r\n\r\n\
+            GET /keys?prefix=a+&limit=1 HTTP/1.1\r\nAccept: application/json\r\n\r\n";
+        let responses = [
+            Response::Ok(String::new()),
+            Response::Ok("value".into()),
+            Response::Error(KEY_NOT_FOUND.into()),
+            Response::Entries(vec![("a b".into(), "value".into())]),
+        ];
+        let (commands, out) = exchange(&mut session, input, &responses);
+        assert_eq!(
+            commands,
+            vec![
+                Command::Set("a b".into(), "value".into()),
+                Command::Get("a b".into()),
+                Command::Clear("missing".into()),
+                Command::Scan(None, "a ".into(), 1),
+            ]
+        );
+        let body = r#"{"entries":[{"key":"a b","value":"value"}],"next":"a b"}"#;
+        assert_eq!(
+            out,
+            format!(
+                "HTTP/1.1 204 No Content\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: 5\r\n\r\nvalue\
+                 HTTP/1.1 404 Not Found\r\nContent-Type: {}\r\nContent-Length: 14\r\n\r\n\
+                 Key not found\n\
+                 HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                TEXT,
+                TEXT,
+                JSON,
+                body.len(),
+                body
+            )
+        );
+    }
+
+    #[test]
+    fn it_reads_json_bodies() {
+        let mut session = Session::default();
+        let body = r#"{"value": "v"}"#;
+        let input = format!(
+            "PUT /keys/k HTTP/1.1\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}\
+             PUT /keys/k HTTP/1.1\r\nContent-Type: application/json\r\n\
+             Accept: application/json\r\nContent-Length: 2\r\n\r\n[]",
+            body.len(),
+            body
+        );
+        let (commands, out) = exchange(
+            &mut session,
+            input.as_bytes(),
+            &[Response::Ok(String::new())],
+        );
+        assert_eq!(commands, vec![Command::Set("k".into(), "v".into())]);
+        assert!(out.ends_with(
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: 54\
+             \r\n\r\n{\"error\":\"Expected a JSON object with a string value\"}"
+        ));
+    }
+
+    #[test]
+    fn it_authenticates_when_the_credentials_change() {
+        let mut session = Session::default();
+        // app:secret
+        let input = b"GET /keys/k HTTP/1.1\r\nAuthorization: Basic YXBwOnNlY3JldA==\r\n\r\n\
+            GET /keys/k HTTP/1.1\r\nAuthorization: Basic YXBwOnNlY3JldA==\r\n\r\n\
+            GET /keys/k HTTP/1.1\r\n\r\n";
+        let responses = [
+            Response::Ok("app".into()),
+            Response::Ok("1".into()),
+            Response::Ok("2".into()),
+            Response::Unauthorized("Invalid user name or password".into()),
+        ];
+        let (commands, out) = exchange(&mut session, input, &responses);
+        assert_eq!(
+            commands,
+            vec![
+                Command::Auth("app".into(), "secret".into()),
+                Command::Get("k".into()),
+                Command::Get("k".into()),
+                Command::Auth(DEFAULT_USER.into(), String::new()),
+            ]
+        );
+        assert!(out.ends_with(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 30\r\nWWW-Authenticate: Basic realm=\"kvstore\"\r\n\r\n\
+             Invalid user name or password\n"
+        ));
+    }
+
+    #[test]
+    fn it_rejects_invalid_requests() {
+        let mut session = Session::default();
+        let input = b"POST /keys/k HTTP/1.1\r\n\r\n\
+            GET /other HTTP/1.1\r\n\r\n\
+            GET /keys?limit=0 HTTP/1.1\r\n\r\n\
+            GET /keys HTTP/1.0\r\n\r\n";
+        let (_, out) = exchange(&mut session, input, &[Response::Entries(vec![])]);
+        let statuses: Vec<&str> = out
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| &response[..3])
+            .collect();
+        assert_eq!(statuses, vec!["405", "404", "400", "200"]);
+        assert!(out.ends_with("Connection: close\r\n\r\n{\"entries\":[],\"next\":null}"));
+
+        let mut session = Session::default();
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..]);
+        let mut out = vec![];
+        assert!(matches!(
+            session.next_command(&mut buf, &mut out).unwrap(),
+            Next::Quit
+        ));
+        assert!(out.starts_with(b"HTTP/1.1 501 Not Implemented\r\n"));
+    }
+}
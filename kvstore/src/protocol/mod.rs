@@ -1,6 +1,11 @@
+use crate::cdc::Change;
+use crate::cluster::{VectorClock, Versioned};
+use crate::executor::KEY_NOT_FOUND;
+use crate::notify::{Event, Operation};
 use crate::Result;
-use bytes::Buf;
+use bytes::{Buf, BytesMut};
 use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
 pub use tokio::io::{AsyncWriteExt, BufWriter};
 
 mod parser;
@@ -9,23 +14,223 @@ pub use parser::Parser;
 mod writer;
 pub use writer::Writer;
 
-#[derive(Debug, PartialEq)]
+mod codec;
+pub use codec::{ClientCodec, ServerCodec};
+
+pub(crate) mod http;
+pub(crate) mod resp;
+
+/// The server side of a connection speaking another protocol than the native one, translating its
+/// requests into commands, and the responses of the commands back.
+pub(crate) trait Gateway: Send {
+    /// Returns the next command to execute, parsing the requests of the buffer as needed. The
+    /// replies of the requests that don't need the executor are appended to `out`.
+    fn next_command(&mut self, buf: &mut BytesMut, out: &mut Vec<u8>) -> Result<Next>;
+
+    /// Appends the reply to `out` once the responses of all of the commands of a request arrived.
+    fn write_response(&mut self, response: &Response, out: &mut Vec<u8>);
+}
+
+/// What the connection handler of a `Gateway` does next.
+pub(crate) enum Next {
+    /// Hand the command to the executor.
+    Command(Command),
+    /// Read more data, the buffer does not hold a full request.
+    Incomplete,
+    /// Close the connection.
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Set(String, String),
     Get(String),
     Clear(String),
     Ping(String),
+    /// Subscribe the connection to the given channel, switching it to push mode.
+    Subscribe(String),
+    /// Unsubscribe the connection from the given channel or pattern.
+    Unsubscribe(String),
+    /// Subscribe the connection to every channel matching the given glob pattern.
+    PSubscribe(String),
+    /// Publish a message (second argument) on the given channel.
+    Publish(String, String),
+    /// Watch the changes of the keys starting with the given prefix, switching to push mode.
+    Watch(String),
+    /// Stop watching the keys starting with the given prefix.
+    Unwatch(String),
+    /// Read at most the given number of changes from the change log, starting from the offset.
+    Changes(u64, u32),
+    /// Write a snapshot of the whole store to the file with the given name, in the server's
+    /// snapshot directory.
+    Snapshot(String),
+    /// Return at most the given number of key-value pairs whose key starts with the prefix (second
+    /// argument), in ascending order of keys, starting right after the given key if any.
+    Scan(Option<String>, String, u32),
+    /// Set all of the given key-value pairs at once.
+    MSet(Vec<(String, String)>),
+    /// Add the node with the given address to the cluster's ring.
+    AddNode(String),
+    /// Remove the node with the given address from the cluster's ring.
+    RemoveNode(String),
+    /// Move the local keys whose hash is in the given inclusive range, and that are owned by other
+    /// nodes, to their owners. The range wraps around the ring if the start is after the end.
+    Migrate(u64, u64),
+    /// Return the cluster's topology as known by the node.
+    Topology,
+    /// Read the versions of the key from its replicas, waiting for the given number of them to
+    /// answer, 0 standing for the node's default read quorum.
+    ReplicatedGet(String, u8),
+    /// Write a new version of the key, removing it if the value is `None`, that supersedes the
+    /// versions whose clocks are merged in the context (third argument). The node waits for the
+    /// given number of replicas to acknowledge the write, 0 standing for its default write quorum.
+    ReplicatedSet(String, Option<String>, VectorClock, u8),
+    /// Return the versions of the key held by the node, sent by coordinators to the replicas.
+    ReplicaRead(String),
+    /// Merge the given versions of the key into the ones held by the node, sent by coordinators to
+    /// the replicas.
+    ReplicaWrite(String, Vec<Versioned>),
+    /// Return the hashes of the nodes at the given positions of a level (second argument) of the
+    /// Merkle tree shared with the node with the given address, sent during anti-entropy rounds.
+    TreeHashes(String, u8, Vec<u32>),
+    /// Return the replicated keys shared with the node with the given address that fall in the
+    /// given buckets of the Merkle tree, along with their encoded versions.
+    BucketVersions(String, Vec<u32>),
+    /// Execute the wrapped command, unless the deadline, in milliseconds since the UNIX epoch, has
+    /// already passed. The node propagates the deadline to the requests it sends to other nodes on
+    /// behalf of the command.
+    ///
+    /// The deadline is compared to the wall clock of every node, which must be kept in sync with
+    /// the ones of the clients, e.g. with NTP: a node whose clock is ahead refuses commands that
+    /// still had time left, one whose clock is behind keeps executing them past their deadline.
+    Deadline(u64, Box<Command>),
+    /// Authenticate the connection as the given user (first argument) with its password, the
+    /// following commands are checked against the user's ACL.
+    Auth(String, String),
 }
 
-#[derive(Debug, PartialEq)]
+impl Command {
+    /// Whether executing the command more than once has the same effect as executing it once, in
+    /// which case clients can safely send it again when the connection is lost.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Deadline(_, command) => command.is_idempotent(),
+            command => matches!(
+                command,
+                Command::Get(_)
+                    | Command::Ping(_)
+                    | Command::Changes(..)
+                    | Command::Scan(..)
+                    | Command::Topology
+                    | Command::ReplicatedGet(..)
+                    | Command::ReplicaRead(_)
+                    | Command::TreeHashes(..)
+                    | Command::BucketVersions(..)
+                    | Command::Auth(..)
+            ),
+        }
+    }
+
+    /// The name of the command, as used in the ACL rules.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set(..) => "SET",
+            Command::Get(_) => "GET",
+            Command::Clear(_) => "UNSET",
+            Command::Ping(_) => "PING",
+            Command::Subscribe(_) => "SUBSCRIBE",
+            Command::Unsubscribe(_) => "UNSUBSCRIBE",
+            Command::PSubscribe(_) => "PSUBSCRIBE",
+            Command::Publish(..) => "PUBLISH",
+            Command::Watch(_) => "WATCH",
+            Command::Unwatch(_) => "UNWATCH",
+            Command::Changes(..) => "CHANGES",
+            Command::Snapshot(_) => "SNAPSHOT",
+            Command::Scan(..) => "SCAN",
+            Command::MSet(_) => "MSET",
+            Command::AddNode(_) => "ADDNODE",
+            Command::RemoveNode(_) => "REMOVENODE",
+            Command::Migrate(..) => "MIGRATE",
+            Command::Topology => "TOPOLOGY",
+            Command::ReplicatedGet(..) => "RGET",
+            Command::ReplicatedSet(..) => "RSET",
+            Command::ReplicaRead(_) => "REPLICAREAD",
+            Command::ReplicaWrite(..) => "REPLICAWRITE",
+            Command::TreeHashes(..) => "TREEHASHES",
+            Command::BucketVersions(..) => "BUCKETVERSIONS",
+            Command::Deadline(..) => "DEADLINE",
+            Command::Auth(..) => "AUTH",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Response {
     Ok(String),
     Error(String),
+    /// A message pushed to a subscribed connection, holding the channel it was published on,
+    /// followed by its payload.
+    Message(String, String),
+    /// A change of a watched key pushed to the connection.
+    Event(Event),
+    /// The number of events a watching connection missed because it was too slow.
+    Lagged(u64),
+    /// A batch of changes read from the change log.
+    Changes(Vec<Change>),
+    /// A list of key-value pairs.
+    Entries(Vec<(String, String)>),
+    /// The key is not owned by this node, the client should retry on the node with the given
+    /// address.
+    Moved(String),
+    /// The topology of the cluster: the number of virtual nodes per node, followed by the
+    /// addresses of all of the nodes.
+    Topology(u32, Vec<String>),
+    /// The versions of a replicated key, more than one if they are conflicting.
+    Versions(Vec<Versioned>),
+    /// Hashes of the nodes of a Merkle tree.
+    Hashes(Vec<u64>),
+    /// The deadline of the command passed before the node could execute it.
+    DeadlineExceeded,
+    /// The connection's user is not allowed to run the command, or the authentication failed.
+    Unauthorized(String),
+}
+
+impl Response {
+    /// Whether the response tells that the key of the command doesn't exist.
+    pub fn is_key_not_found(&self) -> bool {
+        matches!(self, Response::Error(msg) if msg == KEY_NOT_FOUND)
+    }
+}
+
+/// Converts a point in time to the milliseconds since the UNIX epoch used by
+/// `Command::Deadline`.
+pub fn epoch_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Error returned by the parsing functions when the buffer does not hold a full frame yet.
+///
+/// It is used by the connection handler to tell apart a malformed frame from one that is still
+/// being received, in which case more data should be read from the socket before retrying.
+#[derive(Debug)]
+pub struct Incomplete;
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Buffer exhausted before being able to read the required data"
+        )
+    }
 }
 
-fn get_u8(cur: &mut Cursor<&[u8]>) -> Result<u8> {
+impl std::error::Error for Incomplete {}
+
+pub(crate) fn get_u8(cur: &mut Cursor<&[u8]>) -> Result<u8> {
     if !cur.has_remaining() {
-        return Err("Buffer is exhausted".into());
+        return Err(Incomplete.into());
     }
     Ok(cur.get_u8())
 }
@@ -35,26 +240,102 @@ fn get_u16(cur: &mut Cursor<&[u8]>) -> Result<u16> {
     Ok(((line[0] as u16) << 8) | (line[1] as u16))
 }
 
-fn get_u32(cur: &mut Cursor<&[u8]>) -> Result<u32> {
+pub(crate) fn get_u32(cur: &mut Cursor<&[u8]>) -> Result<u32> {
     let line = get_slice(cur, 4)?;
     Ok(((line[0] as u32) << 24)
         + ((line[1] as u32) << 16)
         + ((line[2] as u32) << 8)
-        + ((line[3] as u32) << 0))
+        + (line[3] as u32))
+}
+
+pub(crate) fn get_u64(cur: &mut Cursor<&[u8]>) -> Result<u64> {
+    let high = get_u32(cur)? as u64;
+    let low = get_u32(cur)? as u64;
+    Ok((high << 32) | low)
 }
 
-fn get_string(cur: &mut Cursor<&[u8]>) -> Result<String> {
+pub(crate) fn get_string(cur: &mut Cursor<&[u8]>) -> Result<String> {
     let len = get_u32(cur)?;
     let data = get_slice(cur, len as usize)?;
     let key = String::from_utf8(data.to_vec())?;
     Ok(key)
 }
 
+/// Appends the string to the buffer in the protocol's format, 4 bytes for the length `n`, followed
+/// by `n` bytes of the actual string.
+pub(crate) fn put_string(buf: &mut Vec<u8>, data: &str) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data.as_bytes());
+}
+
+pub(crate) fn get_entries(cur: &mut Cursor<&[u8]>) -> Result<Vec<(String, String)>> {
+    let count = get_u32(cur)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let key = get_string(cur)?;
+        let value = get_string(cur)?;
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+pub(crate) fn get_event(cur: &mut Cursor<&[u8]>) -> Result<Event> {
+    let key = get_string(cur)?;
+    let operation = match get_u8(cur)? {
+        0 => Operation::Set,
+        1 => Operation::Unset,
+        _ => return Err("Unknown event operation".into()),
+    };
+    let value = match get_u8(cur)? {
+        0 => None,
+        _ => Some(get_string(cur)?),
+    };
+    Ok(Event {
+        key,
+        operation,
+        value,
+    })
+}
+
+pub(crate) fn get_clock(cur: &mut Cursor<&[u8]>) -> Result<VectorClock> {
+    let count = get_u32(cur)?;
+    let mut clock = VectorClock::new();
+    for _ in 0..count {
+        let node = get_string(cur)?;
+        clock.set(&node, get_u64(cur)?);
+    }
+    Ok(clock)
+}
+
+pub(crate) fn get_versions(cur: &mut Cursor<&[u8]>) -> Result<Vec<Versioned>> {
+    let count = get_u32(cur)?;
+    let mut versions = vec![];
+    for _ in 0..count {
+        let clock = get_clock(cur)?;
+        let value = match get_u8(cur)? {
+            0 => None,
+            _ => Some(get_string(cur)?),
+        };
+        versions.push(Versioned { clock, value });
+    }
+    Ok(versions)
+}
+
+pub(crate) fn get_u32_list(cur: &mut Cursor<&[u8]>) -> Result<Vec<u32>> {
+    let count = get_u32(cur)?;
+    (0..count).map(|_| get_u32(cur)).collect()
+}
+
+pub(crate) fn get_u64_list(cur: &mut Cursor<&[u8]>) -> Result<Vec<u64>> {
+    let count = get_u32(cur)?;
+    (0..count).map(|_| get_u64(cur)).collect()
+}
+
 fn get_slice<'a>(cur: &mut Cursor<&'a [u8]>, len: usize) -> Result<&'a [u8]> {
     let from = cur.position() as usize;
     let until = from + len;
     if cur.get_ref().len() < until {
-        return Err("Buffer exhaused before being able to read the required data".into());
+        return Err(Incomplete.into());
     }
     cur.set_position(until as u64);
     Ok(&cur.get_ref()[from..until])
@@ -1,6 +1,9 @@
-use crate::protocol::{get_string, get_u16, get_u8};
-use crate::protocol::{Command, Response};
+use crate::cdc::Change;
+use crate::protocol::{get_clock, get_entries, get_event, get_string, get_versions};
+use crate::protocol::{get_u16, get_u32, get_u32_list, get_u64, get_u64_list, get_u8};
+use crate::protocol::{Command, Incomplete, Response};
 use crate::Result;
+use bytes::{Buf, BytesMut};
 use std::io::Cursor;
 
 /// Parser is a struct holder for protocol parsing methods.
@@ -19,12 +22,62 @@ impl Parser {
     /// internal pointer in any random location.
     pub fn parse(data: &mut Cursor<&[u8]>) -> Result<Command> {
         let _header = crate::protocol::get_u8(data)?;
-        let command = get_u16(data)?;
+        match get_u16(data)? {
+            // The command of a deadline is parsed here rather than recursively, so that a frame
+            // can't nest deadlines until the stack overflows.
+            24 => {
+                let deadline = get_u64(data)?;
+                let _header = crate::protocol::get_u8(data)?;
+                match get_u16(data)? {
+                    24 => Err("Deadlines can't be nested".into()),
+                    command => Ok(Command::Deadline(
+                        deadline,
+                        Box::new(Parser::parse_command(data, command)?),
+                    )),
+                }
+            }
+            command => Parser::parse_command(data, command),
+        }
+    }
+
+    /// Parse the fields of the command with the given number, other than a deadline.
+    fn parse_command(data: &mut Cursor<&[u8]>, command: u16) -> Result<Command> {
         match command {
             0 => Parser::parse_get(data),
             1 => Parser::parse_set(data),
             2 => Parser::parse_clear(data),
             3 => Parser::parse_ping(data),
+            4 => Ok(Command::Subscribe(get_string(data)?)),
+            5 => Ok(Command::Unsubscribe(get_string(data)?)),
+            6 => Ok(Command::PSubscribe(get_string(data)?)),
+            7 => Parser::parse_publish(data),
+            8 => Ok(Command::Watch(get_string(data)?)),
+            9 => Ok(Command::Unwatch(get_string(data)?)),
+            10 => Ok(Command::Changes(get_u64(data)?, get_u32(data)?)),
+            11 => Ok(Command::Snapshot(get_string(data)?)),
+            12 => Parser::parse_scan(data),
+            13 => Ok(Command::MSet(get_entries(data)?)),
+            14 => Ok(Command::AddNode(get_string(data)?)),
+            15 => Ok(Command::RemoveNode(get_string(data)?)),
+            16 => Ok(Command::Migrate(get_u64(data)?, get_u64(data)?)),
+            17 => Ok(Command::Topology),
+            18 => Ok(Command::ReplicatedGet(get_string(data)?, get_u8(data)?)),
+            19 => Parser::parse_replicated_set(data),
+            20 => Ok(Command::ReplicaRead(get_string(data)?)),
+            21 => Ok(Command::ReplicaWrite(
+                get_string(data)?,
+                get_versions(data)?,
+            )),
+            22 => Ok(Command::TreeHashes(
+                get_string(data)?,
+                get_u8(data)?,
+                get_u32_list(data)?,
+            )),
+            23 => Ok(Command::BucketVersions(
+                get_string(data)?,
+                get_u32_list(data)?,
+            )),
+            25 => Ok(Command::Auth(get_string(data)?, get_string(data)?)),
             _ => Err("Unknown command number".into()),
         }
     }
@@ -45,12 +98,94 @@ impl Parser {
         let response = match response_type {
             0 => Response::Ok(get_string(data)?),
             1 => Response::Error(get_string(data)?),
+            2 => Response::Message(get_string(data)?, get_string(data)?),
+            3 => Response::Event(get_event(data)?),
+            4 => Response::Lagged(get_u64(data)?),
+            5 => Parser::parse_changes(data)?,
+            6 => Response::Entries(get_entries(data)?),
+            7 => Response::Moved(get_string(data)?),
+            8 => Parser::parse_topology(data)?,
+            9 => Response::Versions(get_versions(data)?),
+            10 => Response::Hashes(get_u64_list(data)?),
+            11 => Response::DeadlineExceeded,
+            12 => Response::Unauthorized(get_string(data)?),
             _ => Response::Error("Unknown response type".into()),
         };
 
         Ok(response)
     }
 
+    /// Parse a frame from the start of the buffer with the given function, and remove it from the
+    /// buffer. `None` is returned if the buffer does not hold a full frame yet, in which case the
+    /// buffer is left untouched so that parsing can be retried once more data was received.
+    pub fn next_frame<T>(
+        buf: &mut BytesMut,
+        parse: fn(&mut Cursor<&[u8]>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let mut cursor = Cursor::new(&buf[..]);
+        match parse(&mut cursor) {
+            Ok(frame) => {
+                // If reading the frame was successfull, we should advance the internal pointer of
+                // the buffer as the cursor only updates it's own.
+                let length = cursor.position() as usize;
+                buf.advance(length);
+                Ok(Some(frame))
+            }
+            Err(err) if err.is::<Incomplete>() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn parse_scan(data: &mut Cursor<&[u8]>) -> Result<Command> {
+        let after = match get_u8(data)? {
+            0 => None,
+            _ => Some(get_string(data)?),
+        };
+        let prefix = get_string(data)?;
+        let count = get_u32(data)?;
+        Ok(Command::Scan(after, prefix, count))
+    }
+
+    fn parse_replicated_set(data: &mut Cursor<&[u8]>) -> Result<Command> {
+        let key = get_string(data)?;
+        let value = match get_u8(data)? {
+            0 => None,
+            _ => Some(get_string(data)?),
+        };
+        let context = get_clock(data)?;
+        let quorum = get_u8(data)?;
+        Ok(Command::ReplicatedSet(key, value, context, quorum))
+    }
+
+    fn parse_topology(data: &mut Cursor<&[u8]>) -> Result<Response> {
+        let vnodes = get_u32(data)?;
+        let count = get_u32(data)?;
+        let mut nodes = vec![];
+        for _ in 0..count {
+            nodes.push(get_string(data)?);
+        }
+        Ok(Response::Topology(vnodes, nodes))
+    }
+
+    fn parse_changes(data: &mut Cursor<&[u8]>) -> Result<Response> {
+        let count = get_u32(data)?;
+        let mut changes = vec![];
+        for _ in 0..count {
+            let seq = get_u64(data)?;
+            let timestamp = get_u64(data)?;
+            let event = get_event(data)?;
+            changes.push(Change {
+                seq,
+                timestamp,
+                event,
+            });
+        }
+        Ok(Response::Changes(changes))
+    }
+
     fn parse_get(data: &mut Cursor<&[u8]>) -> Result<Command> {
         let key = get_string(data)?;
         Ok(Command::Get(key))
@@ -71,11 +206,19 @@ impl Parser {
         let key = get_string(data)?;
         Ok(Command::Ping(key))
     }
+
+    fn parse_publish(data: &mut Cursor<&[u8]>) -> Result<Command> {
+        let channel = get_string(data)?;
+        let message = get_string(data)?;
+        Ok(Command::Publish(channel, message))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::notify::{Event, Operation};
+    use crate::protocol::Writer;
 
     #[test]
     fn it_works_for_get() {
@@ -98,6 +241,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_rejects_nested_deadlines() {
+        let get = Command::Get(String::from("foobar"));
+        let mut buf = BytesMut::new();
+        Writer::encode_command(&mut buf, &Command::Deadline(1, Box::new(get.clone())));
+        let mut cur = Cursor::new(&buf[..]);
+        assert_eq!(
+            Parser::parse(&mut cur).unwrap(),
+            Command::Deadline(1, Box::new(get.clone()))
+        );
+
+        let nested = Command::Deadline(1, Box::new(Command::Deadline(2, Box::new(get))));
+        let mut buf = BytesMut::new();
+        Writer::encode_command(&mut buf, &nested);
+        let mut cur = Cursor::new(&buf[..]);
+        let err = Parser::parse(&mut cur).unwrap_err();
+        assert_eq!(err.to_string(), "Deadlines can't be nested");
+    }
+
     #[test]
     fn it_works_for_clear() {
         let mut buf: Vec<u8> = vec![];
@@ -136,6 +298,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_works_for_scan() {
+        let mut buf: Vec<u8> = vec![];
+        let command_num: u16 = 12;
+        buf.push(0); // header bit
+        write_u16(&mut buf, command_num);
+        buf.push(1); // has a starting key
+        write_str(&mut buf, "user:1");
+        write_str(&mut buf, "user:");
+        write_u32(&mut buf, 10);
+        let mut cur = Cursor::new(buf.as_slice());
+        let command = Parser::parse(&mut cur).unwrap();
+        assert_eq!(
+            command,
+            Command::Scan(Some(String::from("user:1")), String::from("user:"), 10)
+        );
+    }
+
     #[test]
     fn it_works_for_response_ok() {
         let mut buf: Vec<u8> = vec![];
@@ -154,6 +334,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_works_for_publish() {
+        let mut buf: Vec<u8> = vec![];
+        let command_num: u16 = 7;
+        buf.push(0); // header bit
+        write_u16(&mut buf, command_num);
+        write_str(&mut buf, "news");
+        write_str(&mut buf, "hello");
+        let mut cur = Cursor::new(buf.as_slice());
+        let command = Parser::parse(&mut cur).unwrap();
+        assert_eq!(
+            command,
+            Command::Publish(String::from("news"), String::from("hello"))
+        );
+    }
+
+    #[test]
+    fn it_works_for_response_message() {
+        let mut buf: Vec<u8> = vec![];
+        let response_type: u8 = 2; // message
+        buf.push(0); // header bit
+        buf.push(response_type);
+        write_str(&mut buf, "news");
+        write_str(&mut buf, "hello");
+        let mut cur = Cursor::new(buf.as_slice());
+        let response = Parser::parse_response(&mut cur).unwrap();
+        assert_eq!(
+            response,
+            Response::Message(String::from("news"), String::from("hello"))
+        );
+    }
+
+    #[test]
+    fn it_works_for_response_event() {
+        let mut buf: Vec<u8> = vec![];
+        let response_type: u8 = 3; // event
+        buf.push(0); // header bit
+        buf.push(response_type);
+        write_str(&mut buf, "key");
+        buf.push(0); // set operation
+        buf.push(1); // has a value
+        write_str(&mut buf, "value");
+        let mut cur = Cursor::new(buf.as_slice());
+        let response = Parser::parse_response(&mut cur).unwrap();
+        let expected = Event {
+            key: String::from("key"),
+            operation: Operation::Set,
+            value: Some(String::from("value")),
+        };
+        assert_eq!(response, Response::Event(expected));
+    }
+
+    #[test]
+    fn it_works_for_deadline() {
+        let mut buf: Vec<u8> = vec![];
+        buf.push(0); // header bit
+        write_u16(&mut buf, 24);
+        buf.extend_from_slice(&1_600_000_000_000u64.to_be_bytes());
+        buf.push(0); // header bit of the wrapped command
+        write_u16(&mut buf, 0);
+        write_str(&mut buf, "foobar");
+        let mut cur = Cursor::new(buf.as_slice());
+        let command = Parser::parse(&mut cur).unwrap();
+        let get = Command::Get(String::from("foobar"));
+        assert_eq!(command, Command::Deadline(1_600_000_000_000, Box::new(get)));
+    }
+
+    #[test]
+    fn it_works_for_auth() {
+        let mut buf: Vec<u8> = vec![];
+        buf.push(0); // header bit
+        write_u16(&mut buf, 25);
+        write_str(&mut buf, "alice");
+        write_str(&mut buf, "secret");
+        let mut cur = Cursor::new(buf.as_slice());
+        let command = Parser::parse(&mut cur).unwrap();
+        assert_eq!(
+            command,
+            Command::Auth(String::from("alice"), String::from("secret"))
+        );
+    }
+
+    #[test]
+    fn it_reports_incomplete_frames() {
+        let mut buf: Vec<u8> = vec![];
+        buf.push(0); // header bit
+        write_u16(&mut buf, 1);
+        write_str(&mut buf, "foobar");
+        // the value is missing.
+        let mut cur = Cursor::new(buf.as_slice());
+        let err = Parser::parse(&mut cur).unwrap_err();
+        assert!(err.is::<crate::protocol::Incomplete>());
+    }
+
     fn write_u16(buf: &mut Vec<u8>, val: u16) {
         buf.extend_from_slice(&val.to_be_bytes());
     }
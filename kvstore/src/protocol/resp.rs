@@ -0,0 +1,640 @@
+//! The Redis serialization protocol, RESP2 and RESP3, spoken on the server's Redis compatible
+//! listener so that `redis-cli` and the Redis client libraries can talk to the server.
+//!
+//! The requests are translated into `Command`s, which go through the executor like the ones of
+//! the native protocol, and their `Response`s are translated back into replies. A request may
+//! expand into several commands, e.g. `DEL a b` unsets both keys, in which case the responses of
+//! the commands are combined into a single reply.
+//!
+//! The supported requests are `PING`, `ECHO`, `GET`, `SET`, `DEL`, `UNLINK`, `EXISTS`, `MGET`,
+//! `MSET`, `PUBLISH`, `SUBSCRIBE`, `PSUBSCRIBE`, `UNSUBSCRIBE`, `PUNSUBSCRIBE`, `AUTH`, `HELLO`,
+//! along with `SELECT 0`, `CLIENT SETNAME`, `CLIENT SETINFO`, `COMMAND` and `QUIT` which the
+//! clients send when they connect or disconnect. Connections start with RESP2, and switch to
+//! RESP3 with `HELLO 3`.
+
+use bytes::{Buf, BytesMut};
+use std::collections::{HashSet, VecDeque};
+use std::io::Cursor;
+
+use crate::executor::KEY_NOT_FOUND;
+use crate::notify::Operation;
+use crate::protocol::{get_slice, get_u8, Command, Gateway, Incomplete, Next, Parser, Response};
+use crate::Result;
+
+/// Maximum number of arguments of a request.
+const MAX_ARGS: i64 = 1 << 20;
+
+/// Maximum length of an argument of a request, as in Redis.
+const MAX_BULK: i64 = 512 << 20;
+
+/// Maximum length of an inline request, typed e.g. in telnet.
+const MAX_INLINE: usize = 64 << 10;
+
+/// A RESP value.
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Frame>),
+    /// Sent as an array of keys and values with RESP2.
+    Map(Vec<(Frame, Frame)>),
+    /// Out of band data, e.g. the messages of the subscribed channels, sent as an array with
+    /// RESP2.
+    Push(Vec<Frame>),
+}
+
+impl Frame {
+    fn bulk<S: Into<String>>(data: S) -> Frame {
+        Frame::Bulk(data.into())
+    }
+}
+
+/// How the responses of the commands of a request are turned into its reply.
+#[derive(Debug, Clone, Copy)]
+enum Reply {
+    /// `OK`, once every command succeeded.
+    Ok,
+    Pong,
+    /// The value of the response as a bulk string.
+    Bulk,
+    /// The values of the keys, null for the missing ones, as an array if `array` is set.
+    Values {
+        array: bool,
+    },
+    /// The number of keys that exist.
+    Exists,
+    /// The number of keys that were removed.
+    Deleted,
+    /// The value of the response as an integer.
+    Integer,
+    /// The acknowledgement of a (un)subscription, e.g. `subscribe`.
+    Subscription(&'static str),
+    Auth,
+    /// The connection switches to the given version of the protocol once authenticated.
+    Hello(u8),
+}
+
+/// A reply waiting for the responses of its commands.
+struct Pending {
+    reply: Reply,
+    commands: usize,
+    responses: Vec<Response>,
+}
+
+/// The state of a connection speaking RESP.
+///
+/// The executor writes the response of a command before reading the next one, so the replies are
+/// sent in the order of the requests.
+pub(crate) struct Session {
+    /// 2 or 3.
+    version: u8,
+    /// The commands of the current request not handed to the executor yet.
+    queued: VecDeque<Command>,
+    /// The replies of the commands handed to the executor, in order.
+    pending: VecDeque<Pending>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    quit: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            version: 2,
+            queued: VecDeque::new(),
+            pending: VecDeque::new(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            quit: false,
+        }
+    }
+}
+
+impl Gateway for Session {
+    fn next_command(&mut self, buf: &mut BytesMut, out: &mut Vec<u8>) -> Result<Next> {
+        loop {
+            if let Some(command) = self.queued.pop_front() {
+                return Ok(Next::Command(command));
+            }
+            if self.quit {
+                return Ok(Next::Quit);
+            }
+            let args = match Parser::next_frame(buf, parse_request) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(Next::Incomplete),
+                Err(err) => {
+                    let frame = Frame::Error(format!("ERR Protocol error: {}", err));
+                    self.encode(&frame, out);
+                    return Err(err);
+                }
+            };
+            if args.is_empty() {
+                continue;
+            }
+            let frames = self
+                .request(args)
+                .unwrap_or_else(|msg| vec![Frame::Error(msg)]);
+            for frame in frames {
+                self.encode(&frame, out);
+            }
+        }
+    }
+
+    fn write_response(&mut self, response: &Response, out: &mut Vec<u8>) {
+        let frame = match response {
+            // Pushed by the server, in between the replies.
+            Response::Message(channel, payload) => Frame::Push(vec![
+                Frame::bulk("message"),
+                Frame::bulk(channel.as_str()),
+                Frame::bulk(payload.as_str()),
+            ]),
+            Response::Event(event) => Frame::Push(vec![
+                Frame::bulk("event"),
+                Frame::bulk(event.key.as_str()),
+                Frame::bulk(match event.operation {
+                    Operation::Set => "set",
+                    Operation::Unset => "unset",
+                }),
+                event.value.clone().map_or(Frame::Null, Frame::Bulk),
+            ]),
+            Response::Lagged(missed) => {
+                Frame::Push(vec![Frame::bulk("lagged"), Frame::Integer(*missed as i64)])
+            }
+            response => {
+                let pending = match self.pending.front_mut() {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                pending.responses.push(response.clone());
+                if pending.responses.len() < pending.commands {
+                    return;
+                }
+                let pending = self.pending.pop_front().unwrap();
+                self.reply(pending.reply, pending.responses)
+            }
+        };
+        self.encode(&frame, out);
+    }
+}
+
+impl Session {
+    /// Translates the request into commands, which are queued, or returns its replies if it does
+    /// not need the executor.
+    fn request(&mut self, args: Vec<Vec<u8>>) -> std::result::Result<Vec<Frame>, String> {
+        let args = args
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<String>, _>>()
+            .map_err(|_| String::from("ERR the arguments must be valid UTF-8"))?;
+        let name = args[0].to_uppercase();
+        let args = &args[1..];
+        let arity = || {
+            format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )
+        };
+        match (name.as_str(), args) {
+            ("PING", []) => self.queue(vec![Command::Ping(String::new())], Reply::Pong),
+            // An empty key is a plain ping for the executor.
+            ("PING", [msg]) | ("ECHO", [msg]) if msg.is_empty() => {
+                return Ok(vec![Frame::bulk("")])
+            }
+            ("PING", [msg]) | ("ECHO", [msg]) => {
+                self.queue(vec![Command::Ping(msg.clone())], Reply::Bulk)
+            }
+            ("GET", [key]) => self.queue(
+                vec![Command::Get(key.clone())],
+                Reply::Values { array: false },
+            ),
+            ("SET", [key, value]) => {
+                self.queue(vec![Command::Set(key.clone(), value.clone())], Reply::Ok)
+            }
+            ("SET", [_, _, ..]) => return Err(String::from("ERR syntax error")),
+            ("DEL", [_, ..]) | ("UNLINK", [_, ..]) => {
+                let commands = args.iter().cloned().map(Command::Clear).collect();
+                self.queue(commands, Reply::Deleted)
+            }
+            ("EXISTS", [_, ..]) => {
+                let commands = args.iter().cloned().map(Command::Get).collect();
+                self.queue(commands, Reply::Exists)
+            }
+            ("MGET", [_, ..]) => {
+                let commands = args.iter().cloned().map(Command::Get).collect();
+                self.queue(commands, Reply::Values { array: true })
+            }
+            ("MSET", [_, _, ..]) if args.len() % 2 == 0 => {
+                let entries = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                self.queue(vec![Command::MSet(entries)], Reply::Ok)
+            }
+            ("PUBLISH", [channel, message]) => self.queue(
+                vec![Command::Publish(channel.clone(), message.clone())],
+                Reply::Integer,
+            ),
+            ("SUBSCRIBE", [_, ..]) => {
+                for channel in args {
+                    let command = Command::Subscribe(channel.clone());
+                    self.queue(vec![command], Reply::Subscription("subscribe"));
+                }
+            }
+            ("PSUBSCRIBE", [_, ..]) => {
+                for pattern in args {
+                    let command = Command::PSubscribe(pattern.clone());
+                    self.queue(vec![command], Reply::Subscription("psubscribe"));
+                }
+            }
+            ("UNSUBSCRIBE", _) => return Ok(self.unsubscribe("unsubscribe", args)),
+            ("PUNSUBSCRIBE", _) => return Ok(self.unsubscribe("punsubscribe", args)),
+            ("AUTH", [password]) => self.queue(
+                vec![Command::Auth(String::from("default"), password.clone())],
+                Reply::Auth,
+            ),
+            ("AUTH", [user, password]) => self.queue(
+                vec![Command::Auth(user.clone(), password.clone())],
+                Reply::Auth,
+            ),
+            ("HELLO", _) => return self.hello(args),
+            ("SELECT", [index]) if index == "0" => return Ok(vec![Frame::Simple("OK".into())]),
+            ("SELECT", [_]) => return Err(String::from("ERR DB index is out of range")),
+            ("CLIENT", [subcommand, _, ..])
+                if subcommand.eq_ignore_ascii_case("SETNAME")
+                    || subcommand.eq_ignore_ascii_case("SETINFO") =>
+            {
+                return Ok(vec![Frame::Simple("OK".into())])
+            }
+            // The clients only ask for the documentation of the commands, e.g. for hints.
+            ("COMMAND", _) => return Ok(vec![Frame::Array(vec![])]),
+            ("QUIT", _) => {
+                self.quit = true;
+                return Ok(vec![Frame::Simple("OK".into())]);
+            }
+            (
+                "PING" | "ECHO" | "GET" | "SET" | "DEL" | "UNLINK" | "EXISTS" | "MGET" | "MSET"
+                | "PUBLISH" | "SUBSCRIBE" | "PSUBSCRIBE" | "AUTH" | "SELECT",
+                _,
+            ) => return Err(arity()),
+            _ => return Err(format!("ERR unknown command '{}'", name.to_lowercase())),
+        }
+        Ok(vec![])
+    }
+
+    fn queue(&mut self, commands: Vec<Command>, reply: Reply) {
+        self.pending.push_back(Pending {
+            reply,
+            commands: commands.len(),
+            responses: vec![],
+        });
+        self.queued.extend(commands);
+    }
+
+    /// Unsubscribes from the given channels or patterns, or from all of them if none is given.
+    fn unsubscribe(&mut self, kind: &'static str, names: &[String]) -> Vec<Frame> {
+        let subscribed = match kind {
+            "unsubscribe" => &self.channels,
+            _ => &self.patterns,
+        };
+        let names: Vec<String> = match names {
+            [] => subscribed.iter().cloned().collect(),
+            names => names.to_vec(),
+        };
+        // Outside of push mode, the executor refuses to unsubscribe.
+        if self.channels.is_empty() && self.patterns.is_empty() {
+            if names.is_empty() {
+                return vec![self.subscription(kind, Frame::Null)];
+            }
+            return names
+                .into_iter()
+                .map(|name| self.subscription(kind, Frame::Bulk(name)))
+                .collect();
+        }
+        for name in names {
+            self.queue(vec![Command::Unsubscribe(name)], Reply::Subscription(kind));
+        }
+        vec![]
+    }
+
+    /// `HELLO [protover [AUTH username password] [SETNAME clientname]]`.
+    fn hello(&mut self, args: &[String]) -> std::result::Result<Vec<Frame>, String> {
+        let (version, options) = match args {
+            [] => (self.version, args),
+            [version, options @ ..] => match version.as_str() {
+                "2" => (2, options),
+                "3" => (3, options),
+                _ => return Err(String::from("NOPROTO unsupported protocol version")),
+            },
+        };
+        let mut auth = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            if option.eq_ignore_ascii_case("AUTH") {
+                match (options.next(), options.next()) {
+                    (Some(user), Some(password)) => auth = Some((user.clone(), password.clone())),
+                    _ => return Err(String::from("ERR syntax error in HELLO option 'auth'")),
+                }
+            } else if option.eq_ignore_ascii_case("SETNAME") {
+                options
+                    .next()
+                    .ok_or("ERR syntax error in HELLO option 'setname'")?;
+            } else {
+                return Err(format!("ERR syntax error in HELLO option '{}'", option));
+            }
+        }
+        match auth {
+            Some((user, password)) => {
+                self.queue(vec![Command::Auth(user, password)], Reply::Hello(version));
+                Ok(vec![])
+            }
+            None => {
+                self.version = version;
+                Ok(vec![self.server_info()])
+            }
+        }
+    }
+
+    fn server_info(&self) -> Frame {
+        let entry = |key: &str, value: Frame| (Frame::bulk(key), value);
+        Frame::Map(vec![
+            entry("server", Frame::bulk("kvstore")),
+            entry("version", Frame::bulk(env!("CARGO_PKG_VERSION"))),
+            entry("proto", Frame::Integer(self.version as i64)),
+            entry("id", Frame::Integer(0)),
+            entry("mode", Frame::bulk("standalone")),
+            entry("role", Frame::bulk("master")),
+            entry("modules", Frame::Array(vec![])),
+        ])
+    }
+
+    fn subscription(&self, kind: &'static str, name: Frame) -> Frame {
+        let count = (self.channels.len() + self.patterns.len()) as i64;
+        Frame::Push(vec![Frame::bulk(kind), name, Frame::Integer(count)])
+    }
+
+    fn reply(&mut self, reply: Reply, responses: Vec<Response>) -> Frame {
+        // The first failed command fails the whole request, the missing keys are not failures.
+        for response in &responses {
+            match response {
+                Response::Ok(_) => {}
+                Response::Error(msg)
+                    if msg == KEY_NOT_FOUND
+                        && matches!(
+                            reply,
+                            Reply::Values { .. } | Reply::Exists | Reply::Deleted
+                        ) => {}
+                Response::Error(msg) => return Frame::Error(format!("ERR {}", msg)),
+                Response::Unauthorized(msg) => {
+                    return match reply {
+                        Reply::Auth | Reply::Hello(_) => Frame::Error(format!("WRONGPASS {}", msg)),
+                        _ => Frame::Error(format!("NOPERM {}", msg)),
+                    }
+                }
+                Response::Moved(node) => return Frame::Error(format!("MOVED {}", node)),
+                Response::DeadlineExceeded => {
+                    return Frame::Error(String::from("ERR the deadline of the command passed"))
+                }
+                response => return Frame::Error(format!("ERR unexpected response {:?}", response)),
+            }
+        }
+        let mut values = responses.into_iter().map(|response| match response {
+            Response::Ok(value) => Some(value),
+            _ => None,
+        });
+        match reply {
+            Reply::Ok | Reply::Auth => Frame::Simple("OK".into()),
+            Reply::Pong => Frame::Simple("PONG".into()),
+            Reply::Bulk => Frame::Bulk(values.next().flatten().unwrap_or_default()),
+            Reply::Values { array: false } => {
+                values.next().flatten().map_or(Frame::Null, Frame::Bulk)
+            }
+            Reply::Values { array: true } => Frame::Array(
+                values
+                    .map(|value| value.map_or(Frame::Null, Frame::Bulk))
+                    .collect(),
+            ),
+            // The executor answers with the value of the keys found, and an error for the others.
+            Reply::Exists | Reply::Deleted => Frame::Integer(values.flatten().count() as i64),
+            Reply::Integer => match values.next().flatten().map(|value| value.parse()) {
+                Some(Ok(value)) => Frame::Integer(value),
+                _ => Frame::Error(String::from("ERR expected an integer")),
+            },
+            Reply::Subscription(kind) => {
+                let name = values.next().flatten().unwrap_or_default();
+                match kind {
+                    "subscribe" => self.channels.insert(name.clone()),
+                    "psubscribe" => self.patterns.insert(name.clone()),
+                    "unsubscribe" => self.channels.remove(&name),
+                    _ => self.patterns.remove(&name),
+                };
+                self.subscription(kind, Frame::Bulk(name))
+            }
+            Reply::Hello(version) => {
+                self.version = version;
+                self.server_info()
+            }
+        }
+    }
+
+    fn encode(&self, frame: &Frame, out: &mut Vec<u8>) {
+        encode(frame, self.version, out)
+    }
+}
+
+fn encode(frame: &Frame, version: u8, out: &mut Vec<u8>) {
+    match frame {
+        Frame::Simple(value) => out.extend_from_slice(format!("+{}\r\n", value).as_bytes()),
+        Frame::Error(msg) => out.extend_from_slice(format!("-{}\r\n", msg).as_bytes()),
+        Frame::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+        Frame::Bulk(data) => {
+            out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+            out.extend_from_slice(data.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Frame::Null if version >= 3 => out.extend_from_slice(b"_\r\n"),
+        Frame::Null => out.extend_from_slice(b"$-1\r\n"),
+        Frame::Array(items) => encode_aggregate('*', items, version, out),
+        Frame::Push(items) if version >= 3 => encode_aggregate('>', items, version, out),
+        Frame::Push(items) => encode_aggregate('*', items, version, out),
+        Frame::Map(entries) => {
+            match version {
+                3 => out.extend_from_slice(format!("%{}\r\n", entries.len()).as_bytes()),
+                _ => out.extend_from_slice(format!("*{}\r\n", entries.len() * 2).as_bytes()),
+            }
+            for (key, value) in entries {
+                encode(key, version, out);
+                encode(value, version, out);
+            }
+        }
+    }
+}
+
+fn encode_aggregate(kind: char, items: &[Frame], version: u8, out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("{}{}\r\n", kind, items.len()).as_bytes());
+    for item in items {
+        encode(item, version, out);
+    }
+}
+
+/// Parses the arguments of a request, an array of bulk strings, or an inline request made of
+/// arguments separated by spaces.
+fn parse_request(cur: &mut Cursor<&[u8]>) -> Result<Vec<Vec<u8>>> {
+    if !cur.has_remaining() {
+        return Err(Incomplete.into());
+    }
+    if cur.chunk()[0] != b'*' {
+        let line = get_line(cur, MAX_INLINE)?;
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(args);
+    }
+    cur.advance(1);
+    let count = get_number(cur)?;
+    if !(-1..=MAX_ARGS).contains(&count) {
+        return Err("invalid multibulk length".into());
+    }
+    let mut args = vec![];
+    for _ in 0..count.max(0) {
+        if get_u8(cur)? != b'$' {
+            return Err("expected '$'".into());
+        }
+        let len = get_number(cur)?;
+        if !(0..=MAX_BULK).contains(&len) {
+            return Err("invalid bulk length".into());
+        }
+        let data = get_slice(cur, len as usize)?;
+        if get_slice(cur, 2)? != b"\r\n" {
+            return Err("expected CRLF after the bulk string".into());
+        }
+        args.push(data.to_vec());
+    }
+    Ok(args)
+}
+
+/// Reads a line ending with `\r\n`, or with a single `\n` as Redis accepts.
+fn get_line<'a>(cur: &mut Cursor<&'a [u8]>, max: usize) -> Result<&'a [u8]> {
+    let start = cur.position() as usize;
+    let data = &cur.get_ref()[start..];
+    match data.iter().position(|byte| *byte == b'\n') {
+        Some(end) => {
+            cur.set_position((start + end + 1) as u64);
+            Ok(data[..end].strip_suffix(b"\r").unwrap_or(&data[..end]))
+        }
+        None if data.len() > max => Err("too big request".into()),
+        None => Err(Incomplete.into()),
+    }
+}
+
+fn get_number(cur: &mut Cursor<&[u8]>) -> Result<i64> {
+    let line = get_line(cur, 32)?;
+    let number = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok());
+    number.ok_or_else(|| "invalid length".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the bytes to the session, answering every command with the given responses, and
+    /// returns what the session sent back.
+    fn exchange(session: &mut Session, input: &[u8], responses: &[Response]) -> String {
+        let mut buf = BytesMut::from(input);
+        let mut out = vec![];
+        let mut responses = responses.iter();
+        while let Next::Command(_) = session.next_command(&mut buf, &mut out).unwrap() {
+            session.write_response(responses.next().unwrap(), &mut out);
+        }
+        assert!(responses.next().is_none());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn it_parses_requests_received_in_pieces() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\nGET key\r\n";
+        let mut buf = BytesMut::new();
+        let mut parsed = vec![];
+        for byte in input.iter() {
+            buf.extend_from_slice(&[*byte]);
+            if let Some(args) = Parser::next_frame(&mut buf, parse_request).unwrap() {
+                parsed.push(args);
+            }
+        }
+        let expected: Vec<Vec<Vec<u8>>> = vec![
+            vec![b"SET".to_vec(), b"key".to_vec(), b"value".to_vec()],
+            vec![b"GET".to_vec(), b"key".to_vec()],
+        ];
+        assert_eq!(parsed, expected);
+
+        let mut buf = BytesMut::from(&b"*1\r\n$-5\r\n"[..]);
+        assert!(Parser::next_frame(&mut buf, parse_request).is_err());
+    }
+
+    #[test]
+    fn it_combines_the_responses_of_a_request() {
+        let mut session = Session::default();
+        let input = b"DEL a b c\r\nMGET a b\r\nEXISTS a b\r\n";
+        // an empty value was removed all the same.
+        let responses = [
+            Response::Ok("1".into()),
+            Response::Ok("".into()),
+            Response::Error(KEY_NOT_FOUND.into()),
+            Response::Ok("1".into()),
+            Response::Error(KEY_NOT_FOUND.into()),
+            Response::Ok("1".into()),
+            Response::Error(KEY_NOT_FOUND.into()),
+        ];
+        assert_eq!(
+            exchange(&mut session, input, &responses),
+            ":2\r\n*2\r\n$1\r\n1\r\n$-1\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn it_switches_to_resp3() {
+        let mut session = Session::default();
+        let out = exchange(
+            &mut session,
+            b"HELLO 3\r\nGET a\r\n",
+            &[Response::Error(KEY_NOT_FOUND.into())],
+        );
+        assert!(out.starts_with("%7\r\n$6\r\nserver\r\n$7\r\nkvstore\r\n"));
+        assert!(out.ends_with("_\r\n"));
+
+        let out = exchange(
+            &mut session,
+            b"SUBSCRIBE news\r\n",
+            &[Response::Ok("news".into())],
+        );
+        assert_eq!(out, ">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        let mut out = vec![];
+        session.write_response(&Response::Message("news".into(), "hi".into()), &mut out);
+        assert_eq!(out, b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+    }
+
+    #[test]
+    fn it_answers_errors() {
+        let mut session = Session::default();
+        let input = b"FLUSHALL\r\nGET\r\nSET a b EX 10\r\nHELLO 4\r\nSET a b\r\n";
+        let out = exchange(
+            &mut session,
+            input,
+            &[Response::Unauthorized("denied".into())],
+        );
+        assert_eq!(
+            out,
+            "-ERR unknown command 'flushall'\r\n\
+             -ERR wrong number of arguments for 'get' command\r\n\
+             -ERR syntax error\r\n\
+             -NOPROTO unsupported protocol version\r\n\
+             -NOPERM denied\r\n"
+        );
+    }
+}
@@ -1,9 +1,16 @@
+use crate::cluster::{VectorClock, Versioned};
+use crate::notify::{Event, Operation};
 use crate::protocol::Command;
 use crate::protocol::Response;
+use bytes::BufMut;
 use std::io::Result;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+/// Writer is a struct holder for protocol encoding methods.
+///
+/// The encoding itself is synchronous and does not depend on any runtime, the `write_*` methods
+/// send the encoded frames to any asynchronous stream, see also
+/// `ClientCodec` and `ServerCodec` to use them with `Framed`.
 pub struct Writer {}
 
 impl Writer {
@@ -11,73 +18,362 @@ impl Writer {
     ///
     /// Any errors occured at the moment of writing to the output stream are propagated back to the
     /// caller.
-    pub async fn write_command(buf: &mut BufWriter<TcpStream>, cmd: &Command) -> Result<()> {
+    pub async fn write_command<W: AsyncWrite + Unpin>(stream: &mut W, cmd: &Command) -> Result<()> {
+        let mut buf = vec![];
+        Writer::encode_command(&mut buf, cmd);
+        Writer::send(stream, &buf).await
+    }
+
+    /// Write the given Response to the output stream asynchronously.
+    ///
+    /// Any errors occured at the moment of writing to the output stream are propagated back to the
+    /// caller.
+    pub async fn write_response<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        res: &Response,
+    ) -> Result<()> {
+        let mut buf = vec![];
+        Writer::encode_response(&mut buf, res);
+        Writer::send(stream, &buf).await
+    }
+
+    async fn send<W: AsyncWrite + Unpin>(stream: &mut W, frame: &[u8]) -> Result<()> {
+        stream.write_all(frame).await?;
+        // ensure the buffered stream is flushed into the socket.
+        // if we don't flush explicitly, no data will be written to the socket until
+        // the buffer is full.
+        stream.flush().await
+    }
+
+    /// Append the encoded command to the buffer.
+    pub fn encode_command<B: BufMut>(buf: &mut B, cmd: &Command) {
         match cmd {
             Command::Get(key) => {
-                buf.write_u8(0).await?;
-                buf.write_u16(0).await?;
-                write_string(buf, &key).await?;
+                buf.put_u8(0);
+                buf.put_u16(0);
+                write_string(buf, key);
             }
             Command::Set(key, value) => {
-                buf.write_u8(0).await?;
-                buf.write_u16(1).await?;
-                write_string(buf, &key).await?;
-                write_string(buf, &value).await?;
+                buf.put_u8(0);
+                buf.put_u16(1);
+                write_string(buf, key);
+                write_string(buf, value);
             }
             Command::Clear(key) => {
-                buf.write_u8(0).await?;
-                buf.write_u16(2).await?;
-                write_string(buf, &key).await?;
+                buf.put_u8(0);
+                buf.put_u16(2);
+                write_string(buf, key);
             }
             Command::Ping(key) => {
-                buf.write_u8(0).await?;
-                buf.write_u16(3).await?;
+                buf.put_u8(0);
+                buf.put_u16(3);
                 if key.is_empty() {
                     // Default to `PONG`
-                    write_string(buf, &"PONG".into()).await?;
+                    write_string(buf, "PONG");
                 } else {
-                    write_string(buf, &key).await?;
+                    write_string(buf, key);
                 }
             }
+            Command::Subscribe(channel) => {
+                buf.put_u8(0);
+                buf.put_u16(4);
+                write_string(buf, channel);
+            }
+            Command::Unsubscribe(channel) => {
+                buf.put_u8(0);
+                buf.put_u16(5);
+                write_string(buf, channel);
+            }
+            Command::PSubscribe(pattern) => {
+                buf.put_u8(0);
+                buf.put_u16(6);
+                write_string(buf, pattern);
+            }
+            Command::Publish(channel, message) => {
+                buf.put_u8(0);
+                buf.put_u16(7);
+                write_string(buf, channel);
+                write_string(buf, message);
+            }
+            Command::Watch(prefix) => {
+                buf.put_u8(0);
+                buf.put_u16(8);
+                write_string(buf, prefix);
+            }
+            Command::Unwatch(prefix) => {
+                buf.put_u8(0);
+                buf.put_u16(9);
+                write_string(buf, prefix);
+            }
+            Command::Changes(from, limit) => {
+                buf.put_u8(0);
+                buf.put_u16(10);
+                buf.put_u64(*from);
+                buf.put_u32(*limit);
+            }
+            Command::Snapshot(name) => {
+                buf.put_u8(0);
+                buf.put_u16(11);
+                write_string(buf, name);
+            }
+            Command::Scan(after, prefix, count) => {
+                buf.put_u8(0);
+                buf.put_u16(12);
+                match after {
+                    Some(after) => {
+                        buf.put_u8(1);
+                        write_string(buf, after);
+                    }
+                    None => buf.put_u8(0),
+                }
+                write_string(buf, prefix);
+                buf.put_u32(*count);
+            }
+            Command::MSet(entries) => {
+                buf.put_u8(0);
+                buf.put_u16(13);
+                write_entries(buf, entries);
+            }
+            Command::AddNode(node) => {
+                buf.put_u8(0);
+                buf.put_u16(14);
+                write_string(buf, node);
+            }
+            Command::RemoveNode(node) => {
+                buf.put_u8(0);
+                buf.put_u16(15);
+                write_string(buf, node);
+            }
+            Command::Migrate(start, end) => {
+                buf.put_u8(0);
+                buf.put_u16(16);
+                buf.put_u64(*start);
+                buf.put_u64(*end);
+            }
+            Command::Topology => {
+                buf.put_u8(0);
+                buf.put_u16(17);
+            }
+            Command::ReplicatedGet(key, quorum) => {
+                buf.put_u8(0);
+                buf.put_u16(18);
+                write_string(buf, key);
+                buf.put_u8(*quorum);
+            }
+            Command::ReplicatedSet(key, value, context, quorum) => {
+                buf.put_u8(0);
+                buf.put_u16(19);
+                write_string(buf, key);
+                write_optional_string(buf, value.as_deref());
+                write_clock(buf, context);
+                buf.put_u8(*quorum);
+            }
+            Command::ReplicaRead(key) => {
+                buf.put_u8(0);
+                buf.put_u16(20);
+                write_string(buf, key);
+            }
+            Command::ReplicaWrite(key, versions) => {
+                buf.put_u8(0);
+                buf.put_u16(21);
+                write_string(buf, key);
+                write_versions(buf, versions);
+            }
+            Command::TreeHashes(node, level, positions) => {
+                buf.put_u8(0);
+                buf.put_u16(22);
+                write_string(buf, node);
+                buf.put_u8(*level);
+                buf.put_u32(positions.len() as u32);
+                for position in positions {
+                    buf.put_u32(*position);
+                }
+            }
+            Command::BucketVersions(node, buckets) => {
+                buf.put_u8(0);
+                buf.put_u16(23);
+                write_string(buf, node);
+                buf.put_u32(buckets.len() as u32);
+                for bucket in buckets {
+                    buf.put_u32(*bucket);
+                }
+            }
+            Command::Deadline(deadline, command) => {
+                buf.put_u8(0);
+                buf.put_u16(24);
+                buf.put_u64(*deadline);
+                Writer::encode_command(buf, command);
+            }
+            Command::Auth(user, password) => {
+                buf.put_u8(0);
+                buf.put_u16(25);
+                write_string(buf, user);
+                write_string(buf, password);
+            }
         }
-
-        // ensure the buffered stream is flushed into the socket.
-        // if we don't flush explicitly, no data will be written to the socket until
-        // the buffer is full.
-        buf.flush().await?;
-
-        Ok(())
     }
 
-    /// Write the given Response to the output stream asynchronously.
-    ///
-    /// Any errors occured at the moment of writing to the output stream are propagated back to the
-    /// caller.
-    pub async fn write_response(buf: &mut BufWriter<TcpStream>, res: &Response) -> Result<()> {
+    /// Append the encoded response to the buffer.
+    pub fn encode_response<B: BufMut>(buf: &mut B, res: &Response) {
         match res {
             Response::Ok(msg) => {
-                buf.write_u8(0).await?;
+                buf.put_u8(0);
                 // 0 indicates success status
-                buf.write_u8(0).await?;
-                write_string(buf, &msg).await?;
+                buf.put_u8(0);
+                write_string(buf, msg);
             }
             Response::Error(msg) => {
-                buf.write_u8(0).await?;
+                buf.put_u8(0);
                 // 1 indicates failure status
-                buf.write_u8(1).await?;
-                write_string(buf, &msg).await?;
+                buf.put_u8(1);
+                write_string(buf, msg);
+            }
+            Response::Message(channel, msg) => {
+                buf.put_u8(0);
+                // 2 indicates a message pushed to a subscriber
+                buf.put_u8(2);
+                write_string(buf, channel);
+                write_string(buf, msg);
+            }
+            Response::Event(event) => {
+                buf.put_u8(0);
+                // 3 indicates a change of a watched key
+                buf.put_u8(3);
+                write_event(buf, event);
+            }
+            Response::Lagged(missed) => {
+                buf.put_u8(0);
+                // 4 indicates the number of dropped events
+                buf.put_u8(4);
+                buf.put_u64(*missed);
+            }
+            Response::Changes(changes) => {
+                buf.put_u8(0);
+                // 5 indicates a batch of changes from the change log
+                buf.put_u8(5);
+                buf.put_u32(changes.len() as u32);
+                for change in changes {
+                    buf.put_u64(change.seq);
+                    buf.put_u64(change.timestamp);
+                    write_event(buf, &change.event);
+                }
+            }
+            Response::Entries(entries) => {
+                buf.put_u8(0);
+                // 6 indicates a list of key-value pairs
+                buf.put_u8(6);
+                write_entries(buf, entries);
+            }
+            Response::Moved(node) => {
+                buf.put_u8(0);
+                // 7 indicates a redirect to the node owning the key
+                buf.put_u8(7);
+                write_string(buf, node);
+            }
+            Response::Topology(vnodes, nodes) => {
+                buf.put_u8(0);
+                // 8 indicates the topology of the cluster
+                buf.put_u8(8);
+                buf.put_u32(*vnodes);
+                buf.put_u32(nodes.len() as u32);
+                for node in nodes {
+                    write_string(buf, node);
+                }
+            }
+            Response::Versions(versions) => {
+                buf.put_u8(0);
+                // 9 indicates the versions of a replicated key
+                buf.put_u8(9);
+                write_versions(buf, versions);
+            }
+            Response::Hashes(hashes) => {
+                buf.put_u8(0);
+                // 10 indicates the hashes of Merkle tree nodes
+                buf.put_u8(10);
+                buf.put_u32(hashes.len() as u32);
+                for hash in hashes {
+                    buf.put_u64(*hash);
+                }
+            }
+            Response::DeadlineExceeded => {
+                buf.put_u8(0);
+                // 11 indicates that the deadline of the command passed
+                buf.put_u8(11);
+            }
+            Response::Unauthorized(msg) => {
+                buf.put_u8(0);
+                // 12 indicates that the user is not allowed to run the command
+                buf.put_u8(12);
+                write_string(buf, msg);
             }
         }
-        buf.flush().await?;
-        Ok(())
     }
 }
 
 // Utility method to write a string to an output stream in a standard format, 4 bytes for the
 // length `n`, followd by `n` bytes of the actual string.
-async fn write_string(buf: &mut BufWriter<TcpStream>, data: &String) -> Result<()> {
+fn write_string<B: BufMut>(buf: &mut B, data: &str) {
     let len = data.len() as u32;
-    buf.write_u32(len).await?;
-    buf.write(data.as_bytes()).await?;
-    Ok(())
+    buf.put_u32(len);
+    buf.put_slice(data.as_bytes());
+}
+
+// Utility method to write an event: the key, one byte for the operation, and one byte indicating
+// whether the new value follows.
+fn write_event<B: BufMut>(buf: &mut B, event: &Event) {
+    write_string(buf, &event.key);
+    let operation = match event.operation {
+        Operation::Set => 0,
+        Operation::Unset => 1,
+    };
+    buf.put_u8(operation);
+    match &event.value {
+        Some(value) => {
+            buf.put_u8(1);
+            write_string(buf, value);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+// Utility method to write a list of key-value pairs, 4 bytes for the number of pairs, followed by
+// the pairs themselves.
+fn write_entries<B: BufMut>(buf: &mut B, entries: &[(String, String)]) {
+    buf.put_u32(entries.len() as u32);
+    for (key, value) in entries {
+        write_string(buf, key);
+        write_string(buf, value);
+    }
+}
+
+// Utility method to write a string that might be missing, one byte indicating whether the string
+// follows.
+fn write_optional_string<B: BufMut>(buf: &mut B, data: Option<&str>) {
+    match data {
+        Some(data) => {
+            buf.put_u8(1);
+            write_string(buf, data);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+// Utility method to write a vector clock, 4 bytes for the number of nodes, followed by the address
+// and counter of every node.
+fn write_clock<B: BufMut>(buf: &mut B, clock: &VectorClock) {
+    buf.put_u32(clock.entries().count() as u32);
+    for (node, counter) in clock.entries() {
+        write_string(buf, node);
+        buf.put_u64(*counter);
+    }
+}
+
+// Utility method to write a list of versions, 4 bytes for the number of versions, followed by the
+// clock and the optional value of every version.
+fn write_versions<B: BufMut>(buf: &mut B, versions: &[Versioned]) {
+    buf.put_u32(versions.len() as u32);
+    for version in versions {
+        write_clock(buf, &version.clock);
+        write_optional_string(buf, version.value.as_deref());
+    }
 }
@@ -0,0 +1,255 @@
+//! A TCP proxy injecting faults between clients and a server, used to test the framing, timeout
+//! and reconnection logic against real sockets.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::Instant;
+
+use crate::cluster::membership::Rng;
+use crate::Result;
+
+/// A direction of the traffic going through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// The faults injected by the proxy, the default injects none.
+///
+/// Latency and bandwidth apply to both directions, the other faults only to `direction` if it's
+/// set. The byte counts are per connection and direction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Delay added to every chunk of data.
+    pub latency: Duration,
+    /// Maximum throughput of a connection, in bytes per second.
+    pub bandwidth: Option<u64>,
+    /// New connections are accepted, then closed right away.
+    pub drop_connections: bool,
+    /// Data is silently discarded, while the connections are kept open.
+    pub blackhole: bool,
+    /// The connection is closed after this many bytes, cutting frames in the middle.
+    pub truncate_after: Option<usize>,
+    /// The connection is reset after this many bytes.
+    pub reset_after: Option<usize>,
+    /// Probability of every byte to get one of its bits flipped.
+    pub corruption: f64,
+    pub direction: Option<Direction>,
+}
+
+impl Faults {
+    fn applies_to(&self, direction: Direction) -> bool {
+        self.direction.is_none_or(|only| only == direction)
+    }
+}
+
+/// A proxy listening on a local port, forwarding the connections to the upstream server.
+///
+/// The faults can be changed at any time, or scheduled ahead. Corrupted bytes are picked by a
+/// generator seeded at creation. The proxy stops accepting connections when dropped.
+pub struct FaultProxy {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    _stop: oneshot::Sender<()>,
+}
+
+struct Shared {
+    upstream: String,
+    faults: Mutex<Faults>,
+    rng: Mutex<Rng>,
+    /// Notifies the open connections to reset.
+    resets: broadcast::Sender<()>,
+}
+
+impl Shared {
+    fn faults(&self) -> Faults {
+        self.faults.lock().unwrap().clone()
+    }
+}
+
+/// Why the forwarding of a connection stopped.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Closed,
+    Reset,
+}
+
+impl FaultProxy {
+    /// Starts a proxy forwarding to the upstream address, on a random local port.
+    pub async fn start<S: Into<String>>(upstream: S, seed: u64) -> Result<FaultProxy> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (resets, _) = broadcast::channel(1);
+        let shared = Arc::new(Shared {
+            upstream: upstream.into(),
+            faults: Mutex::new(Faults::default()),
+            rng: Mutex::new(Rng::scrambled(seed)),
+            resets,
+        });
+        let (stop, mut stopped) = oneshot::channel();
+        let accepting = shared.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = &mut stopped => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(_) => return,
+                    },
+                };
+                if accepting.faults().drop_connections {
+                    continue;
+                }
+                tokio::spawn(forward(stream, accepting.clone()));
+            }
+        });
+        Ok(FaultProxy {
+            addr,
+            shared,
+            _stop: stop,
+        })
+    }
+
+    /// The address the clients should connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.shared.faults()
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        *self.shared.faults.lock().unwrap() = faults;
+    }
+
+    /// Applies every set of faults once its delay, counted from now, elapsed.
+    pub fn schedule(&self, steps: Vec<(Duration, Faults)>) {
+        let shared = self.shared.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for (delay, faults) in steps {
+                tokio::time::sleep_until(start + delay).await;
+                *shared.faults.lock().unwrap() = faults;
+            }
+        });
+    }
+
+    /// Resets all of the connections currently open.
+    pub fn reset_connections(&self) {
+        let _ = self.shared.resets.send(());
+    }
+}
+
+/// Forwards the traffic between the client and the upstream server, until either side closes its
+/// connection or a fault ends it.
+async fn forward(mut client: TcpStream, shared: Arc<Shared>) {
+    let mut server = match TcpStream::connect(shared.upstream.as_str()).await {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+    let mut resets = shared.resets.subscribe();
+    let outcome = {
+        let (client_read, client_write) = client.split();
+        let (server_read, server_write) = server.split();
+        tokio::select! {
+            outcome = pump(client_read, server_write, Direction::ToServer, &shared) => outcome,
+            outcome = pump(server_read, client_write, Direction::ToClient, &shared) => outcome,
+            _ = resets.recv() => Outcome::Reset,
+        }
+    };
+    if outcome == Outcome::Reset {
+        // Closing a socket with a zero linger time sends a RST instead of a FIN.
+        let _ = client.set_linger(Some(Duration::from_secs(0)));
+        let _ = server.set_linger(Some(Duration::from_secs(0)));
+    }
+}
+
+/// Forwards the data read from one side to the other, delaying every chunk by the latency.
+async fn pump(
+    mut from: ReadHalf<'_>,
+    mut to: WriteHalf<'_>,
+    direction: Direction,
+    shared: &Shared,
+) -> Outcome {
+    let mut queue: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+    let mut buf = vec![0; 16 * 1024];
+    let mut forwarded = 0;
+    let mut eof = false;
+    loop {
+        if eof && queue.is_empty() {
+            let _ = to.shutdown().await;
+            return Outcome::Closed;
+        }
+        let due = queue.front().map(|(at, _)| *at);
+        tokio::select! {
+            read = from.read(&mut buf), if !eof => match read {
+                Ok(0) | Err(_) => eof = true,
+                Ok(n) => {
+                    let latency = shared.faults().latency;
+                    queue.push_back((Instant::now() + latency, buf[..n].to_vec()));
+                }
+            },
+            _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                let (_, chunk) = queue.pop_front().unwrap();
+                if let Some(outcome) = deliver(&mut to, chunk, direction, shared, &mut forwarded).await {
+                    return outcome;
+                }
+            }
+        }
+    }
+}
+
+/// Writes the chunk after applying the faults, returns the outcome if the connection must end.
+async fn deliver(
+    to: &mut WriteHalf<'_>,
+    mut chunk: Vec<u8>,
+    direction: Direction,
+    shared: &Shared,
+    forwarded: &mut usize,
+) -> Option<Outcome> {
+    let faults = shared.faults();
+    let mut outcome = None;
+    if faults.applies_to(direction) {
+        if faults.blackhole {
+            return None;
+        }
+        let limits = [
+            (faults.truncate_after, Outcome::Closed),
+            (faults.reset_after, Outcome::Reset),
+        ];
+        for (limit, end) in limits {
+            match limit {
+                Some(limit) if *forwarded + chunk.len() >= limit && outcome.is_none() => {
+                    chunk.truncate(limit.saturating_sub(*forwarded));
+                    outcome = Some(end);
+                }
+                _ => {}
+            }
+        }
+        if faults.corruption > 0.0 {
+            let mut rng = shared.rng.lock().unwrap();
+            for byte in chunk.iter_mut() {
+                if rng.next_f64() < faults.corruption {
+                    *byte ^= 1 << (rng.next_u64() % 8);
+                }
+            }
+        }
+    }
+    if let Some(bandwidth) = faults.bandwidth.filter(|bandwidth| *bandwidth > 0) {
+        let secs = chunk.len() as f64 / bandwidth as f64;
+        tokio::time::sleep(Duration::from_secs_f64(secs)).await;
+    }
+    if to.write_all(&chunk).await.is_err() {
+        return Some(Outcome::Closed);
+    }
+    *forwarded += chunk.len();
+    outcome
+}
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// Default number of messages that can be queued for a single subscriber before new messages
+/// start getting dropped.
+pub const DEFAULT_SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// A message published on a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The channel the message was published on.
+    pub channel: String,
+    /// The actual content of the message.
+    pub payload: String,
+}
+
+/// The server-wide message broker, shared between all of the executors.
+///
+/// Every subscribed connection gets a bounded queue of messages, publishing to a channel only
+/// tries to push the message to each of the matching queues, if a queue is full (the subscriber is
+/// too slow to consume its messages) the message is dropped for that subscriber, this way a
+/// publisher is never blocked waiting on a subscriber.
+pub struct Broker {
+    registry: Mutex<Registry>,
+
+    /// Maximum number of pending messages per subscriber.
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    /// Subscribers of each channel, indexed by their id.
+    channels: HashMap<String, HashMap<u64, Sender<Message>>>,
+    /// Subscribers of each glob pattern, indexed by their id.
+    patterns: HashMap<String, HashMap<u64, Sender<Message>>>,
+}
+
+impl Broker {
+    pub fn new(capacity: usize) -> Self {
+        Broker {
+            registry: Mutex::new(Registry::default()),
+            capacity,
+        }
+    }
+
+    /// Creates a new subscription with no channels, the subscription is removed from the broker
+    /// once it's dropped.
+    pub fn subscription(self: &Arc<Self>) -> Subscription {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        Subscription {
+            id,
+            broker: Arc::clone(self),
+            sender,
+            receiver,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    /// Publish the payload on the given channel, and return the number of subscribers that
+    /// received the message.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let message = Message {
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        };
+        // A subscriber can match the channel multiple times (by name and through patterns), but it
+        // should only receive the message once.
+        let mut delivered = HashSet::new();
+        let by_name = registry.channels.get(channel).into_iter();
+        let by_pattern = registry
+            .patterns
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, channel))
+            .map(|(_, subscribers)| subscribers);
+        for subscribers in by_name.chain(by_pattern) {
+            for (id, sender) in subscribers {
+                if delivered.contains(id) {
+                    continue;
+                }
+                match sender.try_send(message.clone()) {
+                    Ok(_) => {
+                        delivered.insert(*id);
+                    }
+                    // Slow subscribers miss the message, and closed ones will soon unregister.
+                    Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {}
+                }
+            }
+        }
+        delivered.len()
+    }
+
+    fn register(&self, index: Index, name: &str, id: u64, sender: Sender<Message>) {
+        let mut registry = self.registry.lock().unwrap();
+        registry
+            .index(index)
+            .entry(name.to_string())
+            .or_default()
+            .insert(id, sender);
+    }
+
+    fn unregister(&self, index: Index, name: &str, id: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        let subscribers = registry.index(index);
+        if let Some(ids) = subscribers.get_mut(name) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                subscribers.remove(name);
+            }
+        }
+    }
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Broker::new(DEFAULT_SUBSCRIBER_CAPACITY)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Index {
+    Channels,
+    Patterns,
+}
+
+impl Registry {
+    fn index(&mut self, index: Index) -> &mut HashMap<String, HashMap<u64, Sender<Message>>> {
+        match index {
+            Index::Channels => &mut self.channels,
+            Index::Patterns => &mut self.patterns,
+        }
+    }
+}
+
+/// The set of channels and patterns a single connection is subscribed to.
+pub struct Subscription {
+    id: u64,
+    broker: Arc<Broker>,
+    // kept around to register the subscription on new channels.
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscription {
+    pub fn subscribe(&mut self, channel: String) {
+        self.broker
+            .register(Index::Channels, &channel, self.id, self.sender.clone());
+        self.channels.insert(channel);
+    }
+
+    pub fn psubscribe(&mut self, pattern: String) {
+        self.broker
+            .register(Index::Patterns, &pattern, self.id, self.sender.clone());
+        self.patterns.insert(pattern);
+    }
+
+    /// Remove the channel or pattern with the given name from the subscription.
+    pub fn unsubscribe(&mut self, name: &str) {
+        if self.channels.remove(name) {
+            self.broker.unregister(Index::Channels, name, self.id);
+        }
+        if self.patterns.remove(name) {
+            self.broker.unregister(Index::Patterns, name, self.id);
+        }
+    }
+
+    /// Returns true if the subscription does not listen to any channel or pattern.
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Wait for the next message published on one of the subscribed channels.
+    ///
+    /// This method is cancellation safe.
+    pub async fn recv(&mut self) -> Message {
+        // The subscription holds a sender, so the channel can never be closed.
+        self.receiver.recv().await.unwrap()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        for channel in self.channels.iter() {
+            self.broker.unregister(Index::Channels, channel, self.id);
+        }
+        for pattern in self.patterns.iter() {
+            self.broker.unregister(Index::Patterns, pattern, self.id);
+        }
+    }
+}
+
+/// Matches the text against a glob-style pattern.
+///
+/// Supported wildcards are `*` (any sequence of characters), `?` (exactly one character) and
+/// `[...]` (one character from the set, `[^...]` to negate it), a `\` escapes the next character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_from(&pattern, &text)
+}
+
+fn glob_match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position to backtrack to, in the pattern and the text, when the last `*` seen should match
+    // one more character.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == text[t] {
+                    Some(2)
+                } else {
+                    None
+                }
+            }
+            Some(c) if *c == text[t] => Some(1),
+            _ => None,
+        };
+        match step {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches a character against the class at the start of the pattern, returning the length of the
+/// class in the pattern if it matches.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != ']' {
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= pattern[i] <= c && c <= pattern[i + 2];
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        // Unterminated class, treat the `[` as a literal.
+        return if c == '[' { Some(1) } else { None };
+    }
+    if matched != negate {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("news.*", "news.sport"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(!glob_match("a*b", "aXXc"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let broker = Arc::new(Broker::default());
+        let mut by_name = broker.subscription();
+        by_name.subscribe(String::from("news.sport"));
+        let mut by_pattern = broker.subscription();
+        by_pattern.psubscribe(String::from("news.*"));
+        by_pattern.subscribe(String::from("news.sport"));
+
+        assert_eq!(broker.publish("news.sport", "goal"), 2);
+        assert_eq!(broker.publish("news.tech", "rust"), 1);
+        assert_eq!(broker.publish("weather", "sunny"), 0);
+
+        assert_eq!(by_name.recv().await.payload, "goal");
+        assert_eq!(by_pattern.recv().await.payload, "goal");
+        assert_eq!(by_pattern.recv().await.payload, "rust");
+
+        drop(by_pattern);
+        by_name.unsubscribe("news.sport");
+        assert!(by_name.is_empty());
+        assert_eq!(broker.publish("news.sport", "goal"), 0);
+    }
+
+    #[test]
+    fn test_slow_subscribers_do_not_block() {
+        let broker = Arc::new(Broker::new(1));
+        let mut subscription = broker.subscription();
+        subscription.subscribe(String::from("a"));
+        assert_eq!(broker.publish("a", "first"), 1);
+        // the queue is full, the message is dropped.
+        assert_eq!(broker.publish("a", "second"), 0);
+    }
+}
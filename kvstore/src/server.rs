@@ -1,37 +1,517 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::{
-    executor::Executor,
-    storage::{memory::InMemStorage, Storage},
+    acl::Acl,
+    cdc::{self, ChangeLog, ChangeLogOptions},
+    cluster::{anti_entropy, membership::UdpTransport, Cluster, ClusterOptions, Membership},
+    env::{Connection, Env, Listener},
+    executor::{Context, Executor, StorageEngine},
+    metrics::{ErrorKind, Metrics},
+    notify::Notifier,
+    protocol::{http, resp},
+    pubsub::Broker,
+    snapshot,
+    storage::memory::InMemStorage,
     ConnectionHandler, Result, StorageOptions,
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default maximum length of the body of the HTTP requests, see `ServerOptions::http_max_body`.
+pub const DEFAULT_HTTP_MAX_BODY: usize = 4 << 20;
+
+/// Groups the options used to tweak the features enabled on the server.
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Enables the change data capture log, which records every change applied to the store.
+    pub changelog: Option<ChangeLogOptions>,
+
+    /// Enables the `SNAPSHOT` command, snapshots are written to this directory.
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Snapshot file to load into the store before accepting connections.
+    pub restore_from: Option<PathBuf>,
+
+    /// Runs the server as a node of a sharded cluster.
+    pub cluster: Option<ClusterOptions>,
+
+    /// Also accepts connections on a Unix domain socket, e.g. for the clients running on the
+    /// same host.
+    pub unix_socket: Option<UnixSocketOptions>,
+
+    /// Also accepts connections speaking the Redis protocol on this endpoint, e.g. for
+    /// `redis-cli`, see `protocol::resp` for the supported commands.
+    pub resp: Option<Endpoint>,
+
+    /// Also accepts HTTP requests on this endpoint, e.g. for `curl`, see `protocol::http` for the
+    /// REST endpoints.
+    pub http: Option<Endpoint>,
+
+    /// Maximum length of the body of the HTTP requests, `DEFAULT_HTTP_MAX_BODY` if not set. Every
+    /// connection may buffer a body of this size, larger ones are refused.
+    pub http_max_body: Option<usize>,
+
+    /// Serves the metrics of the server in the Prometheus text format at `/metrics` on this
+    /// endpoint.
+    pub metrics: Option<Endpoint>,
+
+    /// Requires the clients to authenticate, and restricts the commands and keys of each user.
+    pub acl: Option<Acl>,
+
+    /// Encrypts the connections accepted by the server's TCP listeners with TLS, the ones of the
+    /// Unix domain socket are not.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsAcceptor>,
+
+    /// Reloads the certificates of `tls` when the process receives a SIGHUP, on Unix. The failed
+    /// reloads are reported on stderr and counted in the metrics, the current certificates are
+    /// kept.
+    #[cfg(feature = "tls")]
+    pub reload_tls_on_hangup: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixSocketOptions {
+    /// Path of the socket, a file left there by a previous run is replaced, and the file is removed
+    /// when the server stops.
+    pub path: PathBuf,
+    /// Permissions of the socket file, only the users allowed to write to it can connect.
+    pub mode: u32,
+}
+
+impl UnixSocketOptions {
+    /// Options for a socket at the given path, which only its owner and group can connect to.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        UnixSocketOptions {
+            path: path.into(),
+            mode: 0o660,
+        }
+    }
+}
+
+/// Where a server accepts the connections of one of its protocols.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    /// An address the server binds through its network.
+    Addr(SocketAddr),
+    /// A listener bound beforehand, e.g. on port 0 to learn the port it was given without racing
+    /// another process for it. Only the first server started with it takes it over.
+    Listener(Arc<Mutex<Option<std::net::TcpListener>>>),
+}
+
+impl Endpoint {
+    pub fn listener(listener: std::net::TcpListener) -> Self {
+        Endpoint::Listener(Arc::new(Mutex::new(Some(listener))))
+    }
+
+    /// The address of the endpoint, which the server accepts the connections on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Endpoint::Addr(addr) => Ok(*addr),
+            Endpoint::Listener(listener) => match &*listener.lock().unwrap() {
+                Some(listener) => listener.local_addr(),
+                None => Err(taken_listener()),
+            },
+        }
+    }
+
+    async fn bind(&self, env: &Env) -> io::Result<Box<dyn Listener>> {
+        match self {
+            Endpoint::Addr(addr) => env.network.bind(&addr.to_string()).await,
+            Endpoint::Listener(listener) => {
+                let listener = listener.lock().unwrap().take().ok_or_else(taken_listener)?;
+                listener.set_nonblocking(true)?;
+                Ok(Box::new(TcpListener::from_std(listener)?))
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Addr(addr)
+    }
+}
+
+fn taken_listener() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "The listener was taken over by another server",
+    )
+}
+
+/// The protocol spoken on the connections of a listener.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Native,
+    Resp,
+    Http,
+    Metrics,
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Native => "native",
+            Protocol::Resp => "resp",
+            Protocol::Http => "http",
+            Protocol::Metrics => "metrics",
+        }
+    }
+}
 
 async fn process(
-    stream: TcpStream,
-    store: Arc<Mutex<Box<dyn Storage + Send + Sync>>>,
+    stream: Connection,
+    ctx: Context,
+    handshake: Handshake,
+    protocol: Protocol,
 ) -> Result<()> {
-    let handler = ConnectionHandler::new(stream);
-    let mut executor = Executor::new(handler, store);
-    return executor.run().await;
+    let metrics = ctx.metrics.clone();
+    let stream = Box::new(metrics.meter(protocol.name(), stream));
+    let stream = match handshake.run(stream).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            metrics.error(ErrorKind::Handshake);
+            return Err(err.into());
+        }
+    };
+    let handler = match protocol {
+        Protocol::Native => ConnectionHandler::new(stream),
+        Protocol::Resp => ConnectionHandler::gateway(stream, Box::new(resp::Session::default())),
+        Protocol::Http => {
+            let session = http::Session::new(ctx.http_max_body);
+            ConnectionHandler::gateway(stream, Box::new(session))
+        }
+        Protocol::Metrics => {
+            ConnectionHandler::gateway(stream, Box::new(http::Session::metrics(ctx.clone())))
+        }
+    };
+    let mut executor = Executor::new(handler, ctx);
+    let result = executor.run().await;
+    if result.is_err() {
+        metrics.error(ErrorKind::Connection);
+    }
+    result
+}
+
+pub async fn run(listener: TcpListener) -> io::Result<()> {
+    run_with_options(listener, ServerOptions::default()).await
 }
 
-pub async fn run(listener: TcpListener) {
-    let store: Arc<Mutex<Box<dyn Storage + Send + Sync>>> =
-        Arc::new(Mutex::new(Box::new(InMemStorage::new())));
+pub async fn run_with_options(listener: TcpListener, options: ServerOptions) -> io::Result<()> {
+    run_with_env(Env::system(), Box::new(listener), options).await
+}
+
+/// Runs the server in the given environment, serving the connections accepted by the listener.
+///
+/// All of the listeners of the options are bound before serving any connection, and an error is
+/// returned if one of them can't be, or the server can't be set up. The gossip membership
+/// protocol, when enabled, always runs over UDP.
+pub async fn run_with_env(
+    env: Env,
+    listener: Box<dyn Listener>,
+    options: ServerOptions,
+) -> io::Result<()> {
+    let store: StorageEngine = Arc::new(Mutex::new(Box::new(InMemStorage::new())));
+    serve(env, listener, options, store).await
+}
 
+/// Runs the server on top of an existing store, which outlives the server, e.g. to simulate a
+/// restart that keeps the data.
+pub(crate) async fn serve(
+    env: Env,
+    listener: Box<dyn Listener>,
+    options: ServerOptions,
+    store: StorageEngine,
+) -> io::Result<()> {
     // Calling open in this case is useless as the implementation does not
     // use open for anything, but it's just to showcase where you might want to
     // alter the code to use open in order to make it work with your own storage implementation.
     let _ = store.lock().unwrap().open(String::new(), StorageOptions {});
 
+    if let Some(path) = &options.restore_from {
+        let mut guard = store.lock().unwrap();
+        snapshot::restore(path, guard.as_mut())
+            .map_err(|err| setup_error("Unable to restore the snapshot", err))?;
+    }
+
+    let changelog = match options.changelog {
+        Some(options) => Some(
+            ChangeLog::open(options)
+                .map_err(|err| setup_error("Unable to open the change log", err))?,
+        ),
+        None => None,
+    };
+
+    let anti_entropy = match &options.cluster {
+        Some(options) => Some(Arc::new(
+            anti_entropy::AntiEntropy::new(options.anti_entropy.clone())
+                .map_err(|err| setup_error("Invalid anti-entropy options", err))?,
+        )),
+        None => None,
+    };
+
+    // Every listener is bound before anything is served, so that a server that can't listen on
+    // all of its addresses fails to start instead of running partially.
+    let mut listeners = vec![];
+    if let Some(unix_socket) = &options.unix_socket {
+        let listener = bind_unix(unix_socket)
+            .map_err(|err| setup_error("Unable to bind the Unix domain socket", err))?;
+        listeners.push((listener, Protocol::Native, false));
+    }
+    let endpoints = [
+        (&options.resp, Protocol::Resp),
+        (&options.http, Protocol::Http),
+        (&options.metrics, Protocol::Metrics),
+    ];
+    for (endpoint, protocol) in endpoints {
+        if let Some(endpoint) = endpoint {
+            let listener = endpoint.bind(&env).await.map_err(|err| {
+                let msg = format!("Unable to bind the {} listener", protocol.name());
+                setup_error(&msg, err)
+            })?;
+            listeners.push((listener, protocol, true));
+        }
+    }
+
+    let mut membership = None;
+    if let Some(cluster) = &options.cluster {
+        if let Some(membership_options) = &cluster.membership {
+            let port = cluster.node.rsplit(':').next().unwrap_or_default();
+            let (transport, incoming) = UdpTransport::bind(&format!("0.0.0.0:{}", port))
+                .await
+                .map_err(|err| setup_error("Unable to bind the membership socket", err))?;
+            membership = Some(Arc::new(Membership::start(
+                cluster.node.clone(),
+                &cluster.peers,
+                transport,
+                incoming,
+                membership_options.clone(),
+            )));
+        }
+    }
+    let metrics = Arc::new(Metrics::default());
+    metrics.load(store.lock().unwrap().as_ref());
+    let ctx = Context {
+        store,
+        broker: Arc::new(Broker::default()),
+        notifier: Arc::new(Notifier::default()),
+        changelog: changelog.map(Arc::new),
+        snapshot_dir: options.snapshot_dir,
+        http_max_body: options.http_max_body.unwrap_or(DEFAULT_HTTP_MAX_BODY),
+        cluster: options
+            .cluster
+            .map(|options| Arc::new(Cluster::new(options))),
+        anti_entropy,
+        membership,
+        acl: options.acl.map(Arc::new),
+        metrics,
+        env,
+    };
+    let handshake = Handshake {
+        #[cfg(feature = "tls")]
+        tls: options.tls,
+    };
+    // The tasks stop along with the main listener, e.g. when a simulated host crashes.
+    let mut tasks = Tasks::default();
+    for (listener, protocol, tls) in listeners {
+        // The connections of the Unix domain socket are local, they are not encrypted.
+        let handshake = match tls {
+            true => handshake.clone(),
+            false => Handshake::default(),
+        };
+        tasks.spawn(accept(listener, ctx.clone(), handshake, protocol));
+    }
+    if let Some(interval) = ctx
+        .anti_entropy
+        .as_ref()
+        .and_then(|anti_entropy| anti_entropy.options().interval)
+    {
+        tasks.spawn(anti_entropy::run(ctx.clone(), interval));
+    }
+    tasks.spawn(cdc::expire(ctx.clone()));
+    #[cfg(all(feature = "tls", unix))]
+    if let (Some(acceptor), true) = (&handshake.tls, options.reload_tls_on_hangup) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let hangups = signal(SignalKind::hangup())
+            .map_err(|err| setup_error("Unable to listen to SIGHUP", err))?;
+        let metrics = ctx.metrics.clone();
+        tasks.spawn(reload_on_hangup(acceptor.clone(), hangups, metrics));
+    }
+
+    accept(listener, ctx, handshake, Protocol::Native).await;
+    Ok(())
+}
+
+/// Reloads the certificates of the acceptor on every SIGHUP.
+#[cfg(all(feature = "tls", unix))]
+async fn reload_on_hangup(
+    acceptor: crate::tls::TlsAcceptor,
+    mut hangups: tokio::signal::unix::Signal,
+    metrics: Arc<Metrics>,
+) {
+    while hangups.recv().await.is_some() {
+        if let Err(err) = acceptor.reload() {
+            eprintln!("Unable to reload the TLS certificates: {}", err);
+            metrics.error(ErrorKind::TlsReload);
+        }
+    }
+}
+
+/// The background tasks of a server, aborted when it's dropped.
+#[derive(Default)]
+struct Tasks(Vec<tokio::task::JoinHandle<()>>);
+
+impl Tasks {
+    fn spawn(&mut self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        self.0.push(tokio::spawn(task));
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Wraps an error that prevents the server from starting with what was being done.
+fn setup_error(msg: &str, err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("{}: {}", msg, err))
+}
+
+/// What's done on the accepted connections before serving them.
+#[derive(Clone, Default)]
+struct Handshake {
+    #[cfg(feature = "tls")]
+    tls: Option<crate::tls::TlsAcceptor>,
+}
+
+impl Handshake {
+    async fn run(&self, stream: Connection) -> io::Result<Connection> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.accept(stream).await;
+        }
+        Ok(stream)
+    }
+}
+
+/// Serves the connections accepted by the listener, until the listener is closed for good.
+///
+/// Other accept errors, e.g. running out of file descriptors, are transient: the listener is
+/// retried after a delay growing up to `MAX_ACCEPT_BACKOFF`.
+async fn accept(
+    mut listener: Box<dyn Listener>,
+    ctx: Context,
+    handshake: Handshake,
+    protocol: Protocol,
+) {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        let store = Arc::clone(&store);
-        tokio::spawn(async move {
-            if let Err(msg) = process(stream, store).await {
-                println!("An error happened while processing the request: {:?}", msg);
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => return,
+            Err(_) => {
+                ctx.metrics.error(ErrorKind::Accept);
+                ctx.env.clock.sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
             }
+        };
+        backoff = MIN_ACCEPT_BACKOFF;
+        let ctx = ctx.clone();
+        let handshake = handshake.clone();
+        // The errors of the connections are counted in the metrics.
+        tokio::spawn(async move {
+            let _ = process(stream, ctx, handshake, protocol).await;
         });
     }
 }
+
+/// Binds a Unix domain socket with the permissions of the options.
+///
+/// The socket is bound in a private directory next to its path, and moved to its path once its
+/// permissions are set, so that no other user can connect to it in between.
+#[cfg(unix)]
+fn bind_unix(options: &UnixSocketOptions) -> io::Result<Box<dyn Listener>> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    remove_stale_socket(&options.path)?;
+    let name = options.path.file_name().ok_or_else(|| {
+        let msg = format!("{} is not a file path", options.path.display());
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+    let private = options
+        .path
+        .with_file_name(format!(".{}.bind", name.to_string_lossy()));
+    // Left over if a previous run crashed while binding.
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bind = || {
+        let path = private.join(name);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(options.mode))?;
+        std::fs::rename(&path, &options.path)?;
+        Ok::<_, io::Error>(listener)
+    };
+    let listener = bind();
+    let _ = std::fs::remove_dir_all(&private);
+    Ok(Box::new(UnixSocketListener {
+        listener: listener?,
+        path: options.path.clone(),
+    }))
+}
+
+/// A Unix domain socket which removes its file once the server stops listening on it.
+#[cfg(unix)]
+struct UnixSocketListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    fn accept(&mut self) -> crate::env::BoxFuture<'_, io::Result<(Connection, String)>> {
+        Listener::accept(&mut self.listener)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &UnixSocketOptions) -> io::Result<Box<dyn Listener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Removes the socket file left by a previous run, binding fails if the path exists.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
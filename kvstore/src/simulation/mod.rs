@@ -0,0 +1,572 @@
+//! Deterministic simulation of a cluster, used to find the bugs that only show up under rare
+//! interleavings and faults.
+//!
+//! The nodes, the clients and the fault injector all run on a single thread with a paused clock,
+//! talking over an in-memory network. Every random choice — the workload, the network delays, the
+//! drops, the partitions and the crashes — is derived from a single seed, so a failing run can be
+//! replayed exactly from its seed.
+//!
+//! The simulator needs tokio's `test-util` feature, it's only built for the tests of this crate,
+//! or when the `simulation` feature is enabled.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+mod network;
+pub use network::{NetworkFaults, SimHost, SimNet};
+
+use crate::client;
+use crate::cluster::membership::Rng;
+use crate::cluster::replication;
+use crate::cluster::{
+    self, Causality, Cluster, ClusterOptions, ReplicationOptions, VectorClock, Versioned,
+};
+use crate::env::{self, BoxFuture, Clock, Env, Random};
+use crate::executor::StorageEngine;
+use crate::server::{self, ServerOptions};
+use crate::storage::memory::InMemStorage;
+use crate::{Response, Storage};
+
+/// Environment variable replaying a single seed in `explore`.
+pub const SEED_VAR: &str = "KVSTORE_SIM_SEED";
+
+/// Groups the options of a simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimOptions {
+    pub nodes: usize,
+    pub clients: usize,
+    /// Number of operations issued by every client, one at a time.
+    pub operations: usize,
+    /// Number of distinct keys the clients read and write, fewer keys mean more conflicts.
+    pub keys: usize,
+    pub replication: ReplicationOptions,
+    pub network: NetworkFaults,
+    /// Enables network partitions, splitting the nodes in two random sides for a while.
+    pub partitions: bool,
+    /// Enables node crashes. A crashed node loses its connections and in-flight messages, and
+    /// restarts after a while with the data it stored, as if it was persisted.
+    pub crashes: bool,
+    /// Average time between two faults, only one partition or crash happens at a time.
+    pub fault_interval: Duration,
+    /// Time given to the cluster to repair itself once the workload is done and the faults are
+    /// healed, before its final state is captured.
+    pub settle_time: Duration,
+}
+
+impl Default for SimOptions {
+    fn default() -> Self {
+        SimOptions {
+            nodes: 3,
+            clients: 3,
+            operations: 30,
+            keys: 4,
+            replication: ReplicationOptions::default(),
+            network: NetworkFaults::default(),
+            partitions: false,
+            crashes: false,
+            fault_interval: Duration::from_secs(1),
+            settle_time: Duration::from_secs(10),
+        }
+    }
+}
+
+/// An operation of the workload, as observed by the client which issued it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub client: usize,
+    pub key: String,
+    pub kind: OperationKind,
+    /// The times the operation was issued and completed at, since the start of the simulation.
+    pub invoked: Duration,
+    pub completed: Duration,
+    /// The versions read, or the version written, `Err` if the operation failed or timed out, in
+    /// which case a write might still have been applied.
+    pub outcome: Result<Vec<Versioned>, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperationKind {
+    Read,
+    /// Writes the value, or removes the key if it's `None`, with the context of the last read.
+    Write(Option<String>, VectorClock),
+}
+
+/// The versions of the replicated keys stored by a node.
+pub type KeyVersions = BTreeMap<String, Vec<Versioned>>;
+
+/// The outcome of a simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub seed: u64,
+    /// The operations, in the order they completed.
+    pub history: Vec<Operation>,
+    /// The faults injected, along with the time they were injected at.
+    pub faults: Vec<(Duration, String)>,
+    /// The versions of every key stored by every node once the cluster settled, keyed by node.
+    pub state: BTreeMap<String, KeyVersions>,
+    /// The replicas of every key.
+    pub replicas: BTreeMap<String, Vec<String>>,
+}
+
+impl Report {
+    /// Checks that the replicas of every key converged to the same versions.
+    pub fn check_convergence(&self) -> Result<(), String> {
+        for (key, replicas) in self.replicas.iter() {
+            let versions: Vec<Option<&Vec<Versioned>>> = replicas
+                .iter()
+                .map(|node| self.state.get(node).and_then(|state| state.get(key)))
+                .collect();
+            if versions.iter().any(|v| *v != versions[0]) {
+                return Err(format!(
+                    "The replicas of {} diverged: {:?}",
+                    key,
+                    replicas.iter().zip(versions.iter()).collect::<Vec<_>>()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every acknowledged write is either part of the final versions of its key, or
+    /// superseded by one of them.
+    pub fn check_durability(&self) -> Result<(), String> {
+        for operation in self.history.iter() {
+            let written = match (&operation.kind, &operation.outcome) {
+                (OperationKind::Write(..), Ok(written)) => written,
+                _ => continue,
+            };
+            for version in written {
+                let survived = self.replicas[&operation.key].iter().any(|node| {
+                    let versions = self
+                        .state
+                        .get(node)
+                        .and_then(|state| state.get(&operation.key));
+                    versions.is_some_and(|versions| {
+                        versions.iter().any(|current| {
+                            matches!(
+                                current.clock.compare(&version.clock),
+                                Causality::After | Causality::Equal
+                            )
+                        })
+                    })
+                });
+                if !survived {
+                    return Err(format!(
+                        "The acknowledged write {:?} of {} was lost",
+                        version, operation.key
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the simulation for the given seed.
+pub fn run(seed: u64, options: &SimOptions) -> Report {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .expect("Unable to build the simulation runtime");
+    runtime.block_on(simulate(seed, options.clone()))
+}
+
+/// Runs the simulation for every seed of the range, and checks its report. The failing seed is
+/// reported, and can be replayed alone by setting `KVSTORE_SIM_SEED`.
+pub fn explore<F>(seeds: Range<u64>, options: &SimOptions, check: F)
+where
+    F: Fn(&Report) -> Result<(), String>,
+{
+    let seeds: Vec<u64> = match std::env::var(SEED_VAR) {
+        Ok(seed) => vec![seed.parse().expect("Invalid simulation seed")],
+        Err(_) => seeds.collect(),
+    };
+    for seed in seeds {
+        let report = run(seed, options);
+        if let Err(err) = check(&report) {
+            panic!(
+                "Simulation failed with seed {}: {}\nFaults: {:?}\nReplay it with {}={}",
+                seed, err, report.faults, SEED_VAR, seed
+            );
+        }
+    }
+}
+
+/// A clock running on tokio's paused clock, with a wall clock starting at a fixed date.
+struct SimClock {
+    start: Instant,
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000) + (Instant::now() - self.start)
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+struct SeededRandom(Mutex<Rng>);
+
+impl Random for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        self.0.lock().unwrap().next_u64()
+    }
+}
+
+struct Sim {
+    options: SimOptions,
+    net: SimNet,
+    clock: Arc<SimClock>,
+    rng: Rng,
+    nodes: Vec<String>,
+    /// The stores of the nodes, kept across crashes.
+    stores: Vec<StorageEngine>,
+    history: Arc<Mutex<Vec<Operation>>>,
+    faults: Vec<(Duration, String)>,
+}
+
+impl Sim {
+    fn elapsed(&self) -> Duration {
+        self.clock.now() - self.clock.start
+    }
+
+    fn env(&mut self, host: &str) -> Env {
+        Env {
+            network: Arc::new(self.net.host(host)),
+            clock: self.clock.clone(),
+            random: Arc::new(SeededRandom(Mutex::new(Rng::new(self.rng.next_u64())))),
+        }
+    }
+
+    fn cluster_options(&self, node: usize) -> ClusterOptions {
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|peer| **peer != self.nodes[node])
+            .cloned()
+            .collect();
+        let mut options = ClusterOptions::new(self.nodes[node].clone(), peers);
+        options.replication = self.options.replication;
+        options.anti_entropy.interval = Some(Duration::from_millis(200));
+        options
+    }
+
+    async fn start_node(&mut self, node: usize) {
+        let env = self.env(&self.nodes[node].clone());
+        let listener = env
+            .network
+            .bind(&self.nodes[node])
+            .await
+            .expect("Unable to bind the simulated node");
+        let options = ServerOptions {
+            cluster: Some(self.cluster_options(node)),
+            ..Default::default()
+        };
+        tokio::spawn(server::serve(
+            env,
+            listener,
+            options,
+            self.stores[node].clone(),
+        ));
+    }
+
+    fn record_fault(&mut self, fault: String) {
+        let at = self.elapsed();
+        self.faults.push((at, fault));
+    }
+
+    /// Injects faults until the workload is done, and heals the last one.
+    async fn inject_faults(&mut self, done: &AtomicBool) {
+        let interval = self.options.fault_interval;
+        while !done.load(Ordering::SeqCst) {
+            tokio::time::sleep(interval.mul_f64(2.0 * self.rng.next_f64())).await;
+            let crash = match (self.options.partitions, self.options.crashes) {
+                (true, true) => self.rng.next_f64() < 0.5,
+                (false, true) => true,
+                (true, false) => false,
+                (false, false) => return,
+            };
+            let duration = interval.mul_f64(2.0 * self.rng.next_f64());
+            if crash {
+                let node = (self.rng.next_u64() % self.nodes.len() as u64) as usize;
+                self.record_fault(format!("crash {}", self.nodes[node]));
+                self.net.crash(&self.nodes[node]);
+                tokio::time::sleep(duration).await;
+                self.record_fault(format!("restart {}", self.nodes[node]));
+                self.start_node(node).await;
+            } else if self.nodes.len() > 1 {
+                let mut nodes = self.nodes.clone();
+                self.rng.shuffle(&mut nodes);
+                let split = 1 + (self.rng.next_u64() % (nodes.len() as u64 - 1)) as usize;
+                let (left, right) = nodes.split_at(split);
+                self.record_fault(format!("partition {:?} / {:?}", left, right));
+                self.net.partition(left, right);
+                tokio::time::sleep(duration).await;
+                self.record_fault(String::from("heal"));
+                self.net.heal();
+            }
+        }
+    }
+
+    /// Captures the versions stored by every node, along with all of the keys stored.
+    fn capture(&self) -> (BTreeMap<String, KeyVersions>, Vec<String>) {
+        let mut state = BTreeMap::new();
+        let mut keys = vec![];
+        for (node, store) in self.nodes.iter().zip(self.stores.iter()) {
+            let guard = store.lock().unwrap();
+            let mut versions = BTreeMap::new();
+            for (key, _) in guard.iter().unwrap() {
+                if let Ok(read) = replication::read(&**guard, key) {
+                    versions.insert(key.clone(), read);
+                    keys.push(key.clone());
+                }
+            }
+            state.insert(node.clone(), versions);
+        }
+        keys.sort();
+        keys.dedup();
+        (state, keys)
+    }
+}
+
+async fn simulate(seed: u64, options: SimOptions) -> Report {
+    let mut rng = Rng::scrambled(seed);
+    let nodes: Vec<String> = (0..options.nodes).map(|i| format!("node{}", i)).collect();
+    let mut sim = Sim {
+        net: SimNet::new(rng.next_u64(), options.network.clone()),
+        clock: Arc::new(SimClock {
+            start: Instant::now(),
+        }),
+        stores: nodes
+            .iter()
+            .map(|_| {
+                Arc::new(Mutex::new(
+                    Box::new(InMemStorage::new()) as Box<dyn Storage + Send + Sync>
+                ))
+            })
+            .collect(),
+        nodes,
+        history: Arc::new(Mutex::new(vec![])),
+        faults: vec![],
+        options,
+        rng,
+    };
+    for node in 0..sim.nodes.len() {
+        sim.start_node(node).await;
+    }
+
+    let mut clients = vec![];
+    for client in 0..sim.options.clients {
+        let env = sim.env(&format!("client{}", client));
+        let workload = Workload {
+            client,
+            env,
+            rng: Rng::new(sim.rng.next_u64()),
+            nodes: sim.nodes.clone(),
+            keys: sim.options.keys,
+            start: sim.clock.start,
+            history: sim.history.clone(),
+            contexts: BTreeMap::new(),
+        };
+        clients.push(tokio::spawn(workload.run(sim.options.operations)));
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let done = done.clone();
+        tokio::spawn(async move {
+            for client in clients {
+                let _ = client.await;
+            }
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    sim.inject_faults(&done).await;
+    let _ = waiter.await;
+
+    sim.net.set_faults(NetworkFaults {
+        drop: 0.0,
+        ..sim.options.network.clone()
+    });
+    tokio::time::sleep(sim.options.settle_time).await;
+
+    let (state, keys) = sim.capture();
+    let cluster = Cluster::new(sim.cluster_options(0));
+    let replicas = keys
+        .into_iter()
+        .map(|key| {
+            let replicas = cluster.replicas(&key);
+            (key, replicas)
+        })
+        .collect();
+    let history = sim.history.lock().unwrap().clone();
+    Report {
+        seed,
+        history,
+        faults: sim.faults,
+        state,
+        replicas,
+    }
+}
+
+/// Time after which a client gives up on an operation.
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The operations of a client: reads and writes of random keys through random nodes, writes
+/// passing the context of the last read of the key.
+struct Workload {
+    client: usize,
+    env: Env,
+    rng: Rng,
+    nodes: Vec<String>,
+    keys: usize,
+    start: Instant,
+    history: Arc<Mutex<Vec<Operation>>>,
+    contexts: BTreeMap<String, VectorClock>,
+}
+
+impl Workload {
+    async fn run(mut self, operations: usize) {
+        for i in 0..operations {
+            tokio::time::sleep(Duration::from_millis(self.rng.next_u64() % 50)).await;
+            let node = self.nodes[(self.rng.next_u64() % self.nodes.len() as u64) as usize].clone();
+            let key = format!("key{}", self.rng.next_u64() % self.keys as u64);
+            let context = self.contexts.get(&key).cloned().unwrap_or_default();
+            let kind = match self.rng.next_u64() % 10 {
+                0..=4 => OperationKind::Read,
+                5..=8 => OperationKind::Write(Some(format!("c{}-{}", self.client, i)), context),
+                _ => OperationKind::Write(None, context),
+            };
+            let invoked = Instant::now() - self.start;
+            let outcome = self.execute(&node, &key, &kind).await;
+            if let (OperationKind::Read, Ok(versions)) = (&kind, &outcome) {
+                self.contexts
+                    .insert(key.clone(), cluster::context(versions));
+            }
+            let operation = Operation {
+                client: self.client,
+                key,
+                kind,
+                invoked,
+                completed: Instant::now() - self.start,
+                outcome,
+            };
+            self.history.lock().unwrap().push(operation);
+        }
+    }
+
+    async fn execute(
+        &self,
+        node: &str,
+        key: &str,
+        kind: &OperationKind,
+    ) -> Result<Vec<Versioned>, String> {
+        let request = async {
+            let mut client = client::create_with(&*self.env.network, node).await?;
+            match kind {
+                OperationKind::Read => client.replicated_get(key.to_string(), 0).await,
+                OperationKind::Write(Some(value), context) => {
+                    client
+                        .replicated_set(key.to_string(), value.clone(), context.clone(), 0)
+                        .await
+                }
+                OperationKind::Write(None, context) => {
+                    client
+                        .replicated_unset(key.to_string(), context.clone(), 0)
+                        .await
+                }
+            }
+        };
+        match env::timeout(&*self.env.clock, OPERATION_TIMEOUT, request).await {
+            Some(Ok(Some(Response::Versions(versions)))) => Ok(versions),
+            Some(Ok(res)) => Err(format!("Unexpected response {:?}", res)),
+            Some(Err(err)) => Err(err.to_string()),
+            None => Err(String::from("Timed out")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Network;
+
+    fn faulty() -> SimOptions {
+        SimOptions {
+            network: NetworkFaults {
+                drop: 0.01,
+                min_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(50),
+            },
+            partitions: true,
+            crashes: true,
+            fault_interval: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let options = faulty();
+        let first = run(7, &options);
+        assert!(!first.faults.is_empty());
+        assert!(first.history.iter().any(|op| op.outcome.is_ok()));
+        assert!(first.history.iter().any(|op| op.outcome.is_err()));
+        assert_eq!(first, run(7, &options));
+        assert_ne!(first.history, run(8, &options).history);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crash_stops_the_server() {
+        let net = SimNet::new(3, NetworkFaults::default());
+        let env = Env {
+            network: Arc::new(net.host("a")),
+            ..Env::system()
+        };
+        let listener = env.network.bind("a").await.unwrap();
+        let mut options = ClusterOptions::new(String::from("a"), vec![]);
+        options.anti_entropy.interval = Some(Duration::from_millis(200));
+        let options = ServerOptions {
+            cluster: Some(options),
+            ..Default::default()
+        };
+        let store: StorageEngine = Arc::new(Mutex::new(Box::new(InMemStorage::new())));
+        let server = tokio::spawn(server::serve(env, listener, options, store));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(net.host("b").connect("a").await.is_ok());
+
+        net.crash("a");
+        let stopped = tokio::time::timeout(Duration::from_secs(1), server).await;
+        assert!(stopped.unwrap().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_reliable_network() {
+        explore(0..5, &SimOptions::default(), |report| {
+            if report.history.iter().any(|op| op.outcome.is_err()) {
+                return Err(String::from("An operation failed without faults"));
+            }
+            report.check_convergence()?;
+            report.check_durability()
+        });
+    }
+
+    #[test]
+    fn test_faults() {
+        explore(0..20, &faulty(), |report| {
+            report.check_convergence()?;
+            report.check_durability()
+        });
+    }
+}
@@ -0,0 +1,434 @@
+use bytes::{Buf, Bytes};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+
+use crate::cluster::membership::Rng;
+use crate::env::{BoxFuture, Connection, Listener, Network};
+
+/// The faults injected by the simulated network on every connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkFaults {
+    /// Probability of a write to be lost. As a stream can't skip bytes, the connection is reset.
+    pub drop: f64,
+    /// Every write is delivered after a random delay in this range, so messages sent on different
+    /// connections are reordered. The order of the bytes of a single connection is kept.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for NetworkFaults {
+    fn default() -> Self {
+        NetworkFaults {
+            drop: 0.0,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+/// An in-memory network of hosts exchanging byte streams, with injected faults.
+///
+/// Hosts are named after the address they listen on, if any. All of the faults are drawn from a
+/// generator seeded at creation, and delays rely on tokio's clock, so a simulation running on a
+/// single thread with a paused clock always sees the same faults.
+#[derive(Clone)]
+pub struct SimNet {
+    state: Arc<Mutex<NetState>>,
+}
+
+struct NetState {
+    rng: Rng,
+    faults: NetworkFaults,
+    /// The current incarnation of every host, bumped when the host crashes.
+    incarnations: HashMap<String, u64>,
+    /// The side of the partition every partitioned host is on, hosts that are not listed reach
+    /// everyone.
+    sides: HashMap<String, usize>,
+    listeners: HashMap<String, mpsc::UnboundedSender<(Connection, String)>>,
+    pipes: Vec<Weak<Mutex<Pipe>>>,
+}
+
+impl NetState {
+    fn reachable(&self, a: &str, b: &str) -> bool {
+        match (self.sides.get(a), self.sides.get(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    fn is_current(&self, host: &str, incarnation: u64) -> bool {
+        self.incarnations.get(host).copied().unwrap_or(0) == incarnation
+    }
+
+    fn delay(&mut self) -> Duration {
+        let jitter = self.faults.max_delay.saturating_sub(self.faults.min_delay);
+        self.faults.min_delay + jitter.mul_f64(self.rng.next_f64())
+    }
+
+    /// Break the connections matching the predicate, both of their ends see a reset.
+    fn break_pipes<F: Fn(&Pipe) -> bool>(&mut self, predicate: F) {
+        self.pipes.retain(|pipe| pipe.strong_count() > 0);
+        for pipe in self.pipes.iter().filter_map(Weak::upgrade) {
+            let mut pipe = pipe.lock().unwrap();
+            if predicate(&pipe) {
+                pipe.reset();
+            }
+        }
+    }
+}
+
+impl SimNet {
+    pub fn new(seed: u64, faults: NetworkFaults) -> Self {
+        SimNet {
+            state: Arc::new(Mutex::new(NetState {
+                rng: Rng::scrambled(seed),
+                faults,
+                incarnations: HashMap::new(),
+                sides: HashMap::new(),
+                listeners: HashMap::new(),
+                pipes: vec![],
+            })),
+        }
+    }
+
+    pub fn set_faults(&self, faults: NetworkFaults) {
+        self.state.lock().unwrap().faults = faults;
+    }
+
+    /// Returns the network as seen by the host, used to connect to the others or listen.
+    pub fn host(&self, name: &str) -> SimHost {
+        let incarnation = {
+            let state = self.state.lock().unwrap();
+            state.incarnations.get(name).copied().unwrap_or(0)
+        };
+        SimHost {
+            net: self.clone(),
+            name: name.to_string(),
+            incarnation,
+        }
+    }
+
+    /// Splits the hosts in two sides that can't reach each other, the hosts that are not listed
+    /// still reach both sides.
+    pub fn partition(&self, left: &[String], right: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.sides.clear();
+        for host in left.iter() {
+            state.sides.insert(host.clone(), 0);
+        }
+        for host in right.iter() {
+            state.sides.insert(host.clone(), 1);
+        }
+        let sides = state.sides.clone();
+        state.break_pipes(|pipe| match (sides.get(&pipe.from), sides.get(&pipe.to)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        });
+    }
+
+    pub fn heal(&self) {
+        self.state.lock().unwrap().sides.clear();
+    }
+
+    /// Crashes the host: its connections are reset, it stops listening, and the handles obtained
+    /// before the crash can't be used anymore. A new handle must be obtained to restart it.
+    pub fn crash(&self, host: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.incarnations.entry(host.to_string()).or_default() += 1;
+        state.listeners.remove(host);
+        state.break_pipes(|pipe| pipe.from == host || pipe.to == host);
+    }
+}
+
+/// The network as seen by one of the hosts.
+#[derive(Clone)]
+pub struct SimHost {
+    net: SimNet,
+    name: String,
+    incarnation: u64,
+}
+
+impl SimHost {
+    fn crashed() -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected, "The host crashed")
+    }
+}
+
+impl Network for SimHost {
+    fn connect(&self, addr: &str) -> BoxFuture<'static, io::Result<Connection>> {
+        let host = self.clone();
+        let addr = addr.to_string();
+        Box::pin(async move {
+            let delay = host.net.state.lock().unwrap().delay();
+            tokio::time::sleep(delay).await;
+
+            let mut state = host.net.state.lock().unwrap();
+            if !state.is_current(&host.name, host.incarnation) {
+                return Err(SimHost::crashed());
+            }
+            let listener = match state.listeners.get(&addr) {
+                Some(listener) if state.reachable(&host.name, &addr) => listener.clone(),
+                _ => return Err(io::ErrorKind::ConnectionRefused.into()),
+            };
+            let outbound = Arc::new(Mutex::new(Pipe::new(&host.name, &addr)));
+            let inbound = Arc::new(Mutex::new(Pipe::new(&addr, &host.name)));
+            state.pipes.push(Arc::downgrade(&outbound));
+            state.pipes.push(Arc::downgrade(&inbound));
+            let client = SimStream::new(&host.net, inbound.clone(), outbound.clone());
+            let server = SimStream::new(&host.net, outbound, inbound);
+            if listener
+                .send((Box::new(server), host.name.clone()))
+                .is_err()
+            {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            Ok(Box::new(client) as Connection)
+        })
+    }
+
+    fn bind(&self, addr: &str) -> BoxFuture<'static, io::Result<Box<dyn Listener>>> {
+        let host = self.clone();
+        let addr = addr.to_string();
+        Box::pin(async move {
+            let mut state = host.net.state.lock().unwrap();
+            if !state.is_current(&host.name, host.incarnation) {
+                return Err(SimHost::crashed());
+            }
+            if addr != host.name {
+                return Err(io::ErrorKind::AddrNotAvailable.into());
+            }
+            if state.listeners.contains_key(&addr) {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            state.listeners.insert(addr, tx);
+            Ok(Box::new(SimListener { incoming: rx }) as Box<dyn Listener>)
+        })
+    }
+}
+
+struct SimListener {
+    incoming: mpsc::UnboundedReceiver<(Connection, String)>,
+}
+
+impl Listener for SimListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Connection, String)>> {
+        Box::pin(async move {
+            match self.incoming.recv().await {
+                Some(accepted) => Ok(accepted),
+                None => Err(SimHost::crashed()),
+            }
+        })
+    }
+}
+
+/// One direction of a connection, holding the bytes in flight along with their delivery time.
+struct Pipe {
+    from: String,
+    to: String,
+    chunks: VecDeque<(Instant, Bytes)>,
+    /// The time at which the last chunk is delivered, later chunks are never delivered before it.
+    last_delivery: Instant,
+    /// The writer shut the pipe down, the reader sees the end of the stream.
+    closed: bool,
+    broken: bool,
+    reader: Option<Waker>,
+}
+
+impl Pipe {
+    fn new(from: &str, to: &str) -> Self {
+        Pipe {
+            from: from.to_string(),
+            to: to.to_string(),
+            chunks: VecDeque::new(),
+            last_delivery: Instant::now(),
+            closed: false,
+            broken: false,
+            reader: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.broken = true;
+        self.chunks.clear();
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct SimStream {
+    net: SimNet,
+    inbound: Arc<Mutex<Pipe>>,
+    outbound: Arc<Mutex<Pipe>>,
+    /// Wakes the reader up when the next chunk is due.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl SimStream {
+    fn new(net: &SimNet, inbound: Arc<Mutex<Pipe>>, outbound: Arc<Mutex<Pipe>>) -> Self {
+        SimStream {
+            net: net.clone(),
+            inbound,
+            outbound,
+            timer: None,
+        }
+    }
+
+    fn reset(&self) {
+        self.inbound.lock().unwrap().reset();
+        self.outbound.lock().unwrap().reset();
+    }
+}
+
+fn reset_error() -> io::Error {
+    io::ErrorKind::ConnectionReset.into()
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let mut pipe = this.inbound.lock().unwrap();
+            if pipe.broken {
+                return Poll::Ready(Err(reset_error()));
+            }
+            let closed = pipe.closed;
+            let due = match pipe.chunks.front_mut() {
+                Some((at, chunk)) if *at <= Instant::now() => {
+                    let len = chunk.len().min(buf.remaining());
+                    buf.put_slice(&chunk[..len]);
+                    chunk.advance(len);
+                    if chunk.is_empty() {
+                        pipe.chunks.pop_front();
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Some((at, _)) => *at,
+                None if closed => return Poll::Ready(Ok(())),
+                None => {
+                    pipe.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            pipe.reader = Some(cx.waker().clone());
+            drop(pipe);
+            let timer = this
+                .timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due)));
+            timer.as_mut().reset(due);
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.net.state.lock().unwrap();
+        let mut pipe = self.outbound.lock().unwrap();
+        if pipe.broken {
+            return Poll::Ready(Err(reset_error()));
+        }
+        let dropped = state.faults.drop > 0.0 && state.rng.next_f64() < state.faults.drop;
+        if dropped || !state.reachable(&pipe.from, &pipe.to) {
+            drop(pipe);
+            drop(state);
+            self.reset();
+            return Poll::Ready(Err(reset_error()));
+        }
+        let at = pipe.last_delivery.max(Instant::now() + state.delay());
+        pipe.last_delivery = at;
+        pipe.chunks.push_back((at, Bytes::copy_from_slice(data)));
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outbound.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.outbound.lock().unwrap().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delivery() {
+        let net = SimNet::new(1, NetworkFaults::default());
+        let mut listener = net.host("server").bind("server").await.unwrap();
+        let mut client = net.host("client").connect("server").await.unwrap();
+        let (mut server, from) = listener.accept().await.unwrap();
+        assert_eq!(from, "client");
+
+        client.write_all(b"hello ").await.unwrap();
+        client.write_all(b"world").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "hello world");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partition_and_crash() {
+        let net = SimNet::new(2, NetworkFaults::default());
+        let mut listener = net.host("a").bind("a").await.unwrap();
+        let mut stream = net.host("b").connect("a").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        net.partition(&[String::from("a")], &[String::from("b")]);
+        let mut buf = [0; 8];
+        assert!(accepted.read(&mut buf).await.is_err());
+        assert!(stream.write_all(b"lost").await.is_err());
+        assert!(net.host("b").connect("a").await.is_err());
+        assert!(net.host("c").connect("a").await.is_ok());
+
+        net.heal();
+        let host = net.host("b");
+        assert!(host.connect("a").await.is_ok());
+        net.crash("b");
+        assert!(host.connect("a").await.is_err());
+        assert!(net.host("b").connect("a").await.is_ok());
+        // the connections queued before the crash are still returned, then accepting fails.
+        net.crash("a");
+        while listener.accept().await.is_ok() {}
+    }
+}
@@ -0,0 +1,207 @@
+use crate::protocol::{get_string, get_u32, get_u64, put_string};
+use crate::{Result, Storage};
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes identifying a snapshot file.
+const MAGIC: &[u8] = b"KVSNAP";
+
+/// Version of the snapshot format, bumped on every incompatible change.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Information stored in the header of a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub version: u32,
+    /// The time at which the snapshot was taken, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Number of key-value pairs in the snapshot.
+    pub entries: u64,
+}
+
+/// Writes the given key-value pairs as a snapshot file.
+///
+/// The layout of a snapshot is the magic bytes, the version, timestamp and number of entries,
+/// followed by the key-value pairs, and finally a CRC32 checksum of everything that precedes it.
+///
+/// The snapshot is first written to a temporary file which is renamed once complete, so a crash
+/// in the middle of the write never leaves a partial snapshot behind.
+pub fn write<'a, P, I>(path: P, entries: I) -> Result<SnapshotInfo>
+where
+    P: AsRef<Path>,
+    I: ExactSizeIterator<Item = (&'a String, &'a String)>,
+{
+    let path = path.as_ref();
+    let tmp = temp_path(path);
+    let info = SnapshotInfo {
+        version: SNAPSHOT_VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0),
+        entries: entries.len() as u64,
+    };
+
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![];
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&info.version.to_be_bytes());
+    buf.extend_from_slice(&info.timestamp.to_be_bytes());
+    buf.extend_from_slice(&info.entries.to_be_bytes());
+    for (key, value) in entries {
+        put_string(&mut buf, key);
+        put_string(&mut buf, value);
+        // Flush the buffer once in a while to keep the memory usage low.
+        if buf.len() >= 1 << 16 {
+            hasher.update(&buf);
+            file.write_all(&buf)?;
+            buf.clear();
+        }
+    }
+    hasher.update(&buf);
+    file.write_all(&buf)?;
+    file.write_all(&hasher.finalize().to_be_bytes())?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+    Ok(info)
+}
+
+/// Returns a temporary path next to the snapshot, unique to the write so that concurrent writes of
+/// the same snapshot don't clobber each other's file.
+fn temp_path(path: &Path) -> PathBuf {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    );
+    path.with_file_name(tmp)
+}
+
+/// Reads a snapshot file, and returns its header along with all of its key-value pairs.
+///
+/// An error is returned if the file is not a snapshot, was written by an unsupported version, or
+/// its checksum does not match its content.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<(SnapshotInfo, Vec<(String, String)>)> {
+    let data = fs::read(path)?;
+    if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+        return Err("Not a snapshot file".into());
+    }
+    let (content, checksum) = data.split_at(data.len() - 4);
+    let mut cursor = Cursor::new(checksum);
+    if crc32fast::hash(content) != get_u32(&mut cursor)? {
+        return Err("Snapshot checksum mismatch, the file is corrupted".into());
+    }
+
+    let mut cursor = Cursor::new(content);
+    cursor.set_position(MAGIC.len() as u64);
+    let version = get_u32(&mut cursor)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("Unsupported snapshot version {}", version).into());
+    }
+    let info = SnapshotInfo {
+        version,
+        timestamp: get_u64(&mut cursor)?,
+        entries: get_u64(&mut cursor)?,
+    };
+    // The count comes from the file, so the allocation is bounded by the entries it can hold, each
+    // one taking at least the 8 bytes of the lengths of its key and value.
+    let remaining = content.len() - cursor.position() as usize;
+    let mut entries = Vec::with_capacity((info.entries as usize).min(remaining / 8));
+    for _ in 0..info.entries {
+        let key = get_string(&mut cursor)?;
+        let value = get_string(&mut cursor)?;
+        entries.push((key, value));
+    }
+    Ok((info, entries))
+}
+
+/// Loads the snapshot into the given storage, keys that are not in the snapshot are left
+/// untouched.
+pub fn restore<P, S>(path: P, storage: &mut S) -> Result<SnapshotInfo>
+where
+    P: AsRef<Path>,
+    S: Storage + ?Sized,
+{
+    let (info, entries) = read(path)?;
+    for (key, value) in entries {
+        storage.set(key, value)?;
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::InMemStorage;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kvstore-snapshot-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_write_restore() {
+        let path = temp_file("restore");
+        let mut storage = InMemStorage::new();
+        storage.set(String::from("a"), String::from("1")).unwrap();
+        storage.set(String::from("b"), String::from("2")).unwrap();
+        let entries: Vec<(&String, &String)> = storage.iter().unwrap().collect();
+        let info = write(&path, entries.into_iter()).unwrap();
+        assert_eq!(info.entries, 2);
+
+        let mut restored = InMemStorage::new();
+        let restored_info = restore(&path, &mut restored).unwrap();
+        assert_eq!(restored_info, info);
+        assert_eq!(
+            restored.get(&String::from("a")).unwrap(),
+            Some(&String::from("1"))
+        );
+        assert_eq!(
+            restored.get(&String::from("b")).unwrap(),
+            Some(&String::from("2"))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_detects_corruption() {
+        let path = temp_file("corrupted");
+        let (key, value) = (String::from("key"), String::from("value"));
+        write(&path, vec![(&key, &value)].into_iter()).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_forged_entry_count() {
+        let path = temp_file("forged");
+        let mut data = vec![];
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        let checksum = crc32fast::hash(&data);
+        data.extend_from_slice(&checksum.to_be_bytes());
+        fs::write(&path, data).unwrap();
+        // the entries are missing, so reading fails instead of allocating for the count.
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 use super::{Storage, StorageOptions};
 use crate::Result;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 
 pub struct InMemStorage {
-    db: HashMap<String, String>,
+    db: BTreeMap<String, String>,
 }
 
 // A dummy implementation of the `Storage` trait.
@@ -26,6 +27,19 @@ impl Storage for InMemStorage {
         Ok(self.db.remove(key))
     }
 
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (&String, &String)> + '_>> {
+        Ok(Box::new(self.db.iter()))
+    }
+
+    fn range(
+        &self,
+        start: Bound<&String>,
+    ) -> Result<Box<dyn Iterator<Item = (&String, &String)> + '_>> {
+        Ok(Box::new(
+            self.db.range::<String, _>((start, Bound::Unbounded)),
+        ))
+    }
+
     fn close(self) -> Result<()> {
         Ok(())
     }
@@ -33,10 +47,19 @@ impl Storage for InMemStorage {
 
 impl InMemStorage {
     pub fn new() -> Self {
-        InMemStorage { db: HashMap::new() }
+        InMemStorage {
+            db: BTreeMap::new(),
+        }
     }
 }
 
+impl Default for InMemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -67,6 +90,42 @@ mod tests {
         assert_eq!(value.unwrap(), String::from("b"));
 
         let v = storage.get(&String::from("a")).unwrap();
-        assert!(!v.is_some());
+        assert!(v.is_none());
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut storage = InMemStorage::new();
+        let _ = storage.open(String::from("dummy"), StorageOptions {});
+        let _ = storage.set(String::from("a"), String::from("1"));
+        let _ = storage.set(String::from("b"), String::from("2"));
+        let mut entries: Vec<(&String, &String)> = storage.iter().unwrap().collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (&String::from("a"), &String::from("1")),
+                (&String::from("b"), &String::from("2"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let mut storage = InMemStorage::new();
+        for key in ["c", "a", "b"] {
+            let _ = storage.set(String::from(key), String::from("v"));
+        }
+        let keys = |start| -> Vec<String> {
+            storage
+                .range(start)
+                .unwrap()
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        let b = String::from("b");
+        assert_eq!(keys(Bound::Unbounded), vec!["a", "b", "c"]);
+        assert_eq!(keys(Bound::Included(&b)), vec!["b", "c"]);
+        assert_eq!(keys(Bound::Excluded(&b)), vec!["c"]);
     }
 }
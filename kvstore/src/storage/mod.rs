@@ -1,4 +1,5 @@
 pub mod memory;
+#[allow(clippy::module_inception)]
 pub mod storage;
 
 pub use storage::*;
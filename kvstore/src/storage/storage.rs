@@ -1,10 +1,10 @@
 use crate::Result;
+use std::ops::Bound;
 
 /// Abstraction over the storage operations that the key-value store is able to do.
 ///
 /// The storage trait contains the methods necessary for representing a Storage engine
 /// while abstract enough to not bind it to a specific implementation.
-// TODO: Maybe should add iterator implementation? Maybe it does not matter for the demo...?
 pub trait Storage {
     /// Opens the underlying storage, and initializes necessary datastructures.
     ///
@@ -17,12 +17,44 @@ pub trait Storage {
     fn set(&mut self, key: String, value: String) -> Result<()>;
 
     /// Get the value identified by the given key.
+    // `&String` is part of the trait's public signature, implementors depend on it.
+    #[allow(clippy::ptr_arg)]
     fn get(&self, key: &String) -> Result<Option<&String>>;
 
     /// Unset the value assigned to the given key and return it.
     /// A `None` option is returned if no value is assigned to the given key.
+    #[allow(clippy::ptr_arg)]
     fn unset(&mut self, key: &String) -> Result<Option<String>>;
 
+    /// Returns an iterator over all of the key-value pairs of the storage, in no particular order.
+    ///
+    /// Snapshots, scans and migrations rely on it, so they fail with the storages that don't
+    /// implement it.
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (&String, &String)> + '_>> {
+        Err("The storage doesn't support iterating over its entries".into())
+    }
+
+    /// Returns an iterator over the key-value pairs whose key comes after `start`, in ascending
+    /// order of keys.
+    ///
+    /// The default implementation sorts all of the pairs of the storage, ordered storages should
+    /// override it.
+    fn range(
+        &self,
+        start: Bound<&String>,
+    ) -> Result<Box<dyn Iterator<Item = (&String, &String)> + '_>> {
+        let mut entries: Vec<(&String, &String)> = self
+            .iter()?
+            .filter(|(key, _)| match start {
+                Bound::Included(start) => *key >= start,
+                Bound::Excluded(start) => *key > start,
+                Bound::Unbounded => true,
+            })
+            .collect();
+        entries.sort();
+        Ok(Box::new(entries.into_iter()))
+    }
+
     /// Flushes any pending writes and cleans up the internal datastructures.
     fn close(self) -> Result<()>;
 }
@@ -0,0 +1,199 @@
+//! TLS encryption of the connections between the clients and the server, and authentication of
+//! the clients with certificates (mutual TLS).
+//!
+//! Certificates and keys are read from PEM files. The server reads them again on `reload`, so that
+//! they can be rotated without a restart: the new connections use the new certificates, while the
+//! open ones keep going.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+
+use crate::env::{Connection, Stream};
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct ServerTlsOptions {
+    /// The certificate chain of the server, the server's own certificate first.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Requires the clients to present a certificate signed by one of these authorities.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTlsOptions {
+    pub fn new<P: Into<PathBuf>>(cert: P, key: P) -> Self {
+        ServerTlsOptions {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+        }
+    }
+}
+
+/// Accepts the TLS connections of a server.
+///
+/// Cloning the acceptor is cheap, and the clones share its certificates: a clone kept around can
+/// `reload` the certificates of a running server.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    options: ServerTlsOptions,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(options: ServerTlsOptions) -> Result<Self> {
+        let config = server_config(&options)?;
+        Ok(TlsAcceptor {
+            options,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Reads the certificates and keys from their files again. The current ones are kept if the
+    /// new ones can't be loaded.
+    pub fn reload(&self) -> Result<()> {
+        let config = server_config(&self.options)?;
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    pub fn options(&self) -> &ServerTlsOptions {
+        &self.options
+    }
+
+    /// Runs the server side of the handshake on the stream.
+    pub(crate) async fn accept<S: Stream + 'static>(&self, stream: S) -> io::Result<Connection> {
+        let config = self.config.read().unwrap().clone();
+        let stream = tokio_rustls::TlsAcceptor::from(config)
+            .accept(stream)
+            .await?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsAcceptor")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientTlsOptions {
+    /// The authorities trusted to sign the certificate of the server.
+    pub ca: PathBuf,
+    /// The name the certificate of the server must be valid for, a DNS name or an IP address.
+    pub server_name: String,
+    /// The certificate chain and the key presented to the servers requiring mutual TLS.
+    pub identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl ClientTlsOptions {
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(ca: P, server_name: S) -> Self {
+        ClientTlsOptions {
+            ca: ca.into(),
+            server_name: server_name.into(),
+            identity: None,
+        }
+    }
+}
+
+/// Opens the TLS connections of a client, cloning it is cheap.
+#[derive(Clone)]
+pub struct TlsConnector {
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    pub fn new(options: &ClientTlsOptions) -> Result<Self> {
+        let server_name = ServerName::try_from(options.server_name.as_str())
+            .map_err(|_| format!("Invalid server name {}", options.server_name))?;
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots(&options.ca)?);
+        let config = match &options.identity {
+            Some((cert, key)) => builder.with_client_auth_cert(certs(cert)?, private_key(key)?)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsConnector {
+            server_name,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Runs the client side of the handshake on the stream.
+    pub(crate) async fn connect<S: Stream + 'static>(&self, stream: S) -> io::Result<Connection> {
+        let stream = tokio_rustls::TlsConnector::from(self.config.clone())
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnector")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+fn server_config(options: &ServerTlsOptions) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &options.client_ca {
+        Some(ca) => {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots(ca)?).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs(&options.cert)?, private_key(&options.key)?)?;
+    Ok(config)
+}
+
+fn certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut reader(path)?)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// Reads the first private key of the file, in either of the PKCS#8, PKCS#1 or SEC1 formats.
+fn private_key(path: &Path) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut reader = reader(path)?;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+    Err(format!("No private key found in {}", path.display()).into())
+}
+
+fn reader(path: &Path) -> Result<BufReader<File>> {
+    let file =
+        File::open(path).map_err(|err| format!("Unable to open {}: {}", path.display(), err))?;
+    Ok(BufReader::new(file))
+}
@@ -0,0 +1,139 @@
+#![cfg(feature = "blocking")]
+
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use kvstore::protocol::Response;
+use kvstore::server::ServerOptions;
+use kvstore::{Event, Notification, Operation};
+
+mod common;
+
+#[test]
+fn test_ping() {
+    let addr = common::start_server(ServerOptions::default());
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    let res = client.ping(String::from("")).unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+}
+
+#[test]
+fn test_set_get_unset() {
+    let addr = common::start_server(ServerOptions::default());
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    let res = client
+        .set(String::from("key"), String::from("value"))
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("key"))));
+
+    let res = client.get(String::from("key")).unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    client.unset(String::from("key")).unwrap();
+    let res = client.get(String::from("key")).unwrap();
+    assert_eq!(res, Some(Response::Error(String::from("Key not found"))));
+}
+
+#[test]
+fn test_mset_scan() {
+    let addr = common::start_server(ServerOptions::default());
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    let entries: Vec<(String, String)> = (0..5)
+        .map(|i| (format!("user:{}", i), i.to_string()))
+        .collect();
+    let res = client.mset(entries).unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("5"))));
+
+    let res = client
+        .scan(Some(String::from("user:1")), String::from("user:"), 2)
+        .unwrap();
+    let expected: Vec<(String, String)> = (2..4)
+        .map(|i| (format!("user:{}", i), i.to_string()))
+        .collect();
+    assert_eq!(res, Some(Response::Entries(expected)));
+}
+
+#[test]
+fn test_publish_subscribe() {
+    let addr = common::start_server(ServerOptions::default());
+    let subscriber = kvstore::blocking::create(addr).unwrap();
+    let mut subscriber = subscriber.subscribe(&[String::from("news")]).unwrap();
+
+    let mut publisher = kvstore::blocking::create(addr).unwrap();
+    let res = publisher
+        .publish(String::from("news"), String::from("hello"))
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("1"))));
+
+    let message = subscriber.next().unwrap().unwrap();
+    assert_eq!(message.channel, "news");
+    assert_eq!(message.payload, "hello");
+
+    subscriber.unsubscribe(&[String::from("news")]).unwrap();
+    let res = publisher
+        .publish(String::from("news"), String::from("again"))
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("0"))));
+}
+
+#[test]
+fn test_watch_prefix() {
+    let addr = common::start_server(ServerOptions::default());
+    let watcher = kvstore::blocking::create(addr).unwrap();
+    let mut watcher = watcher.watch(&[String::from("user:")]).unwrap();
+
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    client
+        .set(String::from("order:1"), String::from("book"))
+        .unwrap();
+    client
+        .set(String::from("user:1"), String::from("alice"))
+        .unwrap();
+
+    let expected = Event {
+        key: String::from("user:1"),
+        operation: Operation::Set,
+        value: Some(String::from("alice")),
+    };
+    let notification = watcher.next_notification().unwrap();
+    assert_eq!(notification, Some(Notification::Event(expected)));
+}
+
+#[test]
+fn test_read_timeout() {
+    // accepts connections, and never answers.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let err = client.ping(String::from("")).unwrap_err();
+    let err = err.downcast_ref::<std::io::Error>().unwrap();
+    assert!(matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+fn test_connection_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        drop(stream);
+    });
+
+    let mut client = kvstore::blocking::create(addr).unwrap();
+    // the write may fail if the connection is already closed.
+    if let Ok(res) = client.ping(String::from("")) {
+        assert_eq!(res, None);
+    }
+}
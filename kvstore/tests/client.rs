@@ -1,21 +1,92 @@
+use futures_util::SinkExt;
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::DuplexStream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
 
-use kvstore::protocol::Response;
+use kvstore::acl::{self, Acl};
+use kvstore::client::Stage;
+#[cfg(unix)]
+use kvstore::client::UNIX_SCHEME;
+use kvstore::cluster::{
+    context, AntiEntropyOptions, ClusterOptions, PartialWrite, Ring, VectorClock,
+};
+use kvstore::env::{BoxFuture, Connection, Listener};
+use kvstore::linearizability::{self, Recorder};
+use kvstore::pool::{PoolError, PoolMetrics};
+use kvstore::protocol::{epoch_millis, ClientCodec, Command, Response, ServerCodec};
+use kvstore::proxy::{Direction, FaultProxy, Faults};
+use kvstore::server::ServerOptions;
+#[cfg(unix)]
+use kvstore::server::UnixSocketOptions;
 use kvstore::Result;
+use kvstore::{
+    ChangeLogOptions, Client, ClientError, ClientOptions, ConnectionHandler, Credentials, Env,
+    Event, Notification, Operation, Pool, PoolOptions, RetryPolicy,
+};
 
-#[tokio::test]
-async fn test_ping() {
-    let addr = start_server().await.unwrap();
-    let mut client = kvstore::client::create(addr).await.unwrap();
+/// The transports a server accepts connections on.
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Tcp,
+    #[cfg(unix)]
+    Unix,
+}
+
+/// Defines a module per test function, running it against a server listening on TCP, and against
+/// one listening on a Unix domain socket where they are supported.
+macro_rules! on_both_transports {
+    ($($name:ident),* $(,)?) => {
+        $(
+            mod $name {
+                use super::Transport;
+
+                #[tokio::test]
+                async fn tcp() {
+                    super::$name(Transport::Tcp).await
+                }
+
+                #[cfg(unix)]
+                #[tokio::test]
+                async fn unix() {
+                    super::$name(Transport::Unix).await
+                }
+            }
+        )*
+    };
+}
+
+on_both_transports!(
+    test_ping,
+    test_set_get,
+    test_set_override,
+    test_unset,
+    test_ping_with_value,
+    test_publish_subscribe,
+    test_psubscribe_stream,
+    test_unsubscribe_leaves_push_mode,
+    test_watch_prefix,
+    test_changes_resume_from_offset,
+    test_snapshot_restore,
+    test_mset_scan,
+    test_pool_shared_by_tasks,
+    test_pool_checkout_timeout,
+    test_pool_evicts_idle_connections,
+);
+
+async fn test_ping(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
     let res = client.ping(String::from("")).await.unwrap();
     assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
 }
 
-#[tokio::test]
-async fn test_set_get() {
-    let addr = start_server().await.unwrap();
-    let mut client = kvstore::client::create(addr).await.unwrap();
+async fn test_set_get(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
     let res = client
         .set(String::from("key"), String::from("value"))
         .await
@@ -26,10 +97,9 @@ async fn test_set_get() {
     assert_eq!(res, Some(Response::Ok(String::from("value"))));
 }
 
-#[tokio::test]
-async fn test_set_override() {
-    let addr = start_server().await.unwrap();
-    let mut client = kvstore::client::create(addr).await.unwrap();
+async fn test_set_override(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
     let res = client
         .set(String::from("key"), String::from("value1"))
         .await
@@ -45,10 +115,9 @@ async fn test_set_override() {
     assert_eq!(res, Some(Response::Ok(String::from("value2"))));
 }
 
-#[tokio::test]
-async fn test_unset() {
-    let addr = start_server().await.unwrap();
-    let mut client = kvstore::client::create(addr).await.unwrap();
+async fn test_unset(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
     let res = client
         .set(String::from("key"), String::from("value"))
         .await
@@ -63,12 +132,1515 @@ async fn test_unset() {
     assert_eq!(res, Some(Response::Error(String::from("Key not found"))));
 }
 
+async fn test_ping_with_value(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    let res = client.ping(String::from("Value")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("Value"))));
+}
+
+async fn test_publish_subscribe(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let subscriber = kvstore::client::create(&addr).await.unwrap();
+    let mut subscriber = subscriber.subscribe(&[String::from("news")]).await.unwrap();
+
+    let mut publisher = kvstore::client::create(&addr).await.unwrap();
+    let res = publisher
+        .publish(String::from("news"), String::from("hello"))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("1"))));
+    let res = publisher
+        .publish(String::from("weather"), String::from("sunny"))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("0"))));
+
+    let message = subscriber.next_message().await.unwrap().unwrap();
+    assert_eq!(message.channel, "news");
+    assert_eq!(message.payload, "hello");
+}
+
+async fn test_psubscribe_stream(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let subscriber = kvstore::client::create(&addr).await.unwrap();
+    let subscriber = subscriber
+        .psubscribe(&[String::from("cache.*")])
+        .await
+        .unwrap();
+    let mut messages = subscriber.into_stream();
+
+    let mut publisher = kvstore::client::create(&addr).await.unwrap();
+    for key in &["cache.a", "other", "cache.b"] {
+        publisher
+            .publish(key.to_string(), String::from("invalidate"))
+            .await
+            .unwrap();
+    }
+
+    let message = messages.next().await.unwrap().unwrap();
+    assert_eq!(message.channel, "cache.a");
+    let message = messages.next().await.unwrap().unwrap();
+    assert_eq!(message.channel, "cache.b");
+}
+
+async fn test_unsubscribe_leaves_push_mode(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let client = kvstore::client::create(&addr).await.unwrap();
+    let mut subscriber = client.subscribe(&[String::from("news")]).await.unwrap();
+    subscriber
+        .unsubscribe(&[String::from("news")])
+        .await
+        .unwrap();
+
+    let mut publisher = kvstore::client::create(&addr).await.unwrap();
+    let res = publisher
+        .publish(String::from("news"), String::from("hello"))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("0"))));
+}
+
+async fn test_watch_prefix(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let client = kvstore::client::create(&addr).await.unwrap();
+    let mut watcher = client.watch(&[String::from("user:")]).await.unwrap();
+
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    client
+        .set(String::from("user:1"), String::from("alice"))
+        .await
+        .unwrap();
+    client
+        .set(String::from("order:1"), String::from("book"))
+        .await
+        .unwrap();
+    client.unset(String::from("user:1")).await.unwrap();
+    // unsetting a missing key does not change anything.
+    client.unset(String::from("user:2")).await.unwrap();
+    client
+        .set(String::from("user:3"), String::from("bob"))
+        .await
+        .unwrap();
+
+    let expected = vec![
+        (
+            String::from("user:1"),
+            Operation::Set,
+            Some(String::from("alice")),
+        ),
+        (String::from("user:1"), Operation::Unset, None),
+        (
+            String::from("user:3"),
+            Operation::Set,
+            Some(String::from("bob")),
+        ),
+    ];
+    for (key, operation, value) in expected {
+        let notification = watcher.next_notification().await.unwrap().unwrap();
+        let event = Event {
+            key,
+            operation,
+            value,
+        };
+        assert_eq!(notification, Notification::Event(event));
+    }
+}
+
+async fn test_changes_resume_from_offset(transport: Transport) {
+    let dir = std::env::temp_dir().join(format!(
+        "kvstore-changes-{}-{:?}",
+        std::process::id(),
+        transport
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let options = ServerOptions {
+        changelog: Some(ChangeLogOptions::new(&dir)),
+        ..Default::default()
+    };
+    let addr = start_server_with_options_on(transport, options)
+        .await
+        .unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    client
+        .set(String::from("a"), String::from("1"))
+        .await
+        .unwrap();
+    client
+        .set(String::from("b"), String::from("2"))
+        .await
+        .unwrap();
+    client.unset(String::from("a")).await.unwrap();
+
+    let changes = match client.changes(1, 2).await.unwrap() {
+        Some(Response::Changes(changes)) => changes,
+        res => panic!("unexpected response {:?}", res),
+    };
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].seq, 1);
+    assert_eq!(changes[1].event.key, "b");
+
+    // a consumer resumes right after the last change it has seen.
+    let from = changes.last().unwrap().seq + 1;
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    let changes = match client.changes(from, 10).await.unwrap() {
+        Some(Response::Changes(changes)) => changes,
+        res => panic!("unexpected response {:?}", res),
+    };
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].seq, 3);
+    assert_eq!(changes[0].event.operation, Operation::Unset);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+async fn test_snapshot_restore(transport: Transport) {
+    let dir = std::env::temp_dir().join(format!(
+        "kvstore-snapshots-{}-{:?}",
+        std::process::id(),
+        transport
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = ServerOptions {
+        snapshot_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let addr = start_server_with_options_on(transport, options)
+        .await
+        .unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap();
+    let res = client.snapshot(String::from("dump.snap")).await.unwrap();
+    let path = dir.join("dump.snap");
+    assert_eq!(res, Some(Response::Ok(path.display().to_string())));
+
+    // snapshots can't be written outside of the snapshot directory.
+    let res = client.snapshot(String::from("../dump.snap")).await.unwrap();
+    assert_eq!(
+        res,
+        Some(Response::Error(String::from("Invalid snapshot name")))
+    );
+
+    let options = ServerOptions {
+        restore_from: Some(path),
+        ..Default::default()
+    };
+    let addr = start_server_with_options_on(transport, options)
+        .await
+        .unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    let res = client.get(String::from("key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+async fn test_mset_scan(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let mut client = kvstore::client::create(&addr).await.unwrap();
+    let entries: Vec<(String, String)> = (0..5)
+        .map(|i| (format!("user:{}", i), i.to_string()))
+        .chain(vec![(String::from("other"), String::from("x"))])
+        .collect();
+    let res = client.mset(entries).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("6"))));
+
+    let res = client.scan(None, String::from("user:"), 3).await.unwrap();
+    let expected: Vec<(String, String)> = (0..3)
+        .map(|i| (format!("user:{}", i), i.to_string()))
+        .collect();
+    assert_eq!(res, Some(Response::Entries(expected)));
+
+    let res = client
+        .scan(Some(String::from("user:2")), String::from("user:"), 3)
+        .await
+        .unwrap();
+    let expected: Vec<(String, String)> = (3..5)
+        .map(|i| (format!("user:{}", i), i.to_string()))
+        .collect();
+    assert_eq!(res, Some(Response::Entries(expected)));
+
+    let res = client
+        .scan(Some(String::from("user:4")), String::from("user:"), 3)
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Entries(vec![])));
+
+    // a cursor before the prefix starts at the first matching key.
+    let res = client
+        .scan(Some(String::from("a")), String::from("user:"), 1)
+        .await
+        .unwrap();
+    let expected = vec![(String::from("user:0"), String::from("0"))];
+    assert_eq!(res, Some(Response::Entries(expected)));
+
+    let res = client.scan(None, String::from("user:"), 0).await.unwrap();
+    assert!(matches!(res, Some(Response::Error(_))));
+}
+
+#[tokio::test]
+async fn test_cluster_redirects() {
+    let addrs = start_cluster(2).await;
+    let mut clients = vec![];
+    for addr in addrs.iter() {
+        clients.push(kvstore::client::create(addr.as_str()).await.unwrap());
+    }
+    let mut redirected = 0;
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        match clients[0].set(key.clone(), i.to_string()).await.unwrap() {
+            Some(Response::Ok(_)) => {}
+            Some(Response::Moved(owner)) => {
+                assert_eq!(owner, addrs[1]);
+                redirected += 1;
+                let res = clients[1].set(key.clone(), i.to_string()).await.unwrap();
+                assert_eq!(res, Some(Response::Ok(key.clone())));
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+    assert!(redirected > 0 && redirected < 20);
+}
+
+#[tokio::test]
+async fn test_cluster_migrate() {
+    let addrs = start_cluster(1).await;
+    let mut first = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let entries: Vec<(String, String)> = (0..50)
+        .map(|i| (format!("key{}", i), i.to_string()))
+        .collect();
+    first.mset(entries.clone()).await.unwrap();
+
+    // a second node joins the cluster.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = listener.local_addr().unwrap().to_string();
+    let options = ServerOptions {
+        cluster: Some(ClusterOptions::new(second.clone(), addrs.clone())),
+        ..Default::default()
+    };
+    tokio::spawn(async move { kvstore::server::run_with_options(listener, options).await });
+    let res = first.add_node(second.clone()).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("true"))));
+
+    let moved = match first.migrate(0, u64::MAX).await.unwrap() {
+        Some(Response::Ok(moved)) => moved.parse::<usize>().unwrap(),
+        res => panic!("unexpected response {:?}", res),
+    };
+    assert!(moved > 0 && moved < 50);
+
+    let mut second = kvstore::client::create(second.as_str()).await.unwrap();
+    let mut on_second = 0;
+    for (key, value) in entries {
+        match first.get(key.clone()).await.unwrap() {
+            Some(Response::Ok(found)) => assert_eq!(found, value),
+            Some(Response::Moved(_)) => {
+                let res = second.get(key).await.unwrap();
+                assert_eq!(res, Some(Response::Ok(value)));
+                on_second += 1;
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+    assert_eq!(on_second, moved);
+}
+
+#[tokio::test]
+async fn test_cluster_client_routing() {
+    let addrs = start_cluster(3).await;
+    let mut client = kvstore::cluster::connect(&addrs[..1]).await.unwrap();
+    let mut nodes = client.nodes();
+    nodes.sort();
+    let mut expected = addrs.clone();
+    expected.sort();
+    assert_eq!(nodes, expected);
+
+    for i in 0..30 {
+        let res = client
+            .set(format!("key{}", i), i.to_string())
+            .await
+            .unwrap();
+        assert_eq!(res, Some(Response::Ok(format!("key{}", i))));
+    }
+    let entries = (30..60).map(|i| (format!("key{}", i), i.to_string()));
+    let res = client.mset(entries.collect()).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("30"))));
+    for i in 0..60 {
+        let res = client.get(format!("key{}", i)).await.unwrap();
+        assert_eq!(res, Some(Response::Ok(i.to_string())));
+    }
+}
+
+#[tokio::test]
+async fn test_cluster_client_retries_idempotent_commands() {
+    // a node that answers the topology requests, and drops the connection on any other command.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let received = std::sync::Arc::new(AtomicUsize::new(0));
+    let (node, counter) = (addr.clone(), received.clone());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, ServerCodec);
+            let (node, counter) = (node.clone(), counter.clone());
+            tokio::spawn(async move {
+                while let Some(Ok(command)) = framed.next().await {
+                    if command != Command::Topology {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        return;
+                    }
+                    let topology = Response::Topology(16, vec![node.clone()]);
+                    framed.send(topology).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let mut client = kvstore::cluster::connect(&[addr]).await.unwrap();
+    let err = client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::ConnectionLost { .. })
+    ));
+    assert_eq!(received.swap(0, Ordering::SeqCst), 1);
+
+    assert!(client.get(String::from("key")).await.is_err());
+    assert!(received.load(Ordering::SeqCst) > 1);
+}
+
+#[tokio::test]
+async fn test_cluster_client_reports_partial_writes() {
+    // the first node sets the keys, the second one refuses them.
+    let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let nodes = vec![
+        first.local_addr().unwrap().to_string(),
+        second.local_addr().unwrap().to_string(),
+    ];
+    let applied = start_fake_node(first, nodes.clone(), |command| match command {
+        Command::MSet(entries) => Some(Response::Ok(entries.len().to_string())),
+        _ => None,
+    });
+    let refused = start_fake_node(second, nodes.clone(), |_| {
+        Some(Response::Unauthorized(String::from("denied")))
+    });
+
+    let mut client = kvstore::cluster::connect(&nodes).await.unwrap();
+    let entries: Vec<(String, String)> = (0..20)
+        .map(|i| (format!("key{}", i), i.to_string()))
+        .collect();
+    let ring = Ring::with_nodes(16, nodes.iter());
+    let owned = entries
+        .iter()
+        .filter(|(key, _)| ring.owner(key) == Some(nodes[1].as_str()))
+        .count();
+    let err = client.mset(entries).await.unwrap_err();
+    // the refused batch is not mistaken for a stale topology and sent again.
+    assert_eq!(refused.load(Ordering::SeqCst), 1);
+    match applied.load(Ordering::SeqCst) {
+        // the refused batch was sent first, nothing was set.
+        0 => assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::Unauthorized(_))
+        )),
+        _ => {
+            let partial = err.downcast_ref::<PartialWrite>().unwrap();
+            assert_eq!(partial.applied, 20 - owned);
+            assert_eq!(partial.failed.len(), owned);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_cluster_client_timeouts() {
+    // a node that answers the topology requests, and never answers the other commands.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let node = listener.local_addr().unwrap().to_string();
+    start_fake_node(listener, vec![node.clone()], |_| None);
+
+    let options = ClientOptions {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let mut client = kvstore::cluster::connect_with_options(&[node], options)
+        .await
+        .unwrap();
+    let err = client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ClientError>(),
+        Some(&ClientError::Timeout(Stage::Read))
+    );
+}
+
+#[tokio::test]
+async fn test_cluster_client_follows_topology_changes() {
+    let addrs = start_cluster(1).await;
+    let mut client = kvstore::cluster::connect(&addrs).await.unwrap();
+    for i in 0..30 {
+        client
+            .set(format!("key{}", i), i.to_string())
+            .await
+            .unwrap();
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = listener.local_addr().unwrap().to_string();
+    let options = ServerOptions {
+        cluster: Some(ClusterOptions::new(second.clone(), addrs.clone())),
+        ..Default::default()
+    };
+    tokio::spawn(async move { kvstore::server::run_with_options(listener, options).await });
+    let mut admin = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    admin.add_node(second.clone()).await.unwrap();
+    admin.migrate(0, u64::MAX).await.unwrap();
+
+    // the client still believes the first node owns everything, and gets redirected.
+    for i in 0..30 {
+        let res = client.get(format!("key{}", i)).await.unwrap();
+        assert_eq!(res, Some(Response::Ok(i.to_string())));
+    }
+    assert_eq!(client.nodes().len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_linearizable_registers() {
+    let addr = start_server().await.unwrap();
+    let recorder = Recorder::new();
+    let mut tasks = vec![];
+    for process in 0..4 {
+        let recorder = recorder.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut client = kvstore::client::create(addr).await.unwrap();
+            // a small deterministic generator per client, the interleavings come from the threads.
+            let mut state = 0x9e3779b97f4a7c15u64 ^ process as u64;
+            for i in 0..50 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let key = format!("register{}", state % 2);
+                match (state >> 8) % 4 {
+                    0 | 1 => recorder.get(&mut client, process, &key).await,
+                    2 => {
+                        let value = format!("{}-{}", process, i);
+                        recorder.set(&mut client, process, &key, &value).await
+                    }
+                    _ => recorder.unset(&mut client, process, &key).await,
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let history = recorder.history();
+    assert_eq!(history.len(), 200);
+    if let Err(counterexample) = linearizability::check(&history) {
+        panic!("{}", counterexample);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_latency_and_bandwidth() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    proxy.set_faults(Faults {
+        latency: Duration::from_millis(50),
+        ..Default::default()
+    });
+    let start = tokio::time::Instant::now();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+    // the request and the response are both delayed.
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    proxy.set_faults(Faults {
+        bandwidth: Some(20_000),
+        ..Default::default()
+    });
+    let start = tokio::time::Instant::now();
+    let value = "v".repeat(4_000);
+    let res = client.set(String::from("key"), value).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("key"))));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_truncated_frames() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    proxy.set_faults(Faults {
+        truncate_after: Some(3),
+        direction: Some(Direction::ToClient),
+        ..Default::default()
+    });
+    let mut client = create_without_retries(proxy.addr()).await.unwrap();
+    let err = client.ping(String::from("")).await.unwrap_err();
+    let cause = String::from("Connection closed in the middle of a frame");
+    assert_eq!(
+        err.downcast_ref::<ClientError>(),
+        Some(&ClientError::ConnectionLost { attempts: 1, cause })
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_dropped_and_reset_connections() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let mut client = create_without_retries(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let res = client.ping(String::from("")).await;
+    assert!(!matches!(res, Ok(Some(_))));
+
+    proxy.set_faults(Faults {
+        drop_connections: true,
+        ..Default::default()
+    });
+    // the connection may already be closed when the client creates it.
+    if let Ok(mut client) = create_without_retries(proxy.addr()).await {
+        let res = client.ping(String::from("")).await;
+        assert!(!matches!(res, Ok(Some(_))));
+    }
+
+    proxy.set_faults(Faults::default());
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_reset_after_bytes() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    proxy.set_faults(Faults {
+        reset_after: Some(4),
+        direction: Some(Direction::ToServer),
+        ..Default::default()
+    });
+    let mut client = create_without_retries(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await;
+    assert!(!matches!(res, Ok(Some(_))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_corruption() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 7).await.unwrap();
+    proxy.set_faults(Faults {
+        corruption: 1.0,
+        direction: Some(Direction::ToServer),
+        ..Default::default()
+    });
+    let mut client = create_without_retries(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await;
+    assert!(!matches!(res, Ok(Some(Response::Ok(_)))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_proxy_schedule() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let blackhole = Faults {
+        blackhole: true,
+        ..Default::default()
+    };
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+    // the paused clock jumps to the next timer while waiting for the sockets, so the faults are
+    // scheduled once the client is connected.
+    proxy.schedule(vec![
+        (Duration::from_millis(100), blackhole.clone()),
+        (Duration::from_millis(300), Faults::default()),
+    ]);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(proxy.faults(), blackhole);
+    let res = tokio::time::timeout(Duration::from_millis(50), client.ping(String::from(""))).await;
+    assert!(res.is_err());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(proxy.faults(), Faults::default());
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+}
+
+#[tokio::test]
+async fn test_client_reconnects() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    let res = client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("key"))));
+
+    // idempotent commands are sent again on a new connection.
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let res = client.get(String::from("key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    // the other ones are not, unless the policy allows it.
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let err = client
+        .set(String::from("key"), String::from("other"))
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::ConnectionLost { attempts: 1, .. }) => {}
+        _ => panic!("Unexpected error: {}", err),
+    }
+    let res = client.get(String::from("key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    client.set_retry_policy(RetryPolicy {
+        retry_writes: true,
+        ..Default::default()
+    });
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let res = client
+        .set(String::from("key"), String::from("other"))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("key"))));
+}
+
+#[tokio::test]
+async fn test_client_gives_up_reconnecting() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let mut client = kvstore::client::create(proxy.addr()).await.unwrap();
+    client.set_retry_policy(RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(20),
+        ..Default::default()
+    });
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+
+    // the ping was sent once, and both retries were spent failing to reconnect: it may have been
+    // received.
+    proxy.reset_connections();
+    drop(proxy);
+    let err = client.ping(String::from("")).await.unwrap_err();
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::ConnectionLost { attempts: 1, .. }) => {}
+        _ => panic!("Unexpected error: {}", err),
+    }
+    // a command that was never sent is not.
+    let err = client.ping(String::from("")).await.unwrap_err();
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::Unavailable { attempts: 3, .. }) => {}
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+async fn test_pool_shared_by_tasks(transport: Transport) {
+    fn assert_shareable<T: Clone + Send + Sync>() {}
+    assert_shareable::<Pool>();
+
+    let addr = start_server_on(transport).await.unwrap();
+    let options = PoolOptions {
+        min_connections: 2,
+        max_connections: 2,
+        ..Default::default()
+    };
+    let pool = Pool::new(&addr, options).await.unwrap();
+    assert_eq!(pool.metrics().connections, 2);
+    assert_eq!(pool.metrics().idle, 2);
+
+    let mut tasks = vec![];
+    for i in 0..8 {
+        let pool = pool.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut client = pool.get().await.unwrap();
+            let key = format!("key{}", i);
+            client.set(key.clone(), i.to_string()).await.unwrap();
+            let res = client.get(key).await.unwrap();
+            assert_eq!(res, Some(Response::Ok(i.to_string())));
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let metrics = pool.metrics();
+    assert_eq!(metrics.checkouts, 8);
+    assert_eq!(metrics.created, 2);
+    assert_eq!(metrics.connections, 2);
+    assert_eq!(metrics.in_use, 0);
+}
+
+async fn test_pool_checkout_timeout(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let options = PoolOptions {
+        max_connections: 1,
+        checkout_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let pool = Pool::new(&addr, options).await.unwrap();
+    let client = pool.get().await.unwrap();
+    assert_eq!(pool.metrics().utilization(), 1.0);
+
+    let err = pool.get().await.err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<PoolError>(),
+        Some(PoolError::Timeout(_))
+    ));
+    assert_eq!(pool.metrics().timeouts, 1);
+
+    drop(client);
+    assert_eq!(pool.metrics().utilization(), 0.0);
+    let _client = pool.get().await.unwrap();
+    assert_eq!(pool.metrics().checkouts, 2);
+}
+
+#[tokio::test]
+async fn test_pool_health_checks() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    // the health check doesn't go through the retry policy, the connection is not reopened.
+    let pool = Pool::new(proxy.addr(), PoolOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(pool.metrics().created, 1);
+    // makes sure the connection is established through the proxy before resetting it.
+    let res = pool
+        .get()
+        .await
+        .unwrap()
+        .ping(String::from(""))
+        .await
+        .unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut client = pool.get().await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+    let metrics = pool.metrics();
+    assert_eq!(metrics.failed_health_checks, 1);
+    assert_eq!(metrics.created, 2);
+    assert_eq!(metrics.connections, 1);
+}
+
+#[tokio::test]
+async fn test_pool_checkout_timeout_covers_connecting() {
+    // a server that never answers the authentication.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    let options = PoolOptions {
+        min_connections: 0,
+        checkout_timeout: Duration::from_millis(100),
+        client: ClientOptions {
+            credentials: Some(Credentials::new("user", "password")),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let pool = Pool::new(addr, options).await.unwrap();
+    let err = pool.get().await.err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<PoolError>(),
+        Some(PoolError::Timeout(_))
+    ));
+    let metrics = pool.metrics();
+    assert_eq!((metrics.timeouts, metrics.connections), (1, 0));
+}
+
+#[tokio::test]
+async fn test_pool_fill_takes_permits() {
+    let addr = start_server().await.unwrap();
+    let options = PoolOptions {
+        min_connections: 3,
+        max_connections: 2,
+        ..Default::default()
+    };
+    let pool = Pool::new(addr, options).await.unwrap();
+    assert_eq!(pool.metrics().connections, 2);
+}
+
+#[test]
+fn test_pool_average_wait() {
+    let metrics = PoolMetrics {
+        checkouts: 1 << 32,
+        total_wait: Duration::from_secs(1 << 32),
+        ..Default::default()
+    };
+    assert_eq!(metrics.average_wait(), Duration::from_secs(1));
+    assert_eq!(
+        PoolMetrics::default().average_wait(),
+        Duration::from_secs(0)
+    );
+}
+
+async fn test_pool_evicts_idle_connections(transport: Transport) {
+    let addr = start_server_on(transport).await.unwrap();
+    let options = PoolOptions {
+        min_connections: 1,
+        max_connections: 3,
+        idle_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let pool = Pool::new(&addr, options).await.unwrap();
+    let clients = vec![
+        pool.get().await.unwrap(),
+        pool.get().await.unwrap(),
+        pool.get().await.unwrap(),
+    ];
+    assert_eq!(pool.metrics().in_use, 3);
+    drop(clients);
+    assert_eq!(pool.metrics().idle, 3);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let metrics = pool.metrics();
+    assert_eq!(metrics.connections, 1);
+    assert_eq!(metrics.evicted, 2);
+}
+
+#[tokio::test]
+async fn test_client_timeouts() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let options = ClientOptions {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mut client = kvstore::client::create_with_options(proxy.addr(), options)
+        .await
+        .unwrap();
+    proxy.set_faults(Faults {
+        blackhole: true,
+        direction: Some(Direction::ToClient),
+        ..Default::default()
+    });
+    let start = Instant::now();
+    let err = client.ping(String::from("")).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ClientError>(),
+        Some(&ClientError::Timeout(Stage::Read))
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // the connection timed out is dropped, the next command uses a new one.
+    proxy.set_faults(Faults::default());
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+}
+
+#[tokio::test]
+async fn test_client_request_deadlines() {
+    let addr = start_server().await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let options = ClientOptions {
+        request_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mut client = kvstore::client::create_with_options(proxy.addr(), options)
+        .await
+        .unwrap();
+    let res = client.get(String::from("key")).await.unwrap();
+    assert_eq!(res, Some(Response::Error(String::from("Key not found"))));
+
+    proxy.set_faults(Faults {
+        latency: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let err = client.get(String::from("key")).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ClientError>(),
+        Some(&ClientError::DeadlineExceeded)
+    );
+
+    // a deadline already passed fails without reaching the server.
+    proxy.set_faults(Faults::default());
+    client.set_deadline(Some(SystemTime::now() - Duration::from_secs(1)));
+    let err = client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ClientError>(),
+        Some(&ClientError::DeadlineExceeded)
+    );
+}
+
 #[tokio::test]
-async fn test_ping_with_value() {
+async fn test_server_refuses_expired_requests() {
     let addr = start_server().await.unwrap();
+    let mut handler = ConnectionHandler::new(TcpStream::connect(addr).await.unwrap());
+    let set = Command::Set(String::from("key"), String::from("value"));
+    let expired = epoch_millis(SystemTime::now() - Duration::from_secs(1));
+    handler
+        .write_command(&Command::Deadline(expired, Box::new(set.clone())))
+        .await
+        .unwrap();
+    let res = handler.read_response().await.unwrap();
+    assert_eq!(res, Some(Response::DeadlineExceeded));
+
+    let get = Command::Get(String::from("key"));
+    handler.write_command(&get).await.unwrap();
+    let res = handler.read_response().await.unwrap();
+    assert_eq!(res, Some(Response::Error(String::from("Key not found"))));
+
+    let later = epoch_millis(SystemTime::now() + Duration::from_secs(10));
+    handler
+        .write_command(&Command::Deadline(later, Box::new(set)))
+        .await
+        .unwrap();
+    let res = handler.read_response().await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("key"))));
+}
+
+#[tokio::test]
+async fn test_deadlines_propagate_to_replicas() {
+    let mut listeners = vec![];
+    for _ in 0..2 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    // the third replica accepts connections, but never reads the requests.
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    addrs.push(silent.local_addr().unwrap().to_string());
+    for listener in listeners {
+        start_node(listener, &addrs);
+    }
+
+    let mut handler = ConnectionHandler::new(TcpStream::connect(&addrs[0]).await.unwrap());
+    let set = Command::ReplicatedSet(
+        String::from("key"),
+        Some(String::from("value")),
+        VectorClock::new(),
+        3,
+    );
+    let deadline = epoch_millis(SystemTime::now() + Duration::from_millis(300));
+    let start = Instant::now();
+    handler
+        .write_command(&Command::Deadline(deadline, Box::new(set)))
+        .await
+        .unwrap();
+    let res = handler.read_response().await.unwrap();
+    assert!(matches!(res, Some(Response::Error(_))));
+    // the coordinator stopped waiting for the silent replica at the deadline.
+    assert!(start.elapsed() < Duration::from_millis(1500));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_options() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // a socket file left by a previous run is replaced.
+    let path = socket_path();
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let options = ServerOptions {
+        unix_socket: Some(UnixSocketOptions {
+            path: path.clone(),
+            mode: 0o600,
+        }),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp = listener.local_addr().unwrap();
+    let server =
+        tokio::spawn(async move { kvstore::server::run_with_options(listener, options).await });
+    let addr = format!("{}{}", UNIX_SCHEME, path.display());
+    let mut client = loop {
+        match kvstore::client::create(&addr).await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(1)).await,
+        }
+    };
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the private directory the socket was bound in is removed.
+    let name = path.file_name().unwrap().to_string_lossy();
+    assert!(!path.with_file_name(format!(".{}.bind", name)).exists());
+
+    // both transports serve the same store.
+    client
+        .set(String::from("key"), String::from("value"))
+        .await
+        .unwrap();
+    let mut client = kvstore::client::create(tcp).await.unwrap();
+    let res = client.get(String::from("key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    // the socket file is removed when the server stops.
+    server.abort();
+    let _ = server.await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_server_fails_to_bind() {
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = ServerOptions {
+        resp: Some(taken.local_addr().unwrap().into()),
+        ..Default::default()
+    };
+    let err = kvstore::server::run_with_options(listener, options)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("resp listener"), "{}", err);
+    // the server returned before serving the connections of its main listener.
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_authentication() {
+    let options = ServerOptions {
+        acl: Some(test_acl()),
+        ..Default::default()
+    };
+    let addr = start_server_with_options(options).await.unwrap();
+
+    // the connections that did not authenticate run as the default user.
     let mut client = kvstore::client::create(addr).await.unwrap();
-    let res = client.ping(String::from("Value")).await.unwrap();
-    assert_eq!(res, Some(Response::Ok(String::from("Value"))));
+    let res = client.get(String::from("public:key")).await.unwrap();
+    assert_eq!(res, Some(Response::Error(String::from("Key not found"))));
+    let err = client
+        .set(String::from("public:key"), String::from("value"))
+        .await
+        .unwrap_err();
+    assert_unauthorized(err, "User default is not allowed to run SET");
+
+    let err = client
+        .auth(String::from("admin"), String::from("wrong"))
+        .await
+        .unwrap_err();
+    assert_unauthorized(err, "Invalid user name or password");
+    client
+        .auth(String::from("admin"), String::from("admin-secret"))
+        .await
+        .unwrap();
+    for key in &["public:key", "private:key"] {
+        let res = client.set(key.to_string(), String::from("value")).await;
+        assert_eq!(res.unwrap(), Some(Response::Ok(key.to_string())));
+    }
+
+    // the keys of a user are restricted to its patterns.
+    let mut client = kvstore::client::create(addr).await.unwrap();
+    client
+        .auth(String::from("reader"), String::from("reader-secret"))
+        .await
+        .unwrap();
+    let res = client.get(String::from("public:key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+    let err = client.get(String::from("private:key")).await.unwrap_err();
+    assert_unauthorized(
+        err,
+        "User reader is not allowed to access the key private:key",
+    );
+    let res = client
+        .scan(None, String::from("public:"), 10)
+        .await
+        .unwrap();
+    let entries = vec![(String::from("public:key"), String::from("value"))];
+    assert_eq!(res, Some(Response::Entries(entries)));
+    let err = client.scan(None, String::new(), 10).await.unwrap_err();
+    assert_unauthorized(err, "User reader is not allowed to access the key *");
+    let err = client
+        .watch(&[String::from("private:")])
+        .await
+        .err()
+        .unwrap();
+    assert_unauthorized(
+        err,
+        "User reader is not allowed to access the key private:*",
+    );
+}
+
+#[tokio::test]
+async fn test_authentication_failures_close_the_connection() {
+    let options = ServerOptions {
+        acl: Some(test_acl()),
+        ..Default::default()
+    };
+    let addr = start_server_with_options(options).await.unwrap();
+    let mut client = create_without_retries(addr).await.unwrap();
+    // the users that don't exist are refused like a wrong password.
+    for _ in 0..acl::MAX_AUTH_FAILURES {
+        let err = client
+            .auth(String::from("nobody"), String::from("secret"))
+            .await
+            .unwrap_err();
+        assert_unauthorized(err, "Invalid user name or password");
+    }
+    assert!(client.ping(String::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_cluster_authentication() {
+    let credentials = Credentials::new("node", "node-secret");
+    let addrs = start_cluster_with_acl(2, Some(credentials.clone())).await;
+    let mut client = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let res = client
+        .replicated_set(
+            String::from("key"),
+            String::from("value"),
+            VectorClock::new(),
+            2,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(res, Some(Response::Versions(_))));
+
+    // the default user may not replicate the keys.
+    let addrs = start_cluster_with_acl(2, None).await;
+    let mut client = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let res = client
+        .replicated_set(
+            String::from("key"),
+            String::from("value"),
+            VectorClock::new(),
+            2,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(res, Some(Response::Error(_))), "{:?}", res);
+
+    // the cluster client authenticates on every node it connects to.
+    let addrs = start_cluster_with_acl(2, Some(credentials.clone())).await;
+    assert!(kvstore::cluster::connect(&addrs).await.is_err());
+    let options = ClientOptions {
+        credentials: Some(credentials),
+        ..Default::default()
+    };
+    let mut client = kvstore::cluster::connect_with_options(&addrs, options)
+        .await
+        .unwrap();
+    let entries = (0..10).map(|i| (format!("key{}", i), i.to_string()));
+    let res = client.mset(entries.collect()).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("10"))));
+}
+
+#[tokio::test]
+async fn test_client_reauthenticates() {
+    let options = ServerOptions {
+        acl: Some(test_acl()),
+        ..Default::default()
+    };
+    let addr = start_server_with_options(options).await.unwrap();
+    let proxy = FaultProxy::start(addr.to_string(), 1).await.unwrap();
+    let options = ClientOptions {
+        credentials: Some(Credentials::new("admin", "admin-secret")),
+        ..Default::default()
+    };
+    let mut client = kvstore::client::create_with_options(proxy.addr(), options.clone())
+        .await
+        .unwrap();
+    client
+        .set(String::from("private:key"), String::from("value"))
+        .await
+        .unwrap();
+
+    proxy.reset_connections();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let res = client.get(String::from("private:key")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    // wrong credentials are not worth retrying.
+    let options = ClientOptions {
+        credentials: Some(Credentials::new("admin", "wrong")),
+        ..options
+    };
+    let err = kvstore::client::create_with_options(addr, options)
+        .await
+        .err()
+        .unwrap();
+    assert_unauthorized(err, "Invalid user name or password");
+}
+
+#[tokio::test]
+async fn test_replicated_quorums() {
+    let addrs = start_cluster(3).await;
+    let mut client = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let res = client
+        .replicated_set(
+            String::from("key"),
+            String::from("value"),
+            VectorClock::new(),
+            3,
+        )
+        .await
+        .unwrap();
+    let written = match res {
+        Some(Response::Versions(versions)) => versions,
+        res => panic!("Unexpected response {:?}", res),
+    };
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].value, Some(String::from("value")));
+
+    // every replica acknowledged the write, so any single one of them is enough to read it.
+    for addr in addrs.iter() {
+        let mut client = kvstore::client::create(addr.as_str()).await.unwrap();
+        let res = client.replicated_get(String::from("key"), 1).await.unwrap();
+        assert_eq!(res, Some(Response::Versions(written.clone())));
+    }
+
+    let res = client.replicated_get(String::from("key"), 4).await.unwrap();
+    assert!(matches!(res, Some(Response::Error(_))));
+}
+
+#[tokio::test]
+async fn test_replicated_siblings() {
+    let addrs = start_cluster(3).await;
+    let mut first = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let mut second = kvstore::client::create(addrs[1].as_str()).await.unwrap();
+
+    // both writes are coordinated by different nodes without knowing about each other.
+    first
+        .replicated_set(
+            String::from("key"),
+            String::from("a"),
+            VectorClock::new(),
+            3,
+        )
+        .await
+        .unwrap();
+    second
+        .replicated_set(
+            String::from("key"),
+            String::from("b"),
+            VectorClock::new(),
+            3,
+        )
+        .await
+        .unwrap();
+
+    let siblings = match first.replicated_get(String::from("key"), 3).await.unwrap() {
+        Some(Response::Versions(versions)) => versions,
+        res => panic!("Unexpected response {:?}", res),
+    };
+    let mut values: Vec<_> = siblings.iter().filter_map(|v| v.value.clone()).collect();
+    values.sort();
+    assert_eq!(values, vec![String::from("a"), String::from("b")]);
+
+    // writing with the context of the siblings resolves the conflict.
+    second
+        .replicated_set(
+            String::from("key"),
+            String::from("ab"),
+            context(&siblings),
+            3,
+        )
+        .await
+        .unwrap();
+    let resolved = match first.replicated_get(String::from("key"), 3).await.unwrap() {
+        Some(Response::Versions(versions)) => versions,
+        res => panic!("Unexpected response {:?}", res),
+    };
+    assert_eq!(resolved.len(), 1);
+    assert_eq!(resolved[0].value, Some(String::from("ab")));
+
+    // removals are versioned too, and supersede the value.
+    first
+        .replicated_unset(String::from("key"), context(&resolved), 3)
+        .await
+        .unwrap();
+    let res = second.replicated_get(String::from("key"), 3).await.unwrap();
+    match res {
+        Some(Response::Versions(versions)) => {
+            assert_eq!(versions.len(), 1);
+            assert_eq!(versions[0].value, None);
+        }
+        res => panic!("Unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn test_read_repair() {
+    let mut listeners = vec![];
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    // the last node is down while the key is written, so it misses the write.
+    listeners.pop();
+    for listener in listeners {
+        start_node(listener, &addrs);
+    }
+    let mut client = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    let res = client
+        .replicated_set(
+            String::from("key"),
+            String::from("value"),
+            VectorClock::new(),
+            2,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(res, Some(Response::Versions(_))));
+
+    start_node(TcpListener::bind(addrs[2].as_str()).await.unwrap(), &addrs);
+    let mut stale = kvstore::client::create(addrs[2].as_str()).await.unwrap();
+    let res = stale.scan(None, String::from("key"), 10).await.unwrap();
+    assert_eq!(res, Some(Response::Entries(vec![])));
+
+    let res = client.replicated_get(String::from("key"), 3).await.unwrap();
+    assert!(matches!(res, Some(Response::Versions(versions)) if versions.len() == 1));
+
+    // the stale replica is repaired in the background.
+    for _ in 0..50 {
+        if let Some(Response::Entries(entries)) =
+            stale.scan(None, String::from("key"), 10).await.unwrap()
+        {
+            if !entries.is_empty() {
+                return;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("The stale replica was not repaired");
+}
+
+#[tokio::test]
+async fn test_anti_entropy() {
+    let mut listeners = vec![];
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    let anti_entropy = AntiEntropyOptions {
+        interval: Some(std::time::Duration::from_millis(20)),
+        depth: 6,
+        pause: std::time::Duration::from_millis(1),
+        ..Default::default()
+    };
+    // the last node is down while the keys are written, so it misses all of them.
+    listeners.pop();
+    for listener in listeners {
+        start_node_with(listener, &addrs, anti_entropy.clone());
+    }
+    let mut client = kvstore::client::create(addrs[0].as_str()).await.unwrap();
+    for i in 0..20 {
+        let res = client
+            .replicated_set(
+                format!("key{}", i),
+                String::from("value"),
+                VectorClock::new(),
+                2,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(res, Some(Response::Versions(_))));
+    }
+
+    // the node catches up in the background, without any read of the keys.
+    start_node_with(
+        TcpListener::bind(addrs[2].as_str()).await.unwrap(),
+        &addrs,
+        anti_entropy,
+    );
+    let mut stale = kvstore::client::create(addrs[2].as_str()).await.unwrap();
+    for _ in 0..250 {
+        if let Some(Response::Entries(entries)) =
+            stale.scan(None, String::from("key"), 100).await.unwrap()
+        {
+            if entries.len() == 20 {
+                return;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("The node did not catch up");
+}
+
+#[tokio::test]
+async fn test_codec_over_pipes() {
+    let connect = start_pipe_server();
+    let mut framed = Framed::new(connect(), ClientCodec);
+    framed
+        .send(Command::Set(String::from("key"), String::from("value")))
+        .await
+        .unwrap();
+    framed
+        .send(Command::Get(String::from("key")))
+        .await
+        .unwrap();
+    let res = framed.next().await.unwrap().unwrap();
+    assert_eq!(res, Response::Ok(String::from("key")));
+    let res = framed.next().await.unwrap().unwrap();
+    assert_eq!(res, Response::Ok(String::from("value")));
+
+    // the connection handler works with any stream as well.
+    let mut handler = ConnectionHandler::new(connect());
+    handler
+        .write_command(&Command::Get(String::from("key")))
+        .await
+        .unwrap();
+    let res = handler.read_response().await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("value"))));
+
+    drop(framed);
+    let mut framed = Framed::new(connect(), ClientCodec);
+    framed.send(Command::Ping(String::new())).await.unwrap();
+    let res = framed.next().await.unwrap().unwrap();
+    assert_eq!(res, Response::Ok(String::from("PONG")));
+}
+
+/// The users of the authentication tests, their passwords are hashed with few iterations to keep
+/// the tests fast.
+fn test_acl() -> Acl {
+    let admin = acl::hash_password_with_iterations("admin-secret", 1000);
+    let reader = acl::hash_password_with_iterations("reader-secret", 1000);
+    let config = format!(
+        "admin {} +@all allkeys\nreader {} +@read ~public:*\ndefault nopass +GET ~public:*",
+        admin, reader
+    );
+    Acl::parse(&config).unwrap()
+}
+
+fn assert_unauthorized(err: kvstore::Err, msg: &str) {
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::Unauthorized(err)) => assert_eq!(err, msg),
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+/// Creates a client that gives up as soon as its connection is lost.
+async fn create_without_retries(addr: SocketAddr) -> Result<Client> {
+    let options = ClientOptions {
+        retry: RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    kvstore::client::create_with_options(addr, options).await
 }
 
 /// Starts a server for integration tests.
@@ -79,3 +1651,189 @@ async fn start_server() -> Result<SocketAddr> {
     tokio::spawn(async move { kvstore::server::run(listener).await });
     Ok(addr)
 }
+
+/// Starts a server with the given options on a random non-used port.
+async fn start_server_with_options(options: ServerOptions) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { kvstore::server::run_with_options(listener, options).await });
+    Ok(addr)
+}
+
+/// Starts a server for integration tests, listening on the given transport, and returns the address
+/// clients should connect to.
+async fn start_server_on(transport: Transport) -> Result<String> {
+    start_server_with_options_on(transport, ServerOptions::default()).await
+}
+
+async fn start_server_with_options_on(
+    transport: Transport,
+    options: ServerOptions,
+) -> Result<String> {
+    match transport {
+        Transport::Tcp => Ok(start_server_with_options(options).await?.to_string()),
+        #[cfg(unix)]
+        Transport::Unix => {
+            let path = socket_path();
+            let options = ServerOptions {
+                unix_socket: Some(UnixSocketOptions::new(&path)),
+                ..options
+            };
+            start_server_with_options(options).await?;
+            // the socket is bound by the server's task.
+            while !path.exists() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(format!("{}{}", UNIX_SCHEME, path.display()))
+        }
+    }
+}
+
+/// A path for a Unix domain socket, unique to the test.
+#[cfg(unix)]
+fn socket_path() -> std::path::PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("kvstore-{}-{}.sock", std::process::id(), id))
+}
+
+/// Starts a cluster of the given number of nodes, and return their addresses.
+async fn start_cluster(nodes: usize) -> Vec<String> {
+    let mut listeners = vec![];
+    for _ in 0..nodes {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    for listener in listeners {
+        start_node(listener, &addrs);
+    }
+    addrs
+}
+
+/// Serves the topology of the given nodes on the listener, and answers the other commands as
+/// told by `respond`, or never if it returns `None`. Returns the number of the other commands.
+fn start_fake_node(
+    listener: TcpListener,
+    nodes: Vec<String>,
+    respond: fn(&Command) -> Option<Response>,
+) -> std::sync::Arc<AtomicUsize> {
+    let received = std::sync::Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, ServerCodec);
+            let (nodes, counter) = (nodes.clone(), counter.clone());
+            tokio::spawn(async move {
+                while let Some(Ok(command)) = framed.next().await {
+                    let response = match command {
+                        Command::Topology => Response::Topology(16, nodes.clone()),
+                        command => {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            match respond(&command) {
+                                Some(response) => response,
+                                None => continue,
+                            }
+                        }
+                    };
+                    framed.send(response).await.unwrap();
+                }
+            });
+        }
+    });
+    received
+}
+
+/// Starts a cluster whose nodes require authentication, and authenticate on each other with the
+/// credentials.
+async fn start_cluster_with_acl(nodes: usize, credentials: Option<Credentials>) -> Vec<String> {
+    let node = acl::hash_password_with_iterations("node-secret", 1000);
+    let config = format!(
+        "node {} +@cluster +RSET +MSET allkeys\ndefault nopass +@read +@write allkeys",
+        node
+    );
+    let acl = Acl::parse(&config).unwrap();
+    let mut listeners = vec![];
+    for _ in 0..nodes {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    for listener in listeners {
+        let node = listener.local_addr().unwrap().to_string();
+        let peers = addrs
+            .iter()
+            .filter(|addr| **addr != node)
+            .cloned()
+            .collect();
+        let options = ServerOptions {
+            cluster: Some(ClusterOptions {
+                credentials: credentials.clone(),
+                ..ClusterOptions::new(node, peers)
+            }),
+            acl: Some(acl.clone()),
+            ..Default::default()
+        };
+        tokio::spawn(kvstore::server::run_with_options(listener, options));
+    }
+    addrs
+}
+
+/// Starts the node of the cluster made of the given nodes, listening on the listener.
+fn start_node(listener: TcpListener, addrs: &[String]) {
+    start_node_with(listener, addrs, AntiEntropyOptions::default());
+}
+
+fn start_node_with(listener: TcpListener, addrs: &[String], anti_entropy: AntiEntropyOptions) {
+    let node = listener.local_addr().unwrap().to_string();
+    let peers = addrs
+        .iter()
+        .filter(|addr| **addr != node)
+        .cloned()
+        .collect();
+    let options = ServerOptions {
+        cluster: Some(ClusterOptions {
+            anti_entropy,
+            ..ClusterOptions::new(node, peers)
+        }),
+        ..Default::default()
+    };
+    tokio::spawn(async move { kvstore::server::run_with_options(listener, options).await });
+}
+
+/// A listener handing out the server's ends of in-memory pipes.
+struct PipeListener {
+    incoming: tokio::sync::mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl Listener for PipeListener {
+    fn accept(&mut self) -> BoxFuture<'_, std::io::Result<(Connection, String)>> {
+        Box::pin(async move {
+            match self.incoming.recv().await {
+                Some(stream) => Ok((Box::new(stream) as Connection, String::from("pipe"))),
+                None => Err(std::io::ErrorKind::NotConnected.into()),
+            }
+        })
+    }
+}
+
+/// Starts a server serving in-memory pipes, the returned function opens a new pipe to it.
+fn start_pipe_server() -> impl Fn() -> DuplexStream {
+    let (sender, incoming) = tokio::sync::mpsc::unbounded_channel();
+    let listener = Box::new(PipeListener { incoming });
+    tokio::spawn(kvstore::server::run_with_env(
+        Env::system(),
+        listener,
+        ServerOptions::default(),
+    ));
+    move || {
+        let (client, server) = tokio::io::duplex(64);
+        sender.send(server).unwrap();
+        client
+    }
+}
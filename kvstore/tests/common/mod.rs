@@ -0,0 +1,40 @@
+//! Helpers shared by the integration tests driving a server from blocking code, each test crate
+//! only uses some of them.
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+use kvstore::server::{Endpoint, ServerOptions};
+
+/// Binds a listener on a free port for the server to take over, along with its address.
+///
+/// The port is held from now on, so the server can't lose it to another test, and the connections
+/// made before the server starts wait in the backlog instead of being refused.
+pub fn bind() -> (SocketAddr, Endpoint) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    (addr, Endpoint::listener(listener))
+}
+
+/// Starts a server on its own runtime, in a background thread, and returns the address of its
+/// native listener.
+///
+/// The listeners are bound before the server starts, so there is nothing to wait for. If the server
+/// fails, its listeners are closed along with its runtime, and the clients get an error instead of
+/// waiting forever.
+pub fn start_server(options: ServerOptions) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                kvstore::server::run_with_options(listener, options).await
+            })
+            .unwrap();
+    });
+    addr
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use kvstore::server::ServerOptions;
+use kvstore::snapshot;
+
+mod common;
+
+#[test]
+fn test_export_snapshot() {
+    let path = temp_file("snapshot");
+    let entries = [("user:2", "b"), ("other", "x"), ("user:1", "a,\"quoted\"")];
+    let entries: Vec<(String, String)> = entries
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    snapshot::write(&path, entries.iter().map(|(k, v)| (k, v))).unwrap();
+
+    let output = dump(&[
+        "export",
+        "--snapshot",
+        path.to_str().unwrap(),
+        "--prefix",
+        "user:",
+        "--format",
+        "csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "key,value\nuser:1,\"a,\"\"quoted\"\"\"\nuser:2,b\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Exported 2 keys\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_import_export_server() {
+    let addr = common::start_server(ServerOptions::default()).to_string();
+    let input = temp_file("import.jsonl");
+    let mut lines = String::new();
+    for i in 0..5 {
+        lines.push_str(&format!("{{\"key\":\"key{}\",\"value\":\"{}\"}}\n", i, i));
+    }
+    // the base64 values are decoded on import.
+    lines.push_str("{\"key\":\"binary\",\"value_base64\":\"dmFsdWU=\"}\n");
+    std::fs::write(&input, lines).unwrap();
+
+    let output = dump(&[
+        "import",
+        "--addr",
+        &addr,
+        "--input",
+        input.to_str().unwrap(),
+        "--batch-size",
+        "2",
+    ]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .ends_with("Imported 6 keys\n"));
+
+    let output = dump(&["export", "--addr", &addr, "--prefix", "b", "--base64"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "{\"key\":\"binary\",\"value_base64\":\"dmFsdWU=\"}\n"
+    );
+    let output = dump(&["export", "--addr", &addr, "--prefix", "key"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 5);
+    std::fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_invalid_arguments() {
+    let output = dump(&["export"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Either --addr or --snapshot is required"));
+    assert!(!dump(&["import", "--batch-size", "x"]).status.success());
+    assert!(!dump(&["import", "--batch-size", "0"]).status.success());
+    assert!(!dump(&["unknown"]).status.success());
+}
+
+fn dump(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kvstore-dump"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("kvstore-dump-{}-{}", name, std::process::id()))
+}
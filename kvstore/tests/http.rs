@@ -0,0 +1,231 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use kvstore::acl::{self, Acl};
+use kvstore::server::ServerOptions;
+
+mod common;
+
+#[test]
+fn test_keys() {
+    let (addr, native) = start_server(ServerOptions::default());
+    let mut con = Connection::open(addr);
+
+    let (status, body) = con.send("GET /keys/missing HTTP/1.1\r\n\r\n");
+    assert_eq!((status, body.as_str()), (404, "Key not found\n"));
+    let (status, _) = con.send("PUT /keys/a%2Fb HTTP/1.1\r\nContent-Length: 5\r\n\r\nvalue");
+    assert_eq!(status, 204);
+    let (status, body) = con.send("GET /keys/a%2Fb HTTP/1.1\r\n\r\n");
+    assert_eq!((status, body.as_str()), (200, "value"));
+
+    let request = "PUT /keys/json HTTP/1.1\r\nContent-Type: application/json\r\n\
+        Content-Length: 17\r\n\r\n{\"value\": \"v\\n1\"}";
+    assert_eq!(con.send(request).0, 204);
+    let (status, body) = con.send("GET /keys/json HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"{"key":"json","value":"v\n1"}"#);
+
+    assert_eq!(con.send("DELETE /keys/json HTTP/1.1\r\n\r\n").0, 204);
+    assert_eq!(con.send("DELETE /keys/json HTTP/1.1\r\n\r\n").0, 404);
+    let (status, _) = con.send("PUT /keys/empty HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(status, 204);
+    assert_eq!(con.send("DELETE /keys/empty HTTP/1.1\r\n\r\n").0, 204);
+    assert_eq!(con.send("POST /keys/json HTTP/1.1\r\n\r\n").0, 405);
+    assert_eq!(con.send("GET /other HTTP/1.1\r\n\r\n").0, 404);
+
+    // the keys are shared with the clients of the native protocol.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let native = runtime.block_on(async {
+        let mut client = kvstore::create(native).await.unwrap();
+        client.get(String::from("a/b")).await.unwrap()
+    });
+    assert_eq!(native, Some(kvstore::Response::Ok(String::from("value"))));
+}
+
+#[test]
+fn test_scan() {
+    let (addr, _) = start_server(ServerOptions::default());
+    let mut con = Connection::open(addr);
+    for key in ["user:1", "user:2", "user:3", "other"] {
+        let request = format!("PUT /keys/{} HTTP/1.1\r\nContent-Length: 1\r\n\r\nv", key);
+        assert_eq!(con.send(&request).0, 204);
+    }
+
+    let (status, body) = con.send("GET /keys?prefix=user%3A&limit=2 HTTP/1.1\r\n\r\n");
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        r#"{"entries":[{"key":"user:1","value":"v"},{"key":"user:2","value":"v"}],"next":"user:2"}"#
+    );
+    let (_, body) = con.send("GET /keys?prefix=user:&limit=2&after=user:2 HTTP/1.1\r\n\r\n");
+    assert_eq!(
+        body,
+        r#"{"entries":[{"key":"user:3","value":"v"}],"next":null}"#
+    );
+    assert_eq!(con.send("GET /keys?limit=5000 HTTP/1.1\r\n\r\n").0, 400);
+}
+
+#[test]
+fn test_basic_authentication() {
+    let hash = acl::hash_password_with_iterations("secret", 1000);
+    let config = format!("app {} +@read +@write ~app:*", hash);
+    let options = ServerOptions {
+        acl: Some(Acl::parse(&config).unwrap()),
+        ..Default::default()
+    };
+    let (addr, _) = start_server(options);
+    let mut con = Connection::open(addr);
+
+    // app:secret and app:wrong
+    let authorized = "Authorization: Basic YXBwOnNlY3JldA==\r\n";
+    let wrong = "Authorization: Basic YXBwOndyb25n\r\n";
+    let (status, body) = con.send("GET /keys/app:key HTTP/1.1\r\n\r\n");
+    assert_eq!((status, body.as_str()), (401, "Authentication required\n"));
+    let request = format!("GET /keys/app:key HTTP/1.1\r\n{}\r\n", wrong);
+    assert_eq!(con.send(&request).0, 401);
+
+    let request = format!(
+        "PUT /keys/app:key HTTP/1.1\r\n{}Content-Length: 1\r\n\r\nv",
+        authorized
+    );
+    assert_eq!(con.send(&request).0, 204);
+    let request = format!("GET /keys/app:key HTTP/1.1\r\n{}\r\n", authorized);
+    assert_eq!(con.send(&request), (200, String::from("v")));
+    let request = format!("GET /keys/other HTTP/1.1\r\n{}\r\n", authorized);
+    assert_eq!(con.send(&request).0, 403);
+
+    // the requests without credentials don't reuse the ones of the previous requests.
+    assert_eq!(con.send("GET /keys/app:key HTTP/1.1\r\n\r\n").0, 401);
+}
+
+#[test]
+fn test_connection_close() {
+    let (addr, _) = start_server(ServerOptions::default());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"PUT /keys/k HTTP/1.1\r\nContent-Length: 1\r\n\r\nv\
+            GET /keys/k HTTP/1.1\r\nConnection: close\r\n\r\n\
+            GET /keys/k HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses).unwrap();
+    assert_eq!(
+        responses,
+        "HTTP/1.1 204 No Content\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 1\r\n\
+         Connection: close\r\n\r\nv"
+    );
+}
+
+#[test]
+fn test_body_limit() {
+    let options = ServerOptions {
+        http_max_body: Some(8),
+        ..Default::default()
+    };
+    let (addr, _) = start_server(options);
+    let mut con = Connection::open(addr);
+    let (status, _) = con.send("PUT /keys/key HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678");
+    assert_eq!(status, 204);
+
+    // the body is refused before it's received.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"PUT /keys/key HTTP/1.1\r\nContent-Length: 9\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+}
+
+#[test]
+fn test_metrics() {
+    let (metrics, endpoint) = common::bind();
+    let options = ServerOptions {
+        metrics: Some(endpoint),
+        ..Default::default()
+    };
+    let (addr, _) = start_server(options);
+    let mut con = Connection::open(addr);
+    con.send("PUT /keys/key HTTP/1.1\r\nContent-Length: 5\r\n\r\nvalue");
+    con.send("GET /keys/key HTTP/1.1\r\n\r\n");
+    con.send("GET /keys/missing HTTP/1.1\r\n\r\n");
+
+    let mut con = Connection::open(metrics);
+    let (status, body) = con.send("GET /metrics HTTP/1.1\r\n\r\n");
+    assert_eq!(status, 200);
+    for line in [
+        "kvstore_keys 1",
+        "kvstore_storage_bytes 8",
+        "kvstore_command_duration_seconds_count{command=\"GET\"} 2",
+        "kvstore_command_duration_seconds_count{command=\"SET\"} 1",
+        // a missing key is not an error.
+        "kvstore_errors_total{kind=\"command\"} 0",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{} missing from\n{}",
+            line,
+            body
+        );
+    }
+    // the connections waiting for the listeners to be bound are counted too.
+    assert!(body.contains("kvstore_connections_total{protocol=\"metrics\"} "));
+    assert_eq!(con.send("GET /keys/key HTTP/1.1\r\n\r\n").0, 404);
+}
+
+/// A keep-alive connection to the HTTP listener.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Connection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// Sends the raw request, and returns the status and the body of its response.
+    fn send(&mut self, request: &str) -> (u16, String) {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).unwrap();
+        (status, String::from_utf8(body).unwrap())
+    }
+}
+
+/// Starts a server, and returns the address of its HTTP listener, followed by the one of its native
+/// listener.
+fn start_server(options: ServerOptions) -> (SocketAddr, SocketAddr) {
+    let (addr, http) = common::bind();
+    let options = ServerOptions {
+        http: Some(http),
+        ..options
+    };
+    (addr, common::start_server(options))
+}
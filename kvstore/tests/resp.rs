@@ -0,0 +1,132 @@
+use redis::{Commands, ErrorKind, RedisError, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use kvstore::acl::{self, Acl};
+use kvstore::server::ServerOptions;
+
+mod common;
+
+#[test]
+fn test_redis_client() {
+    let (addr, native) = start_server(ServerOptions::default());
+    let mut con = connect(&format!("redis://{}/", addr)).unwrap();
+
+    let pong: String = redis::cmd("PING").query(&mut con).unwrap();
+    assert_eq!(pong, "PONG");
+    let echo: String = redis::cmd("ECHO").arg("hello").query(&mut con).unwrap();
+    assert_eq!(echo, "hello");
+
+    let _: () = con.set("key", "value").unwrap();
+    let value: Option<String> = con.get("key").unwrap();
+    assert_eq!(value, Some(String::from("value")));
+    let value: Option<String> = con.get("missing").unwrap();
+    assert_eq!(value, None);
+
+    let _: () = con.mset(&[("a", "1"), ("b", "2")]).unwrap();
+    let values: Vec<Option<String>> = con.mget(&["a", "missing", "b"]).unwrap();
+    assert_eq!(values, vec![Some("1".into()), None, Some("2".into())]);
+    let exists: u32 = con.exists(&["a", "b", "missing"]).unwrap();
+    assert_eq!(exists, 2);
+    let _: () = con.set("empty", "").unwrap();
+    let deleted: u32 = con.del(&["a", "missing", "empty"]).unwrap();
+    assert_eq!(deleted, 2);
+    assert!(!con.exists::<_, bool>("a").unwrap());
+
+    let err: RedisError = redis::cmd("FLUSHALL").query::<()>(&mut con).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ResponseError);
+
+    // the keys are shared with the clients of the native protocol.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let native = runtime.block_on(async {
+        let mut client = kvstore::create(native).await.unwrap();
+        client.get(String::from("key")).await.unwrap()
+    });
+    assert_eq!(native, Some(kvstore::Response::Ok(String::from("value"))));
+}
+
+#[test]
+fn test_resp3_and_acl() {
+    let hash = acl::hash_password_with_iterations("secret", 1000);
+    let config = format!("app {} +@read +@write ~app:*", hash);
+    let options = ServerOptions {
+        acl: Some(Acl::parse(&config).unwrap()),
+        ..Default::default()
+    };
+    let (addr, _) = start_server(options);
+
+    let err = connect(&format!("redis://app:wrong@{}/?protocol=resp3", addr))
+        .err()
+        .unwrap();
+    assert_eq!(err.code(), Some("WRONGPASS"));
+
+    let mut con = connect(&format!("redis://app:secret@{}/?protocol=resp3", addr)).unwrap();
+    let _: () = con.set("app:key", "value").unwrap();
+    let value: Value = con.get("app:missing").unwrap();
+    assert_eq!(value, Value::Nil);
+    let err = con.set::<_, _, ()>("other", "value").unwrap_err();
+    assert_eq!(err.code(), Some("NOPERM"));
+    let err = con.publish::<_, _, u32>("news", "hello").unwrap_err();
+    assert_eq!(err.code(), Some("NOPERM"));
+}
+
+#[test]
+fn test_publish_subscribe() {
+    let (addr, _) = start_server(ServerOptions::default());
+    let mut subscriber = connect(&format!("redis://{}/", addr)).unwrap();
+    let mut subscriber = subscriber.as_pubsub();
+    subscriber.subscribe("news").unwrap();
+    subscriber.psubscribe("sport.*").unwrap();
+
+    let mut publisher = connect(&format!("redis://{}/", addr)).unwrap();
+    let receivers: u32 = publisher.publish("news", "hello").unwrap();
+    assert_eq!(receivers, 1);
+    let _: u32 = publisher.publish("sport.tennis", "ace").unwrap();
+
+    let message = subscriber.get_message().unwrap();
+    assert_eq!(message.get_channel_name(), "news");
+    assert_eq!(message.get_payload::<String>().unwrap(), "hello");
+    let message = subscriber.get_message().unwrap();
+    assert_eq!(message.get_channel_name(), "sport.tennis");
+
+    subscriber.unsubscribe("news").unwrap();
+    subscriber.punsubscribe("sport.*").unwrap();
+}
+
+#[test]
+fn test_inline_and_pipelined_requests() {
+    let (addr, _) = start_server(ServerOptions::default());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"PING\r\nSET key value\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\nGET missing\nQUIT\r\n",
+        )
+        .unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "+PONG\r\n+OK\r\n$5\r\nvalue\r\n$-1\r\n+OK\r\n");
+
+    // a malformed request closes the connection.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"*1\r\n$x\r\n").unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert!(replies.starts_with("-ERR Protocol error"));
+}
+
+fn connect(url: &str) -> redis::RedisResult<redis::Connection> {
+    let client = redis::Client::open(url)?;
+    client.get_connection_with_timeout(Duration::from_secs(5))
+}
+
+/// Starts a server, and returns the address of its Redis compatible listener, followed by the one
+/// of its native listener.
+fn start_server(options: ServerOptions) -> (SocketAddr, SocketAddr) {
+    let (addr, resp) = common::bind();
+    let options = ServerOptions {
+        resp: Some(resp),
+        ..options
+    };
+    (addr, common::start_server(options))
+}
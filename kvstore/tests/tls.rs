@@ -0,0 +1,243 @@
+#![cfg(feature = "tls")]
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+use kvstore::cluster::{AntiEntropyOptions, ClusterOptions, VectorClock};
+use kvstore::protocol::Response;
+use kvstore::server::ServerOptions;
+use kvstore::tls::{ClientTlsOptions, ServerTlsOptions, TlsAcceptor, TlsConnector};
+use kvstore::{Client, ClientOptions, Result, RetryPolicy};
+
+mod common;
+
+#[tokio::test]
+async fn test_tls_connections() {
+    let pki = Pki::generate("connections");
+    let acceptor = TlsAcceptor::new(pki.server_options()).unwrap();
+    let addr = start_server(acceptor);
+
+    let mut client = create(addr, Some(pki.client_options())).await.unwrap();
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+
+    // the server does not talk to the clients in plain text.
+    assert!(ping(addr, None).await.is_err());
+
+    // nor do the clients to a server whose certificate they don't trust.
+    let other = Pki::generate("connections-other");
+    assert!(ping(addr, Some(other.client_options())).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    let pki = Pki::generate("mutual");
+    let options = ServerTlsOptions {
+        client_ca: Some(pki.path("ca.pem")),
+        ..pki.server_options()
+    };
+    let addr = start_server(TlsAcceptor::new(options).unwrap());
+
+    assert!(ping(addr, Some(pki.client_options())).await.is_err());
+
+    let options = ClientTlsOptions {
+        identity: Some((pki.path("client.pem"), pki.path("client.key"))),
+        ..pki.client_options()
+    };
+    assert!(ping(addr, Some(options)).await.is_ok());
+
+    // the certificate of a client must be signed by the expected authority.
+    let other = Pki::generate("mutual-other");
+    let options = ClientTlsOptions {
+        identity: Some((other.path("client.pem"), other.path("client.key"))),
+        ..pki.client_options()
+    };
+    assert!(ping(addr, Some(options)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tls_reload() {
+    let pki = Pki::generate("reload");
+    let acceptor = TlsAcceptor::new(pki.server_options()).unwrap();
+    let addr = start_server(acceptor.clone());
+    let mut client = create(addr, Some(pki.client_options())).await.unwrap();
+
+    let rotated = Pki::generate("reload-rotated");
+    assert!(ping(addr, Some(rotated.client_options())).await.is_err());
+    for file in &["server.pem", "server.key"] {
+        std::fs::copy(rotated.path(file), pki.path(file)).unwrap();
+    }
+    acceptor.reload().unwrap();
+    assert!(ping(addr, Some(rotated.client_options())).await.is_ok());
+    assert!(ping(addr, Some(pki.client_options())).await.is_err());
+
+    // the connections opened before the reload keep working.
+    let res = client.ping(String::from("")).await.unwrap();
+    assert_eq!(res, Some(Response::Ok(String::from("PONG"))));
+
+    // a failed reload keeps the current certificates.
+    std::fs::write(pki.path("server.pem"), "").unwrap();
+    assert!(acceptor.reload().is_err());
+    assert!(ping(addr, Some(rotated.client_options())).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_cluster_over_tls() {
+    let pki = Pki::generate("cluster");
+    let mut listeners = vec![];
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    // the nodes only accept the peers presenting a certificate of the cluster.
+    let server = ServerTlsOptions {
+        client_ca: Some(pki.path("ca.pem")),
+        ..pki.server_options()
+    };
+    let peer = ClientTlsOptions {
+        identity: Some((pki.path("server.pem"), pki.path("server.key"))),
+        ..pki.client_options()
+    };
+    let start_node = |listener: TcpListener| {
+        let node = listener.local_addr().unwrap().to_string();
+        let peers = addrs
+            .iter()
+            .filter(|addr| **addr != node)
+            .cloned()
+            .collect();
+        let options = ServerOptions {
+            cluster: Some(ClusterOptions {
+                anti_entropy: AntiEntropyOptions {
+                    interval: Some(std::time::Duration::from_millis(20)),
+                    ..Default::default()
+                },
+                tls: Some(TlsConnector::new(&peer).unwrap()),
+                ..ClusterOptions::new(node, peers)
+            }),
+            tls: Some(TlsAcceptor::new(server.clone()).unwrap()),
+            ..Default::default()
+        };
+        tokio::spawn(kvstore::server::run_with_options(listener, options));
+    };
+    // the last node is down while the key is written, and catches up with anti-entropy.
+    let last = listeners.pop().unwrap();
+    for listener in listeners {
+        start_node(listener);
+    }
+
+    let client = ClientTlsOptions {
+        identity: Some((pki.path("client.pem"), pki.path("client.key"))),
+        ..pki.client_options()
+    };
+    let mut first = create(addrs[0].parse().unwrap(), Some(client.clone()))
+        .await
+        .unwrap();
+    let res = first
+        .replicated_set(
+            String::from("key"),
+            String::from("value"),
+            VectorClock::new(),
+            2,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(res, Some(Response::Versions(_))));
+
+    start_node(last);
+    let mut stale = create(addrs[2].parse().unwrap(), Some(client))
+        .await
+        .unwrap();
+    for _ in 0..250 {
+        let res = stale.replicated_get(String::from("key"), 1).await.unwrap();
+        if let Some(Response::Versions(versions)) = res {
+            if !versions.is_empty() {
+                return;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("The node did not catch up");
+}
+
+/// A certificate authority, along with a server and a client certificate it signed, written to a
+/// temporary directory.
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn generate(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("kvstore-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+        for (file, name) in &[("server", "localhost"), ("client", "client")] {
+            let cert = Certificate::from_params(CertificateParams::new(vec![name.to_string()]));
+            let cert = cert.unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", file)), pem).unwrap();
+            let key = cert.serialize_private_key_pem();
+            std::fs::write(dir.join(format!("{}.key", file)), key).unwrap();
+        }
+        Pki { dir }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn server_options(&self) -> ServerTlsOptions {
+        ServerTlsOptions::new(self.path("server.pem"), self.path("server.key"))
+    }
+
+    fn client_options(&self) -> ClientTlsOptions {
+        ClientTlsOptions::new(self.path("ca.pem"), "localhost")
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn start_server(acceptor: TlsAcceptor) -> SocketAddr {
+    common::start_server(ServerOptions {
+        tls: Some(acceptor),
+        ..Default::default()
+    })
+}
+
+/// Creates a client that does not retry, with TLS if the options are given.
+async fn create(addr: SocketAddr, tls: Option<ClientTlsOptions>) -> Result<Client> {
+    let tls = match tls {
+        Some(options) => Some(TlsConnector::new(&options)?),
+        None => None,
+    };
+    let options = ClientOptions {
+        retry: RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        },
+        tls,
+        ..Default::default()
+    };
+    kvstore::client::create_with_options(addr, options).await
+}
+
+/// Connects to the server and sends a PING. With TLS 1.3, a client certificate the server refuses
+/// only fails the first read, after the handshake completed on the client's side.
+async fn ping(addr: SocketAddr, tls: Option<ClientTlsOptions>) -> Result<()> {
+    let mut client = create(addr, tls).await?;
+    match client.ping(String::from("")).await? {
+        Some(Response::Ok(_)) => Ok(()),
+        res => Err(format!("Unexpected response {:?}", res).into()),
+    }
+}